@@ -1,87 +1,151 @@
-(async () => {
-  try {
-    console.log("🚀 Fetching and instantiating WASM...");
-
-    const response = await fetch("ui.wasm");
-    if (!response.ok) {
-      throw new Error(`❌ Failed to fetch WASM: ${response.statusText}`);
-    }
-
-    const wasm = await WebAssembly.instantiateStreaming(response, {
-      env: {
-        memory: new WebAssembly.Memory({ initial: 10 }),
-
-        drawRect: (x, y, w, h) => {
-          console.log(`🟥 drawRect called with x=${x}, y=${y}, w=${w}, h=${h}`);
-
-          const canvas = document.getElementById("screen");
-          if (!canvas) {
-            console.error("❌ Canvas element with id='screen' not found.");
-            return;
-          }
-
-          const ctx = canvas.getContext("2d");
-          if (!ctx) {
-            console.error("❌ Failed to get 2D context from canvas.");
-            return;
-          }
-
-          ctx.lineWidth = 4;
-          ctx.strokeStyle = "red";
-          ctx.fillStyle = "rgba(255, 0, 0, 0.4)";
-
-          ctx.beginPath();
-          ctx.rect(x, y, w, h);
-          ctx.fill();
-          ctx.stroke();
-        },
-
-        drawText: (x, y, ptr, len) => {
-          console.log(`🔤 drawText called at x=${x}, y=${y}, ptr=${ptr}, len=${len}`);
-
-          const memory = wasm.instance.exports.memory;
-          if (!memory) {
-            console.error("❌ WASM memory not exported.");
-            return;
-          }
-
-          const bytes = new Uint8Array(memory.buffer, ptr, len);
-          const str = new TextDecoder().decode(bytes);
-          console.log(`🔤 Text to draw: "${str}"`);
+const rgba = (color) => {
+  const c = color >>> 0;
+  return `rgba(${c >>> 24}, ${(c >>> 16) & 0xFF}, ${(c >>> 8) & 0xFF}, ${(c & 0xFF) / 255})`;
+};
+// A text's font is `family | size << 8`, family indexing FONTS
+const FONTS = ["sans-serif", "serif", "monospace"];
+const cssFont = (font) => `${font >> 8}px ${FONTS[font & 0xFF] ?? FONTS[0]}`;
+// Colors are 0xRRGGBBAA (0 = none), opacity a percentage
+const paint = (trace, fill, stroke, strokeWidth, opacity) => {
+  const ctx = document.getElementById("screen").getContext("2d");
+  ctx.globalAlpha = opacity / 100;
+  ctx.beginPath();
+  trace(ctx);
+  if (fill) {
+    ctx.fillStyle = rgba(fill);
+    ctx.fill();
+  }
+  if (stroke && strokeWidth) {
+    ctx.strokeStyle = rgba(stroke);
+    ctx.lineWidth = strokeWidth;
+    ctx.stroke();
+  }
+  ctx.globalAlpha = 1;
+};
+// Images load once; one drawn before it arrives appears when it does
+const images = new Map();
 
-          const canvas = document.getElementById("screen");
-          if (!canvas) {
-            console.error("❌ Canvas element with id='screen' not found.");
-            return;
-          }
+// The module imports its memory; strings and buffers it hands over are read from here
+const memory = new WebAssembly.Memory({ initial: 10 });
+const decode = (ptr, len) => new TextDecoder().decode(new Uint8Array(memory.buffer, ptr, len));
+// Bump allocator as in runtime.js: an 8-byte [type][mark] header ahead of each object, never freed
+const HEADER_SIZE = 8;
+let nextAlloc = 1024;
+const gc_alloc = (size, type) => {
+  const base = nextAlloc;
+  const end = base + HEADER_SIZE + size;
+  if (end > memory.buffer.byteLength) memory.grow(Math.ceil((end - memory.buffer.byteLength) / 65536));
+  const header = new DataView(memory.buffer, base, HEADER_SIZE);
+  header.setUint32(0, type, true);
+  header.setUint32(4, 1, true); // mark bit
+  nextAlloc = (end + 7) & ~7;
+  return base + HEADER_SIZE;
+};
+// Copies bytes into a GC buffer laid out as [len: u32][bytes] and returns its pointer
+const allocBytes = (bytes) => {
+  const ptr = gc_alloc(bytes.length + 4, 7);
+  new DataView(memory.buffer).setUint32(ptr, bytes.length, true);
+  new Uint8Array(memory.buffer).set(bytes, ptr + 4);
+  return ptr;
+};
 
-          const ctx = canvas.getContext("2d");
-          if (!ctx) {
-            console.error("❌ Failed to get 2D context from canvas.");
-            return;
+(async () => {
+  const wasm = await WebAssembly.instantiateStreaming(fetch("ui.wasm"), {
+    env: {
+      memory,
+      drawRect: (x, y, w, h, fill, stroke, strokeWidth, radius, opacity) =>
+        paint((ctx) => ctx.roundRect(x, y, w, h, radius), fill, stroke, strokeWidth, opacity),
+      drawText: (x, y, ptr, len, color, font, opacity) => {
+        const str = decode(ptr, len);
+        const ctx = document.getElementById("screen").getContext("2d");
+        ctx.fillStyle = rgba(color);
+        ctx.font = cssFont(font);
+        ctx.globalAlpha = opacity / 100;
+        ctx.fillText(str, x, y);
+        ctx.globalAlpha = 1;
+      },
+      // Width of the UTF-8 text in font `font` (see cssFont), used to lay out groups and lists
+      measureText: (ptr, len, font) => {
+        const ctx = document.getElementById("screen").getContext("2d");
+        ctx.font = cssFont(font);
+        return Math.ceil(ctx.measureText(decode(ptr, len)).width);
+      },
+      drawLine: (x1, y1, x2, y2, stroke, strokeWidth, opacity) =>
+        paint((ctx) => { ctx.moveTo(x1, y1); ctx.lineTo(x2, y2); }, 0, stroke, strokeWidth, opacity),
+      drawCircle: (cx, cy, radius, fill, stroke, strokeWidth, opacity) =>
+        paint((ctx) => ctx.arc(cx, cy, radius, 0, 2 * Math.PI), fill, stroke, strokeWidth, opacity),
+      // The commands (M, L, C, Z) are relative to x, y
+      drawPath: (x, y, ptr, len, fill, stroke, strokeWidth, opacity) => {
+        const commands = decode(ptr, len).split(/[\s,]+/).filter(Boolean);
+        const at = (i) => [x + Number(commands[i]), y + Number(commands[i + 1])];
+        paint((ctx) => {
+          for (let i = 0; i < commands.length; ) {
+            switch (commands[i++]) {
+              case "M": ctx.moveTo(...at(i)); i += 2; break;
+              case "L": ctx.lineTo(...at(i)); i += 2; break;
+              case "C": ctx.bezierCurveTo(...at(i), ...at(i + 2), ...at(i + 4)); i += 6; break;
+              case "Z": ctx.closePath(); break;
+            }
           }
+        }, fill, stroke, strokeWidth, opacity);
+      },
+      drawImage: (x, y, w, h, ptr, len, opacity) => {
+        const src = decode(ptr, len);
+        if (!images.has(src)) {
+          const image = new Image();
+          image.src = src;
+          images.set(src, image);
+        }
+        const image = images.get(src);
+        const draw = () => {
+          const ctx = document.getElementById("screen").getContext("2d");
+          ctx.globalAlpha = opacity / 100;
+          ctx.drawImage(image, x, y, w, h);
+          ctx.globalAlpha = 1;
+        };
+        if (image.complete) draw();
+        else image.addEventListener("load", draw, { once: true });
+      },
+      gc_alloc,
+      add_root: () => {},
+      mark_used: () => {},
+      gc_tick: () => {},
+      // `render()` clears and redraws only what changed; state changes ask for a render on the next frame
+      clearRect: (x, y, w, h) => document.getElementById("screen").getContext("2d").clearRect(x, y, w, h),
+      request_render: () => requestAnimationFrame(() => wasm.instance.exports.render()),
+      // `await sleep(ms)` / `load(path)` / `fetch(url)` → resume(task, result) when done
+      host_await: (op, a, b, task) => {
+        const resume = (value) => wasm.instance.exports.resume(task, value);
+        if (op === 0) {
+          setTimeout(() => resume(0), a);
+          return;
+        }
+        fetch(decode(a, b))
+          .then((res) => res.arrayBuffer())
+          .then((buf) => resume(allocBytes(new Uint8Array(buf))), () => resume(0));
+      },
+    },
+  });
 
-          ctx.fillStyle = "green";
-          ctx.font = "16px sans-serif";
-          ctx.fillText(str, x, y);
-        },
+  // Top-level groups follow the page's canvas size
+  const canvas = document.getElementById("screen");
+  wasm.instance.exports.resize?.(canvas.width, canvas.height);
+  wasm.instance.exports.run(); // run main
 
-        gc_alloc: () => {
-          console.log("🧠 gc_alloc stub called");
-          return 1024;
-        },
-        add_root: () => {
-          console.log("🌱 add_root stub called");
-        },
-        gc_tick: () => {
-          console.log("🧹 gc_tick stub called");
-        },
-      },
+  // Forward canvas input to dispatch_event(kind, x, y, key): 0 = click, 1 = key, 2 = hover
+  const { dispatch_event } = wasm.instance.exports;
+  let pointer = { x: -1, y: -1 }; // key events go to the element under the pointer
+  const track = (event) => {
+    const rect = canvas.getBoundingClientRect();
+    pointer = { x: Math.floor(event.clientX - rect.left), y: Math.floor(event.clientY - rect.top) };
+  };
+  if (dispatch_event) {
+    canvas.addEventListener("click", (event) => { track(event); dispatch_event(0, pointer.x, pointer.y, 0); });
+    canvas.addEventListener("mousemove", (event) => { track(event); dispatch_event(2, pointer.x, pointer.y, 0); });
+    canvas.addEventListener("mouseleave", () => { pointer = { x: -1, y: -1 }; dispatch_event(2, -1, -1, 0); });
+    window.addEventListener("keydown", (event) => {
+      const key = event.key.length === 1 ? event.key.codePointAt(0) : event.keyCode;
+      if (dispatch_event(1, pointer.x, pointer.y, key) === 1) event.preventDefault();
     });
-
-    console.log("✅ WASM instantiated successfully. Calling `run()`...");
-    wasm.instance.exports.run?.();
-  } catch (err) {
-    console.error("🔥 Error during WASM loading or execution:", err);
   }
 })();
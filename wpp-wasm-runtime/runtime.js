@@ -54,19 +54,98 @@ function gc_tick() {
     }
 }
 
+// === Async host operations ===
+// W++ `await` calls host_await(op, a, b, task); we call exports.resume(task, result) when done.
+const ASYNC_SLEEP = 0;
+const ASYNC_LOAD = 1;
+const ASYNC_FETCH = 2;
+
+function readString(ptr, len) {
+    return new TextDecoder("utf-8").decode(heap.subarray(ptr, ptr + len));
+}
+
+// Copies bytes into a GC buffer laid out as [len: u32][bytes] and returns its pointer
+function allocBytes(bytes) {
+    const ptr = gc_alloc(bytes.length + 4, 7);
+    new DataView(memory.buffer).setUint32(ptr, bytes.length, true);
+    heap.set(bytes, ptr + 4);
+    return ptr;
+}
+
+function host_await(op, a, b, task) {
+    console.log(`⏳ host_await(op=${op}, task=${task})`);
+    const resume = (value) => instance.exports.resume(task, value);
+
+    switch (op) {
+        case ASYNC_SLEEP:
+            setTimeout(() => resume(0), a);
+            break;
+        case ASYNC_LOAD:
+        case ASYNC_FETCH:
+            fetch(readString(a, b))
+                .then((res) => res.arrayBuffer())
+                .then((buf) => resume(allocBytes(new Uint8Array(buf))))
+                .catch((err) => {
+                    console.error("❌ host_await fetch failed:", err);
+                    resume(0);
+                });
+            break;
+        default:
+            console.warn(`⚠️ Unknown async op ${op}`);
+            resume(0);
+    }
+}
+
 // === Canvas drawing ===
-function drawRect(x, y, w, h) {
+// Colors arrive as 0xRRGGBBAA in an i32; 0 means "don't paint this"
+function rgba(color) {
+    const c = color >>> 0;
+    return `rgba(${c >>> 24}, ${(c >>> 16) & 0xFF}, ${(c >>> 8) & 0xFF}, ${(c & 0xFF) / 255})`;
+}
+
+// Stroke and fill the shape traced by `trace(ctx)`, `opacity` a percentage
+function paint(trace, fill, stroke, strokeWidth, opacity) {
     const canvas = document.getElementById("screen");
     if (!canvas) return;
     const ctx = canvas.getContext("2d");
 
-    ctx.fillStyle = "blue";
-    ctx.fillRect(x, y, w, h);
+    ctx.globalAlpha = opacity / 100;
+    ctx.beginPath();
+    trace(ctx);
+    if (fill) {
+        ctx.fillStyle = rgba(fill);
+        ctx.fill();
+    }
+    if (stroke && strokeWidth) {
+        ctx.strokeStyle = rgba(stroke);
+        ctx.lineWidth = strokeWidth;
+        ctx.stroke();
+    }
+    ctx.globalAlpha = 1;
+}
 
+function drawRect(x, y, w, h, fill, stroke, strokeWidth, radius, opacity) {
+    paint((ctx) => ctx.roundRect(x, y, w, h, radius), fill, stroke, strokeWidth, opacity);
     console.log(`🟦 drawRect(${x}, ${y}, ${w}, ${h})`);
 }
 
-function drawText(x, y, ptr, len) {
+// Font families a text's `font` refers to by index; `font` is `family | size << 8`
+const FONTS = ["sans-serif", "serif", "monospace"];
+
+function cssFont(font) {
+    return `${font >> 8}px ${FONTS[font & 0xFF] ?? FONTS[0]}`;
+}
+
+// Width in pixels of the UTF-8 text at ptr, so layout at render time fits the real glyphs
+function measureText(ptr, len, font) {
+    const canvas = document.getElementById("screen");
+    if (!canvas) return len * 8;
+    const ctx = canvas.getContext("2d");
+    ctx.font = cssFont(font);
+    return Math.ceil(ctx.measureText(readString(ptr, len)).width);
+}
+
+function drawText(x, y, ptr, len, color, font, opacity) {
     const canvas = document.getElementById("screen");
     if (!canvas) return;
 
@@ -80,13 +159,15 @@ function drawText(x, y, ptr, len) {
     const rawBytes = heap.subarray(ptr, ptr + len);
     const decoded = new TextDecoder("utf-8").decode(rawBytes);
 
-    ctx.fillStyle = "black"; // or even "lime", "cyan", etc.
-    ctx.font = "16px sans-serif";
+    ctx.fillStyle = rgba(color);
+    ctx.font = cssFont(font);
+    ctx.globalAlpha = opacity / 100;
     ctx.textBaseline = "middle";
 
     const metrics = ctx.measureText(decoded);
     const baselineOffset = metrics.actualBoundingBoxAscent / 2;
     ctx.fillText(decoded, x, y + baselineOffset);
+    ctx.globalAlpha = 1;
 console.log(`🧠 Memory ptr=${ptr}, len=${len}`);
 console.log("Heap dump:", [...heap.slice(ptr - 8, ptr + len + 8)]);
 
@@ -97,6 +178,280 @@ console.log("Heap dump:", [...heap.slice(ptr - 8, ptr + len + 8)]);
     console.log("✅ drawText finished");
 }
 
+function drawLine(x1, y1, x2, y2, stroke, strokeWidth, opacity) {
+    paint((ctx) => { ctx.moveTo(x1, y1); ctx.lineTo(x2, y2); }, 0, stroke, strokeWidth, opacity);
+    console.log(`📏 drawLine(${x1}, ${y1}, ${x2}, ${y2})`);
+}
+
+function drawCircle(cx, cy, radius, fill, stroke, strokeWidth, opacity) {
+    paint((ctx) => ctx.arc(cx, cy, radius, 0, 2 * Math.PI), fill, stroke, strokeWidth, opacity);
+    console.log(`⚪ drawCircle(${cx}, ${cy}, ${radius})`);
+}
+
+// The commands (M, L, C, Z) are relative to x, y: an SVG path once translated
+function drawPath(x, y, ptr, len, fill, stroke, strokeWidth, opacity) {
+    const commands = readString(ptr, len).split(/[\s,]+/).filter(Boolean);
+    const at = (i) => [x + Number(commands[i]), y + Number(commands[i + 1])];
+    paint((ctx) => {
+        for (let i = 0; i < commands.length; ) {
+            switch (commands[i++]) {
+                case "M": ctx.moveTo(...at(i)); i += 2; break;
+                case "L": ctx.lineTo(...at(i)); i += 2; break;
+                case "C": ctx.bezierCurveTo(...at(i), ...at(i + 2), ...at(i + 4)); i += 6; break;
+                case "Z": ctx.closePath(); break;
+            }
+        }
+    }, fill, stroke, strokeWidth, opacity);
+    console.log(`✒️ drawPath(${x}, ${y}, "${readString(ptr, len)}")`);
+}
+
+// Images load once; one drawn before it arrives appears when it does
+const images = new Map();
+
+function drawImage(x, y, w, h, ptr, len, opacity) {
+    const canvas = document.getElementById("screen");
+    if (!canvas) return;
+    const ctx = canvas.getContext("2d");
+    const src = readString(ptr, len);
+
+    let image = images.get(src);
+    if (!image) {
+        image = new Image();
+        image.src = src;
+        images.set(src, image);
+    }
+    const draw = () => {
+        ctx.globalAlpha = opacity / 100;
+        ctx.drawImage(image, x, y, w, h);
+        ctx.globalAlpha = 1;
+    };
+    if (image.complete) draw();
+    else image.addEventListener("load", draw, { once: true });
+    console.log(`🖼️ drawImage(${x}, ${y}, ${w}, ${h}, "${src}")`);
+}
+
+// === Re-rendering ===
+// `render()` diffs the new frame against the last one and only clears and
+// redraws the changed region; assigning a `state` variable calls
+// request_render() once, and we redraw on the next frame.
+function clearRect(x, y, w, h) {
+    const canvas = document.getElementById("screen");
+    if (!canvas) return;
+    canvas.getContext("2d").clearRect(x, y, w, h);
+    console.log(`🧽 clearRect(${x}, ${y}, ${w}, ${h})`);
+}
+
+function request_render() {
+    requestAnimationFrame(() => {
+        console.log("🔁 State changed, re-rendering");
+        instance.exports.render();
+        mirrorA11yTree();
+    });
+}
+
+// === Accessibility mirror ===
+// Canvas pixels are invisible to screen readers, so the tree returned by the
+// `a11y_tree` export is rebuilt as transparent DOM nodes laid over the canvas.
+// That tree is everything the program could draw; after every render the frame
+// on screen (the `frame` export) says which of it is drawn, where, how often and
+// what texts bound to variables read.
+const A11Y_TAGS = { list: "ul", listitem: "li", button: "button" };
+const A11Y_CONTAINERS = ["group", "list", "listitem"];
+const NODE_RECORD = 32; // [kind, x, y, a, b, fill, stroke, shape] as i32, see frame.rs
+const KIND_MASK = 7;
+const KIND_TEXT = 1;
+const TAG_SHIFT = 8;    // kind >> TAG_SHIFT = element id + 1, 0 when drawn by a function
+let a11yTree = null;
+
+// Nodes of the frame on screen in paint order, with the element each one draws
+function frameNodes() {
+    const view = new DataView(memory.buffer);
+    const base = instance.exports.frame.value;
+    const nodes = [];
+    for (let index = 0; index < instance.exports.frame_len.value; index++) {
+        const field = (i) => view.getInt32(base + index * NODE_RECORD + 4 * i, true);
+        const node = { id: (field(0) >>> TAG_SHIFT) - 1, index, bounds: { x: field(1), y: field(2), width: field(3), height: field(4) } };
+        if ((field(0) & KIND_MASK) === KIND_TEXT) {
+            const [ptr, len, font] = [field(3), field(4), field(6)];
+            node.text = readString(ptr, len);
+            node.bounds = { x: node.bounds.x, y: node.bounds.y, width: measureText(ptr, len, font), height: font >> 8 };
+        }
+        nodes.push(node);
+    }
+    return nodes;
+}
+
+// Copies of `node` for every time frame nodes `from` to `to` draw it: one per node
+// tagged with its id, each holding what is drawn up to the next. A group laid out
+// while compiling has no node of its own and counts once if anything in it is drawn.
+function realize(node, frame, from, to) {
+    const drawn = frame.filter((at) => at.id === node.id && at.index >= from && at.index < to);
+    if (node.id === null || (drawn.length === 0 && node.children.length > 0)) {
+        const children = node.children.flatMap((child) => realize(child, frame, from, to));
+        if (node.id !== null && children.length === 0) return [];
+        return [{ ...node, children, index: children[0]?.index ?? from }];
+    }
+    return drawn.map((at, i) => {
+        const end = i + 1 < drawn.length ? drawn[i + 1].index : to;
+        const children = node.children.flatMap((child) => realize(child, frame, at.index, end));
+        // Only a container's copy holds what is drawn up to `end`, see adoptUntagged
+        const container = node.children.length > 0 || A11Y_CONTAINERS.includes(node.role);
+        return { ...node, bounds: at.bounds, text: at.text, children, index: at.index, end: container ? end : undefined };
+    });
+}
+
+// Texts a function draws have no element: they go to the innermost container they sit
+// in, the copy drawn right before them when a `for` repeats it
+function adoptUntagged(root, frame) {
+    const inside = ({ x, y, width, height }, at) => at.bounds.x >= x && at.bounds.x < x + width && at.bounds.y >= y && at.bounds.y < y + height;
+    const around = (child, at) => inside(child.bounds, at)
+        && (child.end !== undefined ? child.index <= at.index && at.index < child.end : child.children.length > 0);
+    for (const at of frame.filter((at) => at.id < 0 && at.text !== undefined)) {
+        let parent = root;
+        for (let inner = root; inner; ) {
+            inner = inner.children.find((child) => around(child, at));
+            if (inner) parent = inner;
+        }
+        const text = { role: "text", name: at.text, description: null, bounds: at.bounds, source: null, children: [], index: at.index };
+        const before = parent.children.findIndex((child) => child.index > at.index);
+        parent.children.splice(before < 0 ? parent.children.length : before, 0, text);
+    }
+}
+
+// Names taken from what is drawn, then reading order: depth-first, document order
+function finish(node, order) {
+    node.order = order.next++;
+    node.children.forEach((child) => finish(child, order));
+    if (node.from_content) {
+        const content = (n) => n.role === "text" ? n.name ?? "" : n.children.map(content).filter(Boolean).join(" ");
+        node.name = (node.role === "text" ? node.text : content(node)) || null;
+    }
+    return node;
+}
+
+function a11yFromFrame() {
+    if (!a11yTree) {
+        const ptr = instance.exports.a11y_tree();
+        const len = new DataView(memory.buffer).getUint32(ptr, true);
+        a11yTree = JSON.parse(readString(ptr + 4, len));
+    }
+    const frame = frameNodes();
+    const [root] = realize(a11yTree, frame, 0, frame.length);
+    adoptUntagged(root, frame);
+    if (root.children.length > 0) {
+        const [left, top] = ["x", "y"].map((axis) => Math.min(...root.children.map((child) => child.bounds[axis])));
+        const right = Math.max(...root.children.map(({ bounds }) => bounds.x + bounds.width));
+        const bottom = Math.max(...root.children.map(({ bounds }) => bounds.y + bounds.height));
+        root.bounds = { x: left, y: top, width: right - left, height: bottom - top };
+    }
+    return finish(root, { next: 0 });
+}
+
+function buildA11yNode(node, parentBounds) {
+    const el = document.createElement(A11Y_TAGS[node.role] || "div");
+    if (node.role !== "text") el.setAttribute("role", node.role);
+    if (node.name) {
+        // img ignores its text content, so it is named like a container
+        if (node.children.length === 0 && node.role !== "img") el.textContent = node.name;
+        else el.setAttribute("aria-label", node.name);
+    }
+    if (node.description) el.setAttribute("aria-description", node.description);
+
+    const { x, y, width, height } = node.bounds;
+    Object.assign(el.style, {
+        position: "absolute",
+        left: `${x - parentBounds.x}px`,
+        top: `${y - parentBounds.y}px`,
+        width: `${width}px`,
+        height: `${height}px`,
+        margin: "0",
+        padding: "0",
+        listStyle: "none",
+        color: "transparent",
+        background: "none",
+        border: "none",
+    });
+
+    for (const child of node.children) {
+        el.appendChild(buildA11yNode(child, node.bounds));
+    }
+    return el;
+}
+
+function mirrorA11yTree() {
+    if (!instance?.exports.a11y_tree) return;
+
+    const tree = a11yFromFrame();
+
+    const canvas = document.getElementById("screen");
+    let host = document.getElementById("a11y-mirror");
+    if (!host) {
+        host = document.createElement("div");
+        host.id = "a11y-mirror";
+        host.attachShadow({ mode: "open" });
+        canvas.insertAdjacentElement("afterend", host);
+    }
+    Object.assign(host.style, {
+        position: "absolute",
+        left: `${canvas.offsetLeft}px`,
+        top: `${canvas.offsetTop}px`,
+        width: `${canvas.width}px`,
+        height: `${canvas.height}px`,
+        pointerEvents: "none",
+    });
+
+    host.shadowRoot.replaceChildren(buildA11yNode(tree, { x: 0, y: 0 }));
+    console.log("♿ Mirrored accessibility tree:", tree);
+}
+
+// === Input events: dispatch_event(kind, x, y, key) hit-tests and calls the W++ on_* handler ===
+const EVENT_CLICK = 0;
+const EVENT_KEY = 1;
+const EVENT_HOVER = 2;
+let semanticMap = null;
+let pointer = { x: -1, y: -1 }; // key events go to the element under the pointer
+
+function canvasPoint(canvas, event) {
+    const rect = canvas.getBoundingClientRect();
+    return {
+        x: Math.floor(event.clientX - rect.left - canvas.clientLeft),
+        y: Math.floor(event.clientY - rect.top - canvas.clientTop),
+    };
+}
+
+function setupEvents() {
+    if (!instance?.exports.hit_test) return;
+
+    fetch("ui.wpp.map.json?cachebust=" + Date.now())
+        .then((response) => response.json())
+        .then((map) => { semanticMap = map; })
+        .catch(() => console.warn("⚠️ No ui.wpp.map.json; clicks report element ids only"));
+
+    const canvas = document.getElementById("screen");
+    const dispatch = (kind, key = 0) => instance.exports.dispatch_event?.(kind, pointer.x, pointer.y, key) === 1;
+
+    canvas.addEventListener("click", (event) => {
+        pointer = canvasPoint(canvas, event);
+        const id = instance.exports.hit_test(pointer.x, pointer.y);
+        const element = semanticMap?.elements[id];
+        const where = element?.source ? `${element.source.file}:${element.source.line}:${element.source.column}` : "";
+        const handled = dispatch(EVENT_CLICK);
+        if (id >= 0) console.log(`🎯 Clicked element ${id} at (${pointer.x}, ${pointer.y})`, element ? `${element.kind} ${where}` : "", handled ? "→ handled" : "");
+    });
+    canvas.addEventListener("mousemove", (event) => {
+        pointer = canvasPoint(canvas, event);
+        dispatch(EVENT_HOVER);
+    });
+    canvas.addEventListener("mouseleave", () => {
+        pointer = { x: -1, y: -1 };
+        dispatch(EVENT_HOVER); // resets the hovered element
+    });
+    window.addEventListener("keydown", (event) => {
+        const key = event.key.length === 1 ? event.key.codePointAt(0) : event.keyCode;
+        if (dispatch(EVENT_KEY, key)) event.preventDefault();
+    });
+}
+
 // === Entry Point ===
 async function runWasm() {
     try {
@@ -113,16 +468,30 @@ async function runWasm() {
                 add_root,
                 mark_used,
                 gc_tick,
+                host_await,
                 drawRect,
                 drawText,
+                measureText,
+                clearRect,
+                drawLine,
+                drawCircle,
+                drawPath,
+                drawImage,
+                request_render,
             },
         });
 
         instance = result.instance;
         heap = new Uint8Array(memory.buffer); // ensure heap points to final buffer
 
+        // Top-level groups follow the canvas the page actually has, not the one compiled in
+        const screen = document.getElementById("screen");
+        if (screen) instance.exports.resize?.(screen.width, screen.height);
+
         console.log("🚀 Running WASM program...");
         instance.exports.run();
+        mirrorA11yTree();
+        setupEvents();
     } catch (err) {
         console.error("❌ Failed to run W++ WASM:", err);
     }
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use crate::map::SourceLocation;
+use crate::parser::A11yAttrs;
+
+/// Roles a user can activate; they need an accessible name
+const INTERACTIVE_ROLES: &[&str] = &["button", "link", "checkbox", "switch", "tab", "menuitem", "textbox"];
+/// Roles that take their name from the text inside them when unlabelled, as in HTML
+const NAME_FROM_CONTENT: &[&str] = &["button", "link", "tab", "menuitem", "listitem"];
+
+/// Canvas-space rectangle, in pixels
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Bounds {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Bounds {
+    /// Smallest rectangle covering every rect in `rects`, or an empty one at 0,0
+    pub fn union(rects: impl IntoIterator<Item = Bounds>) -> Bounds {
+        let mut rects = rects.into_iter();
+        let Some(first) = rects.next() else {
+            return Bounds::default();
+        };
+        let (mut left, mut top) = (first.x, first.y);
+        let (mut right, mut bottom) = (first.x + first.width, first.y + first.height);
+        for r in rects {
+            left = left.min(r.x);
+            top = top.min(r.y);
+            right = right.max(r.x + r.width);
+            bottom = bottom.max(r.y + r.height);
+        }
+        Bounds { x: left, y: top, width: right - left, height: bottom - top }
+    }
+}
+
+/// One node of the accessibility tree written to `ui.wpp.a11y.json`
+/// and handed to the host by the `a11y_tree` export.
+#[derive(Serialize)]
+pub struct A11yNode {
+    pub id: Option<u32>,      // semantic map element, whose frame nodes tell the host where and whether it is drawn
+    pub role: String,         // document, group, list, listitem, text, button
+    pub name: Option<String>, // accessible name, e.g. the text a node shows
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub from_content: bool,   // `name` is the text drawn inside, which the host reads from the frame on screen
+    pub description: Option<String>,
+    pub order: u32,           // reading order: depth-first, document order
+    pub bounds: Bounds,
+    pub source: Option<SourceLocation>,
+    pub children: Vec<A11yNode>,
+}
+
+impl A11yNode {
+    pub fn new(role: &str, name: Option<String>, bounds: Bounds, source: Option<SourceLocation>) -> Self {
+        A11yNode { id: None, role: role.to_string(), name, from_content: false, description: None, order: 0, bounds, source, children: vec![] }
+    }
+
+    fn text_content(&self) -> String {
+        if self.role == "text" {
+            return self.name.clone().unwrap_or_default();
+        }
+        let parts: Vec<String> = self.children.iter().map(A11yNode::text_content).filter(|t| !t.is_empty()).collect();
+        parts.join(" ")
+    }
+}
+
+/// Node for layout element `id`, or `None` when it is `hidden: true` or a purely
+/// decorative box (no role, label or description).
+pub fn element(
+    id: u32,
+    default_role: Option<&str>,
+    content_name: Option<String>,
+    attrs: &A11yAttrs,
+    bounds: Bounds,
+    source: Option<SourceLocation>,
+    children: Vec<A11yNode>,
+) -> Option<A11yNode> {
+    if attrs.hidden {
+        return None;
+    }
+    let described = attrs.label.is_some() || attrs.description.is_some();
+    let role = match (&attrs.role, default_role) {
+        (Some(role), _) => role.as_str(),
+        (None, Some(role)) => role,
+        (None, None) if described => "img", // a labelled shape
+        (None, None) => return None,
+    };
+
+    let from_content = attrs.label.is_none() && content_name.is_none() && (role == "text" || NAME_FROM_CONTENT.contains(&role));
+    let mut node = A11yNode::new(role, attrs.label.clone().or(content_name), bounds, source);
+    node.id = Some(id);
+    node.from_content = from_content;
+    node.description = attrs.description.clone();
+    node.children = children;
+    if node.name.is_none() && NAME_FROM_CONTENT.contains(&role) {
+        node.name = Some(node.text_content()).filter(|text| !text.is_empty());
+    }
+    Some(node)
+}
+
+/// Wraps the top-level nodes in a `document` root and numbers the reading order.
+pub fn document(children: Vec<A11yNode>) -> A11yNode {
+    let bounds = Bounds::union(children.iter().map(|c| c.bounds));
+    let mut root = A11yNode { children, ..A11yNode::new("document", None, bounds, None) };
+    let mut next = 0;
+    number(&mut root, &mut next);
+    lint(&root);
+    root
+}
+
+/// Warns about interactive elements a screen reader could not announce
+fn lint(node: &A11yNode) {
+    if INTERACTIVE_ROLES.contains(&node.role.as_str()) && node.name.is_none() {
+        let at = node.source.as_ref()
+            .map(|s| format!("{}:{}:{}", s.file, s.span.line, s.span.column))
+            .unwrap_or_default();
+        println!("⚠️ [a11y] {} '{}' has no label; add `label: \"...\"` or text inside it", at, node.role);
+    }
+    for child in &node.children {
+        lint(child);
+    }
+}
+
+fn number(node: &mut A11yNode, next: &mut u32) {
+    node.order = *next;
+    *next += 1;
+    for child in &mut node.children {
+        number(child, next);
+    }
+}
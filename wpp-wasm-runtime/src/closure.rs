@@ -0,0 +1,410 @@
+use crate::parser::{Expr, Node, Span};
+use crate::transpile::FunctionSignature;
+use std::collections::HashSet;
+
+/// Largest lambda arity we pre-declare `call_indirect` types for.
+pub const MAX_CLOSURE_PARAMS: usize = 8;
+
+/// Extra metadata for functions that are reached through the funcref table.
+#[derive(Debug, Clone)]
+pub struct ClosureMeta {
+    pub captures: Vec<String>,             // free variables, stored in the env object in this order
+    pub returns_value: bool,               // lifted function returns one i32
+    pub target: Option<FunctionSignature>, // set for `__ref_*` thunks wrapping a named function
+    pub resumes: Option<FunctionSignature>, // set for `__step_*` state machines of an async function
+}
+
+/// A lifted lambda or named-function thunk, ready to be registered like a user function.
+pub struct LiftedFunction {
+    pub sig: FunctionSignature,
+    pub node: Node,
+    pub closure: ClosureMeta,
+}
+
+/// Closure wasm types live at the very start of the type section:
+/// `(env: i32, params: i32 * n) -> [] | [i32]`, two entries per arity.
+pub fn closure_type_index(param_count: usize, returns_value: bool) -> u32 {
+    if param_count > MAX_CLOSURE_PARAMS {
+        panic!("❌ Closures take at most {} params, got {}", MAX_CLOSURE_PARAMS, param_count);
+    }
+    (param_count * 2 + returns_value as usize) as u32
+}
+
+pub fn closure_type_count() -> u32 {
+    ((MAX_CLOSURE_PARAMS + 1) * 2) as u32
+}
+
+pub fn lambda_signature(id: usize, params: &[(String, String)]) -> FunctionSignature {
+    FunctionSignature {
+        name: format!("__lambda{}", id),
+        param_types: params.iter().map(|(_, ty)| ty.clone()).collect(),
+    }
+}
+
+pub fn thunk_name(function: &str) -> String {
+    format!("__ref_{}", function)
+}
+
+/// The resumable step function behind `async func name`. It keeps the async
+/// function's param types so overloads get distinct steps.
+pub fn step_signature(function: &FunctionSignature) -> FunctionSignature {
+    FunctionSignature {
+        name: format!("__step_{}", function.name),
+        param_types: function.param_types.clone(),
+    }
+}
+
+/// W++ type string for a function value, e.g. `func(int,string)->int`.
+pub fn func_type(param_types: &[String], returns_value: bool) -> String {
+    let mut ty = format!("func({})", param_types.join(","));
+    if returns_value {
+        ty.push_str("->int");
+    }
+    ty
+}
+
+/// Splits a `func(...)` type string into (param count, returns a value).
+pub fn parse_func_type(ty: &str) -> Option<(usize, bool)> {
+    let rest = ty.strip_prefix("func(")?;
+    let close = find_matching_paren(rest)?;
+    let args = &rest[..close];
+    let returns_value = rest[close + 1..].starts_with("->");
+
+    let mut depth = 0;
+    let mut count = if args.is_empty() { 0 } else { 1 };
+    for c in args.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => count += 1,
+            _ => {}
+        }
+    }
+    Some((count, returns_value))
+}
+
+fn find_matching_paren(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(i),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Named functions that return a value: those whose body ends in an expression
+/// statement yielding one, as `=> expr` lambdas do. Async functions return their
+/// task instead and are never in here.
+pub fn returning_functions(ast: &[Node]) -> HashSet<FunctionSignature> {
+    let signature = |name: &String, params: &[(String, String)]| FunctionSignature {
+        name: name.clone(),
+        param_types: params.iter().map(|(_, ty)| ty.clone()).collect(),
+    };
+    let signatures: Vec<FunctionSignature> = ast.iter().filter_map(|node| match node {
+        Node::Function { name, params, .. } => Some(signature(name, params)),
+        _ => None,
+    }).collect();
+
+    // Grown until stable, so calls into functions found returning later count too
+    let mut returning = HashSet::new();
+    loop {
+        let before = returning.len();
+        for node in ast {
+            if let Node::Function { name, params, body, is_async: false, .. } = node {
+                if let Some(Node::Expr(expr, _)) = body.last() {
+                    if yields_value(expr, params, &signatures, &returning) {
+                        returning.insert(signature(name, params));
+                    }
+                }
+            }
+        }
+        if returning.len() == before {
+            return returning;
+        }
+    }
+}
+
+/// Whether `expr` leaves a value, inside a function taking `params`. A call does
+/// when it goes through a param of type `func(...) -> int`, or to a named function
+/// whose overloads of that arity all return one.
+fn yields_value(expr: &Expr, params: &[(String, String)], functions: &[FunctionSignature], returning: &HashSet<FunctionSignature>) -> bool {
+    match expr {
+        Expr::Layout(_) | Expr::Await(_) => false,
+        Expr::Call { name, args } => match params.iter().find(|(param, _)| param == name) {
+            Some((_, ty)) => parse_func_type(ty).is_some_and(|(_, returns_value)| returns_value),
+            None => {
+                let mut overloads = functions.iter().filter(|sig| sig.name == *name && sig.param_types.len() == args.len()).peekable();
+                overloads.peek().is_some() && overloads.all(|sig| returning.contains(sig))
+            }
+        },
+        _ => true,
+    }
+}
+
+/// Walks the whole AST and lifts every lambda, plus a thunk for every named
+/// function that is used as a value, into table-callable functions.
+pub fn collect_closures(ast: &[Node]) -> Vec<LiftedFunction> {
+    let mut functions: Vec<(String, Vec<(String, String)>)> = vec![];
+    let mut lifted = vec![];
+    for node in ast {
+        if let Node::Function { name, params, is_async, .. } = node {
+            functions.push((name.clone(), params.clone()));
+
+            if *is_async {
+                let sig = FunctionSignature {
+                    name: name.clone(),
+                    param_types: params.iter().map(|(_, ty)| ty.clone()).collect(),
+                };
+                println!("⏳ [Async] Step function for '{}'", name);
+                lifted.push(LiftedFunction {
+                    sig: step_signature(&sig),
+                    node: node.clone(),
+                    closure: ClosureMeta { captures: vec![], returns_value: false, target: None, resumes: Some(sig) },
+                });
+            }
+        }
+    }
+    let function_names: HashSet<String> = functions.iter().map(|(n, _)| n.clone()).collect();
+    let returning = returning_functions(ast);
+
+    let mut referenced = vec![];
+    for node in ast {
+        lift_node(node, &function_names, &mut lifted, &mut referenced);
+    }
+
+    // State lives in globals, so lambdas read it directly instead of capturing a copy
+    let states: HashSet<&String> = ast.iter().filter_map(|node| match node {
+        Node::State { name, .. } => Some(name),
+        _ => None,
+    }).collect();
+    for function in &mut lifted {
+        function.closure.captures.retain(|name| !states.contains(name));
+    }
+
+    for name in referenced {
+        let overloads: Vec<_> = functions.iter().filter(|(n, _)| *n == name).collect();
+        if overloads.len() != 1 {
+            panic!("❌ Function '{}' is overloaded and cannot be used as a value", name);
+        }
+        let (_, params) = overloads[0];
+        let target = FunctionSignature {
+            name: name.clone(),
+            param_types: params.iter().map(|(_, ty)| ty.clone()).collect(),
+        };
+        println!("🔗 [Closure] Thunk for function reference '{}'", name);
+        lifted.push(LiftedFunction {
+            sig: FunctionSignature { name: thunk_name(&name), param_types: target.param_types.clone() },
+            node: Node::Function { name: thunk_name(&name), params: params.clone(), body: vec![], is_async: false, span: Span::default() },
+            closure: ClosureMeta { captures: vec![], returns_value: returning.contains(&target), target: Some(target), resumes: None },
+        });
+    }
+
+    lifted
+}
+
+fn lift_node(node: &Node, functions: &HashSet<String>, lifted: &mut Vec<LiftedFunction>, referenced: &mut Vec<String>) {
+    match node {
+        Node::Group { children, .. } | Node::Grid { children, .. } => {
+            for child in children {
+                lift_node(child, functions, lifted, referenced);
+            }
+        }
+        Node::List { items: children, .. } | Node::Item { children, .. } => {
+            for child in children {
+                lift_node(child, functions, lifted, referenced);
+            }
+        }
+        Node::For { items, body, .. } => {
+            lift_expr(items, functions, lifted, referenced);
+            for item in body {
+                lift_node(item, functions, lifted, referenced);
+            }
+        }
+        Node::If { condition, then_body, else_body, .. } => {
+            lift_expr(condition, functions, lifted, referenced);
+            for stmt in then_body.iter().chain(else_body.iter().flatten()) {
+                lift_node(stmt, functions, lifted, referenced);
+            }
+        }
+        Node::Match { value, arms, .. } => {
+            lift_expr(value, functions, lifted, referenced);
+            for stmt in arms.iter().flat_map(|(_, body)| body) {
+                lift_node(stmt, functions, lifted, referenced);
+            }
+        }
+        Node::Text { value, .. } => lift_expr(value, functions, lifted, referenced),
+        Node::Expr(expr, _)
+        | Node::Let { value: expr, .. }
+        | Node::State { value: expr, .. }
+        | Node::Assign { value: expr, .. } => lift_expr(expr, functions, lifted, referenced),
+        Node::Function { body, .. } => {
+            for stmt in body {
+                lift_node(stmt, functions, lifted, referenced);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn lift_expr(expr: &Expr, functions: &HashSet<String>, lifted: &mut Vec<LiftedFunction>, referenced: &mut Vec<String>) {
+    match expr {
+        Expr::Identifier(name) if functions.contains(name) && !referenced.contains(name) => {
+            referenced.push(name.clone());
+        }
+        Expr::Binary { left, right, .. } => {
+            lift_expr(left, functions, lifted, referenced);
+            lift_expr(right, functions, lifted, referenced);
+        }
+        Expr::Call { args: elements, .. } | Expr::Array(elements) => {
+            for element in elements {
+                lift_expr(element, functions, lifted, referenced);
+            }
+        }
+        Expr::Layout(node) => lift_node(node, functions, lifted, referenced),
+        Expr::Await(inner) => lift_expr(inner, functions, lifted, referenced),
+        Expr::Lambda { id, params, body, returns_value } => {
+            let mut bound: Vec<String> = params.iter().map(|(n, _)| n.clone()).collect();
+            let mut captures = vec![];
+            for stmt in body {
+                free_vars_node(stmt, functions, &mut bound, &mut captures);
+            }
+            println!("🔒 [Closure] Lambda #{} captures {:?}", id, captures);
+
+            let sig = lambda_signature(*id, params);
+            lifted.push(LiftedFunction {
+                node: Node::Function {
+                    name: sig.name.clone(),
+                    params: params.clone(),
+                    body: body.clone(),
+                    is_async: false,
+                    span: body.first().map(Node::span).unwrap_or_default(),
+                },
+                sig,
+                closure: ClosureMeta { captures, returns_value: *returns_value, target: None, resumes: None },
+            });
+
+            for stmt in body {
+                lift_node(stmt, functions, lifted, referenced);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects names used in `node` that are neither bound locally nor top-level functions.
+fn free_vars_node(node: &Node, functions: &HashSet<String>, bound: &mut Vec<String>, free: &mut Vec<String>) {
+    match node {
+        Node::Group { children, .. } | Node::Grid { children, .. } => {
+            for child in children {
+                free_vars_node(child, functions, bound, free);
+            }
+        }
+        Node::List { items: children, .. } | Node::Item { children, .. } => {
+            for child in children {
+                free_vars_node(child, functions, bound, free);
+            }
+        }
+        Node::For { name, items, body, .. } => {
+            free_vars_expr(items, functions, bound, free);
+            bound.push(name.clone());
+            for item in body {
+                free_vars_node(item, functions, bound, free);
+            }
+        }
+        Node::If { condition, then_body, else_body, .. } => {
+            free_vars_expr(condition, functions, bound, free);
+            for stmt in then_body.iter().chain(else_body.iter().flatten()) {
+                free_vars_node(stmt, functions, bound, free);
+            }
+        }
+        Node::Match { value, arms, .. } => {
+            free_vars_expr(value, functions, bound, free);
+            for stmt in arms.iter().flat_map(|(_, body)| body) {
+                free_vars_node(stmt, functions, bound, free);
+            }
+        }
+        Node::Text { value, .. } => free_vars_expr(value, functions, bound, free),
+        Node::Expr(expr, _) => free_vars_expr(expr, functions, bound, free),
+        Node::Let { name, value, .. } => {
+            free_vars_expr(value, functions, bound, free);
+            bound.push(name.clone());
+        }
+        Node::Assign { name, value, .. } => {
+            free_vars_expr(value, functions, bound, free);
+            free_vars_expr(&Expr::Identifier(name.clone()), functions, bound, free);
+        }
+        _ => {}
+    }
+}
+
+fn free_vars_expr(expr: &Expr, functions: &HashSet<String>, bound: &mut Vec<String>, free: &mut Vec<String>) {
+    let mut note = |name: &String, bound: &Vec<String>| {
+        if !bound.contains(name) && !functions.contains(name) && !free.contains(name) {
+            free.push(name.clone());
+        }
+    };
+
+    match expr {
+        Expr::Identifier(name) => note(name, bound),
+        Expr::Binary { left, right, .. } => {
+            free_vars_expr(left, functions, bound, free);
+            free_vars_expr(right, functions, bound, free);
+        }
+        Expr::Call { name, args } => {
+            note(name, bound);
+            for arg in args {
+                free_vars_expr(arg, functions, bound, free);
+            }
+        }
+        Expr::Array(elements) => {
+            for element in elements {
+                free_vars_expr(element, functions, bound, free);
+            }
+        }
+        Expr::Layout(node) => free_vars_node(node, functions, bound, free),
+        Expr::Await(inner) => free_vars_expr(inner, functions, bound, free),
+        Expr::Lambda { params, body, .. } => {
+            // A nested lambda's free variables must be captured by us as well
+            let mut inner_bound = bound.clone();
+            inner_bound.extend(params.iter().map(|(n, _)| n.clone()));
+            for stmt in body {
+                free_vars_node(stmt, functions, &mut inner_bound, free);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_func_type_counts_params_and_result() {
+        assert_eq!(parse_func_type("func()"), Some((0, false)));
+        assert_eq!(parse_func_type("func(int)->int"), Some((1, true)));
+        assert_eq!(parse_func_type("func(int, string)"), Some((2, false)));
+        assert_eq!(parse_func_type("func(int) -> int"), Some((1, false)));
+    }
+
+    #[test]
+    fn parse_func_type_counts_nested_function_params_once() {
+        assert_eq!(parse_func_type("func(func(int,int)->int,int)->int"), Some((2, true)));
+    }
+
+    #[test]
+    fn parse_func_type_rejects_other_types() {
+        assert_eq!(parse_func_type("int"), None);
+        assert_eq!(parse_func_type("func(int"), None);
+    }
+
+    #[test]
+    fn func_type_round_trips() {
+        let params = ["int".to_string(), "func(int)->int".to_string()];
+        assert_eq!(parse_func_type(&func_type(&params, true)), Some((2, true)));
+    }
+}
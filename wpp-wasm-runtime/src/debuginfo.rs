@@ -0,0 +1,125 @@
+use crate::parser::Span;
+
+/// Custom section holding the W++ line table, readable without the external source map.
+///
+/// Layout (all numbers unsigned LEB128):
+/// `count`, then `count` rows of `[module offset, line, column, end_line, end_column]`,
+/// sorted by offset. A row applies from its offset up to the next row's offset.
+pub const LINE_TABLE_SECTION: &str = "wpp.lines";
+
+/// Byte length of `value` encoded as unsigned LEB128
+pub fn leb128_len(mut value: u32) -> u32 {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+fn write_leb128(mut value: u32, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Builds the `wpp.lines` section payload from `(module offset, span)` rows.
+pub fn line_table(rows: &[(u32, Span)]) -> Vec<u8> {
+    let mut data = vec![];
+    write_leb128(rows.len() as u32, &mut data);
+    for (offset, span) in rows {
+        for value in [*offset, span.line, span.column, span.end_line, span.end_column] {
+            write_leb128(value, &mut data);
+        }
+    }
+    data
+}
+
+/// `sourceMappingURL` section, the one browser devtools follow to a source map.
+pub fn source_mapping_url(url: &str) -> Vec<u8> {
+    let mut data = vec![];
+    write_leb128(url.len() as u32, &mut data);
+    data.extend_from_slice(url.as_bytes());
+    data
+}
+
+/// Source Map v3 for a wasm module: everything sits on generated line 1 and
+/// the generated column is the module byte offset, as devtools expect for wasm.
+pub fn source_map_json(source_file: &str, rows: &[(u32, Span)]) -> String {
+    let mut mappings = String::new();
+    let (mut prev_offset, mut prev_line, mut prev_column) = (0i64, 0i64, 0i64);
+    for (i, (offset, span)) in rows.iter().enumerate() {
+        if i > 0 {
+            mappings.push(',');
+        }
+        // Source map lines and columns are 0-based
+        let (line, column) = (span.line.saturating_sub(1) as i64, span.column.saturating_sub(1) as i64);
+        write_vlq(*offset as i64 - prev_offset, &mut mappings);
+        write_vlq(0, &mut mappings); // always source #0
+        write_vlq(line - prev_line, &mut mappings);
+        write_vlq(column - prev_column, &mut mappings);
+        (prev_offset, prev_line, prev_column) = (*offset as i64, line, column);
+    }
+
+    serde_json::to_string_pretty(&serde_json::json!({
+        "version": 3,
+        "sources": [source_file],
+        "names": [],
+        "mappings": mappings,
+    }))
+    .unwrap()
+}
+
+fn write_vlq(value: i64, out: &mut String) {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut rest = if value < 0 { ((-value) << 1) | 1 } else { value << 1 };
+    loop {
+        let mut digit = rest & 0x1f;
+        rest >>= 5;
+        if rest > 0 {
+            digit |= 0x20; // continuation bit
+        }
+        out.push(BASE64[digit as usize] as char);
+        if rest == 0 {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vlq(value: i64) -> String {
+        let mut out = String::new();
+        write_vlq(value, &mut out);
+        out
+    }
+
+    #[test]
+    fn vlq_encodes_sign_and_continuation() {
+        assert_eq!(vlq(0), "A");
+        assert_eq!(vlq(1), "C");
+        assert_eq!(vlq(-1), "D");
+        assert_eq!(vlq(15), "e");
+        assert_eq!(vlq(16), "gB");
+        assert_eq!(vlq(-16), "hB");
+        assert_eq!(vlq(1000), "w+B");
+    }
+
+    #[test]
+    fn leb128_len_matches_the_encoding() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, 0x1f_ffff, 0x20_0000, u32::MAX] {
+            let mut bytes = vec![];
+            write_leb128(value, &mut bytes);
+            assert_eq!(leb128_len(value), bytes.len() as u32, "length of {:#x}", value);
+        }
+        assert_eq!(leb128_len(u32::MAX), 5);
+    }
+}
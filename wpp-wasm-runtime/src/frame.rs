@@ -0,0 +1,676 @@
+use wasm_encoder::{BlockType, Instruction, MemArg};
+use crate::transpile::{
+    CLEAR_RECT_FUNC, DRAW_RECT_FUNC, DRAW_TEXT_FUNC, DRAW_LINE_FUNC, DRAW_CIRCLE_FUNC, DRAW_PATH_FUNC, DRAW_IMAGE_FUNC, MEASURE_TEXT_FUNC, ADD_ROOT_FUNC, GC_ALLOC_FUNC, NODES_EQUAL_FUNC, RESERVE_NODE_FUNC,
+    FRAME_GLOBAL, FRAME_LEN_GLOBAL, FRAME_CAP_GLOBAL, PREV_FRAME_GLOBAL, PREV_LEN_GLOBAL, PREV_CAP_GLOBAL, RENDERING_GLOBAL, OPEN_DEPTH_GLOBAL, TYPE_FRAME,
+};
+
+// `render` does not draw directly: every box, text, line, ... becomes a node in the
+// current frame, a GC buffer of fixed-size records in paint order. `commit_frame`
+// compares it with the previous frame, clears the union of what changed, grown
+// over every node it touches, and redraws only the nodes inside that region.
+
+pub(crate) const NODE_RECORD: i32 = 32;  // [kind, x, y, a, b, fill, stroke, shape] as i32
+const INITIAL_CAPACITY: i32 = 64;
+pub(crate) const KIND_RECT: i32 = 0;     // a, b = width, height
+pub(crate) const KIND_TEXT: i32 = 1;     // a, b = ptr, len
+// Only between `push_node` and `layout_frame`, see runtime_layout.rs
+pub(crate) const KIND_OPEN: i32 = 2;     // container: x = flags, y = gap | padding << 16, a, b = width, height or -1
+pub(crate) const KIND_CLOSE: i32 = 3;
+pub(crate) const KIND_FLEX: i32 = 4;     // grow, shrink, basis, min/max sizes of the next flow child, see runtime_layout.rs
+pub(crate) const KIND_MASK: i32 = 7;
+pub(crate) const FLOW: i32 = 8;          // placed by its container rather than at its own x, y
+pub(crate) const TAG_SHIFT: i32 = 8;     // kind >> TAG_SHIFT = semantic map id + 1, 0 for none; kept on screen for the host's a11y mirror
+// Generous text height for dirty regions, in quarters of the font size; the width
+// comes from the host's `measureText`
+const TEXT_TOP: i32 = 1;      // glyphs can start above the text's y
+const TEXT_HEIGHT: i32 = 6;
+
+pub(crate) fn field(offset: u64) -> MemArg {
+    MemArg { offset, align: 2, memory_index: 0 }
+}
+
+pub(crate) const KIND: u64 = 0;
+pub(crate) const X: u64 = 4;
+pub(crate) const Y: u64 = 8;
+pub(crate) const A: u64 = 12;
+pub(crate) const B: u64 = 16;
+// Paint, see style.rs
+pub(crate) const FILL: u64 = 20;   // of a box; a text's color
+pub(crate) const STROKE: u64 = 24; // of a box
+pub(crate) const FONT: u64 = 24;   // of a text, `family | size << 8`
+pub(crate) const SHAPE: u64 = 28;  // stroke_width | radius << 8 | opacity << 16 | primitive << 24
+// What a node that is not a text draws in its rect (a, b = width, height), SHAPE >> PRIMITIVE_SHIFT
+pub(crate) const PRIMITIVE_SHIFT: i32 = 24;
+pub(crate) const PRIMITIVE_RECT: i32 = 0;
+pub(crate) const PRIMITIVE_LINE: i32 = 1;        // top-left to bottom-right
+pub(crate) const PRIMITIVE_RISING_LINE: i32 = 2; // bottom-left to top-right
+pub(crate) const PRIMITIVE_CIRCLE: i32 = 3;
+// fill, stroke = ptr, len of their data: [fill][stroke][commands] of a path, the src of an image
+pub(crate) const PRIMITIVE_PATH: i32 = 4;
+pub(crate) const PRIMITIVE_IMAGE: i32 = 5;
+pub(crate) const PATH_PAINT: i32 = 8; // fill and stroke ahead of a path's commands
+
+/// Calls the host import drawing a node with its fields, `field(offset)` pushing the
+/// one at `offset`: `drawText(x, y, ptr, len, color, font, opacity)` for a text,
+/// otherwise the one of its primitive, e.g. `drawRect(x, y, w, h, fill, stroke,
+/// stroke_width, radius, opacity)`.
+pub(crate) fn host_draw(text: bool, field: impl Fn(u64) -> Vec<Instruction<'static>>) -> Vec<Instruction<'static>> {
+    let fields = |offsets: &[u64]| -> Vec<Instruction<'static>> { offsets.iter().flat_map(|offset| field(*offset)).collect() };
+    let part = |shift: i32| -> Vec<Instruction<'static>> {
+        let mut code = field(SHAPE);
+        code.extend([Instruction::I32Const(shift), Instruction::I32ShrU, Instruction::I32Const(0xFF), Instruction::I32And]);
+        code
+    };
+    let is_primitive = |primitive: i32| -> Vec<Instruction<'static>> {
+        let mut code = field(SHAPE);
+        code.extend([Instruction::I32Const(PRIMITIVE_SHIFT), Instruction::I32ShrU, Instruction::I32Const(primitive), Instruction::I32Eq]);
+        code
+    };
+    if text {
+        let mut code = fields(&[X, Y, A, B, FILL, FONT]);
+        code.extend(part(16));
+        code.push(Instruction::Call(DRAW_TEXT_FUNC));
+        return code;
+    }
+
+    // drawRect(x, y, w, h, fill, stroke, stroke_width, radius, opacity)
+    let mut rect = fields(&[X, Y, A, B, FILL, STROKE]);
+    rect.extend([part(0), part(8), part(16)].concat());
+    rect.push(Instruction::Call(DRAW_RECT_FUNC));
+
+    // drawCircle(cx, cy, radius, fill, stroke, stroke_width, opacity)
+    let mut circle = Vec::new();
+    for (offset, size) in [(X, A), (Y, B)] {
+        circle.extend([field(offset), field(size), vec![Instruction::I32Const(1), Instruction::I32ShrS, Instruction::I32Add]].concat());
+    }
+    circle.extend([fields(&[A, B, A, B]), vec![Instruction::I32LtS, Instruction::Select, Instruction::I32Const(1), Instruction::I32ShrS]].concat());
+    circle.extend([fields(&[FILL, STROKE]), part(0), part(16)].concat());
+    circle.push(Instruction::Call(DRAW_CIRCLE_FUNC));
+
+    // drawPath(x, y, ptr, len, fill, stroke, stroke_width, opacity) of the commands
+    let mut path = fields(&[X, Y]);
+    path.extend([field(FILL), vec![Instruction::I32Const(PATH_PAINT), Instruction::I32Add]].concat());
+    path.extend([field(STROKE), vec![Instruction::I32Const(PATH_PAINT), Instruction::I32Sub]].concat());
+    for offset in [0, 4] {
+        path.extend(field(FILL));
+        path.push(Instruction::I32Load(MemArg { offset, align: 2, memory_index: 0 }));
+    }
+    path.extend([part(0), part(16)].concat());
+    path.push(Instruction::Call(DRAW_PATH_FUNC));
+
+    // drawImage(x, y, w, h, ptr, len, opacity)
+    let mut image = fields(&[X, Y, A, B, FILL, STROKE]);
+    image.extend(part(16));
+    image.push(Instruction::Call(DRAW_IMAGE_FUNC));
+
+    // drawLine(x1, y1, x2, y2, stroke, stroke_width, opacity): y1 is the bottom when rising
+    let mut line = field(X);
+    line.extend([field(Y), vec![Instruction::I32Const(0)], field(B), is_primitive(PRIMITIVE_LINE), vec![Instruction::Select, Instruction::I32Add]].concat());
+    line.extend([field(X), field(A), vec![Instruction::I32Add]].concat());
+    line.extend([field(Y), field(B), vec![Instruction::I32Const(0)], is_primitive(PRIMITIVE_LINE), vec![Instruction::Select, Instruction::I32Add]].concat());
+    line.extend([field(STROKE), part(0), part(16)].concat());
+    line.push(Instruction::Call(DRAW_LINE_FUNC));
+
+    let mut code = Vec::new();
+    let cases = [(PRIMITIVE_RECT, rect), (PRIMITIVE_CIRCLE, circle), (PRIMITIVE_PATH, path), (PRIMITIVE_IMAGE, image)];
+    let branches = cases.len();
+    for (primitive, draw) in cases {
+        code.extend(is_primitive(primitive));
+        code.push(Instruction::If(BlockType::Empty));
+        code.extend(draw);
+        code.push(Instruction::Else);
+    }
+    code.extend(line);
+    code.extend(std::iter::repeat_n(Instruction::End, branches));
+    code
+}
+
+/// `draw_rect_node(x, y, w, h, fill, stroke, shape)` / `draw_text_node(x, y, ptr, len, color, font, shape)`:
+/// records a node while rendering, otherwise draws straight away (e.g. from an
+/// event handler). Inside a container recorded for runtime layout (say, a
+/// function called from a list item) the node is one more child for it to place.
+pub fn record_node_body(text: bool) -> Vec<Instruction<'static>> {
+    let kind = if text { KIND_TEXT } else { KIND_RECT };
+    let node = 7;
+    let params = [(X, 0), (Y, 1), (A, 2), (B, 3), (FILL, 4), (STROKE, 5), (SHAPE, 6)];
+    let param = |offset: u64| params.iter().find(|(at, _)| *at == offset).map(|(_, param)| vec![Instruction::LocalGet(*param)]).unwrap();
+    let mut body = vec![
+        Instruction::GlobalGet(RENDERING_GLOBAL),
+        Instruction::GlobalGet(OPEN_DEPTH_GLOBAL),
+        Instruction::I32Or,
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::Empty),
+    ];
+    body.extend(host_draw(text, param));
+    body.extend([
+        Instruction::Return,
+        Instruction::End,
+        Instruction::Call(RESERVE_NODE_FUNC),
+        Instruction::LocalTee(node),
+        Instruction::I32Const(kind | FLOW),
+        Instruction::I32Const(kind),
+        Instruction::GlobalGet(OPEN_DEPTH_GLOBAL),
+        Instruction::Select,
+        Instruction::I32Store(field(KIND)),
+    ]);
+    for (offset, param) in params {
+        body.push(Instruction::LocalGet(node));
+        body.push(Instruction::LocalGet(param));
+        body.push(Instruction::I32Store(field(offset)));
+    }
+    body.push(Instruction::End);
+    body
+}
+
+/// `push_node(kind, x, y, a, b, fill, stroke, shape)`: records a node whatever
+/// `RENDERING` says, for content laid out at render time, and keeps count of the
+/// containers left open.
+pub fn push_node_body() -> Vec<Instruction<'static>> {
+    let node = 8;
+    let mut body = vec![
+        Instruction::Call(RESERVE_NODE_FUNC),
+        Instruction::LocalSet(node),
+    ];
+    for (param, offset) in [(0, KIND), (1, X), (2, Y), (3, A), (4, B), (5, FILL), (6, STROKE), (7, SHAPE)] {
+        body.push(Instruction::LocalGet(node));
+        body.push(Instruction::LocalGet(param));
+        body.push(Instruction::I32Store(field(offset)));
+    }
+    body.push(Instruction::GlobalGet(OPEN_DEPTH_GLOBAL));
+    for (container, step) in [(KIND_OPEN, Instruction::I32Add), (KIND_CLOSE, Instruction::I32Sub)] {
+        body.extend([
+            Instruction::LocalGet(0),
+            Instruction::I32Const(KIND_MASK),
+            Instruction::I32And,
+            Instruction::I32Const(container),
+            Instruction::I32Eq,
+            step,
+        ]);
+    }
+    body.extend([Instruction::GlobalSet(OPEN_DEPTH_GLOBAL), Instruction::End]);
+    body
+}
+
+/// `reserve_node() -> ptr`: appends an empty record to the current frame, growing
+/// its buffer when full.
+pub fn reserve_node_body() -> Vec<Instruction<'static>> {
+    let grown = 0;
+    vec![
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::GlobalGet(FRAME_CAP_GLOBAL),
+        Instruction::I32GeU,
+        Instruction::If(BlockType::Empty),
+        // capacity = capacity == 0 ? INITIAL_CAPACITY : capacity * 2
+        Instruction::I32Const(INITIAL_CAPACITY),
+        Instruction::GlobalGet(FRAME_CAP_GLOBAL),
+        Instruction::I32Const(1),
+        Instruction::I32Shl,
+        Instruction::GlobalGet(FRAME_CAP_GLOBAL),
+        Instruction::I32Eqz,
+        Instruction::Select,
+        Instruction::GlobalSet(FRAME_CAP_GLOBAL),
+        Instruction::GlobalGet(FRAME_CAP_GLOBAL),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Mul,
+        Instruction::I32Const(TYPE_FRAME),
+        Instruction::Call(GC_ALLOC_FUNC),
+        Instruction::LocalTee(grown),
+        Instruction::Call(ADD_ROOT_FUNC),
+        Instruction::LocalGet(grown),
+        Instruction::GlobalGet(FRAME_GLOBAL),
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Mul,
+        Instruction::MemoryCopy { src_mem: 0, dst_mem: 0 },
+        Instruction::LocalGet(grown),
+        Instruction::GlobalSet(FRAME_GLOBAL),
+        Instruction::End,
+        Instruction::GlobalGet(FRAME_GLOBAL),
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Mul,
+        Instruction::I32Add,
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::GlobalSet(FRAME_LEN_GLOBAL),
+        Instruction::End,
+    ]
+}
+
+/// `nodes_equal(a, b) -> 1 if both records draw the same thing`; texts, paths and
+/// images compare by the bytes behind their pointers.
+pub fn nodes_equal_body() -> Vec<Instruction<'static>> {
+    let (a, b) = (0, 1);
+    let mut body = vec![
+        Instruction::LocalGet(a),
+        Instruction::I32Eqz,
+        Instruction::LocalGet(b),
+        Instruction::I32Eqz,
+        Instruction::I32Or,
+        Instruction::If(BlockType::Empty),
+        Instruction::I32Const(0),
+        Instruction::Return,
+        Instruction::End,
+    ];
+    for offset in [KIND, X, Y, B, SHAPE] {
+        unequal_field(offset, &mut body);
+    }
+    body.extend([
+        Instruction::LocalGet(a),
+        Instruction::I32Load(field(KIND)),
+        Instruction::I32Const(KIND_MASK),
+        Instruction::I32And,
+        Instruction::I32Const(KIND_TEXT),
+        Instruction::I32Eq,
+        Instruction::If(BlockType::Empty),
+    ]);
+    unequal_field(FILL, &mut body);
+    unequal_field(STROKE, &mut body);
+    // Same length (B), so compare the bytes behind both pointers (A)
+    unequal_bytes(A, B, &mut body);
+    body.push(Instruction::Else);
+    unequal_field(A, &mut body);
+    body.extend([
+        Instruction::LocalGet(a),
+        Instruction::I32Load(field(SHAPE)),
+        Instruction::I32Const(PRIMITIVE_SHIFT),
+        Instruction::I32ShrU,
+        Instruction::I32Const(PRIMITIVE_PATH),
+        Instruction::I32GeU,
+        Instruction::If(BlockType::Empty),
+    ]);
+    unequal_field(STROKE, &mut body);
+    unequal_bytes(FILL, STROKE, &mut body);
+    body.push(Instruction::Else);
+    unequal_field(FILL, &mut body);
+    unequal_field(STROKE, &mut body);
+    body.extend([Instruction::End, Instruction::End, Instruction::I32Const(1), Instruction::End]);
+    body
+}
+
+/// In nodes_equal: returns 0 when the field at `offset` differs.
+fn unequal_field(offset: u64, body: &mut Vec<Instruction<'static>>) {
+    let (a, b) = (0, 1);
+    body.extend([
+        Instruction::LocalGet(a),
+        Instruction::I32Load(field(offset)),
+        Instruction::LocalGet(b),
+        Instruction::I32Load(field(offset)),
+        Instruction::I32Ne,
+        Instruction::If(BlockType::Empty),
+        Instruction::I32Const(0),
+        Instruction::Return,
+        Instruction::End,
+    ]);
+}
+
+/// In nodes_equal: returns 0 when the `len` bytes behind the pointers at `ptr` differ.
+fn unequal_bytes(ptr: u64, len: u64, body: &mut Vec<Instruction<'static>>) {
+    let (a, b, i, count) = (0, 1, 2, 3);
+    let byte = MemArg { offset: 0, align: 0, memory_index: 0 };
+    body.extend([
+        Instruction::LocalGet(a),
+        Instruction::I32Load(field(len)),
+        Instruction::LocalSet(count),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(i),
+        Instruction::LocalGet(count),
+        Instruction::I32GeU,
+        Instruction::BrIf(1),
+        Instruction::LocalGet(a),
+        Instruction::I32Load(field(ptr)),
+        Instruction::LocalGet(i),
+        Instruction::I32Add,
+        Instruction::I32Load8U(byte),
+        Instruction::LocalGet(b),
+        Instruction::I32Load(field(ptr)),
+        Instruction::LocalGet(i),
+        Instruction::I32Add,
+        Instruction::I32Load8U(byte),
+        Instruction::I32Ne,
+        Instruction::If(BlockType::Empty),
+        Instruction::I32Const(0),
+        Instruction::Return,
+        Instruction::End,
+        Instruction::LocalGet(i),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::LocalSet(i),
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+    ]);
+}
+
+// Locals of commit_frame
+const I: u32 = 0;
+const OLD: u32 = 1;  // node i of the previous frame, 0 past its end
+const NEW: u32 = 2;  // node i of the current frame, 0 past its end
+const X0: u32 = 3;   // dirty region [X0, X1) × [Y0, Y1), empty while X0 > X1
+const Y0: u32 = 4;
+const X1: u32 = 5;
+const Y1: u32 = 6;
+const BX: u32 = 7;   // bounds of the node being looked at
+const BY: u32 = 8;
+const BW: u32 = 9;
+const BH: u32 = 10;
+const GROWN: u32 = 11; // the region grew over a node this pass
+pub const COMMIT_FRAME_LOCALS: u32 = 12;
+
+/// Node `i` of a frame buffer, or 0 when `i` is past its length.
+fn node_at(frame: u32, len: u32, into: u32, body: &mut Vec<Instruction<'static>>) {
+    body.extend([
+        Instruction::GlobalGet(frame),
+        Instruction::LocalGet(I),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Mul,
+        Instruction::I32Add,
+        Instruction::I32Const(0),
+        Instruction::LocalGet(I),
+        Instruction::GlobalGet(len),
+        Instruction::I32LtU,
+        Instruction::Select,
+        Instruction::LocalSet(into),
+    ]);
+}
+
+/// Loads the bounds of the node in `node` into BX, BY, BW, BH: the rect of a box,
+/// line, circle, ... and the outer half of its stroke, a text's glyphs at its font size.
+fn node_bounds(node: u32, body: &mut Vec<Instruction<'static>>) {
+    let font_size = || vec![
+        Instruction::LocalGet(node),
+        Instruction::I32Load(field(FONT)),
+        Instruction::I32Const(8),
+        Instruction::I32ShrU,
+    ];
+    // Rounded up: a 1px horizontal line still covers a row
+    let half_stroke = || vec![
+        Instruction::LocalGet(node),
+        Instruction::I32Load(field(SHAPE)),
+        Instruction::I32Const(0xFF),
+        Instruction::I32And,
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::I32Const(1),
+        Instruction::I32ShrU,
+    ];
+    body.extend([
+        Instruction::LocalGet(node),
+        Instruction::I32Load(field(KIND)),
+        Instruction::I32Const(KIND_MASK),
+        Instruction::I32And,
+        Instruction::I32Const(KIND_TEXT),
+        Instruction::I32Eq,
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(node),
+        Instruction::I32Load(field(X)),
+        Instruction::LocalSet(BX),
+        Instruction::LocalGet(node),
+        Instruction::I32Load(field(Y)),
+    ]);
+    body.extend(font_size());
+    body.extend([
+        Instruction::I32Const(TEXT_TOP),
+        Instruction::I32Mul,
+        Instruction::I32Const(2),
+        Instruction::I32ShrU,
+        Instruction::I32Sub,
+        Instruction::LocalSet(BY),
+        Instruction::LocalGet(node),
+        Instruction::I32Load(field(A)),
+        Instruction::LocalGet(node),
+        Instruction::I32Load(field(B)),
+        Instruction::LocalGet(node),
+        Instruction::I32Load(field(FONT)),
+        Instruction::Call(MEASURE_TEXT_FUNC),
+        Instruction::LocalSet(BW),
+    ]);
+    body.extend(font_size());
+    body.extend([
+        Instruction::I32Const(TEXT_HEIGHT),
+        Instruction::I32Mul,
+        Instruction::I32Const(2),
+        Instruction::I32ShrU,
+        Instruction::LocalSet(BH),
+        Instruction::Else,
+    ]);
+    for (offset, target) in [(X, BX), (Y, BY)] {
+        body.extend([Instruction::LocalGet(node), Instruction::I32Load(field(offset))]);
+        body.extend(half_stroke());
+        body.extend([Instruction::I32Sub, Instruction::LocalSet(target)]);
+    }
+    for (offset, target) in [(A, BW), (B, BH)] {
+        body.extend([Instruction::LocalGet(node), Instruction::I32Load(field(offset))]);
+        body.extend(half_stroke());
+        body.extend([Instruction::I32Const(1), Instruction::I32Shl, Instruction::I32Add, Instruction::LocalSet(target)]);
+    }
+    body.push(Instruction::End);
+}
+
+/// `target = a <op> b ? a : b` for min/max
+fn keep(target: u32, value: Vec<Instruction<'static>>, compare: Instruction<'static>, body: &mut Vec<Instruction<'static>>) {
+    body.push(Instruction::LocalGet(target));
+    body.extend(value.iter().cloned());
+    body.push(Instruction::LocalGet(target));
+    body.extend(value);
+    body.push(compare);
+    body.push(Instruction::Select);
+    body.push(Instruction::LocalSet(target));
+}
+
+/// Grows the dirty region to cover the bounds in BX, BY, BW, BH.
+fn cover_bounds(body: &mut Vec<Instruction<'static>>) {
+    keep(X0, vec![Instruction::LocalGet(BX)], Instruction::I32LtS, body);
+    keep(Y0, vec![Instruction::LocalGet(BY)], Instruction::I32LtS, body);
+    keep(X1, vec![Instruction::LocalGet(BX), Instruction::LocalGet(BW), Instruction::I32Add], Instruction::I32GtS, body);
+    keep(Y1, vec![Instruction::LocalGet(BY), Instruction::LocalGet(BH), Instruction::I32Add], Instruction::I32GtS, body);
+}
+
+/// Grows the dirty region to cover the node in `node`, if any.
+fn add_dirty(node: u32, body: &mut Vec<Instruction<'static>>) {
+    body.extend([
+        Instruction::LocalGet(node),
+        Instruction::If(BlockType::Empty),
+    ]);
+    node_bounds(node, body);
+    cover_bounds(body);
+    body.push(Instruction::End);
+}
+
+/// Whether the bounds in BX, BY, BW, BH overlap the dirty region
+fn overlaps_dirty() -> Vec<Instruction<'static>> {
+    vec![
+        Instruction::LocalGet(BX),
+        Instruction::LocalGet(X1),
+        Instruction::I32LtS,
+        Instruction::LocalGet(BX),
+        Instruction::LocalGet(BW),
+        Instruction::I32Add,
+        Instruction::LocalGet(X0),
+        Instruction::I32GtS,
+        Instruction::I32And,
+        Instruction::LocalGet(BY),
+        Instruction::LocalGet(Y1),
+        Instruction::I32LtS,
+        Instruction::I32And,
+        Instruction::LocalGet(BY),
+        Instruction::LocalGet(BH),
+        Instruction::I32Add,
+        Instruction::LocalGet(Y0),
+        Instruction::I32GtS,
+        Instruction::I32And,
+    ]
+}
+
+/// Whether the bounds in BX, BY, BW, BH stick out of the dirty region
+fn outside_dirty() -> Vec<Instruction<'static>> {
+    vec![
+        Instruction::LocalGet(BX),
+        Instruction::LocalGet(X0),
+        Instruction::I32LtS,
+        Instruction::LocalGet(BY),
+        Instruction::LocalGet(Y0),
+        Instruction::I32LtS,
+        Instruction::I32Or,
+        Instruction::LocalGet(BX),
+        Instruction::LocalGet(BW),
+        Instruction::I32Add,
+        Instruction::LocalGet(X1),
+        Instruction::I32GtS,
+        Instruction::I32Or,
+        Instruction::LocalGet(BY),
+        Instruction::LocalGet(BH),
+        Instruction::I32Add,
+        Instruction::LocalGet(Y1),
+        Instruction::I32GtS,
+        Instruction::I32Or,
+    ]
+}
+
+/// `for i in 0..len { each }` over the current frame, node i in NEW
+fn for_each_node(each: impl Fn(&mut Vec<Instruction<'static>>), body: &mut Vec<Instruction<'static>>) {
+    body.extend([
+        Instruction::I32Const(0),
+        Instruction::LocalSet(I),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(I),
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::I32GeU,
+        Instruction::BrIf(1),
+    ]);
+    node_at(FRAME_GLOBAL, FRAME_LEN_GLOBAL, NEW, body);
+    node_bounds(NEW, body);
+    each(body);
+    body.extend([
+        Instruction::LocalGet(I),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::LocalSet(I),
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+    ]);
+}
+
+/// Draws the node in `node` through the host.
+fn redraw(node: u32, body: &mut Vec<Instruction<'static>>) {
+    let load = |offset: u64| vec![Instruction::LocalGet(node), Instruction::I32Load(field(offset))];
+    body.extend(load(KIND));
+    body.extend([Instruction::I32Const(KIND_MASK), Instruction::I32And, Instruction::I32Const(KIND_TEXT), Instruction::I32Eq, Instruction::If(BlockType::Empty)]);
+    body.extend(host_draw(true, load));
+    body.push(Instruction::Else);
+    body.extend(host_draw(false, load));
+    body.push(Instruction::End);
+}
+
+fn swap_globals(a: u32, b: u32, body: &mut Vec<Instruction<'static>>) {
+    body.extend([
+        Instruction::GlobalGet(a),
+        Instruction::GlobalGet(b),
+        Instruction::GlobalSet(a),
+        Instruction::GlobalSet(b),
+    ]);
+}
+
+/// `commit_frame()`: ends a render. Diffs the current frame against the previous
+/// one node by node, clears the region covering every changed node (old and new
+/// position) and every current node overlapping it, redraws those, then keeps
+/// the current frame as the previous one.
+pub fn commit_frame_body() -> Vec<Instruction<'static>> {
+    let mut body = vec![
+        Instruction::I32Const(0),
+        Instruction::GlobalSet(RENDERING_GLOBAL),
+        Instruction::I32Const(i32::MAX),
+        Instruction::LocalSet(X0),
+        Instruction::I32Const(i32::MAX),
+        Instruction::LocalSet(Y0),
+        Instruction::I32Const(i32::MIN),
+        Instruction::LocalSet(X1),
+        Instruction::I32Const(i32::MIN),
+        Instruction::LocalSet(Y1),
+        // for i in 0..max(len, prev_len)
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(I),
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::I32GeU,
+        Instruction::LocalGet(I),
+        Instruction::GlobalGet(PREV_LEN_GLOBAL),
+        Instruction::I32GeU,
+        Instruction::I32And,
+        Instruction::BrIf(1),
+    ];
+    node_at(PREV_FRAME_GLOBAL, PREV_LEN_GLOBAL, OLD, &mut body);
+    node_at(FRAME_GLOBAL, FRAME_LEN_GLOBAL, NEW, &mut body);
+    body.extend([
+        Instruction::LocalGet(OLD),
+        Instruction::LocalGet(NEW),
+        Instruction::Call(NODES_EQUAL_FUNC),
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::Empty),
+    ]);
+    add_dirty(OLD, &mut body);
+    add_dirty(NEW, &mut body);
+    body.extend([
+        Instruction::End,
+        Instruction::LocalGet(I),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::LocalSet(I),
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+        // Nothing changed: no draw calls at all
+        Instruction::LocalGet(X0),
+        Instruction::LocalGet(X1),
+        Instruction::I32LeS,
+        Instruction::If(BlockType::Empty),
+        // Grow over every node the region touches until it holds them whole: a
+        // node is redrawn in full, which must not paint over pixels left uncleared
+        Instruction::Loop(BlockType::Empty),
+        Instruction::I32Const(0),
+        Instruction::LocalSet(GROWN),
+    ]);
+    for_each_node(|body| {
+        body.extend(overlaps_dirty());
+        body.extend(outside_dirty());
+        body.extend([Instruction::I32And, Instruction::If(BlockType::Empty)]);
+        cover_bounds(body);
+        body.extend([Instruction::I32Const(1), Instruction::LocalSet(GROWN), Instruction::End]);
+    }, &mut body);
+    body.extend([
+        Instruction::LocalGet(GROWN),
+        Instruction::BrIf(0),
+        Instruction::End,
+        Instruction::LocalGet(X0),
+        Instruction::LocalGet(Y0),
+        Instruction::LocalGet(X1),
+        Instruction::LocalGet(X0),
+        Instruction::I32Sub,
+        Instruction::LocalGet(Y1),
+        Instruction::LocalGet(Y0),
+        Instruction::I32Sub,
+        Instruction::Call(CLEAR_RECT_FUNC),
+    ]);
+    for_each_node(|body| {
+        body.extend(overlaps_dirty());
+        body.push(Instruction::If(BlockType::Empty));
+        redraw(NEW, body);
+        body.push(Instruction::End);
+    }, &mut body);
+    body.push(Instruction::End);
+    // The frame just drawn is what the next one is compared with
+    swap_globals(FRAME_GLOBAL, PREV_FRAME_GLOBAL, &mut body);
+    swap_globals(FRAME_CAP_GLOBAL, PREV_CAP_GLOBAL, &mut body);
+    body.extend([
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::GlobalSet(PREV_LEN_GLOBAL),
+        Instruction::I32Const(0),
+        Instruction::GlobalSet(FRAME_LEN_GLOBAL),
+        Instruction::End,
+    ]);
+    body
+}
@@ -6,6 +6,7 @@ const HEADER_SIZE: usize = 12; // 4 bytes type, 4 bytes mark, 4 bytes used
 const TYPE_BOX: u32 = 1;
 const TYPE_TEXT: u32 = 2;
 const TYPE_GROUP: u32 = 3;
+const TYPE_CLOSURE: u32 = 4;
 
 thread_local! {
     static HEAP: RefCell<Vec<u8>> = RefCell::new(vec![0; HEAP_SIZE]);
@@ -128,6 +129,14 @@ fn mark(ptr: u32) {
                     }
                 }
             }
+            TYPE_CLOSURE => {
+                // [table slot, env ptr]; env slots may hold plain ints, so they are not traced
+                let env_ptr = u32::from_le_bytes(heap[base + HEADER_SIZE + 4..base + HEADER_SIZE + 8].try_into().unwrap());
+                if env_ptr > 0 {
+                    heap[env_ptr as usize - HEADER_SIZE + 4..env_ptr as usize - HEADER_SIZE + 8]
+                        .copy_from_slice(&1u32.to_le_bytes()); // mark env = 1
+                }
+            }
             _ => {} // box/text have no refs
         }
     });
@@ -0,0 +1,784 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use crate::a11y::Bounds;
+use crate::parser::{Expr, FlexItem, Node, Track};
+use crate::style;
+
+// Static layout: sizes flow bottom-up through `measure`, positions flow top-down
+// as every group or grid `place`s its children inside its own rect. Top-level
+// containers without an explicit size fill the canvas.
+
+/// Canvas of `index.html`, used unless the host passes `--canvas WxH`
+pub const DEFAULT_CANVAS: Size = Size { width: 400, height: 400 };
+/// Size given to children whose extent is only known once they run (if/match bodies)
+const UNKNOWN_SIZE: i32 = 50;
+/// Text width estimate per character at the default font size, for layout decided
+/// while compiling; the runtime layout asks the host (`measureText`) instead
+pub const TEXT_CHAR_WIDTH: i32 = 8;
+/// Font size, and so line height, of texts without `font_size:`
+pub const TEXT_HEIGHT: i32 = 16;
+const NO_FLEX: FlexItem = FlexItem {
+    grow: None,
+    shrink: None,
+    basis: None,
+    min_width: None,
+    max_width: None,
+    min_height: None,
+    max_height: None,
+    row: None,
+    column: None,
+    row_span: None,
+    column_span: None,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Size {
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Parses `600x400`.
+pub fn parse_size(text: &str) -> Option<Size> {
+    let (width, height) = text.split_once('x')?;
+    Some(Size { width: width.trim().parse().ok()?, height: height.trim().parse().ok()? })
+}
+
+/// A node that takes room in a group: its own size and how it may grow or shrink.
+pub struct Child<'a> {
+    pub size: Size,
+    pub flex: &'a FlexItem,
+}
+
+/// How a group lines up its children.
+pub struct Flow<'a> {
+    pub horizontal: bool,
+    pub gap: i32,
+    pub padding: i32,
+    pub align: &'a str,
+    pub justify: &'a str,
+    pub wrap: bool,
+}
+
+/// One child on the main axis while its line is resolved
+struct Item {
+    main: i32,
+    cross: i32,
+    min: i32, // main-axis bounds
+    max: i32,
+    min_cross: i32,
+    max_cross: i32,
+    grow: i32,
+    shrink: i32,
+    frozen: bool,
+}
+
+impl Flow<'_> {
+    /// Room `node` takes among the children of this flow: an `if`/`match` takes that
+    /// of its largest branch, its nodes lined up as in `branch_flow`.
+    pub fn measure_child(&self, node: &Node) -> Option<Size> {
+        match node {
+            Node::If { .. } | Node::Match { .. } => {
+                let flow = branch_flow(self.horizontal, self.gap);
+                branches(node)
+                    .into_iter()
+                    .map(|body| flow.content_size(&body.iter().filter_map(|node| flow.measure_child(node)).collect::<Vec<_>>(), None))
+                    .max_by_key(|size| size.width as i64 * size.height as i64)
+            }
+            _ => measure(node),
+        }
+    }
+
+    /// `node` as a child of this flow, or None when it takes no room.
+    pub fn child<'n>(&self, node: &'n Node) -> Option<Child<'n>> {
+        self.measure_child(node).map(|size| Child { size, flex: flex(node) })
+    }
+
+    /// (main, cross) extent of `size` along this flow
+    fn axes(&self, size: Size) -> (i32, i32) {
+        if self.horizontal { (size.width, size.height) } else { (size.height, size.width) }
+    }
+
+    fn size(&self, main: i32, cross: i32) -> Size {
+        if self.horizontal { Size { width: main, height: cross } } else { Size { width: cross, height: main } }
+    }
+
+    /// Children laid end to end with `gap` between them, plus padding on every side.
+    /// A wrapping group given its main size (`main_limit`) stacks its lines instead.
+    pub fn content_size(&self, children: &[Size], main_limit: Option<i32>) -> Size {
+        let mains: Vec<i32> = children.iter().map(|c| self.axes(*c).0).collect();
+        let lines = self.lines(&mains, main_limit.map_or(i32::MAX, |limit| limit - 2 * self.padding));
+        let mut main = 0;
+        let mut cross = 0;
+        for line in &lines {
+            let line_main = mains[line.clone()].iter().sum::<i32>() + self.gap * (line.len() as i32 - 1).max(0);
+            main = main.max(line_main);
+            cross += children[line.clone()].iter().map(|c| self.axes(*c).1).max().unwrap_or(0);
+        }
+        cross += self.gap * (lines.len() as i32 - 1).max(0);
+        let size = self.size(main, cross);
+        Size { width: size.width + 2 * self.padding, height: size.height + 2 * self.padding }
+    }
+
+    /// Breaks children of main sizes `mains` into lines no longer than `limit`.
+    /// Everything stays on one line unless the group wraps.
+    fn lines(&self, mains: &[i32], limit: i32) -> Vec<Range<usize>> {
+        let mut lines = vec![];
+        let mut first = 0;
+        let mut used = 0;
+        for (i, main) in mains.iter().enumerate() {
+            if self.wrap && i > first && used + self.gap + main > limit {
+                lines.push(first..i);
+                first = i;
+                used = 0;
+            }
+            used += if i > first { self.gap } else { 0 } + main;
+        }
+        if first < mains.len() {
+            lines.push(first..mains.len());
+        }
+        lines
+    }
+
+    fn item(&self, child: &Child) -> Item {
+        let flex = child.flex;
+        let (own_main, cross) = self.axes(child.size);
+        let (min, max, min_cross, max_cross) = if self.horizontal {
+            (flex.min_width, flex.max_width, flex.min_height, flex.max_height)
+        } else {
+            (flex.min_height, flex.max_height, flex.min_width, flex.max_width)
+        };
+        let (min, max) = (min.unwrap_or(0), max.unwrap_or(i32::MAX).max(min.unwrap_or(0)));
+        let (min_cross, max_cross) = (min_cross.unwrap_or(0), max_cross.unwrap_or(i32::MAX).max(min_cross.unwrap_or(0)));
+        Item {
+            main: flex.basis.unwrap_or(own_main).clamp(min, max),
+            cross: cross.clamp(min_cross, max_cross),
+            min,
+            max,
+            min_cross,
+            max_cross,
+            grow: flex.grow.unwrap_or(0),
+            shrink: flex.shrink.unwrap_or(1),
+            frozen: false,
+        }
+    }
+
+    /// Rects of `children` inside a group occupying `frame`.
+    pub fn place(&self, frame: Bounds, children: &[Child]) -> Vec<Bounds> {
+        let inner = inset(frame, self.padding);
+        let (inner_main, inner_cross) = self.axes(Size { width: inner.width, height: inner.height });
+        let mut items: Vec<Item> = children.iter().map(|c| self.item(c)).collect();
+
+        // Break into lines, then grow or shrink each line
+        let mains: Vec<i32> = items.iter().map(|item| item.main).collect();
+        let mut rects = vec![];
+        let mut line_start = 0;
+        for line in self.lines(&mains, inner_main) {
+            let count = line.len() as i32;
+            let line_items = &mut items[line];
+            resolve_flexible(line_items, inner_main - self.gap * (count - 1));
+
+            // A single line spans the group; wrapped lines are as thick as their thickest child
+            let line_cross = if self.wrap {
+                line_items.iter().map(|item| item.cross).max().unwrap_or(0)
+            } else {
+                inner_cross
+            };
+
+            let free = inner_main - line_items.iter().map(|item| item.main).sum::<i32>() - self.gap * (count - 1);
+            let spread = free.max(0);
+            let (mut cursor, step) = match self.justify {
+                "center" => (free / 2, self.gap),
+                "end" => (free, self.gap),
+                "space-between" if count > 1 => (0, self.gap + spread / (count - 1)),
+                "space-around" => (spread / (2 * count), self.gap + spread / count),
+                "space-evenly" => (spread / (count + 1), self.gap + spread / (count + 1)),
+                _ => (0, self.gap),
+            };
+
+            for item in line_items.iter() {
+                let cross = if self.align == "stretch" {
+                    line_cross.clamp(item.min_cross, item.max_cross)
+                } else {
+                    item.cross.min(line_cross)
+                };
+                let offset = match self.align {
+                    "center" => (line_cross - cross) / 2,
+                    "end" => line_cross - cross,
+                    _ => 0,
+                };
+                let (dx, dy) = if self.horizontal {
+                    (cursor, line_start + offset)
+                } else {
+                    (line_start + offset, cursor)
+                };
+                let size = self.size(item.main, cross);
+                rects.push(Bounds { x: inner.x + dx, y: inner.y + dy, width: size.width, height: size.height });
+                cursor += item.main + step;
+            }
+            line_start += line_cross + self.gap;
+        }
+        rects
+    }
+}
+
+/// Grows or shrinks the main size of a line's items so they fill `available`:
+/// free space goes out by `grow`, overflow is taken back by `shrink` × size.
+/// Items that hit their min or max are frozen there and the rest shared again.
+fn resolve_flexible(items: &mut [Item], available: i32) {
+    let growing = items.iter().map(|item| item.main).sum::<i32>() < available;
+    for item in items.iter_mut() {
+        let weight = if growing { item.grow } else { item.shrink * item.main };
+        item.frozen = weight == 0;
+    }
+
+    loop {
+        let unfrozen: Vec<usize> = (0..items.len()).filter(|i| !items[*i].frozen).collect();
+        if unfrozen.is_empty() {
+            return;
+        }
+        let remaining = available - items.iter().map(|item| item.main).sum::<i32>();
+        let weights: Vec<i64> = unfrozen.iter()
+            .map(|i| if growing { items[*i].grow as i64 } else { (items[*i].shrink * items[*i].main) as i64 })
+            .collect();
+        let total: i64 = weights.iter().sum();
+
+        let mut targets: Vec<i32> = unfrozen.iter().zip(&weights)
+            .map(|(i, weight)| items[*i].main + (remaining as i64 * weight / total) as i32)
+            .collect();
+        // Rounding leftovers go to the last item so the line fills exactly
+        let handed_out: i32 = targets.iter().zip(&unfrozen).map(|(t, i)| t - items[*i].main).sum();
+        *targets.last_mut().unwrap() += remaining - handed_out;
+
+        let mut clamped = false;
+        for (target, i) in targets.iter().zip(&unfrozen) {
+            let item = &mut items[*i];
+            let bounded = (*target).clamp(item.min, item.max).max(0);
+            if bounded != *target {
+                item.main = bounded;
+                item.frozen = true;
+                clamped = true;
+            }
+        }
+        if !clamped {
+            for (target, i) in targets.iter().zip(&unfrozen) {
+                items[*i].main = *target;
+            }
+            return;
+        }
+    }
+}
+
+/// `frame` less `padding` on every side
+fn inset(frame: Bounds, padding: i32) -> Bounds {
+    Bounds {
+        x: frame.x + padding,
+        y: frame.y + padding,
+        width: (frame.width - 2 * padding).max(0),
+        height: (frame.height - 2 * padding).max(0),
+    }
+}
+
+/// How a grid sizes its tracks and fills its cells.
+pub struct Grid<'a> {
+    pub columns: &'a [Track],
+    pub rows: &'a [Track], // rows past these are `auto`
+    pub gap: i32,
+    pub padding: i32,
+}
+
+/// Cells a child covers, 0-based
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    row: usize,
+    column: usize,
+    row_span: usize,
+    column_span: usize,
+}
+
+impl Cell {
+    fn covered(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (self.row..self.row + self.row_span)
+            .flat_map(move |row| (self.column..self.column + self.column_span).map(move |column| (row, column)))
+    }
+}
+
+impl Grid<'_> {
+    /// Cell of every child: ones given both `row:` and `column:` first, then ones
+    /// pinned to a row, then the rest in source order, row by row into the next free cells.
+    fn cells(&self, children: &[Child]) -> Vec<Cell> {
+        let columns = self.columns.len();
+        let wanted: Vec<(Option<usize>, Option<usize>, Cell)> = children.iter()
+            .map(|child| {
+                let flex = child.flex;
+                let row = flex.row.map(|row| row as usize - 1);
+                let column = flex.column.map(|column| column as usize - 1);
+                let column_span = flex.column_span.unwrap_or(1) as usize;
+                if column.unwrap_or(0) + column_span > columns {
+                    panic!(
+                        "❌ Grid child at column {} spanning {} does not fit in {} columns",
+                        column.unwrap_or(0) + 1, column_span, columns
+                    );
+                }
+                let cell = Cell { row: row.unwrap_or(0), column: column.unwrap_or(0), row_span: flex.row_span.unwrap_or(1) as usize, column_span };
+                (row, column, cell)
+            })
+            .collect();
+
+        let mut taken = HashSet::new();
+        let mut cells = vec![None; children.len()];
+        for pass in 0..3 {
+            let mut cursor = (0, 0);
+            for (i, (row, column, cell)) in wanted.iter().enumerate() {
+                let mut cell = *cell;
+                match (pass, row, column) {
+                    (0, Some(_), Some(_)) => {}
+                    (1, Some(_), None) => {
+                        // First column of its row with room, or overlap at the start
+                        cell.column = (0..=columns - cell.column_span)
+                            .find(|column| Cell { column: *column, ..cell }.covered().all(|c| !taken.contains(&c)))
+                            .unwrap_or(0);
+                    }
+                    (2, None, _) => {
+                        (cell.row, cell.column) = cursor;
+                        if let Some(column) = column {
+                            if *column < cursor.1 {
+                                cell.row += 1;
+                            }
+                            cell.column = *column;
+                        }
+                        while cell.column + cell.column_span > columns || cell.covered().any(|c| taken.contains(&c)) {
+                            if column.is_some() || cell.column + cell.column_span >= columns {
+                                cell.row += 1;
+                                cell.column = column.unwrap_or(0);
+                            } else {
+                                cell.column += 1;
+                            }
+                        }
+                        cursor = (cell.row, cell.column + cell.column_span);
+                    }
+                    _ => continue,
+                }
+                taken.extend(cell.covered());
+                cells[i] = Some(cell);
+            }
+        }
+        cells.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Column and row sizes for children in `cells`, filling `available` (width, height) when known.
+    fn track_sizes(&self, children: &[Child], cells: &[Cell], available: Option<Size>) -> (Vec<i32>, Vec<i32>) {
+        let row_count = cells.iter().map(|cell| cell.row + cell.row_span).max().unwrap_or(0).max(self.rows.len());
+        let columns = size_tracks(
+            self.columns,
+            self.columns.len(),
+            &cells.iter().zip(children).map(|(cell, child)| (cell.column, cell.column_span, child.size.width)).collect::<Vec<_>>(),
+            self.gap,
+            available.map(|size| size.width),
+        );
+        let rows = size_tracks(
+            self.rows,
+            row_count,
+            &cells.iter().zip(children).map(|(cell, child)| (cell.row, cell.row_span, child.size.height)).collect::<Vec<_>>(),
+            self.gap,
+            available.map(|size| size.height),
+        );
+        (columns, rows)
+    }
+
+    /// Tracks at their content size, gaps between them and padding around.
+    pub fn content_size(&self, children: &[Child]) -> Size {
+        let (columns, rows) = self.track_sizes(children, &self.cells(children), None);
+        let extent = |sizes: &[i32]| sizes.iter().sum::<i32>() + self.gap * (sizes.len() as i32 - 1).max(0) + 2 * self.padding;
+        Size { width: extent(&columns), height: extent(&rows) }
+    }
+
+    /// Rects of `children` inside a grid occupying `frame`: each fills the cells
+    /// it covers, within its `min_*`/`max_*` sizes.
+    pub fn place(&self, frame: Bounds, children: &[Child]) -> Vec<Bounds> {
+        let inner = inset(frame, self.padding);
+        let cells = self.cells(children);
+        let (columns, rows) = self.track_sizes(children, &cells, Some(Size { width: inner.width, height: inner.height }));
+        let starts = |sizes: &[i32]| -> Vec<i32> {
+            sizes.iter().scan(0, |at, size| {
+                let start = *at;
+                *at += size + self.gap;
+                Some(start)
+            }).collect()
+        };
+        let (column_starts, row_starts) = (starts(&columns), starts(&rows));
+
+        cells.iter().zip(children)
+            .map(|(cell, child)| {
+                let last_column = cell.column + cell.column_span - 1;
+                let last_row = cell.row + cell.row_span - 1;
+                let width = column_starts[last_column] + columns[last_column] - column_starts[cell.column];
+                let height = row_starts[last_row] + rows[last_row] - row_starts[cell.row];
+                let flex = child.flex;
+                Bounds {
+                    x: inner.x + column_starts[cell.column],
+                    y: inner.y + row_starts[cell.row],
+                    width: width.min(flex.max_width.unwrap_or(i32::MAX)).max(flex.min_width.unwrap_or(0)),
+                    height: height.min(flex.max_height.unwrap_or(i32::MAX)).max(flex.min_height.unwrap_or(0)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Sizes of `count` tracks, `tracks` then `auto` ones, holding `items` as
+/// (first track, span, content size). `fr` tracks share what `available` leaves
+/// after the others and the gaps; without it they fit their content in proportion.
+fn size_tracks(tracks: &[Track], count: usize, items: &[(usize, usize, i32)], gap: i32, available: Option<i32>) -> Vec<i32> {
+    let track = |i: usize| tracks.get(i).copied().unwrap_or(Track::Auto);
+    let mut sizes: Vec<i32> = (0..count)
+        .map(|i| match track(i) {
+            Track::Fixed(size) => size,
+            _ => 0,
+        })
+        .collect();
+
+    // Single-track items first, then spanning ones top up the tracks they cross that can grow
+    for (first, _, size) in items.iter().filter(|item| item.1 == 1) {
+        if !matches!(track(*first), Track::Fixed(_)) {
+            sizes[*first] = sizes[*first].max(*size);
+        }
+    }
+    for (first, span, size) in items.iter().filter(|item| item.1 > 1) {
+        let covered = *first..first + span;
+        let current = sizes[covered.clone()].iter().sum::<i32>() + gap * (*span as i32 - 1);
+        let growable: Vec<usize> = covered.filter(|i| !matches!(track(*i), Track::Fixed(_))).collect();
+        if *size > current && !growable.is_empty() {
+            let extra = size - current;
+            for (n, i) in growable.iter().enumerate() {
+                sizes[*i] += extra / growable.len() as i32 + if n == 0 { extra % growable.len() as i32 } else { 0 };
+            }
+        }
+    }
+
+    let fractions: Vec<(usize, i32)> = (0..count)
+        .filter_map(|i| match track(i) {
+            Track::Fraction(fr) => Some((i, fr)),
+            _ => None,
+        })
+        .collect();
+    let total: i32 = fractions.iter().map(|(_, fr)| fr).sum();
+    match available {
+        _ if total == 0 => {}
+        Some(available) => {
+            let others: i32 = (0..count).filter(|i| !matches!(track(*i), Track::Fraction(_))).map(|i| sizes[i]).sum();
+            let rest = (available - others - gap * (count as i32 - 1)).max(0);
+            let mut handed_out = 0;
+            for (n, (i, fr)) in fractions.iter().enumerate() {
+                // Rounding leftovers go to the last fr track
+                sizes[*i] = if n + 1 == fractions.len() { rest - handed_out } else { rest * fr / total };
+                handed_out += sizes[*i];
+            }
+        }
+        None => {
+            let unit = fractions.iter().map(|(i, fr)| (sizes[*i] + fr - 1) / fr).max().unwrap_or(0);
+            for (i, fr) in &fractions {
+                sizes[*i] = unit * fr;
+            }
+        }
+    }
+    sizes
+}
+
+/// Estimated extent of a `text` value in `font_size`; the real glyphs are only measured by the host.
+pub fn text_size(value: &Expr, font_size: i32) -> Size {
+    match value {
+        Expr::StringLiteral(s) => Size { width: s.chars().count() as i32 * TEXT_CHAR_WIDTH * font_size / TEXT_HEIGHT, height: font_size },
+        Expr::Identifier(_) => Size { width: 999, height: font_size }, // length unknown at compile time
+        _ => panic!("Unsupported value type in Text layout: {:?}", value),
+    }
+}
+
+/// Room `node` takes in its parent's layout, or None when it takes none (`let`, calls, ...).
+pub fn measure(node: &Node) -> Option<Size> {
+    match node {
+        Node::Let { .. } | Node::Assign { .. } | Node::Expr(..) | Node::State { .. } | Node::Style { .. } | Node::Function { .. } => None,
+        Node::Box { width, height, .. } | Node::Shape { width, height, .. } => Some(Size { width: *width, height: *height }),
+        Node::Text { value, style, .. } => Some(text_size(value, style::font_size(style))),
+        Node::Group { direction, gap, padding, width, height, wrap, children, .. } => {
+            let horizontal = direction == "horizontal";
+            let flow = Flow { horizontal, gap: *gap, padding: *padding, align: "start", justify: "start", wrap: *wrap };
+            let sizes: Vec<Size> = children.iter().filter_map(|child| flow.measure_child(child)).collect();
+            let content = flow.content_size(&sizes, if horizontal { *width } else { *height });
+            Some(Size { width: width.unwrap_or(content.width), height: height.unwrap_or(content.height) })
+        }
+        Node::Grid { columns, rows, gap, padding, width, height, children, .. } => {
+            let grid = Grid { columns, rows, gap: *gap, padding: *padding };
+            let laid_out: Vec<Child> = children.iter().filter_map(child).collect();
+            let content = grid.content_size(&laid_out);
+            Some(Size { width: width.unwrap_or(content.width), height: height.unwrap_or(content.height) })
+        }
+        Node::List { direction, gap, padding, items, .. } => {
+            let flow = list_flow(direction, *gap, *padding);
+            let sizes: Vec<Size> = items.iter().filter_map(|item| flow.measure_child(item)).collect();
+            Some(flow.content_size(&sizes, None))
+        }
+        Node::Item { children, .. } => {
+            let sizes: Vec<Size> = children.iter().filter_map(|child| ITEM_FLOW.measure_child(child)).collect();
+            Some(ITEM_FLOW.content_size(&sizes, None))
+        }
+        _ => Some(Size { width: UNKNOWN_SIZE, height: UNKNOWN_SIZE }),
+    }
+}
+
+/// How a list lines up its items: like a group that neither aligns nor justifies.
+pub fn list_flow(direction: &str, gap: i32, padding: i32) -> Flow<'static> {
+    Flow { horizontal: direction == "horizontal", gap, padding, align: "start", justify: "start", wrap: false }
+}
+
+/// How the nodes of an `if`/`match` branch line up in the room their container
+/// gives the `if`/`match`: as its children, `horizontal` with `gap` between them.
+pub fn branch_flow(horizontal: bool, gap: i32) -> Flow<'static> {
+    Flow { horizontal, gap, padding: 0, align: "start", justify: "start", wrap: false }
+}
+
+/// The bodies of an `if` (`else if` chains flattened, as the compiler does) or `match`
+pub fn branches(node: &Node) -> Vec<&[Node]> {
+    match node {
+        Node::If { then_body, else_body, .. } => {
+            let mut bodies = vec![then_body.as_slice()];
+            match else_body.as_deref() {
+                Some([nested @ Node::If { .. }]) => bodies.extend(branches(nested)),
+                Some(body) => bodies.push(body),
+                None => {}
+            }
+            bodies
+        }
+        Node::Match { arms, .. } => arms.iter().map(|(_, body)| body.as_slice()).collect(),
+        _ => vec![],
+    }
+}
+
+/// How an item stacks its nodes
+pub const ITEM_FLOW: Flow<'static> = Flow { horizontal: false, gap: 0, padding: 0, align: "start", justify: "start", wrap: false };
+
+/// The items of a list with every `for` over a constant array unrolled, one group per
+/// item written, so a list holding one can be estimated too. `arrays` are the constant
+/// arrays bound by `let`; None when some `for` runs over an array known only at runtime.
+pub fn unroll(items: &[Node], arrays: &HashMap<String, Vec<Expr>>) -> Option<Vec<Vec<Node>>> {
+    items.iter().map(|item| match item {
+        Node::For { name, items: array, body, .. } => {
+            let elements = match array {
+                Expr::Array(elements) => elements,
+                Expr::Identifier(var) => arrays.get(var)?,
+                _ => return None,
+            };
+            let mut passes = vec![];
+            for element in elements {
+                let text = match element {
+                    Expr::Literal(n) => n.to_string(),
+                    Expr::StringLiteral(text) => text.clone(),
+                    _ => return None,
+                };
+                let body: Vec<Node> = body.iter().map(|node| substitute(node, name, &text)).collect();
+                passes.extend(unroll(&body, arrays)?.into_iter().flatten());
+            }
+            Some(passes)
+        }
+        _ => Some(vec![item.clone()]),
+    }).collect()
+}
+
+/// `node` with the texts showing `name` showing `text` instead
+fn substitute(node: &Node, name: &str, text: &str) -> Node {
+    let mut node = node.clone();
+    match &mut node {
+        Node::Text { value, .. } => {
+            if matches!(value, Expr::Identifier(var) if var == name) {
+                *value = Expr::StringLiteral(text.to_string());
+            }
+        }
+        Node::Group { children, .. }
+        | Node::Grid { children, .. }
+        | Node::List { items: children, .. }
+        | Node::Item { children, .. }
+        | Node::For { body: children, .. } => {
+            *children = children.iter().map(|child| substitute(child, name, text)).collect();
+        }
+        _ => {}
+    }
+    node
+}
+
+/// Whether the size of `node` is known at compile time, if only as an estimate:
+/// no text bound to a variable and no `for`, whose size depends on what runs. An
+/// `if`/`match` is estimated as its largest branch.
+pub fn is_estimable(node: &Node) -> bool {
+    match node {
+        Node::Text { value, .. } => matches!(value, Expr::StringLiteral(_)),
+        Node::Group { children, .. }
+        | Node::Grid { children, .. }
+        | Node::List { items: children, .. }
+        | Node::Item { children, .. } => children.iter().all(is_estimable),
+        Node::If { .. } | Node::Match { .. } => branches(node).into_iter().flatten().all(is_estimable),
+        Node::For { .. } => false,
+        _ => true,
+    }
+}
+
+/// Whether `node` can be laid out here at compile time. Texts cannot: only the
+/// host knows how wide they are. Grids always are, their texts estimated.
+pub fn is_static(node: &Node) -> bool {
+    match node {
+        Node::Group { children, .. } => children.iter().all(is_static),
+        Node::Grid { .. } => true,
+        Node::Text { .. } | Node::List { .. } | Node::If { .. } | Node::Match { .. } => false,
+        _ => true,
+    }
+}
+
+/// Whether compiling `node` lays out a group or list at render time, see runtime_layout.rs.
+pub fn uses_runtime_layout(node: &Node) -> bool {
+    match node {
+        Node::Group { .. } if !is_static(node) => true,
+        Node::List { .. } => true,
+        Node::Group { children, .. } | Node::Grid { children, .. } | Node::If { then_body: children, else_body: None, .. } => {
+            children.iter().any(uses_runtime_layout)
+        }
+        Node::If { then_body, else_body: Some(else_body), .. } => then_body.iter().chain(else_body).any(uses_runtime_layout),
+        Node::Match { arms, .. } => arms.iter().flat_map(|(_, body)| body).any(uses_runtime_layout),
+        _ => false,
+    }
+}
+
+/// Whether `flex` asks to grow, shrink or be clamped.
+pub fn is_flexible(flex: &FlexItem) -> bool {
+    flex.grow.is_some()
+        || flex.shrink.is_some()
+        || flex.basis.is_some()
+        || flex.min_width.is_some()
+        || flex.max_width.is_some()
+        || flex.min_height.is_some()
+        || flex.max_height.is_some()
+}
+
+/// How `node` grows, shrinks and is clamped as a child of a group.
+pub fn flex(node: &Node) -> &FlexItem {
+    match node {
+        Node::Box { flex, .. }
+        | Node::Text { flex, .. }
+        | Node::Shape { flex, .. }
+        | Node::Group { flex, .. }
+        | Node::Grid { flex, .. }
+        | Node::List { flex, .. } => flex,
+        _ => &NO_FLEX,
+    }
+}
+
+/// `node` as a child of a group, or None when it takes no room.
+pub fn child(node: &Node) -> Option<Child<'_>> {
+    measure(node).map(|size| Child { size, flex: flex(node) })
+}
+
+/// Which of width and height a top-level group takes from the host's canvas at render time:
+/// those it leaves out, when it can be laid out then. Grids, and groups holding one, keep the
+/// compile-time canvas.
+pub fn canvas_fill(node: &Node) -> (bool, bool) {
+    match node {
+        Node::Group { width, height, .. } if !holds_grid(node) => (width.is_none(), height.is_none()),
+        _ => (false, false),
+    }
+}
+
+fn holds_grid(node: &Node) -> bool {
+    match node {
+        Node::Grid { .. } => true,
+        Node::Group { children, .. } => children.iter().any(holds_grid),
+        _ => false,
+    }
+}
+
+/// A top-level group or grid without an explicit size, sized to the canvas instead of its children.
+pub fn fit_to_canvas(node: &Node, canvas: Size) -> Option<Node> {
+    match node {
+        Node::Group { width: None, .. } | Node::Group { height: None, .. }
+        | Node::Grid { width: None, .. } | Node::Grid { height: None, .. } => {
+            let mut fitted = node.clone();
+            if let Node::Group { width, height, .. } | Node::Grid { width, height, .. } = &mut fitted {
+                width.get_or_insert(canvas.width);
+                height.get_or_insert(canvas.height);
+            }
+            Some(fitted)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(x: i32, y: i32, width: i32, height: i32) -> Bounds {
+        Bounds { x, y, width, height }
+    }
+
+    fn sized(width: i32, height: i32, flex: &FlexItem) -> Child<'_> {
+        Child { size: Size { width, height }, flex }
+    }
+
+    fn row(gap: i32, padding: i32, justify: &str, wrap: bool) -> Flow<'_> {
+        Flow { horizontal: true, gap, padding, align: "start", justify, wrap }
+    }
+
+    #[test]
+    fn flow_lines_up_children_with_gap_and_padding() {
+        let children = [sized(10, 10, &NO_FLEX), sized(20, 5, &NO_FLEX)];
+        let rects = row(5, 2, "start", false).place(bounds(0, 0, 100, 30), &children);
+        assert_eq!(rects, [bounds(2, 2, 10, 10), bounds(17, 2, 20, 5)]);
+    }
+
+    #[test]
+    fn flow_justifies_the_free_space() {
+        let children = [sized(10, 10, &NO_FLEX), sized(10, 10, &NO_FLEX)];
+        let rects = row(0, 0, "space-between", false).place(bounds(0, 0, 100, 10), &children);
+        assert_eq!(rects, [bounds(0, 0, 10, 10), bounds(90, 0, 10, 10)]);
+        let rects = row(0, 0, "center", false).place(bounds(0, 0, 100, 10), &children);
+        assert_eq!(rects, [bounds(40, 0, 10, 10), bounds(50, 0, 10, 10)]);
+    }
+
+    #[test]
+    fn flow_grows_into_free_space_within_max() {
+        let grows = FlexItem { grow: Some(1), ..NO_FLEX };
+        let capped = FlexItem { grow: Some(1), max_width: Some(30), ..NO_FLEX };
+        let rects = row(0, 0, "start", false).place(bounds(0, 0, 100, 10), &[sized(10, 10, &grows), sized(10, 10, &capped)]);
+        assert_eq!(rects, [bounds(0, 0, 70, 10), bounds(70, 0, 30, 10)]);
+    }
+
+    #[test]
+    fn flow_shrinks_overflow_by_basis_within_min() {
+        let kept = FlexItem { min_width: Some(60), ..NO_FLEX };
+        let rects = row(0, 0, "start", false).place(bounds(0, 0, 100, 10), &[sized(80, 10, &kept), sized(80, 10, &NO_FLEX)]);
+        assert_eq!(rects, [bounds(0, 0, 60, 10), bounds(60, 0, 40, 10)]);
+    }
+
+    #[test]
+    fn flow_wraps_onto_new_lines() {
+        let children = [sized(10, 10, &NO_FLEX), sized(10, 15, &NO_FLEX), sized(10, 10, &NO_FLEX)];
+        let flow = row(2, 0, "start", true);
+        assert_eq!(flow.place(bounds(0, 0, 25, 40), &children), [bounds(0, 0, 10, 10), bounds(12, 0, 10, 15), bounds(0, 17, 10, 10)]);
+        assert_eq!(flow.content_size(&children.map(|child| child.size), Some(25)), Size { width: 22, height: 27 });
+    }
+
+    #[test]
+    fn grid_sizes_fixed_and_fraction_columns() {
+        let columns = [Track::Fixed(20), Track::Fraction(1), Track::Fraction(2)];
+        let grid = Grid { columns: &columns, rows: &[], gap: 10, padding: 0 };
+        let children = [sized(5, 5, &NO_FLEX), sized(5, 5, &NO_FLEX), sized(5, 8, &NO_FLEX), sized(5, 5, &NO_FLEX)];
+        let rects = grid.place(bounds(0, 0, 100, 100), &children);
+        assert_eq!(rects[..3], [bounds(0, 0, 20, 8), bounds(30, 0, 20, 8), bounds(60, 0, 40, 8)]);
+        assert_eq!(rects[3], bounds(0, 18, 20, 5)); // auto-placed on a new auto row
+    }
+
+    #[test]
+    fn grid_places_explicit_cells_and_spans() {
+        let columns = [Track::Fixed(10), Track::Fixed(10), Track::Fixed(10)];
+        let grid = Grid { columns: &columns, rows: &[], gap: 0, padding: 0 };
+        let spanning = FlexItem { row: Some(2), column: Some(2), column_span: Some(2), ..NO_FLEX };
+        let rects = grid.place(bounds(0, 0, 30, 20), &[sized(5, 10, &spanning), sized(5, 10, &NO_FLEX)]);
+        assert_eq!(rects, [bounds(10, 10, 20, 10), bounds(0, 0, 10, 10)]);
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use crate::parser::Expr;
+
+/// Constants every call would otherwise allocate again: string literals, path and
+/// image data, arrays of literals and closure objects. They are laid out in one passive data segment
+/// that `run` copies once into a rooted GC buffer; a literal is then that buffer's
+/// base plus its offset here.
+#[derive(Default)]
+pub struct LiteralPool {
+    bytes: Vec<u8>,
+    offsets: HashMap<(Vec<u8>, Vec<u32>), u32>, // equal literals share their bytes
+    relocations: Vec<u32>,          // i32s holding pool offsets, rebased by `run` after copying
+}
+
+impl LiteralPool {
+    /// Offset of `bytes`, 4-byte aligned
+    pub fn intern(&mut self, bytes: &[u8]) -> u32 {
+        self.insert(bytes, &[])
+    }
+
+    /// Offset of a string with the NUL terminator every string value carries
+    pub fn intern_string(&mut self, text: &str) -> u32 {
+        self.intern(&[text.as_bytes(), &[0]].concat())
+    }
+
+    /// Offset of `[len][element]...` for an array whose elements are all literals, see `is_constant_array`
+    pub fn intern_array(&mut self, elements: &[Expr]) -> u32 {
+        let mut bytes = (elements.len() as u32).to_le_bytes().to_vec();
+        let mut pointers = vec![];
+        for (i, element) in elements.iter().enumerate() {
+            let value = match element {
+                Expr::Literal(n) => *n as u32,
+                Expr::StringLiteral(text) => {
+                    pointers.push(4 + 4 * i as u32);
+                    self.intern_string(text)
+                }
+                _ => unreachable!("not a constant array element: {:?}", element),
+            };
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        self.insert(&bytes, &pointers)
+    }
+
+    /// Offset of a closure object `[table slot][env ptr]` over a function that captures nothing
+    pub fn intern_closure(&mut self, slot: u32) -> u32 {
+        self.intern(&[slot.to_le_bytes(), [0; 4]].concat())
+    }
+
+    /// Offset of a closure object shared by no other literal, its env of `captures`
+    /// words right behind it. The code creating the closure refills that env each
+    /// time rather than allocating a new closure.
+    pub fn reserve_closure(&mut self, slot: u32, captures: usize) -> u32 {
+        self.align();
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(&slot.to_le_bytes());
+        self.bytes.extend_from_slice(&(offset + 8).to_le_bytes());
+        self.bytes.resize(self.bytes.len() + 4 * captures, 0);
+        self.relocations.push(offset + 4);
+        offset
+    }
+
+    fn insert(&mut self, bytes: &[u8], pointers: &[u32]) -> u32 {
+        let key = (bytes.to_vec(), pointers.to_vec());
+        if let Some(offset) = self.offsets.get(&key) {
+            return *offset;
+        }
+        self.align();
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(bytes);
+        self.relocations.extend(pointers.iter().map(|at| offset + at));
+        self.offsets.insert(key, offset);
+        offset
+    }
+
+    fn align(&mut self) {
+        while !self.bytes.len().is_multiple_of(4) {
+            self.bytes.push(0);
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn relocations(&self) -> &[u32] {
+        &self.relocations
+    }
+}
+
+/// Whether `elements` can be pooled: only int and string literals
+pub fn is_constant_array(elements: &[Expr]) -> bool {
+    !elements.is_empty() && elements.iter().all(|e| matches!(e, Expr::Literal(_) | Expr::StringLiteral(_)))
+}
@@ -3,6 +3,16 @@ mod transpile;
 mod map;
 mod gc;
 mod memory;
+mod closure;
+mod debuginfo;
+mod a11y;
+mod state;
+mod phase;
+mod frame;
+mod layout;
+mod runtime_layout;
+mod style;
+mod literals;
 
 use std::fs;
 use std::env;
@@ -19,26 +29,69 @@ pub extern "C" fn gc_tick() {
 
 fn main() {
     // Step 1: Read W++ source file
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let canvas = take_canvas_size(&mut args);
+    let theme = take_theme(&mut args);
+    if args.get(1).map(String::as_str) == Some("--check-map") {
+        check_map(args.get(2).map(String::as_str).unwrap_or("ui.wpp.map.json"));
+        return;
+    }
 let filename = args.get(1).map(String::as_str).unwrap_or("ui.wpp");
 
 let source = fs::read_to_string(filename)
     .unwrap_or_else(|_| panic!("❌ Failed to read {}", filename));
 
     // Step 2: Parse W++ source into AST
-    let ast: Vec<Node> = parse_wpp(&source);
+    let mut ast: Vec<Node> = parse_wpp(&source);
     println!("✅ Parsed W++ source with {} root nodes", ast.len());
+    if let Some(theme) = theme {
+        ast = style::with_theme(ast, theme);
+    }
 
     // Step 3: Transpile AST to WASM + semantic map
-    let (wasm_bytes, semantic_map_json) = compile_to_wasm(&ast);
+    let output = compile_to_wasm(&ast, filename, "ui.wasm.map", canvas);
 
     // Step 4: Write output files
-    fs::write("ui.wasm", wasm_bytes).expect("❌ Failed to write ui.wasm");
-    fs::write("ui.wpp.map.json", semantic_map_json).expect("❌ Failed to write semantic map");
+    fs::write("ui.wasm", output.wasm).expect("❌ Failed to write ui.wasm");
+    fs::write("ui.wpp.map.json", output.semantic_map).expect("❌ Failed to write semantic map");
+    fs::write("ui.wasm.map", output.source_map).expect("❌ Failed to write source map");
+    fs::write("ui.wpp.a11y.json", output.a11y_tree).expect("❌ Failed to write accessibility tree");
 
-    println!("✅ Compilation complete: ui.wasm & ui.wpp.map.json");
+    println!("✅ Compilation complete: ui.wasm, ui.wpp.map.json, ui.wasm.map & ui.wpp.a11y.json");
 
     // Step 5: Optional GC collection pass after compile
     //gc_collect();
    // println!("🧹 GC run complete");
 }
+
+/// `--canvas WxH`: size of the host's canvas, which top-level groups fill.
+fn take_canvas_size(args: &mut Vec<String>) -> layout::Size {
+    let Some(flag) = args.iter().position(|arg| arg == "--canvas") else {
+        return layout::DEFAULT_CANVAS;
+    };
+    let value = args.get(flag + 1).cloned().unwrap_or_default();
+    args.drain(flag..(flag + 2).min(args.len()));
+    layout::parse_size(&value).unwrap_or_else(|| panic!("❌ --canvas expects WIDTHxHEIGHT, got '{}'", value))
+}
+
+/// `--theme file.wpp`: style declarations replacing the source's ones of the same name.
+fn take_theme(args: &mut Vec<String>) -> Option<Vec<Node>> {
+    let flag = args.iter().position(|arg| arg == "--theme")?;
+    let path = args.get(flag + 1).cloned().unwrap_or_else(|| panic!("❌ --theme expects a file"));
+    args.drain(flag..flag + 2);
+    let source = fs::read_to_string(&path).unwrap_or_else(|_| panic!("❌ Failed to read theme {}", path));
+    let theme = parse_wpp(&source);
+    println!("🎨 Loaded {} styles from theme {}", theme.len(), path);
+    Some(theme)
+}
+
+/// `--check-map [file]`: validates a semantic map written by any compatible compiler.
+fn check_map(path: &str) {
+    match map::read_map(path) {
+        Ok(semantic) => println!(
+            "✅ {} is a valid v{} semantic map with {} elements",
+            path, semantic.version, semantic.elements.len()
+        ),
+        Err(error) => panic!("❌ Invalid semantic map {}", error),
+    }
+}
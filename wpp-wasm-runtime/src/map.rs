@@ -1,16 +1,519 @@
-use serde::Serialize;
-use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use serde::{Deserialize, Serialize};
+use crate::parser::{A11yAttrs, Expr, FlexItem, Handlers, Span, Style};
+use crate::a11y::{A11yNode, Bounds};
+use crate::literals::LiteralPool;
 
-#[derive(Serialize)]
+/// Version of the `ui.wpp.map.json` format, see `schema/semantic-map.schema.json`.
+/// Adding optional fields keeps the version; anything a v1 reader would misread bumps it.
+pub const MAP_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
 pub struct ElementMap {
-    pub kind: String,              // e.g. "box", "button"
-    pub wasm_offset: u32,          // Approximate instruction offset
+    pub id: u32,                   // Index in `elements`, stable for a given source
+    pub parent: Option<u32>,       // Id of the enclosing group/grid/list/item/if, None at top level
+    #[serde(flatten)]
+    pub props: ElementProps,       // `kind` plus the props of that kind
+    pub wasm_start: u32,           // Module byte offset of the element's first instruction
+    pub wasm_end: u32,             // Module byte offset just past its last instruction
     pub pointer: Option<i32>,      // GC pointer if known
-    pub source: Option<String>,    // e.g. "ui.wpp:1"
-    pub props: Option<Value>,      // Extra fields like x/y/width/height/text
+    pub source: Option<SourceLocation>, // where the element is written
+    pub bounds: Option<Bounds>,    // Computed canvas rect, None when only known at runtime
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub repeated: bool,            // Drawn once per pass of a `for`, `bounds` is where the first pass lands
+}
+
+/// Written as `"kind": "box", "props": {...}`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", content = "props", rename_all = "lowercase")]
+pub enum ElementProps {
+    Box(BoxProps),
+    Text(TextProps),
+    Line(LineProps),
+    Circle(CircleProps),
+    Path(PathProps),
+    Image(ImageProps),
+    Group(GroupProps),
+    Grid(GridProps),
+    List(ListProps),
+    Item(ItemProps),
+    If(IfProps),
+    Match(MatchProps),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BoxProps {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub handlers: Handlers,
+    #[serde(flatten)]
+    pub flex: FlexItem,
+    #[serde(flatten)]
+    pub style: Style,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TextProps {
+    pub x: i32,
+    pub y: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>, // only for literal text
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub handlers: Handlers,
+    #[serde(flatten)]
+    pub flex: FlexItem,
+    #[serde(flatten)]
+    pub style: Style,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LineProps {
+    pub x1: i32,
+    pub y1: i32,
+    pub x2: i32,
+    pub y2: i32,
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub handlers: Handlers,
+    #[serde(flatten)]
+    pub flex: FlexItem,
+    #[serde(flatten)]
+    pub style: Style,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CircleProps {
+    pub cx: i32,
+    pub cy: i32,
+    pub radius: i32,
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub handlers: Handlers,
+    #[serde(flatten)]
+    pub flex: FlexItem,
+    #[serde(flatten)]
+    pub style: Style,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PathProps {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,  // extent of its commands
+    pub height: i32,
+    pub d: String,
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub handlers: Handlers,
+    #[serde(flatten)]
+    pub flex: FlexItem,
+    #[serde(flatten)]
+    pub style: Style,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ImageProps {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub src: String,
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub handlers: Handlers,
+    #[serde(flatten)]
+    pub flex: FlexItem,
+    #[serde(flatten)]
+    pub style: Style,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GroupProps {
+    pub direction: String,
+    pub gap: i32,
+    pub padding: i32,
+    pub align: String,
+    pub justify: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,  // explicit or given by the enclosing group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub wrap: bool,
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub handlers: Handlers,
+    #[serde(flatten)]
+    pub flex: FlexItem,
+    #[serde(flatten)]
+    pub style: Style,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GridProps {
+    pub columns: String, // track list as written, e.g. "100 1fr auto"
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub rows: String,
+    pub gap: i32,
+    pub padding: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub handlers: Handlers,
+    #[serde(flatten)]
+    pub flex: FlexItem,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ListProps {
+    pub direction: String,
+    pub gap: i32,
+    pub padding: i32,
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub flex: FlexItem,
+}
+
+/// An entry of a list. Items a `for` generates share the elements of its body,
+/// which get no bounds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ItemProps {
+    #[serde(flatten)]
+    pub a11y: A11yAttrs,
+    #[serde(flatten)]
+    pub handlers: Handlers,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IfProps {
+    pub branches: Vec<IfBranch>,
+    #[serde(rename = "else")]
+    pub else_statements: Option<usize>, // statement count of the final `else`, if any
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IfBranch {
+    pub condition: String, // W++ source text of the condition
+    pub condition_span: Span, // where the condition is written, in the element's file
+    pub statements: usize,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MatchProps {
+    pub value: String, // W++ source text of the matched expression
+    pub value_span: Span,
+    pub arms: Vec<MatchArm>, // in source order, unreachable ones included
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MatchArm {
+    pub pattern: String, // as written: `3`, `"on"` or `_`
+    pub statements: usize,
+}
+
+impl ElementProps {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ElementProps::Box(_) => "box",
+            ElementProps::Text(_) => "text",
+            ElementProps::Line(_) => "line",
+            ElementProps::Circle(_) => "circle",
+            ElementProps::Path(_) => "path",
+            ElementProps::Image(_) => "image",
+            ElementProps::Group(_) => "group",
+            ElementProps::Grid(_) => "grid",
+            ElementProps::List(_) => "list",
+            ElementProps::Item(_) => "item",
+            ElementProps::If(_) => "if",
+            ElementProps::Match(_) => "match",
+        }
+    }
+
+    pub fn handlers(&self) -> Option<&Handlers> {
+        match self {
+            ElementProps::Box(props) => Some(&props.handlers),
+            ElementProps::Text(props) => Some(&props.handlers),
+            ElementProps::Line(props) => Some(&props.handlers),
+            ElementProps::Circle(props) => Some(&props.handlers),
+            ElementProps::Path(props) => Some(&props.handlers),
+            ElementProps::Image(props) => Some(&props.handlers),
+            ElementProps::Group(props) => Some(&props.handlers),
+            ElementProps::Grid(props) => Some(&props.handlers),
+            ElementProps::Item(props) => Some(&props.handlers),
+            ElementProps::List(_) | ElementProps::If(_) | ElementProps::Match(_) => None,
+        }
+    }
+
+    fn is_container(&self) -> bool {
+        matches!(
+            self,
+            ElementProps::Group(_)
+                | ElementProps::Grid(_)
+                | ElementProps::List(_)
+                | ElementProps::Item(_)
+                | ElementProps::If(_)
+                | ElementProps::Match(_)
+        )
+    }
+}
+
+/// `{file, line, column, end_line, end_column}` of an element in the `.wpp` source
+#[derive(Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub file: String,
+    #[serde(flatten)]
+    pub span: Span,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct SemanticMap {
+    pub version: u32,
     pub elements: Vec<ElementMap>,
 }
+
+/// Loads a `ui.wpp.map.json` and checks it against the format this compiler writes.
+pub fn read_map(path: &str) -> Result<SemanticMap, String> {
+    let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse_map(&json).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn parse_map(json: &str) -> Result<SemanticMap, String> {
+    let value: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    match value.get("version").and_then(|v| v.as_u64()) {
+        None => return Err("map has no version; it predates the versioned format, recompile it".to_string()),
+        Some(v) if v == 0 || v > MAP_VERSION as u64 => {
+            return Err(format!("map version {} is not supported (this reader knows 1 to {})", v, MAP_VERSION));
+        }
+        Some(_) => {}
+    }
+    let map: SemanticMap = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    validate(&map)?;
+    Ok(map)
+}
+
+/// Structure the schema cannot express: ids are indices, and elements come
+/// before the container that holds them.
+fn validate(map: &SemanticMap) -> Result<(), String> {
+    for (index, element) in map.elements.iter().enumerate() {
+        if element.id as usize != index {
+            return Err(format!("element #{} has id {}", index, element.id));
+        }
+        if element.wasm_start > element.wasm_end {
+            return Err(format!("element {} ends before it starts", element.id));
+        }
+        if let Some(parent) = element.parent {
+            let Some(container) = map.elements.get(parent as usize) else {
+                return Err(format!("element {} has unknown parent {}", element.id, parent));
+            };
+            if parent <= element.id || !container.props.is_container() {
+                return Err(format!(
+                    "element {} cannot be a child of {} ({})",
+                    element.id, parent, container.props.kind()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether hit_test knows the element: everything but `if` and `match`. Elements laid out at
+/// render time start with empty bounds that `set_bounds` fills in.
+fn is_hit_testable(element: &ElementMap) -> bool {
+    !matches!(element.props, ElementProps::If(_) | ElementProps::Match(_))
+}
+
+/// Whether the element has a record in the compile-time table: a repeated one gets a
+/// record per pass from `set_bounds` instead.
+fn has_static_record(element: &ElementMap) -> bool {
+    is_hit_testable(element) && !element.repeated
+}
+
+/// `(id, bounds)` of every element with a static record in hit-test order: reverse pre-order,
+/// so children win over their container and later siblings over earlier ones.
+pub fn hit_order(elements: &[ElementMap]) -> Vec<(u32, Bounds)> {
+    fn visit(id: u32, children: &[Vec<u32>], order: &mut Vec<u32>) {
+        order.push(id);
+        for child in &children[id as usize] {
+            visit(*child, children, order);
+        }
+    }
+
+    let mut children = vec![vec![]; elements.len()];
+    let mut roots = vec![];
+    for element in elements {
+        match element.parent {
+            Some(parent) => children[parent as usize].push(element.id),
+            None => roots.push(element.id),
+        }
+    }
+    let mut order = vec![];
+    for root in roots {
+        visit(root, &children, &mut order);
+    }
+
+    order.iter().rev()
+        .map(|id| &elements[*id as usize])
+        .filter(|element| has_static_record(element))
+        .map(|element| (element.id, element.bounds.unwrap_or_default()))
+        .collect()
+}
+
+/// Handlers each hit-testable element responds to: per event its own, else the
+/// nearest ancestor's, so a click on a box inside a group reaches the group's `on_click`.
+pub fn bubbled_handlers(elements: &[ElementMap]) -> Vec<(u32, Handlers)> {
+    let mut routes = vec![];
+    for element in elements.iter().filter(|e| is_hit_testable(e)) {
+        let mut handlers = Handlers::default();
+        let mut current = Some(element);
+        while let Some(node) = current {
+            if let Some(own) = node.props.handlers() {
+                handlers.on_click = handlers.on_click.or_else(|| own.on_click.clone());
+                handlers.on_key = handlers.on_key.or_else(|| own.on_key.clone());
+                handlers.on_hover = handlers.on_hover.or_else(|| own.on_hover.clone());
+            }
+            current = node.parent.map(|parent| &elements[parent as usize]);
+        }
+        if handlers != Handlers::default() {
+            routes.push((element.id, handlers));
+        }
+    }
+    routes
+}
+
+/// Everything recorded while compiling one function body.
+/// Line marks and element `wasm_start`/`wasm_end` hold indices into that body's
+/// instruction list until `compile_to_wasm` resolves them to module byte offsets.
+#[derive(Default)]
+pub struct CodeMap {
+    pub elements: Vec<ElementMap>,
+    pub lines: Vec<(usize, Span)>, // (first instruction of a statement, its source span)
+    pub file: String,              // `.wpp` file the spans refer to
+    pub a11y: Vec<A11yNode>,       // accessibility nodes of the statements compiled so far
+    pub records_bounds: bool,      // ids are final (render's map), so runtime layout may report bounds by id
+    pub runtime_layout: bool,      // inside a group or list laid out at render time
+    pub flow: (bool, i32),         // (horizontal, gap) of the container whose children are being compiled there
+    pub origin: Option<(i32, i32)>, // inside a grid placed at render time: its compile-time position, draws are relative to it
+    pub estimated: bool,           // that position is the compile-time estimate of where the grid lands
+    pub estimate: Option<Bounds>,  // compile-time estimate of the rect of the next element placed at render time
+    pub pass_estimates: Vec<Bounds>, // the same for the items of the first pass of the `for` compiled next
+    pub arrays: HashMap<String, Vec<Expr>>, // `let`s bound to a constant array, so a list looping over one can be estimated
+    pub literals: Rc<RefCell<LiteralPool>>, // shared by every function of the module
+    pub canvas_fill: (bool, bool),  // the top-level group being compiled takes the canvas width, height at render time
+    pub tagged: HashSet<u32>,      // ids whose frame nodes carry their tag, so `set_bounds` reports where they land
+}
+
+impl CodeMap {
+    pub fn locate(&self, span: Span) -> SourceLocation {
+        SourceLocation { file: self.file.clone(), span }
+    }
+
+    /// Id the next pushed element gets
+    pub fn next_id(&self) -> u32 {
+        self.elements.len() as u32
+    }
+
+    /// Whether elements compiled now are placed at render time
+    pub fn placed_at_runtime(&self) -> bool {
+        self.runtime_layout || self.origin.is_some()
+    }
+
+    /// Canvas rect of an element compiled now: `bounds` itself, or when it is
+    /// placed at render time the estimate if there is one. hit_test gets the
+    /// real rect from `set_bounds` either way.
+    pub fn bounds(&mut self, bounds: Bounds) -> Option<Bounds> {
+        if self.runtime_layout {
+            self.estimate.take()
+        } else {
+            (self.origin.is_none() || self.estimated).then_some(bounds)
+        }
+    }
+
+    /// Containers are pushed after their children (post-order), so right before
+    /// pushing one, make it the parent of every element since `first` that has none yet.
+    pub fn adopt(&mut self, first: usize) {
+        let parent = self.next_id();
+        for element in &mut self.elements[first..] {
+            if element.parent.is_none() {
+                element.parent = Some(parent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(id: u32, parent: Option<u32>, kind: &str) -> serde_json::Value {
+        let props = match kind {
+            "group" => serde_json::json!({ "direction": "vertical", "gap": 0, "padding": 0, "align": "start", "justify": "start" }),
+            _ => serde_json::json!({ "x": 0, "y": 0, "width": 10, "height": 10 }),
+        };
+        serde_json::json!({
+            "id": id, "parent": parent, "kind": kind, "props": props,
+            "wasm_start": 0, "wasm_end": 4, "pointer": null, "source": null, "bounds": null,
+        })
+    }
+
+    fn map_json(version: Option<u32>, elements: Vec<serde_json::Value>) -> String {
+        let mut map = serde_json::json!({ "elements": elements });
+        if let Some(version) = version {
+            map["version"] = version.into();
+        }
+        map.to_string()
+    }
+
+    fn rejection(json: &str) -> String {
+        parse_map(json).err().expect("map should be rejected")
+    }
+
+    #[test]
+    fn parse_map_reads_a_valid_map() {
+        let json = map_json(Some(MAP_VERSION), vec![element(0, Some(1), "box"), element(1, None, "group")]);
+        let map = parse_map(&json).unwrap();
+        assert_eq!(map.elements.len(), 2);
+        assert_eq!(map.elements[0].props.kind(), "box");
+    }
+
+    #[test]
+    fn parse_map_rejects_missing_and_unknown_versions() {
+        assert!(rejection(&map_json(None, vec![])).contains("no version"));
+        assert!(rejection(&map_json(Some(0), vec![])).contains("not supported"));
+        assert!(rejection(&map_json(Some(MAP_VERSION + 1), vec![])).contains("not supported"));
+    }
+
+    #[test]
+    fn parse_map_rejects_ids_out_of_order() {
+        let json = map_json(Some(MAP_VERSION), vec![element(1, None, "box"), element(0, None, "box")]);
+        assert_eq!(rejection(&json), "element #0 has id 1");
+    }
+
+    #[test]
+    fn parse_map_rejects_bad_parents() {
+        let unknown = map_json(Some(MAP_VERSION), vec![element(0, Some(5), "box")]);
+        assert_eq!(rejection(&unknown), "element 0 has unknown parent 5");
+        let not_container = map_json(Some(MAP_VERSION), vec![element(0, Some(1), "box"), element(1, None, "box")]);
+        assert_eq!(rejection(&not_container), "element 0 cannot be a child of 1 (box)");
+        let before_child = map_json(Some(MAP_VERSION), vec![element(0, None, "group"), element(1, Some(0), "box")]);
+        assert_eq!(rejection(&before_child), "element 1 cannot be a child of 0 (group)");
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::HashMap;
 use std::str::Chars;
 use std::iter::Peekable;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
 
@@ -10,7 +12,17 @@ pub enum Node {
         align: String,
         justify: String,
         padding: i32,
+        x: i32, // placed by the enclosing group, 0 at top level
+        y: i32,
+        width: Option<i32>,  // explicit size; otherwise sized to its children
+        height: Option<i32>,
+        wrap: bool,          // start a new line when children overflow the main axis
         children: Vec<Node>,
+        a11y: A11yAttrs,
+        handlers: Handlers,
+        flex: FlexItem,
+        style: Style, // fill/stroke draw a background; font, color and opacity pass to its children
+        span: Span,
     },
     
     Box {
@@ -18,37 +30,360 @@ pub enum Node {
         y: i32,
         width: i32,
         height: i32,
+        a11y: A11yAttrs,
+        handlers: Handlers,
+        flex: FlexItem,
+        style: Style,
+        span: Span,
     },
     If {
         condition: Expr,
+        condition_source: String, // the condition as written, for the semantic map
+        condition_span: Span,
         then_body: Vec<Node>,
         else_body: Option<Vec<Node>>,
+        span: Span,
+    },
+    Match {
+        value: Expr,
+        value_source: String, // the matched expression as written, for the semantic map
+        value_span: Span,
+        arms: Vec<(Pattern, Vec<Node>)>, // tried in order, first match wins
+        span: Span,
+    },
+        Text { x: i32, y: i32, value: Expr, a11y: A11yAttrs, handlers: Handlers, flex: FlexItem, style: Style, span: Span },
+
+    /// `line(...)`, `circle(...)`, `path(...)` or `image(...)`: drawn by its own host
+    /// import within the rect around it, which groups place like a box that keeps its size
+    Shape {
+        primitive: Primitive,
+        x: i32, // top-left corner of the rect around it
+        y: i32,
+        width: i32,
+        height: i32,
+        a11y: A11yAttrs,
+        handlers: Handlers,
+        flex: FlexItem,
+        style: Style,
+        span: Span,
+    },
+
+    /// `grid(columns: "100 1fr auto", rows: ..., gap: ...) { ... }`, children placed in cells
+    Grid {
+        columns: Vec<Track>,
+        rows: Vec<Track>, // explicit rows; rows added by auto-placement are `auto`
+        gap: i32,
+        padding: i32,
+        x: i32, // placed by the enclosing container, 0 at top level
+        y: i32,
+        width: Option<i32>,
+        height: Option<i32>,
+        children: Vec<Node>,
+        a11y: A11yAttrs,
+        handlers: Handlers,
+        flex: FlexItem,
+        span: Span,
     },
-        Text { x: i32, y: i32, value: Expr },
 
     List {
         direction: String,
         gap: i32,
         padding: i32,
-        items: Vec<Node>,
+        x: i32, // placed by the enclosing container, 0 at top level
+        y: i32,
+        items: Vec<Node>, // `Item`s and `For`s
+        a11y: A11yAttrs,
+        flex: FlexItem,
+        span: Span,
     },
+    /// `item(value: "a")` or `item { ... }`: one entry of a list, its nodes stacked vertically
     Item {
-        value: String,
+        children: Vec<Node>,
+        a11y: A11yAttrs,
+        handlers: Handlers,
+        span: Span,
+    },
+    /// `for name in items { item ... }` in a list: its items once per element of the array `items`
+    For {
+        name: String,
+        items: Expr,
+        body: Vec<Node>,
+        span: Span,
     },
     Print(String),
-    Expr(Expr),
+    Expr(Expr, Span),
     Let {
     name: String,
     value: Expr,
+    span: Span,
 },
+    /// `state name = value`, top level only; assigning to it requests a redraw
+    State {
+        name: String,
+        value: Expr,
+        span: Span,
+    },
+    /// `style Primary { fill: "#0af", radius: 4 }`, top level only; nodes refer to it with `style: Primary`
+    Style {
+        name: String,
+        style: Style,
+        span: Span,
+    },
+    /// `name = value` for a `let` or `state`
+    Assign {
+        name: String,
+        value: Expr,
+        span: Span,
+    },
 Function {
     name: String,
     params: Vec<(String, String)>, // (name, type)
     body: Vec<Node>,
+    is_async: bool, // `async func`, lowered to a resumable state machine
+    span: Span,
+}
+
+
+}
+
+/// `label:`, `role:`, `description:` and `hidden:` properties, accepted by every layout node
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct A11yAttrs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hidden: bool, // leaves the node and its children out of the accessibility tree
+}
+
+/// `on_click:`, `on_key:` and `on_hover:` properties naming the W++ function to call
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Handlers {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_click: Option<String>, // func() or func(x: int, y: int)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_key: Option<String>,   // func() or func(key: int)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_hover: Option<String>, // same as on_click, called when the pointer enters the node
+}
+
+/// `grow:`, `shrink:`, `basis:`, `min_width:`, `max_width:`, `min_height:` and
+/// `max_height:` properties: how a node sizes itself inside a group; `row:`, `column:`,
+/// `row_span:` and `column_span:` pick its cell inside a grid
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlexItem {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grow: Option<i32>,   // share of the free main-axis space, 0 when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shrink: Option<i32>, // share of the overflow, weighted by basis; 1 when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub basis: Option<i32>,  // main-axis size before growing or shrinking, the node's own size when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub row: Option<i32>,    // 1-based; auto-placed when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub row_span: Option<i32>, // 1 when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column_span: Option<i32>,
+}
+
+/// `fill:`, `stroke:`, `stroke_width:`, `radius:`, `opacity:`, `font:`, `font_size:` and
+/// `color:` properties; unset ones fall back to the `style:` named, then to the enclosing
+/// group's or the defaults in style.rs
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Style {
+    #[serde(default, rename = "style", skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,   // `style: Primary`, a declared style
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill: Option<String>,   // "#0af", "#00aaff", "#00aaff80" or a named color
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stroke: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stroke_width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub radius: Option<i32>,    // corner radius
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opacity: Option<i32>,   // percent, 100 when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font: Option<String>,   // one of FONT_FAMILIES
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font_size: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,  // of a text
+}
+
+/// What a `Shape` draws in its rect
+#[derive(Debug, Clone, PartialEq)]
+pub enum Primitive {
+    Line { x1: i32, y1: i32, x2: i32, y2: i32 }, // endpoints as written; drawn as a diagonal of the rect
+    Circle { cx: i32, cy: i32 }, // centre as written; inscribed in the rect
+    Path(String),          // `d`: M/L/C/Z commands relative to the top-left corner, see path_extent
+    Image(String),         // `src`, scaled to the rect
+}
+
+impl Primitive {
+    /// Its node keyword, also its `kind` in the semantic map
+    pub fn name(&self) -> &'static str {
+        match self {
+            Primitive::Line { .. } => "line",
+            Primitive::Circle { .. } => "circle",
+            Primitive::Path(_) => "path",
+            Primitive::Image(_) => "image",
+        }
+    }
+
+    /// Whether a line runs from the bottom-left to the top-right corner of its rect
+    pub fn rising(&self) -> bool {
+        matches!(self, Primitive::Line { x1, y1, x2, y2 } if (x2 - x1) * (y2 - y1) < 0)
+    }
+}
+
+/// Size of the rect a path's commands stay in: `M x y` moves, `L x y` draws a line,
+/// `C x1 y1 x2 y2 x y` a cubic curve (inside its control points) and `Z` closes the
+/// shape. Coordinates are whole, non-negative pixels.
+pub fn path_extent(d: &str) -> (i32, i32) {
+    let mut tokens = d.split(|c: char| c.is_whitespace() || c == ',').filter(|token| !token.is_empty()).peekable();
+    let (mut width, mut height) = (0, 0);
+    let mut first = true;
+    while let Some(command) = tokens.next() {
+        let coordinates = match command {
+            "M" => 2,
+            "L" => 2,
+            "C" => 6,
+            "Z" => 0,
+            _ => panic!("Unknown path command '{}' in \"{}\", expected M, L, C or Z", command, d),
+        };
+        if first && command != "M" {
+            panic!("Path \"{}\" must start with M", d);
+        }
+        first = false;
+        for i in 0..coordinates {
+            let value: i32 = tokens
+                .next()
+                .and_then(|token| token.parse().ok())
+                .unwrap_or_else(|| panic!("{} in path \"{}\" expects {} whole numbers", command, d, coordinates));
+            if value < 0 {
+                panic!("Path coordinates are relative to its x, y and cannot be negative, got {} in \"{}\"", value, d);
+            }
+            if i % 2 == 0 {
+                width = width.max(value);
+            } else {
+                height = height.max(value);
+            }
+        }
+    }
+    if first {
+        panic!("Path d is empty");
+    }
+    (width, height)
+}
+
+/// One grid track: `100` pixels, `2fr` of the space left over, or `auto` to fit its content
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Track {
+    Fixed(i32),
+    Fraction(i32),
+    Auto,
+}
+
+impl std::fmt::Display for Track {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Track::Fixed(size) => write!(f, "{}", size),
+            Track::Fraction(fr) => write!(f, "{}fr", fr),
+            Track::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Parses a track list such as `"100 1fr auto"`.
+pub fn parse_tracks(text: &str) -> Vec<Track> {
+    text.split_whitespace()
+        .map(|track| {
+            let parsed = match track.strip_suffix("fr") {
+                _ if track == "auto" => Some(Track::Auto),
+                Some(fr) => fr.parse().ok().filter(|fr| *fr > 0).map(Track::Fraction),
+                None => track.parse().ok().filter(|size| *size >= 0).map(Track::Fixed),
+            };
+            parsed.unwrap_or_else(|| panic!("Unknown grid track \"{}\", expected a size, \"<n>fr\" or \"auto\"", track))
+        })
+        .collect()
+}
+
+pub const DIRECTIONS: &[&str] = &["vertical", "horizontal"];
+pub const ALIGNS: &[&str] = &["start", "center", "end", "stretch"];
+pub const JUSTIFIES: &[&str] = &["start", "center", "end", "space-between", "space-around", "space-evenly"];
+pub const FONT_FAMILIES: &[&str] = &["sans-serif", "serif", "monospace"];
+/// Style properties each node takes; a group takes them all
+pub const BOX_STYLE: &[&str] = &["fill", "stroke", "stroke_width", "radius", "opacity"];
+pub const TEXT_STYLE: &[&str] = &["color", "font", "font_size", "opacity"];
+pub const GROUP_STYLE: &[&str] = &["fill", "stroke", "stroke_width", "radius", "opacity", "font", "font_size", "color"];
+pub const LINE_STYLE: &[&str] = &["stroke", "stroke_width", "opacity"];
+pub const FIGURE_STYLE: &[&str] = &["fill", "stroke", "stroke_width", "opacity"]; // circles and paths
+pub const IMAGE_STYLE: &[&str] = &["opacity"];
+
+/// Source range of a node, 1-based lines and columns; `end_*` points just past the last token.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub line: u32,
+    pub column: u32,
+    pub end_line: u32,
+    pub end_column: u32,
 }
 
+impl Node {
+    pub fn span(&self) -> Span {
+        match self {
+            Node::Group { span, .. }
+            | Node::Box { span, .. }
+            | Node::If { span, .. }
+            | Node::Match { span, .. }
+            | Node::Text { span, .. }
+            | Node::Shape { span, .. }
+            | Node::Grid { span, .. }
+            | Node::List { span, .. }
+            | Node::Item { span, .. }
+            | Node::For { span, .. }
+            | Node::Expr(_, span)
+            | Node::Let { span, .. }
+            | Node::State { span, .. }
+            | Node::Style { span, .. }
+            | Node::Assign { span, .. }
+            | Node::Function { span, .. } => *span,
+            Node::Print(_) => Span::default(),
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Int(i32),
+    Str(String),
+    Wildcard, // `_`
+}
 
+impl std::fmt::Display for Pattern {
+    /// The pattern as written in W++
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Int(n) => write!(f, "{}", n),
+            Pattern::Str(s) => write!(f, "{:?}", s),
+            Pattern::Wildcard => write!(f, "_"),
+        }
+    }
 }
+
 #[derive(Clone)]
 pub struct FunctionMeta {
     pub node: Node,
@@ -72,6 +407,14 @@ pub enum Expr {
     args: Vec<Expr>,
 },
 StringLiteral(String),
+    Array(Vec<Expr>), // `["a", "b"]`, elements of one type
+    Await(Box<Expr>), // only valid inside `async func`
+    Lambda {
+        id: usize,                     // unique per parse, used to name the lifted function
+        params: Vec<(String, String)>, // (name, type)
+        body: Vec<Node>,
+        returns_value: bool,           // `=> expr` yields a value, `=> { ... }` does not
+    },
 
 }
 
@@ -90,6 +433,8 @@ enum Token {
     RBrace,
     LParen,
     RParen,
+    LBracket,
+    RBracket,
     Colon,
     Comma,
     EOF,
@@ -98,47 +443,87 @@ enum Token {
 
 struct Lexer<'a> {
     input: Peekable<Chars<'a>>,
+    line: u32,
+    column: u32,
 }
 
 impl<'a> Lexer<'a> {
     fn new(code: &'a str) -> Self {
         Lexer {
             input: code.chars().peekable(),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    /// Consumes one char, keeping `line`/`column` in step
+    fn bump(&mut self) -> Option<char> {
+        let c = self.input.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    /// Next token plus where it sits in the source
+    fn next_spanned(&mut self) -> (Token, Span) {
+        while self.input.peek().is_some_and(|c| c.is_whitespace()) {
+            self.bump();
         }
+        let (line, column) = (self.line, self.column);
+        let token = self.next_token();
+        (token, Span { line, column, end_line: self.line, end_column: self.column })
     }
 
     fn next_token(&mut self) -> Token {
     while let Some(&c) = self.input.peek() {
         match c {
-            '{' => { self.input.next(); return Token::LBrace; }
-            '}' => { self.input.next(); return Token::RBrace; }
-            '(' => { self.input.next(); return Token::LParen; }
-            ')' => { self.input.next(); return Token::RParen; }
-            ':' => { self.input.next(); return Token::Colon; }
-            ',' => { self.input.next(); return Token::Comma; }
+            '{' => { self.bump(); return Token::LBrace; }
+            '}' => { self.bump(); return Token::RBrace; }
+            '(' => { self.bump(); return Token::LParen; }
+            ')' => { self.bump(); return Token::RParen; }
+            '[' => { self.bump(); return Token::LBracket; }
+            ']' => { self.bump(); return Token::RBracket; }
+            ':' => { self.bump(); return Token::Colon; }
+            ',' => { self.bump(); return Token::Comma; }
             '"' => return self.read_string(),
             '0'..='9' => return self.read_number(),
-            c if c.is_alphabetic() => return self.read_ident(),
+            c if c.is_alphabetic() || c == '_' => return self.read_ident(),
             c if c.is_whitespace() => {
-                self.input.next(); // Skip
+                self.bump(); // Skip
                 continue;
             }
-            // 🎯 Handle operators like >, <, ==, >=, <=
+            // 🎯 Handle operators like >, <, ==, >=, <=, and the arrow `=>`
             '>' | '<' | '=' => {
                 let mut op = String::new();
                 op.push(c);
-                self.input.next(); // consume first
+                self.bump(); // consume first
 
                 if let Some(&'=') = self.input.peek() {
                     op.push('=');
-                    self.input.next(); // consume second
+                    self.bump(); // consume second
+                } else if c == '=' && self.input.peek() == Some(&'>') {
+                    op.push('>');
+                    self.bump();
                 }
 
                 return Token::Operator(op);
             }
+            // ➕ Arithmetic, plus `->` for function type annotations
+            '+' | '-' | '*' | '/' => {
+                self.bump();
+                if c == '-' && self.input.peek() == Some(&'>') {
+                    self.bump();
+                    return Token::Operator("->".to_string());
+                }
+                return Token::Operator(c.to_string());
+            }
             // 🧹 Ignore unknown characters (optional: error if strict)
             _ => {
-                self.input.next();
+                self.bump();
                 continue;
             }
         }
@@ -147,17 +532,17 @@ impl<'a> Lexer<'a> {
     Token::EOF
 }
 fn read_string(&mut self) -> Token {
-    self.input.next(); // Skip the opening quote
+    self.bump(); // Skip the opening quote
     let mut value = String::new();
 
     while let Some(&c) = self.input.peek() {
         match c {
             '"' => {
-                self.input.next(); // Consume closing quote
+                self.bump(); // Consume closing quote
                 break;
             }
             '\\' => {
-                self.input.next(); // Skip the '\'
+                self.bump(); // Skip the '\'
                 if let Some(&escaped) = self.input.peek() {
                     value.push(match escaped {
                         'n' => '\n',
@@ -166,12 +551,12 @@ fn read_string(&mut self) -> Token {
                         '\\' => '\\',
                         other => other,
                     });
-                    self.input.next(); // Consume escaped character
+                    self.bump(); // Consume escaped character
                 }
             }
             _ => {
                 value.push(c);
-                self.input.next();
+                self.bump();
             }
         }
     }
@@ -185,7 +570,7 @@ fn read_string(&mut self) -> Token {
         while let Some(&c) = self.input.peek() {
             if c.is_ascii_digit() {
                 num.push(c);
-                self.input.next();
+                self.bump();
             } else {
                 break;
             }
@@ -198,7 +583,7 @@ fn read_string(&mut self) -> Token {
         while let Some(&c) = self.input.peek() {
             if c.is_alphanumeric() || c == '_' {
                 ident.push(c);
-                self.input.next();
+                self.bump();
             } else {
                 break;
             }
@@ -210,21 +595,88 @@ fn read_string(&mut self) -> Token {
 pub fn parse_wpp(source: &str) -> Vec<Node> {
     let mut lexer = Lexer::new(source);
     let mut tokens = Vec::new();
+    let mut spans = Vec::new();
     loop {
-        let token = lexer.next_token();
+        let (token, span) = lexer.next_spanned();
         if token == Token::EOF {
             break;
         }
         tokens.push(token);
+        spans.push(span);
     }
 
-    let mut parser = Parser { tokens, pos: 0 };
-    parser.parse_nodes()
+    let lines = source.lines().map(|line| line.chars().collect()).collect();
+    let mut parser = Parser { tokens, spans, lines, pos: 0, lambda_count: 0 };
+    let nodes = parser.parse_nodes();
+    check_awaits(&nodes, false);
+    nodes
+}
+
+/// Panics on an `await` the compiler cannot suspend at: it must be a statement of
+/// its own or the value of a `let`, directly in an `async func` body or in the
+/// branches of an `if`/`match` there (`in_async`), not in an expression or a container.
+fn check_awaits(body: &[Node], in_async: bool) {
+    for node in body {
+        match node {
+            Node::Expr(Expr::Await(awaited), span) | Node::Let { value: Expr::Await(awaited), span, .. } if in_async => {
+                check_expr_awaits(awaited, *span)
+            }
+            Node::If { condition, then_body, else_body, span, .. } => {
+                check_expr_awaits(condition, *span);
+                check_awaits(then_body, in_async);
+                check_awaits(else_body.as_deref().unwrap_or_default(), in_async);
+            }
+            Node::Match { value, arms, span, .. } => {
+                check_expr_awaits(value, *span);
+                for (_, body) in arms {
+                    check_awaits(body, in_async);
+                }
+            }
+            Node::Function { body, is_async, .. } => check_awaits(body, *is_async),
+            Node::Expr(value, span)
+            | Node::Let { value, span, .. }
+            | Node::Assign { value, span, .. }
+            | Node::State { value, span, .. }
+            | Node::Text { value, span, .. } => check_expr_awaits(value, *span),
+            Node::Group { children, .. } | Node::Grid { children, .. } | Node::List { items: children, .. } | Node::Item { children, .. } => {
+                check_awaits(children, false)
+            }
+            Node::For { items, body, span, .. } => {
+                check_expr_awaits(items, *span);
+                check_awaits(body, false);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn check_expr_awaits(expr: &Expr, span: Span) {
+    match expr {
+        Expr::Await(_) => panic!(
+            "`await` at line {} must be a statement of its own or `let x = await ...`, directly in an `async func` or in an `if`/`match` there",
+            span.line
+        ),
+        Expr::Binary { left, right, .. } => {
+            check_expr_awaits(left, span);
+            check_expr_awaits(right, span);
+        }
+        Expr::Call { args: elements, .. } | Expr::Array(elements) => {
+            for element in elements {
+                check_expr_awaits(element, span);
+            }
+        }
+        Expr::Layout(node) => check_awaits(std::slice::from_ref(node), false),
+        Expr::Lambda { body, .. } => check_awaits(body, false),
+        _ => {}
+    }
 }
 
 struct Parser {
     tokens: Vec<Token>,
+    spans: Vec<Span>, // one per token
+    lines: Vec<Vec<char>>, // source text, for conditions the map records as written
     pos: usize,
+    lambda_count: usize, // hands out Expr::Lambda ids
 }
 
 impl Parser {
@@ -232,6 +684,28 @@ impl Parser {
         self.tokens.get(self.pos).cloned().unwrap_or(Token::EOF)
     }
 
+    /// Span from the token at `start` up to the last consumed token
+    fn span_from(&self, start: usize) -> Span {
+        let first = self.spans.get(start).copied().unwrap_or_default();
+        let last = self.spans.get(self.pos.saturating_sub(1)).copied().unwrap_or(first);
+        Span { line: first.line, column: first.column, end_line: last.end_line, end_column: last.end_column }
+    }
+
+    /// Source text a span covers
+    fn source_text(&self, span: Span) -> String {
+        let mut text = String::new();
+        for line in span.line..=span.end_line {
+            let chars = self.lines.get(line as usize - 1).map(Vec::as_slice).unwrap_or_default();
+            let from = if line == span.line { span.column as usize - 1 } else { 0 };
+            let to = if line == span.end_line { span.end_column as usize - 1 } else { chars.len() };
+            if line != span.line {
+                text.push('\n');
+            }
+            text.extend(&chars[from.min(chars.len())..to.min(chars.len())]);
+        }
+        text
+    }
+
     fn advance(&mut self) -> Token {
         let tok = self.peek();
         self.pos += 1;
@@ -249,32 +723,52 @@ impl Parser {
 
     fn parse_nodes(&mut self) -> Vec<Node> {
     let mut nodes = Vec::new();
-    while self.pos < self.tokens.len() {
-        match self.peek() {
-    Token::Ident(ref s) => match s.as_str() {
-        "box" => nodes.push(self.parse_box()),
-        "group" => nodes.push(self.parse_group()),
-        "func" => nodes.push(self.parse_function()),
-        "if" => nodes.push(self.parse_if()),
-        "text" => nodes.push(self.parse_text()),
-        "list" => nodes.push(self.parse_list()),
-        "let" => nodes.push(self.parse_let()),
-        _ => {
-            let expr = self.parse_expr(); // allow regular identifiers
-            nodes.push(Node::Expr(expr));
-        }
-    },
-    Token::Number(_) | Token::LParen => {
-        let expr = self.parse_expr();
-        nodes.push(Node::Expr(expr));
+    while let Some(node) = self.parse_statement() {
+        nodes.push(node);
     }
-    _ => break,
+    nodes
 }
 
-    }
-    nodes
+/// One statement of the shared grammar used at top level and in every body.
+/// Returns `None` when the next token cannot start a statement (e.g. `}`).
+fn parse_statement(&mut self) -> Option<Node> {
+    let start = self.pos;
+    let node = match self.peek() {
+    Token::Ident(ref s) => match s.as_str() {
+        "box" => self.parse_box(),
+        "group" => self.parse_group(),
+        "func" => self.parse_function(),
+        "async" => {
+            self.advance(); // consume 'async'
+            match self.parse_function() {
+                Node::Function { name, params, body, .. } => {
+                    Node::Function { name, params, body, is_async: true, span: self.span_from(start) }
+                }
+                _ => unreachable!(),
+            }
+        }
+        "if" => self.parse_if(),
+        "match" => self.parse_match(),
+        "text" => self.parse_text(),
+        "line" | "circle" | "path" | "image" if self.tokens.get(self.pos + 1) == Some(&Token::LParen) => {
+            let keyword = s.clone();
+            self.parse_shape(&keyword)
+        }
+        "list" => self.parse_list(),
+        "grid" => self.parse_grid(),
+        "let" => self.parse_let(),
+        "state" => self.parse_state(),
+        "style" if matches!(self.tokens.get(self.pos + 1), Some(Token::Ident(_))) => self.parse_style_declaration(),
+        _ if self.tokens.get(self.pos + 1) == Some(&Token::Operator("=".to_string())) => self.parse_assign(),
+        _ => Node::Expr(self.parse_expr(), self.span_from(start)), // calls, `await`, plain identifiers
+    },
+    Token::Number(_) | Token::LParen => Node::Expr(self.parse_expr(), self.span_from(start)),
+    _ => return None,
+};
+    Some(node)
 }
 fn parse_function(&mut self) -> Node {
+    let start = self.pos;
     self.expect_ident("func");
 
     let name = match self.advance() {
@@ -293,10 +787,7 @@ fn parse_function(&mut self) -> Node {
 
         self.expect(Token::Colon);
 
-        let ptype = match self.advance() {
-            Token::Ident(s) => s,
-            t => panic!("Expected param type, got {:?}", t),
-        };
+        let ptype = self.parse_type();
 
         params.push((pname, ptype));
 
@@ -306,21 +797,15 @@ fn parse_function(&mut self) -> Node {
     }
 
     self.expect(Token::RParen);
-    self.expect(Token::LBrace);
-
-    let mut body = vec![];
-    while self.peek() != Token::RBrace {
-        body.push(self.parse_node());
-    }
-
-    self.expect(Token::RBrace);
+    let body = self.parse_block();
 
-    Node::Function { name, params, body }
+    Node::Function { name, params, body, is_async: false, span: self.span_from(start) }
 }
 
 
 
     fn parse_group(&mut self) -> Node {
+    let start = self.pos;
     self.expect(Token::Ident("group".to_string()));
     self.expect(Token::LBrace);
 
@@ -329,6 +814,13 @@ fn parse_function(&mut self) -> Node {
     let mut align = "start".to_string();
     let mut justify = "start".to_string();
     let mut padding = 0;
+    let mut width = None;
+    let mut height = None;
+    let mut wrap = false;
+    let mut a11y = A11yAttrs::default();
+    let mut handlers = Handlers::default();
+    let mut flex = FlexItem::default();
+    let mut style = Style::default();
     let mut children = Vec::new();
 
     while self.peek() != Token::RBrace {
@@ -336,9 +828,7 @@ fn parse_function(&mut self) -> Node {
             Token::Ident(ref s) => match s.as_str() {
                 "direction" => {
     self.advance(); self.expect(Token::Colon);
-    if let Token::String(dir) = self.advance() {
-        direction = dir;
-    } else { panic!("Expected string for direction"); }
+    direction = self.expect_keyword("direction", DIRECTIONS);
 
     if self.peek() == Token::Comma {
         self.advance(); // ✅ skip comma
@@ -356,9 +846,7 @@ fn parse_function(&mut self) -> Node {
 
                 "align" => {
     self.advance(); self.expect(Token::Colon);
-    if let Token::String(a) = self.advance() {
-        align = a;
-    } else { panic!("Expected string for align"); }
+    align = self.expect_keyword("align", ALIGNS);
 
     if self.peek() == Token::Comma {
         self.advance();
@@ -367,9 +855,7 @@ fn parse_function(&mut self) -> Node {
 
                 "justify" => {
     self.advance(); self.expect(Token::Colon);
-    if let Token::String(j) = self.advance() {
-        justify = j;
-    } else { panic!("Expected string for justify"); }
+    justify = self.expect_keyword("justify", JUSTIFIES);
 
     if self.peek() == Token::Comma {
         self.advance();
@@ -380,19 +866,83 @@ fn parse_function(&mut self) -> Node {
     self.advance(); self.expect(Token::Colon);
     padding = self.expect_number();
 
+    if self.peek() == Token::Comma {
+        self.advance();
+    }
+},
+
+                "width" => {
+    self.advance(); self.expect(Token::Colon);
+    width = Some(self.expect_number());
+
+    if self.peek() == Token::Comma {
+        self.advance();
+    }
+},
+
+                "height" => {
+    self.advance(); self.expect(Token::Colon);
+    height = Some(self.expect_number());
+
+    if self.peek() == Token::Comma {
+        self.advance();
+    }
+},
+
+                "wrap" => {
+    self.advance(); self.expect(Token::Colon);
+    wrap = self.expect_bool("wrap");
+
+    if self.peek() == Token::Comma {
+        self.advance();
+    }
+},
+
+                "grow" | "shrink" | "basis" | "min_width" | "max_width" | "min_height" | "max_height"
+                | "row" | "column" | "row_span" | "column_span" => {
+    let name = s.clone();
+    self.advance(); self.expect(Token::Colon);
+    self.parse_flex_value(&name, &mut flex);
+
+    if self.peek() == Token::Comma {
+        self.advance();
+    }
+},
+
+                "label" | "role" | "description" | "hidden" => {
+    let name = s.clone();
+    self.advance(); self.expect(Token::Colon);
+    self.parse_a11y_value(&name, &mut a11y);
+
+    if self.peek() == Token::Comma {
+        self.advance();
+    }
+},
+
+                "on_click" | "on_key" | "on_hover" => {
+    let name = s.clone();
+    self.advance(); self.expect(Token::Colon);
+    self.parse_handler_value(&name, &mut handlers);
+
+    if self.peek() == Token::Comma {
+        self.advance();
+    }
+},
+
+                name if name == "style" || GROUP_STYLE.contains(&name) => {
+    let name = s.clone();
+    self.advance(); self.expect(Token::Colon);
+    self.parse_style_value(&name, GROUP_STYLE, &mut style);
+
     if self.peek() == Token::Comma {
         self.advance();
     }
 },
 
                 // Parse child nodes
-                "box" => children.push(self.parse_box()),
-                "text" => children.push(self.parse_text()),
-                "if" => children.push(self.parse_if()),
-                "group" => children.push(self.parse_group()),
-                _ => panic!("Unknown property or child: {:?}", s),
+                _ => children.push(self.parse_child_statement("group")),
             },
-            _ => panic!("Unexpected token in group"),
+            _ => children.push(self.parse_child_statement("group")),
         }
     }
 
@@ -404,10 +954,78 @@ fn parse_function(&mut self) -> Node {
         align,
         justify,
         padding,
+        x: 0,
+        y: 0,
+        width,
+        height,
+        wrap,
         children,
+        a11y,
+        handlers,
+        flex,
+        style,
+        span: self.span_from(start),
     }
 }
+fn parse_state(&mut self) -> Node {
+    let start = self.pos;
+    self.expect_ident("state");
+
+    let name = match self.advance() {
+        Token::Ident(s) => s,
+        t => panic!("Expected state name, got {:?}", t),
+    };
+
+    self.expect(Token::Operator("=".to_string()));
+
+    let value = self.parse_expr();
+
+    Node::State { name, value, span: self.span_from(start) }
+}
+
+fn parse_style_declaration(&mut self) -> Node {
+    let start = self.pos;
+    self.expect_ident("style");
+
+    let name = match self.advance() {
+        Token::Ident(s) => s,
+        t => panic!("Expected style name, got {:?}", t),
+    };
+
+    self.expect(Token::LBrace);
+    let mut style = Style::default();
+    while self.peek() != Token::RBrace {
+        match self.advance() {
+            Token::Ident(property) => {
+                self.expect(Token::Colon);
+                if !self.parse_style_value(&property, GROUP_STYLE, &mut style) {
+                    panic!("Unknown style property '{}'", property);
+                }
+                if self.peek() == Token::Comma {
+                    self.advance();
+                }
+            }
+            t => panic!("Expected identifier in style {}, got {:?}", name, t),
+        }
+    }
+    self.expect(Token::RBrace);
+
+    Node::Style { name, style, span: self.span_from(start) }
+}
+
+fn parse_assign(&mut self) -> Node {
+    let start = self.pos;
+    let name = match self.advance() {
+        Token::Ident(s) => s,
+        t => panic!("Expected identifier to assign to, got {:?}", t),
+    };
+    self.expect(Token::Operator("=".to_string()));
+    let value = self.parse_expr();
+    Node::Assign { name, value, span: self.span_from(start) }
+}
+
 fn parse_let(&mut self) -> Node {
+    let start = self.pos;
     self.expect_ident("let");
 
     let name = match self.advance() {
@@ -419,12 +1037,13 @@ fn parse_let(&mut self) -> Node {
 
     let value = self.parse_expr();
 
-    Node::Let { name, value }
+    Node::Let { name, value, span: self.span_from(start) }
 }
 
 
 
     fn parse_box(&mut self) -> Node {
+    let start = self.pos;
     self.expect_ident("box");
     self.expect(Token::LParen);
 
@@ -432,19 +1051,29 @@ fn parse_let(&mut self) -> Node {
     let mut y = None;
     let mut width = None;
     let mut height = None;
+    let mut a11y = A11yAttrs::default();
+    let mut handlers = Handlers::default();
+    let mut flex = FlexItem::default();
+    let mut style = Style::default();
 
     while self.peek() != Token::RParen {
         match self.advance() {
             Token::Ident(name) => {
                 self.expect(Token::Colon);
-                let value = self.expect_number();
-
-                match name.as_str() {
-                    "x" => x = Some(value),
-                    "y" => y = Some(value),
-                    "width" => width = Some(value),
-                    "height" => height = Some(value),
-                    _ => panic!("Unknown parameter '{}'", name),
+                if !self.parse_a11y_value(&name, &mut a11y)
+                    && !self.parse_handler_value(&name, &mut handlers)
+                    && !self.parse_flex_value(&name, &mut flex)
+                    && !self.parse_style_value(&name, BOX_STYLE, &mut style)
+                {
+                    let value = self.expect_number();
+
+                    match name.as_str() {
+                        "x" => x = Some(value),
+                        "y" => y = Some(value),
+                        "width" => width = Some(value),
+                        "height" => height = Some(value),
+                        _ => panic!("Unknown parameter '{}'", name),
+                    }
                 }
 
                 if self.peek() == Token::Comma {
@@ -462,6 +1091,148 @@ fn parse_let(&mut self) -> Node {
         y: y.expect("Missing y"),
         width: width.expect("Missing width"),
         height: height.expect("Missing height"),
+        a11y,
+        handlers,
+        flex,
+        style,
+        span: self.span_from(start),
+    }
+}
+
+/// Parses the value of an accessibility property (the `name:` part is already consumed).
+/// Returns false, consuming nothing, when `name` is not one.
+fn parse_a11y_value(&mut self, name: &str, a11y: &mut A11yAttrs) -> bool {
+    let slot = match name {
+        "label" => &mut a11y.label,
+        "role" => &mut a11y.role,
+        "description" => &mut a11y.description,
+        "hidden" => {
+            a11y.hidden = match self.advance() {
+                Token::Ident(ref b) if b == "true" => true,
+                Token::Ident(ref b) if b == "false" => false,
+                t => panic!("Expected true or false for hidden, got {:?}", t),
+            };
+            return true;
+        }
+        _ => return false,
+    };
+    match self.advance() {
+        Token::String(value) => *slot = Some(value),
+        t => panic!("Expected string for {}, got {:?}", name, t),
+    }
+    true
+}
+
+/// Parses the function name given to an `on_*` property (the `name:` part is already consumed).
+/// Returns false, consuming nothing, when `name` is not a handler property.
+fn parse_handler_value(&mut self, name: &str, handlers: &mut Handlers) -> bool {
+    let slot = match name {
+        "on_click" => &mut handlers.on_click,
+        "on_key" => &mut handlers.on_key,
+        "on_hover" => &mut handlers.on_hover,
+        _ => return false,
+    };
+    match self.advance() {
+        Token::Ident(function) => *slot = Some(function),
+        t => panic!("Expected a function name for {}, got {:?}", name, t),
+    }
+    true
+}
+
+/// Parses the number given to a flex property (the `name:` part is already consumed).
+/// Returns false, consuming nothing, when `name` is not one.
+fn parse_flex_value(&mut self, name: &str, flex: &mut FlexItem) -> bool {
+    let slot = match name {
+        "grow" => &mut flex.grow,
+        "shrink" => &mut flex.shrink,
+        "basis" => &mut flex.basis,
+        "min_width" => &mut flex.min_width,
+        "max_width" => &mut flex.max_width,
+        "min_height" => &mut flex.min_height,
+        "max_height" => &mut flex.max_height,
+        "row" => &mut flex.row,
+        "column" => &mut flex.column,
+        "row_span" => &mut flex.row_span,
+        "column_span" => &mut flex.column_span,
+        _ => return false,
+    };
+    let value = self.expect_number();
+    if value < 0 {
+        panic!("{} cannot be negative, got {}", name, value);
+    }
+    if value == 0 && matches!(name, "row" | "column" | "row_span" | "column_span") {
+        panic!("{} starts at 1, got 0", name);
+    }
+    *slot = Some(value);
+    true
+}
+
+/// Parses the value of a style property (the `name:` part is already consumed): colors
+/// are hex or named, see style.rs, and `style:` names a declared style. Returns false,
+/// consuming nothing, when `name` is not one; `allowed` are the ones this node takes.
+fn parse_style_value(&mut self, name: &str, allowed: &[&str], style: &mut Style) -> bool {
+    if name == "style" {
+        match self.advance() {
+            Token::Ident(declared) => style.name = Some(declared),
+            t => panic!("Expected a style name for style, got {:?}", t),
+        }
+        return true;
+    }
+    if !GROUP_STYLE.contains(&name) {
+        return false;
+    }
+    if !allowed.contains(&name) {
+        panic!("{} is not a style property here, expected one of {}", name, allowed.join(", "));
+    }
+    match name {
+        "fill" | "stroke" | "color" => {
+            let color = match self.advance() {
+                Token::String(color) if crate::style::parse_color(&color).is_some() => color,
+                Token::String(color) => panic!("Unknown color \"{}\" for {}, expected \"#rgb\", \"#rrggbb\", \"#rrggbbaa\" or a color name", color, name),
+                t => panic!("Expected color string for {}, got {:?}", name, t),
+            };
+            let slot = match name {
+                "fill" => &mut style.fill,
+                "stroke" => &mut style.stroke,
+                _ => &mut style.color,
+            };
+            *slot = Some(color);
+        }
+        "font" => style.font = Some(self.expect_keyword("font", FONT_FAMILIES)),
+        _ => {
+            let (slot, range) = match name {
+                "stroke_width" => (&mut style.stroke_width, 0..=255),
+                "radius" => (&mut style.radius, 0..=255),
+                "opacity" => (&mut style.opacity, 0..=100),
+                _ => (&mut style.font_size, 1..=255),
+            };
+            let value = self.expect_number();
+            if !range.contains(&value) {
+                panic!("{} must be between {} and {}, got {}", name, range.start(), range.end(), value);
+            }
+            *slot = Some(value);
+        }
+    }
+    true
+}
+
+/// A string that must be one of `allowed`, e.g. `align: "center"`.
+fn expect_keyword(&mut self, property: &str, allowed: &[&str]) -> String {
+    match self.advance() {
+        Token::String(value) if allowed.contains(&value.as_str()) => value,
+        Token::String(value) => panic!(
+            "Unknown {} \"{}\", expected one of {}",
+            property, value, allowed.iter().map(|v| format!("\"{}\"", v)).collect::<Vec<_>>().join(", ")
+        ),
+        t => panic!("Expected string for {}, got {:?}", property, t),
+    }
+}
+
+fn expect_bool(&mut self, property: &str) -> bool {
+    match self.advance() {
+        Token::Ident(ref b) if b == "true" => true,
+        Token::Ident(ref b) if b == "false" => false,
+        t => panic!("Expected true or false for {}, got {:?}", property, t),
     }
 }
 
@@ -471,13 +1242,93 @@ fn expect_number(&mut self) -> i32 {
         t => panic!("Expected number, got {:?}", t),
     }
 }
+/// `line(x1:, y1:, x2:, y2:)`, `circle(cx:, cy:, radius:)`, `path(x:, y:, d: "M 0 0 L 8 8")`
+/// or `image(x:, y:, width:, height:, src: "icon.png")`
+fn parse_shape(&mut self, keyword: &str) -> Node {
+    let start = self.pos;
+    self.expect_ident(keyword);
+    self.expect(Token::LParen);
+
+    let (numbers, text_property, allowed_style): (&[&str], Option<&str>, &[&str]) = match keyword {
+        "line" => (&["x1", "y1", "x2", "y2"], None, LINE_STYLE),
+        "circle" => (&["cx", "cy", "radius"], None, FIGURE_STYLE),
+        "path" => (&["x", "y"], Some("d"), FIGURE_STYLE),
+        _ => (&["x", "y", "width", "height"], Some("src"), IMAGE_STYLE),
+    };
+    let mut values: HashMap<String, i32> = HashMap::new();
+    let mut text = None;
+    let mut a11y = A11yAttrs::default();
+    let mut handlers = Handlers::default();
+    let mut flex = FlexItem::default();
+    let mut style = Style::default();
+
+    while self.peek() != Token::RParen {
+        match self.advance() {
+            Token::Ident(name) => {
+                self.expect(Token::Colon);
+                if numbers.contains(&name.as_str()) {
+                    let value = self.expect_number();
+                    values.insert(name, value);
+                } else if text_property == Some(name.as_str()) {
+                    match self.advance() {
+                        Token::String(value) => text = Some(value),
+                        t => panic!("Expected string for {}, got {:?}", name, t),
+                    }
+                } else if !self.parse_a11y_value(&name, &mut a11y)
+                    && !self.parse_handler_value(&name, &mut handlers)
+                    && !self.parse_flex_value(&name, &mut flex)
+                    && !self.parse_style_value(&name, allowed_style, &mut style)
+                {
+                    panic!("Unknown {} property '{}'", keyword, name);
+                }
+
+                if self.peek() == Token::Comma {
+                    self.advance();
+                }
+            }
+            t => panic!("Expected identifier in {}(), got {:?}", keyword, t),
+        }
+    }
+    self.expect(Token::RParen);
+
+    let value = |name: &str| *values.get(name).unwrap_or_else(|| panic!("Missing {} in {}()", name, keyword));
+    let string = || text.clone().unwrap_or_else(|| panic!("Missing {} in {}()", text_property.unwrap_or_default(), keyword));
+    let (primitive, x, y, width, height) = match keyword {
+        "line" => {
+            let (x1, y1, x2, y2) = (value("x1"), value("y1"), value("x2"), value("y2"));
+            (Primitive::Line { x1, y1, x2, y2 }, x1.min(x2), y1.min(y2), (x2 - x1).abs(), (y2 - y1).abs())
+        }
+        "circle" => {
+            let radius = value("radius");
+            if radius <= 0 {
+                panic!("circle radius must be positive, got {}", radius);
+            }
+            let (cx, cy) = (value("cx"), value("cy"));
+            (Primitive::Circle { cx, cy }, cx - radius, cy - radius, radius * 2, radius * 2)
+        }
+        "path" => {
+            let d = string();
+            let (width, height) = path_extent(&d);
+            (Primitive::Path(d), value("x"), value("y"), width, height)
+        }
+        _ => (Primitive::Image(string()), value("x"), value("y"), value("width"), value("height")),
+    };
+
+    Node::Shape { primitive, x, y, width, height, a11y, handlers, flex, style, span: self.span_from(start) }
+}
+
 fn parse_text(&mut self) -> Node {
+    let start = self.pos;
     self.expect_ident("text");
     self.expect(Token::LParen);
 
     let mut x = None;
     let mut y = None;
     let mut value = None;
+    let mut a11y = A11yAttrs::default();
+    let mut handlers = Handlers::default();
+    let mut flex = FlexItem::default();
+    let mut style = Style::default();
 
     while self.peek() != Token::RParen {
         match self.advance() {
@@ -498,13 +1349,15 @@ fn parse_text(&mut self) -> Node {
                             panic!("Expected number for y");
                         }
                     }
-                    "value" => {
-                        if let Token::String(s) = self.advance() {
-                            value = Some(Expr::StringLiteral(s));
-                        } else {
-                            panic!("Expected string for value");
-                        }
-                    }
+                    "value" => match self.advance() {
+                        Token::String(s) => value = Some(Expr::StringLiteral(s)),
+                        Token::Ident(var) => value = Some(Expr::Identifier(var)), // a string variable or state
+                        t => panic!("Expected string or variable for value, got {:?}", t),
+                    },
+                    _ if self.parse_a11y_value(&name, &mut a11y) => {}
+                    _ if self.parse_handler_value(&name, &mut handlers) => {}
+                    _ if self.parse_flex_value(&name, &mut flex) => {}
+                    _ if self.parse_style_value(&name, TEXT_STYLE, &mut style) => {}
                     _ => panic!("Unknown text property '{}'", name),
                 }
 
@@ -522,31 +1375,35 @@ fn parse_text(&mut self) -> Node {
         x: x.expect("Missing x"),
         y: y.expect("Missing y"),
         value: value.expect("Missing value"),
+        a11y,
+        handlers,
+        flex,
+        style,
+        span: self.span_from(start),
     }
 }
 
 fn parse_list(&mut self) -> Node {
+    let start = self.pos;
     self.expect_ident("list");
     self.expect(Token::LParen);
 
     let mut direction = "vertical".to_string();
     let mut gap = 0;
     let mut padding = 0;
+    let mut a11y = A11yAttrs::default();
+    let mut flex = FlexItem::default();
 
     while self.peek() != Token::RParen {
         match self.advance() {
             Token::Ident(name) => {
                 self.expect(Token::Colon);
                 match name.as_str() {
-                    "direction" => {
-                        if let Token::String(s) = self.advance() {
-                            direction = s;
-                        } else {
-                            panic!("Expected string for direction");
-                        }
-                    }
+                    "direction" => direction = self.expect_keyword("direction", DIRECTIONS),
                     "gap" => gap = self.expect_number(),
                     "padding" => padding = self.expect_number(),
+                    _ if self.parse_a11y_value(&name, &mut a11y) => {}
+                    _ if self.parse_flex_value(&name, &mut flex) => {}
                     _ => panic!("Unexpected list param '{}'", name),
                 }
                 if self.peek() == Token::Comma {
@@ -557,50 +1414,192 @@ fn parse_list(&mut self) -> Node {
         }
     }
     self.expect(Token::RParen);
-    self.expect(Token::LBrace);
-
-    let mut items = vec![];
-    while self.peek() != Token::RBrace {
-        items.push(self.parse_item());
-    }
-    self.expect(Token::RBrace);
+    let items = self.parse_list_items();
 
     Node::List {
         direction,
         gap,
         padding,
+        x: 0,
+        y: 0,
         items,
+        a11y,
+        flex,
+        span: self.span_from(start),
     }
 }
 
-fn parse_item(&mut self) -> Node {
-    self.expect_ident("item");
+/// `grid(columns: "100 1fr", rows: "auto", gap: 8) { children }`; `columns: 3` is three `1fr` columns.
+fn parse_grid(&mut self) -> Node {
+    let start = self.pos;
+    self.expect_ident("grid");
     self.expect(Token::LParen);
 
-    let mut value = None;
+    let mut columns = vec![Track::Fraction(1)];
+    let mut rows = vec![];
+    let mut gap = 0;
+    let mut padding = 0;
+    let mut width = None;
+    let mut height = None;
+    let mut a11y = A11yAttrs::default();
+    let mut handlers = Handlers::default();
+    let mut flex = FlexItem::default();
 
     while self.peek() != Token::RParen {
         match self.advance() {
-            Token::Ident(name) if name == "value" => {
+            Token::Ident(name) => {
                 self.expect(Token::Colon);
-                if let Token::String(s) = self.advance() {
-                    value = Some(s);
-                } else {
-                    panic!("Expected string for item value");
+                match name.as_str() {
+                    "columns" | "rows" => {
+                        let tracks = match self.advance() {
+                            Token::String(text) => parse_tracks(&text),
+                            Token::Number(count) if count > 0 => vec![Track::Fraction(1); count as usize],
+                            t => panic!("Expected a track list or count for {}, got {:?}", name, t),
+                        };
+                        if name == "columns" { columns = tracks } else { rows = tracks }
+                    }
+                    "gap" => gap = self.expect_number(),
+                    "padding" => padding = self.expect_number(),
+                    "width" => width = Some(self.expect_number()),
+                    "height" => height = Some(self.expect_number()),
+                    _ if self.parse_a11y_value(&name, &mut a11y) => {}
+                    _ if self.parse_handler_value(&name, &mut handlers) => {}
+                    _ if self.parse_flex_value(&name, &mut flex) => {}
+                    _ => panic!("Unknown grid property '{}'", name),
                 }
-
                 if self.peek() == Token::Comma {
                     self.advance();
                 }
             }
-            _ => panic!("Unexpected token in item"),
+            t => panic!("Expected identifier in grid(), got {:?}", t),
         }
     }
-
     self.expect(Token::RParen);
+    if columns.is_empty() {
+        panic!("A grid needs at least one column");
+    }
+
+    self.expect(Token::LBrace);
+    let mut children = vec![];
+    while self.peek() != Token::RBrace {
+        children.push(self.parse_child_statement("grid"));
+    }
+    self.expect(Token::RBrace);
+
+    Node::Grid {
+        columns,
+        rows,
+        gap,
+        padding,
+        x: 0,
+        y: 0,
+        width,
+        height,
+        children,
+        a11y,
+        handlers,
+        flex,
+        span: self.span_from(start),
+    }
+}
+
+/// `{ item(...) ... for name in items { ... } }`, the body of a list or of a `for` in it
+fn parse_list_items(&mut self) -> Vec<Node> {
+    self.expect(Token::LBrace);
+    let mut items = vec![];
+    while self.peek() != Token::RBrace {
+        match self.peek() {
+            Token::Ident(ref s) if s == "item" => items.push(self.parse_item()),
+            Token::Ident(ref s) if s == "for" => items.push(self.parse_for()),
+            t => panic!("Expected item or for in list, got {:?}", t),
+        }
+    }
+    self.expect(Token::RBrace);
+    items
+}
+
+/// `for name in items { item ... }`
+fn parse_for(&mut self) -> Node {
+    let start = self.pos;
+    self.expect_ident("for");
+    let name = match self.advance() {
+        Token::Ident(name) => name,
+        t => panic!("Expected a variable name after for, got {:?}", t),
+    };
+    self.expect_ident("in");
+    let items = self.parse_expr();
+    let body = self.parse_list_items();
+    Node::For { name, items, body, span: self.span_from(start) }
+}
+
+/// `item(value: "a")`, `item(value: name)` or `item(props) { nodes }`; the parentheses are
+/// optional before a body.
+fn parse_item(&mut self) -> Node {
+    let start = self.pos;
+    self.expect_ident("item");
+
+    let mut value = None;
+    let mut a11y = A11yAttrs::default();
+    let mut handlers = Handlers::default();
+
+    if self.peek() == Token::LParen {
+        self.advance();
+        while self.peek() != Token::RParen {
+            let value_start = self.pos;
+            match self.advance() {
+                Token::Ident(name) if name == "value" => {
+                    self.expect(Token::Colon);
+                    let text = match self.advance() {
+                        Token::String(s) => Expr::StringLiteral(s),
+                        Token::Ident(var) => Expr::Identifier(var),
+                        t => panic!("Expected a string or variable for item value, got {:?}", t),
+                    };
+                    value = Some(Node::Text {
+                        x: 0,
+                        y: 0,
+                        value: text,
+                        a11y: A11yAttrs::default(),
+                        handlers: Handlers::default(),
+                        flex: FlexItem::default(),
+                        style: Style::default(),
+                        span: self.span_from(value_start),
+                    });
+                }
+                Token::Ident(name) => {
+                    self.expect(Token::Colon);
+                    if !self.parse_a11y_value(&name, &mut a11y) && !self.parse_handler_value(&name, &mut handlers) {
+                        panic!("Unknown item property '{}'", name);
+                    }
+                }
+                _ => panic!("Unexpected token in item"),
+            }
+            if self.peek() == Token::Comma {
+                self.advance();
+            }
+        }
+        self.expect(Token::RParen);
+    }
+
+    let children = match (value, self.peek() == Token::LBrace) {
+        (Some(text), false) => vec![text],
+        (None, true) => {
+            self.expect(Token::LBrace);
+            let mut children = vec![];
+            while self.peek() != Token::RBrace {
+                children.push(self.parse_child_statement("item"));
+            }
+            self.expect(Token::RBrace);
+            children
+        }
+        (Some(_), true) => panic!("An item takes a value or a body, not both"),
+        (None, false) => panic!("Missing value in item"),
+    };
 
     Node::Item {
-        value: value.expect("Missing value in item"),
+        children,
+        a11y,
+        handlers,
+        span: self.span_from(start),
     }
 }
 
@@ -619,28 +1618,23 @@ fn parse_item(&mut self) -> Node {
         }
     }
     fn parse_if(&mut self) -> Node {
+    let start = self.pos;
     self.expect(Token::Ident("if".into()));
     self.expect(Token::LParen);
+    let condition_start = self.pos;
     let condition = self.parse_expr();
+    let condition_span = self.span_from(condition_start);
     self.expect(Token::RParen);
-    self.expect(Token::LBrace);
-
-    let mut then_body = Vec::new();
-    while self.peek() != Token::RBrace {
-        then_body.push(self.parse_node());
-    }
-    self.expect(Token::RBrace);
+    let then_body = self.parse_block();
 
     let else_body = if let Token::Ident(ref s) = self.peek() {
         if s == "else" {
             self.advance(); // consume 'else'
-            self.expect(Token::LBrace);
-            let mut body = Vec::new();
-            while self.peek() != Token::RBrace {
-                body.push(self.parse_node());
+            if self.peek() == Token::Ident("if".into()) {
+                Some(vec![self.parse_if()]) // `else if` nests as the only else statement
+            } else {
+                Some(self.parse_block())
             }
-            self.expect(Token::RBrace);
-            Some(body)
         } else {
             None
         }
@@ -650,16 +1644,83 @@ fn parse_item(&mut self) -> Node {
 
     Node::If {
         condition,
+        condition_source: self.source_text(condition_span),
+        condition_span,
         then_body,
         else_body,
+        span: self.span_from(start),
     }
 }
 
+/// `{ ... }` holding any statements `parse_nodes` accepts, except `func`
+fn parse_block(&mut self) -> Vec<Node> {
+    self.expect(Token::LBrace);
+    let mut body = Vec::new();
+    while self.peek() != Token::RBrace {
+        body.push(self.parse_child_statement("block"));
+    }
+    self.expect(Token::RBrace);
+    body
+}
+
+fn parse_child_statement(&mut self, context: &str) -> Node {
+    match self.parse_statement() {
+        Some(Node::Function { name, .. }) => {
+            panic!("Function '{}' must be declared at top level, not inside a {}", name, context)
+        }
+        Some(Node::State { name, .. }) => {
+            panic!("State '{}' must be declared at top level, not inside a {}", name, context)
+        }
+        Some(Node::Style { name, .. }) => {
+            panic!("Style '{}' must be declared at top level, not inside a {}", name, context)
+        }
+        Some(node) => node,
+        None => panic!("Unexpected token in {}: {:?}", context, self.peek()),
+    }
+}
+
+/// `match value { 1 => { ... }, "a" => { ... }, _ => { ... } }`
+fn parse_match(&mut self) -> Node {
+    let start = self.pos;
+    self.expect_ident("match");
+    let value_start = self.pos;
+    let value = self.parse_expr();
+    let value_span = self.span_from(value_start);
+    self.expect(Token::LBrace);
+
+    let mut arms = vec![];
+    while self.peek() != Token::RBrace {
+        let pattern = match self.advance() {
+            Token::Number(n) => Pattern::Int(n),
+            Token::String(s) => Pattern::Str(s),
+            Token::Ident(ref s) if s == "_" => Pattern::Wildcard,
+            t => panic!("Expected match pattern (number, string or _), got {:?}", t),
+        };
+
+        match self.advance() {
+            Token::Operator(ref op) if op == "=>" => {}
+            t => panic!("Expected '=>' after match pattern, got {:?}", t),
+        }
+
+        let body = self.parse_block();
+        arms.push((pattern, body));
+
+        if self.peek() == Token::Comma {
+            self.advance();
+        }
+    }
+    self.expect(Token::RBrace);
+
+    Node::Match { value, value_source: self.source_text(value_span), value_span, arms, span: self.span_from(start) }
+}
 
 fn parse_expr(&mut self) -> Expr {
     let mut left = self.parse_primary();
 
     while let Token::Operator(op) = self.peek() {
+        if !is_binary_op(&op) {
+            break; // `=`, `=>` and `->` belong to the enclosing construct
+        }
         eprintln!("DEBUG: Parsing binary op {}", op); // ✅ Add this
 
         let op = match self.advance() {
@@ -690,6 +1751,7 @@ fn parse_primary(&mut self) -> Expr {
             self.advance();
             Expr::StringLiteral(s)
         },
+        Token::LParen if self.at_lambda() => self.parse_lambda(),
         Token::LParen => {
             self.advance();
             let expr = self.parse_expr();
@@ -704,6 +1766,26 @@ fn parse_primary(&mut self) -> Expr {
             let node = self.parse_list();
             Expr::Layout(Box::new(node))
         },
+        Token::Ident(ref s) if s == "grid" => {
+            let node = self.parse_grid();
+            Expr::Layout(Box::new(node))
+        },
+        Token::LBracket => {
+            self.advance();
+            let mut elements = vec![];
+            while self.peek() != Token::RBracket {
+                elements.push(self.parse_expr());
+                if self.peek() == Token::Comma {
+                    self.advance();
+                }
+            }
+            self.expect(Token::RBracket);
+            Expr::Array(elements)
+        },
+        Token::Ident(ref s) if s == "await" => {
+            self.advance();
+            Expr::Await(Box::new(self.parse_primary()))
+        },
         Token::Ident(ref s) => {
             let name = s.clone();
             self.advance();
@@ -733,16 +1815,81 @@ fn parse_primary(&mut self) -> Expr {
 
 
 
-fn parse_node(&mut self) -> Node {
-    match self.peek() {
-        Token::Ident(ref s) if s == "box" => self.parse_box(),
-        Token::Ident(ref s) if s == "group" => self.parse_group(),
-        Token::Ident(ref s) if s == "if" => self.parse_if(),
-        Token::Ident(ref s) if s == "text" => self.parse_text(),
-        _ => panic!("Unexpected token: {:?}", self.peek()),
+/// `(` followed by `)` `=>` or by `name:` starts a lambda rather than a grouped expression.
+fn at_lambda(&self) -> bool {
+    let next = self.tokens.get(self.pos + 1);
+    let after = self.tokens.get(self.pos + 2);
+    match (next, after) {
+        (Some(Token::RParen), Some(Token::Operator(op))) => op == "=>",
+        (Some(Token::Ident(_)), Some(Token::Colon)) => true,
+        _ => false,
+    }
+}
+
+fn parse_lambda(&mut self) -> Expr {
+    self.expect(Token::LParen);
+    let mut params = vec![];
+
+    while self.peek() != Token::RParen {
+        let pname = match self.advance() {
+            Token::Ident(s) => s,
+            t => panic!("Expected lambda param name, got {:?}", t),
+        };
+        self.expect(Token::Colon);
+        params.push((pname, self.parse_type()));
+
+        if self.peek() == Token::Comma {
+            self.advance();
+        }
+    }
+    self.expect(Token::RParen);
+    self.expect(Token::Operator("=>".to_string()));
+
+    let id = self.lambda_count;
+    self.lambda_count += 1;
+
+    // `=> { ... }` runs statements, `=> expr` yields the expression
+    if self.peek() == Token::LBrace {
+        let body = self.parse_block();
+        Expr::Lambda { id, params, body, returns_value: false }
+    } else {
+        let start = self.pos;
+        let value = self.parse_expr();
+        Expr::Lambda { id, params, body: vec![Node::Expr(value, self.span_from(start))], returns_value: true }
     }
 }
 
+/// Parses `int`, `string`, or a function type such as `func(int, int) -> int`.
+fn parse_type(&mut self) -> String {
+    let name = match self.advance() {
+        Token::Ident(s) => s,
+        t => panic!("Expected type name, got {:?}", t),
+    };
+
+    if name != "func" || self.peek() != Token::LParen {
+        return name;
+    }
+
+    self.advance(); // skip (
+    let mut args = vec![];
+    while self.peek() != Token::RParen {
+        args.push(self.parse_type());
+        if self.peek() == Token::Comma {
+            self.advance();
+        }
+    }
+    self.expect(Token::RParen);
+
+    let mut ty = format!("func({})", args.join(","));
+    if self.peek() == Token::Operator("->".to_string()) {
+        self.advance();
+        ty.push_str("->");
+        ty.push_str(&self.parse_type());
+    }
+    ty
+}
+
+
 fn expect_ident(&mut self, expected: &str) {
     match self.advance() {
         Token::Ident(s) if s == expected => {},
@@ -754,4 +1901,8 @@ fn expect_ident(&mut self, expected: &str) {
 
 
 
+}
+
+fn is_binary_op(op: &str) -> bool {
+    matches!(op, "+" | "-" | "*" | "/" | "==" | ">" | "<" | ">=" | "<=")
 }
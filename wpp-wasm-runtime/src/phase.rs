@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use crate::parser::{Expr, Node};
+
+// Top-level statements are split between `run` and `render`: `render` re-runs on
+// every redraw, so it only gets what records frame nodes, while statements that
+// do work (assignments, calls that draw nothing, async calls) run once from `run`.
+
+/// Where a top-level statement is compiled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Render, // records frame nodes
+    Run,    // runs once, before the first frame
+    Both,   // a `let` both may read
+}
+
+/// Functions whose calls record frame nodes, directly or through another function.
+/// Async functions never count: their calls start a task, which `run` does once.
+pub fn drawing_functions(ast: &[Node]) -> HashSet<String> {
+    let mut drawing = HashSet::new();
+    loop {
+        let before = drawing.len();
+        for node in ast {
+            if let Node::Function { name, body, is_async: false, .. } = node {
+                if !drawing.contains(name) && body.iter().any(|stmt| draws(stmt, &drawing)) {
+                    drawing.insert(name.clone());
+                }
+            }
+        }
+        if drawing.len() == before {
+            return drawing;
+        }
+    }
+}
+
+/// Phase of a top-level statement other than a function, state or style declaration.
+pub fn phase(node: &Node, drawing: &HashSet<String>, tasks: &HashSet<String>) -> Phase {
+    match node {
+        Node::Let { value, .. } if expr_draws(value, drawing) => Phase::Render,
+        Node::Let { value, .. } if starts_task(value, tasks) => Phase::Run,
+        Node::Let { .. } => Phase::Both,
+        _ if draws(node, drawing) => Phase::Render,
+        _ => Phase::Run,
+    }
+}
+
+/// Names of the async functions in `ast`
+pub fn async_functions(ast: &[Node]) -> HashSet<String> {
+    ast.iter()
+        .filter_map(|node| match node {
+            Node::Function { name, is_async: true, .. } => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn draws(node: &Node, drawing: &HashSet<String>) -> bool {
+    match node {
+        Node::Group { .. }
+        | Node::Box { .. }
+        | Node::Text { .. }
+        | Node::Shape { .. }
+        | Node::Grid { .. }
+        | Node::List { .. }
+        | Node::Item { .. } => true,
+        Node::If { then_body, else_body, .. } => then_body.iter().chain(else_body.iter().flatten()).any(|n| draws(n, drawing)),
+        Node::Match { arms, .. } => arms.iter().flat_map(|(_, body)| body).any(|n| draws(n, drawing)),
+        Node::For { body, .. } => body.iter().any(|n| draws(n, drawing)),
+        Node::Expr(expr, _) | Node::Let { value: expr, .. } | Node::Assign { value: expr, .. } => expr_draws(expr, drawing),
+        _ => false,
+    }
+}
+
+fn expr_draws(expr: &Expr, drawing: &HashSet<String>) -> bool {
+    match expr {
+        Expr::Layout(_) => true,
+        Expr::Call { name, args } => drawing.contains(name) || args.iter().any(|arg| expr_draws(arg, drawing)),
+        Expr::Binary { left, right, .. } => expr_draws(left, drawing) || expr_draws(right, drawing),
+        Expr::Array(items) => items.iter().any(|item| expr_draws(item, drawing)),
+        Expr::Await(inner) => expr_draws(inner, drawing),
+        _ => false, // a lambda's body runs when it is called
+    }
+}
+
+fn starts_task(expr: &Expr, tasks: &HashSet<String>) -> bool {
+    match expr {
+        Expr::Call { name, args } => tasks.contains(name) || args.iter().any(|arg| starts_task(arg, tasks)),
+        Expr::Binary { left, right, .. } => starts_task(left, tasks) || starts_task(right, tasks),
+        Expr::Array(items) => items.iter().any(|item| starts_task(item, tasks)),
+        Expr::Await(_) => true,
+        _ => false,
+    }
+}
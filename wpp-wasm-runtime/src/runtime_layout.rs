@@ -0,0 +1,1143 @@
+use wasm_encoder::{BlockType, Instruction};
+use crate::frame::{self, field, A, B, FILL, FLOW, FONT, KIND, KIND_CLOSE, KIND_FLEX, KIND_MASK, KIND_OPEN, KIND_RECT, KIND_TEXT, NODE_RECORD, SHAPE, STROKE, TAG_SHIFT, X, Y};
+use crate::parser::FlexItem;
+use crate::transpile::{
+    MEASURE_NODE_FUNC, MEASURE_TEXT_FUNC, PLACE_NODE_FUNC, RESERVE_NODE_FUNC, SET_BOUNDS_FUNC, FRAME_GLOBAL, FRAME_LEN_GLOBAL, OPEN_DEPTH_GLOBAL,
+};
+
+// Layout at render time, for groups whose content is only known then (texts
+// bound to variables, `if`/`match` bodies). Such a group records an OPEN node,
+// its children as FLOW nodes and a CLOSE node instead of drawing at fixed
+// positions; `layout_frame` then sizes every container bottom-up, places its
+// children top-down with the same rules as `layout::Flow`, and removes the
+// container nodes so `commit_frame` only sees boxes and texts, a group with a
+// fill or stroke becoming the box of its background. A child that grows,
+// shrinks or has a basis or min/max size is preceded by a FLEX node holding
+// them. Texts are measured by the host (`measureText`); a grid inside such a
+// group keeps its compile-time cell layout, its nodes are only moved along with it.
+
+/// `x` of an OPEN node: direction, align and justify as indices into the parser's lists, and wrap.
+pub fn open_flags(horizontal: bool, align: usize, justify: usize, wrap: bool) -> i32 {
+    horizontal as i32 | (align as i32) << 1 | (justify as i32) << 3 | (wrap as i32) << 6
+}
+
+/// `y` of an OPEN node
+pub fn open_spacing(gap: i32, padding: i32) -> i32 {
+    if !(0..=0xFFFF).contains(&gap) || !(0..=0xFFFF).contains(&padding) {
+        panic!("❌ gap and padding must be between 0 and 65535 for layout at render time, got {} and {}", gap, padding);
+    }
+    gap | padding << 16
+}
+
+// Fields of a FLEX node, along the direction of its container
+const FLEX_GROW: u64 = X;
+const FLEX_SHRINK: u64 = Y;
+const FLEX_BASIS: u64 = A;      // -1 for the child's own size
+const FLEX_MIN: u64 = B;
+const FLEX_MAX: u64 = FILL;
+const FLEX_MIN_CROSS: u64 = STROKE;
+const FLEX_MAX_CROSS: u64 = SHAPE;
+
+/// Fields of the FLEX node ahead of a child of a container running `horizontal`:
+/// grow, shrink, basis, then min and max main size, min and max cross size,
+/// the unset ones at what `layout::Flow` assumes.
+pub fn flex_fields(flex: &FlexItem, horizontal: bool) -> [i32; 7] {
+    let (min, max, min_cross, max_cross) = if horizontal {
+        (flex.min_width, flex.max_width, flex.min_height, flex.max_height)
+    } else {
+        (flex.min_height, flex.max_height, flex.min_width, flex.max_width)
+    };
+    let (min, min_cross) = (min.unwrap_or(0), min_cross.unwrap_or(0));
+    [
+        flex.grow.unwrap_or(0),
+        flex.shrink.unwrap_or(1),
+        flex.basis.unwrap_or(-1),
+        min,
+        max.unwrap_or(i32::MAX).max(min),
+        min_cross,
+        max_cross.unwrap_or(i32::MAX).max(min_cross),
+    ]
+}
+
+const ALIGN_CENTER: i32 = 1;
+const ALIGN_END: i32 = 2;
+const ALIGN_STRETCH: i32 = 3;
+const JUSTIFY_CENTER: i32 = 1;
+const JUSTIFY_END: i32 = 2;
+const JUSTIFY_BETWEEN: i32 = 3;
+const JUSTIFY_AROUND: i32 = 4;
+const JUSTIFY_EVENLY: i32 = 5;
+
+fn load(node: u32, offset: u64) -> [Instruction<'static>; 2] {
+    [Instruction::LocalGet(node), Instruction::I32Load(field(offset))]
+}
+
+/// `kind & KIND_MASK == expected` of the node in `node`
+fn is_kind(node: u32, expected: i32, body: &mut Vec<Instruction<'static>>) {
+    body.extend(load(node, KIND));
+    body.extend([Instruction::I32Const(KIND_MASK), Instruction::I32And, Instruction::I32Const(expected), Instruction::I32Eq]);
+}
+
+/// `target = a if cond else b`
+fn select(target: u32, a: u32, b: u32, cond: u32, body: &mut Vec<Instruction<'static>>) {
+    body.extend([
+        Instruction::LocalGet(a),
+        Instruction::LocalGet(b),
+        Instruction::LocalGet(cond),
+        Instruction::Select,
+        Instruction::LocalSet(target),
+    ]);
+}
+
+/// `target += value`
+fn add(target: u32, value: Vec<Instruction<'static>>, body: &mut Vec<Instruction<'static>>) {
+    body.push(Instruction::LocalGet(target));
+    body.extend(value);
+    body.extend([Instruction::I32Add, Instruction::LocalSet(target)]);
+}
+
+/// `node.offset = value`
+fn store(node: u32, offset: u64, value: Vec<Instruction<'static>>, body: &mut Vec<Instruction<'static>>) {
+    body.push(Instruction::LocalGet(node));
+    body.extend(value);
+    body.push(Instruction::I32Store(field(offset)));
+}
+
+/// `target = min(target, value)`
+fn at_most(target: u32, value: Vec<Instruction<'static>>, body: &mut Vec<Instruction<'static>>) {
+    body.extend(value.iter().cloned());
+    body.push(Instruction::LocalGet(target));
+    body.extend(value);
+    body.extend([Instruction::LocalGet(target), Instruction::I32LtS, Instruction::Select, Instruction::LocalSet(target)]);
+}
+
+/// `target = max(target, value)`
+fn at_least(target: u32, value: Vec<Instruction<'static>>, body: &mut Vec<Instruction<'static>>) {
+    body.extend(value.iter().cloned());
+    body.push(Instruction::LocalGet(target));
+    body.extend(value);
+    body.extend([Instruction::LocalGet(target), Instruction::I32GtS, Instruction::Select, Instruction::LocalSet(target)]);
+}
+
+/// `target = target.clamp(min, max)`; max is never below min, see `flex_fields`
+fn clamp(target: u32, min: Vec<Instruction<'static>>, max: Vec<Instruction<'static>>, body: &mut Vec<Instruction<'static>>) {
+    at_most(target, max, body);
+    at_least(target, min, body);
+}
+
+/// `measureText(ptr, len, font)` of the text node in `node`
+fn measure_text(node: u32) -> Vec<Instruction<'static>> {
+    let mut code = load(node, A).to_vec();
+    code.extend(load(node, B));
+    code.extend(load(node, FONT));
+    code.push(Instruction::Call(MEASURE_TEXT_FUNC));
+    code
+}
+
+/// Line height of the text node in `node`: its font size
+fn text_height(node: u32) -> Vec<Instruction<'static>> {
+    let mut code = load(node, FONT).to_vec();
+    code.extend([Instruction::I32Const(8), Instruction::I32ShrU]);
+    code
+}
+
+/// Width and height of a flow node into `w`, `h`: a container's measured size,
+/// a box's own, or for a text the width `measure_node` left in its `x`.
+fn node_size(node: u32, w: u32, h: u32, body: &mut Vec<Instruction<'static>>) {
+    is_kind(node, KIND_TEXT, body);
+    body.push(Instruction::If(BlockType::Empty));
+    body.extend(load(node, X));
+    body.push(Instruction::LocalSet(w));
+    body.extend(text_height(node));
+    body.extend([Instruction::LocalSet(h), Instruction::Else]);
+    body.extend(load(node, A));
+    body.push(Instruction::LocalSet(w));
+    body.extend(load(node, B));
+    body.extend([Instruction::LocalSet(h), Instruction::End]);
+}
+
+/// Direction, gap and padding of the OPEN node in `node`
+fn unpack(node: u32, horizontal: u32, gap: u32, padding: u32, body: &mut Vec<Instruction<'static>>) {
+    body.extend(load(node, X));
+    body.extend([Instruction::I32Const(1), Instruction::I32And, Instruction::LocalSet(horizontal)]);
+    body.extend(load(node, Y));
+    body.extend([Instruction::I32Const(0xFFFF), Instruction::I32And, Instruction::LocalSet(gap)]);
+    body.extend(load(node, Y));
+    body.extend([Instruction::I32Const(16), Instruction::I32ShrU, Instruction::LocalSet(padding)]);
+}
+
+/// `(count - 1) * gap` when there are at least two children, else 0
+fn gaps(count: u32, gap: u32) -> Vec<Instruction<'static>> {
+    vec![
+        Instruction::LocalGet(count),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::LocalGet(gap),
+        Instruction::I32Mul,
+        Instruction::I32Const(0),
+        Instruction::LocalGet(count),
+        Instruction::I32Const(1),
+        Instruction::I32GtS,
+        Instruction::Select,
+    ]
+}
+
+/// Calls `set_bounds(tag, x, y, w, h)` for the node in `node`.
+fn set_bounds(node: u32, x: u32, y: u32, w: u32, h: u32, body: &mut Vec<Instruction<'static>>) {
+    body.extend(load(node, KIND));
+    body.extend([
+        Instruction::I32Const(TAG_SHIFT),
+        Instruction::I32ShrU,
+        Instruction::LocalGet(x),
+        Instruction::LocalGet(y),
+        Instruction::LocalGet(w),
+        Instruction::LocalGet(h),
+        Instruction::Call(SET_BOUNDS_FUNC),
+    ]);
+}
+
+// Locals of measure_node(node) -> next
+const M_NODE: u32 = 0;
+const M_CHILD: u32 = 1;
+const M_NEXT: u32 = 2;
+const M_MAIN: u32 = 3;  // longest line
+const M_CROSS: u32 = 4; // lines side by side, gaps between them
+const M_COUNT: u32 = 5; // children on the current line
+const M_W: u32 = 6;
+const M_H: u32 = 7;
+const M_SIDE: u32 = 8;  // one child's main extent
+const M_HORIZONTAL: u32 = 9;
+const M_GAP: u32 = 10;
+const M_PADDING: u32 = 11;
+const M_LIMIT: u32 = 12; // main room for a line: the given main size of a wrapping group
+const M_USED: u32 = 13;  // main extent of the current line
+const M_LINE_CROSS: u32 = 14; // its thickest child
+const M_ACROSS: u32 = 15; // one child's cross extent
+pub const MEASURE_NODE_LOCALS: u32 = 15;
+
+/// `measure_node(open) -> node after its CLOSE`: sizes the container from its
+/// flow children, measuring nested containers first and texts through the
+/// host (the width is kept in the text's `x` until it is placed). A wrapping
+/// container given its main size stacks its children in lines no longer than
+/// that, as `layout::Flow::content_size` does. An explicit width or height (not -1) is kept.
+pub fn measure_node_body() -> Vec<Instruction<'static>> {
+    let mut body = vec![];
+    unpack(M_NODE, M_HORIZONTAL, M_GAP, M_PADDING, &mut body);
+    body.extend([Instruction::I32Const(i32::MAX), Instruction::LocalSet(M_LIMIT)]);
+    body.extend(load(M_NODE, A));
+    body.extend(load(M_NODE, B));
+    body.extend([Instruction::LocalGet(M_HORIZONTAL), Instruction::Select, Instruction::LocalSet(M_W)]);
+    body.extend(load(M_NODE, X));
+    body.extend([
+        Instruction::I32Const(6),
+        Instruction::I32ShrU,
+        Instruction::I32Const(1),
+        Instruction::I32And,
+        Instruction::LocalGet(M_W),
+        Instruction::I32Const(0),
+        Instruction::I32GeS,
+        Instruction::I32And,
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(M_W),
+        Instruction::LocalGet(M_PADDING),
+        Instruction::I32Const(1),
+        Instruction::I32Shl,
+        Instruction::I32Sub,
+        Instruction::LocalSet(M_LIMIT),
+        Instruction::End,
+    ]);
+    body.extend([
+        Instruction::LocalGet(M_NODE),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Add,
+        Instruction::LocalSet(M_CHILD),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+    ]);
+    is_kind(M_CHILD, KIND_CLOSE, &mut body);
+    body.push(Instruction::BrIf(1));
+    body.extend([
+        Instruction::LocalGet(M_CHILD),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Add,
+        Instruction::LocalSet(M_NEXT),
+    ]);
+    body.extend(load(M_CHILD, KIND));
+    body.extend([Instruction::I32Const(FLOW), Instruction::I32And, Instruction::If(BlockType::Empty)]);
+    is_kind(M_CHILD, KIND_OPEN, &mut body);
+    body.extend([
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(M_CHILD),
+        Instruction::Call(MEASURE_NODE_FUNC),
+        Instruction::LocalSet(M_NEXT),
+        Instruction::End,
+    ]);
+    is_kind(M_CHILD, KIND_TEXT, &mut body);
+    body.extend([Instruction::If(BlockType::Empty), Instruction::LocalGet(M_CHILD)]);
+    body.extend(measure_text(M_CHILD));
+    body.extend([Instruction::I32Store(field(X)), Instruction::End]);
+    node_size(M_CHILD, M_W, M_H, &mut body);
+    select(M_SIDE, M_W, M_H, M_HORIZONTAL, &mut body);
+    select(M_ACROSS, M_H, M_W, M_HORIZONTAL, &mut body);
+    // Past the limit the child starts the next line
+    body.extend([
+        Instruction::LocalGet(M_COUNT),
+        Instruction::I32Const(0),
+        Instruction::I32GtS,
+        Instruction::LocalGet(M_USED),
+        Instruction::LocalGet(M_GAP),
+        Instruction::I32Add,
+        Instruction::LocalGet(M_SIDE),
+        Instruction::I32Add,
+        Instruction::LocalGet(M_LIMIT),
+        Instruction::I32GtS,
+        Instruction::I32And,
+        Instruction::If(BlockType::Empty),
+    ]);
+    add(M_CROSS, vec![Instruction::LocalGet(M_LINE_CROSS), Instruction::LocalGet(M_GAP), Instruction::I32Add], &mut body);
+    at_least(M_MAIN, vec![Instruction::LocalGet(M_USED)], &mut body);
+    for local in [M_USED, M_LINE_CROSS, M_COUNT] {
+        body.extend([Instruction::I32Const(0), Instruction::LocalSet(local)]);
+    }
+    body.push(Instruction::End);
+    // Its main extent, after a gap unless it opens the line
+    add(M_USED, vec![
+        Instruction::LocalGet(M_SIDE),
+        Instruction::LocalGet(M_GAP),
+        Instruction::I32Const(0),
+        Instruction::LocalGet(M_COUNT),
+        Instruction::I32Const(0),
+        Instruction::I32GtS,
+        Instruction::Select,
+        Instruction::I32Add,
+    ], &mut body);
+    at_least(M_LINE_CROSS, vec![Instruction::LocalGet(M_ACROSS)], &mut body);
+    add(M_COUNT, vec![Instruction::I32Const(1)], &mut body);
+    body.extend([
+        Instruction::End,
+        Instruction::LocalGet(M_NEXT),
+        Instruction::LocalSet(M_CHILD),
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+    ]);
+    at_least(M_MAIN, vec![Instruction::LocalGet(M_USED)], &mut body);
+    add(M_CROSS, vec![Instruction::LocalGet(M_LINE_CROSS)], &mut body);
+
+    // Content plus padding, unless the group was given a size
+    select(M_W, M_MAIN, M_CROSS, M_HORIZONTAL, &mut body);
+    select(M_H, M_CROSS, M_MAIN, M_HORIZONTAL, &mut body);
+    for (offset, size) in [(A, M_W), (B, M_H)] {
+        body.extend(load(M_NODE, offset));
+        body.extend([
+            Instruction::I32Const(0),
+            Instruction::I32LtS,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(M_NODE),
+            Instruction::LocalGet(size),
+            Instruction::LocalGet(M_PADDING),
+            Instruction::I32Const(1),
+            Instruction::I32Shl,
+            Instruction::I32Add,
+            Instruction::I32Store(field(offset)),
+            Instruction::End,
+        ]);
+    }
+    body.extend([
+        Instruction::LocalGet(M_CHILD),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Add,
+        Instruction::End,
+    ]);
+    body
+}
+
+// Locals of place_node(node, x, y, w, h, items) -> next
+const P_NODE: u32 = 0;
+const P_X: u32 = 1;
+const P_Y: u32 = 2;
+const P_W: u32 = 3;
+const P_H: u32 = 4;
+const P_ITEMS: u32 = 5;  // free scratch for our item records, see layout_frame
+const P_CHILD: u32 = 6;
+const P_NEXT: u32 = 7;
+const P_KIND: u32 = 8;
+const P_DEPTH: u32 = 9;
+const P_HORIZONTAL: u32 = 10;
+const P_GAP: u32 = 11;
+const P_PADDING: u32 = 12;
+const P_ALIGN: u32 = 13;
+const P_JUSTIFY: u32 = 14;
+const P_WRAP: u32 = 15;
+const P_INNER_X: u32 = 16;
+const P_INNER_Y: u32 = 17;
+const P_INNER_MAIN: u32 = 18;
+const P_INNER_CROSS: u32 = 19;
+const P_DEFAULT: u32 = 20;   // FLEX node of the children recorded without one
+const P_FLEX: u32 = 21;      // FLEX node of the next flow child
+const P_END: u32 = 22;       // past our last item
+const P_ITEM: u32 = 23;
+const P_LINE: u32 = 24;      // first item of the line being laid out
+const P_LINE_END: u32 = 25;
+const P_COUNT: u32 = 26;     // items on the line
+const P_USED: u32 = 27;      // their main extent while the line fills up
+const P_AVAILABLE: u32 = 28; // main room for them once gaps are taken
+const P_GROWING: u32 = 29;   // 1 when they leave room, 0 when they overflow
+const P_TOTAL: u32 = 30;     // their main extent, or weight while resolving
+const P_UNFROZEN: u32 = 31;
+const P_REMAINING: u32 = 32;
+const P_HANDED: u32 = 33;    // main size handed out this round
+const P_LAST: u32 = 34;      // last unfrozen item
+const P_CLAMPED: u32 = 35;
+const P_TARGET: u32 = 36;
+const P_LINE_CROSS: u32 = 37;
+const P_LINE_START: u32 = 38; // cross offset of the line
+const P_FREE: u32 = 39;
+const P_SPREAD: u32 = 40;    // free space, 0 when overflowing
+const P_CURSOR: u32 = 41;
+const P_STEP: u32 = 42;
+const P_W2: u32 = 43; // child's own size
+const P_H2: u32 = 44;
+const P_MAIN: u32 = 45;
+const P_CROSS: u32 = 46;
+const P_OFFSET: u32 = 47;
+const P_CX: u32 = 48; // child's rect
+const P_CY: u32 = 49;
+const P_CW: u32 = 50;
+const P_CH: u32 = 51;
+pub const PLACE_NODE_LOCALS: u32 = 46;
+
+// Item record of a flow child while its container is placed, NODE_RECORD bytes each
+const ITEM_NODE: u64 = 0;
+const ITEM_FLEX: u64 = 4;    // its FLEX node, or the default one
+const ITEM_MAIN: u64 = 8;    // basis or own main size within min/max, then grown or shrunk
+const ITEM_CROSS: u64 = 12;  // own cross size within min/max
+const ITEM_FROZEN: u64 = 16; // 1 once its main size is final
+const ITEM_TARGET: u64 = 20; // main size it is offered this round
+
+/// `if local == value { then }`
+fn when(local: u32, value: i32, then: Vec<Instruction<'static>>, body: &mut Vec<Instruction<'static>>) {
+    body.extend([Instruction::LocalGet(local), Instruction::I32Const(value), Instruction::I32Eq, Instruction::If(BlockType::Empty)]);
+    body.extend(then);
+    body.push(Instruction::End);
+}
+
+/// `target = a / b`
+fn divide(target: u32, a: u32, b: Vec<Instruction<'static>>) -> Vec<Instruction<'static>> {
+    let mut code = vec![Instruction::LocalGet(a)];
+    code.extend(b);
+    code.extend([Instruction::I32DivS, Instruction::LocalSet(target)]);
+    code
+}
+
+/// `target = gap + a / b`
+fn step(a: u32, b: Vec<Instruction<'static>>) -> Vec<Instruction<'static>> {
+    let mut code = divide(P_STEP, a, b);
+    code.extend([Instruction::LocalGet(P_STEP), Instruction::LocalGet(P_GAP), Instruction::I32Add, Instruction::LocalSet(P_STEP)]);
+    code
+}
+
+/// `for item in from..to { each }` over item records, the item in P_ITEM
+fn for_items(from: u32, to: u32, each: Vec<Instruction<'static>>, body: &mut Vec<Instruction<'static>>) {
+    body.extend([
+        Instruction::LocalGet(from),
+        Instruction::LocalSet(P_ITEM),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(P_ITEM),
+        Instruction::LocalGet(to),
+        Instruction::I32GeU,
+        Instruction::BrIf(1),
+    ]);
+    body.extend(each);
+    add(P_ITEM, vec![Instruction::I32Const(NODE_RECORD)], body);
+    body.extend([Instruction::Br(0), Instruction::End, Instruction::End]);
+}
+
+/// `each` when the item in P_ITEM is not frozen
+fn unfrozen(each: Vec<Instruction<'static>>) -> Vec<Instruction<'static>> {
+    let mut code = load(P_ITEM, ITEM_FROZEN).to_vec();
+    code.extend([Instruction::I32Eqz, Instruction::If(BlockType::Empty)]);
+    code.extend(each);
+    code.push(Instruction::End);
+    code
+}
+
+/// Field at `offset` of the FLEX node of the item in P_ITEM
+fn flex_field(offset: u64) -> Vec<Instruction<'static>> {
+    let mut code = load(P_ITEM, ITEM_FLEX).to_vec();
+    code.push(Instruction::I32Load(field(offset)));
+    code
+}
+
+/// How much of the line's free space (`grow`) or overflow (`shrink` × size) the item in P_ITEM takes
+fn weight() -> Vec<Instruction<'static>> {
+    let mut code = flex_field(FLEX_GROW);
+    code.extend(flex_field(FLEX_SHRINK));
+    code.extend(load(P_ITEM, ITEM_MAIN));
+    code.extend([Instruction::I32Mul, Instruction::LocalGet(P_GROWING), Instruction::Select]);
+    code
+}
+
+/// Records the flow child in P_CHILD as the next item, with the FLEX node in P_FLEX:
+/// its basis, or else its own size, within its min/max sizes.
+fn gather_item(body: &mut Vec<Instruction<'static>>) {
+    node_size(P_CHILD, P_W2, P_H2, body);
+    select(P_MAIN, P_W2, P_H2, P_HORIZONTAL, body);
+    select(P_CROSS, P_H2, P_W2, P_HORIZONTAL, body);
+    body.extend(load(P_FLEX, FLEX_BASIS));
+    body.push(Instruction::LocalGet(P_MAIN));
+    body.extend(load(P_FLEX, FLEX_BASIS));
+    body.extend([Instruction::I32Const(0), Instruction::I32GeS, Instruction::Select, Instruction::LocalSet(P_MAIN)]);
+    clamp(P_MAIN, load(P_FLEX, FLEX_MIN).to_vec(), load(P_FLEX, FLEX_MAX).to_vec(), body);
+    clamp(P_CROSS, load(P_FLEX, FLEX_MIN_CROSS).to_vec(), load(P_FLEX, FLEX_MAX_CROSS).to_vec(), body);
+    for (offset, value) in [(ITEM_NODE, P_CHILD), (ITEM_FLEX, P_FLEX), (ITEM_MAIN, P_MAIN), (ITEM_CROSS, P_CROSS)] {
+        store(P_END, offset, vec![Instruction::LocalGet(value)], body);
+    }
+    add(P_END, vec![Instruction::I32Const(NODE_RECORD)], body);
+    body.extend([Instruction::LocalGet(P_DEFAULT), Instruction::LocalSet(P_FLEX)]);
+}
+
+/// Moves a grid's node in P_CHILD, recorded relative to the grid, to where the grid landed.
+fn move_grid_node(body: &mut Vec<Instruction<'static>>) {
+    for (offset, origin, target) in [(X, P_X, P_CX), (Y, P_Y, P_CY)] {
+        body.push(Instruction::LocalGet(P_CHILD));
+        body.extend(load(P_CHILD, offset));
+        body.extend([
+            Instruction::LocalGet(origin),
+            Instruction::I32Add,
+            Instruction::LocalTee(target),
+            Instruction::I32Store(field(offset)),
+        ]);
+    }
+    is_kind(P_CHILD, KIND_TEXT, body);
+    body.push(Instruction::If(BlockType::Empty));
+    body.extend(measure_text(P_CHILD));
+    body.push(Instruction::LocalSet(P_CW));
+    body.extend(text_height(P_CHILD));
+    body.extend([Instruction::LocalSet(P_CH), Instruction::Else]);
+    body.extend(load(P_CHILD, A));
+    body.push(Instruction::LocalSet(P_CW));
+    body.extend(load(P_CHILD, B));
+    body.extend([Instruction::LocalSet(P_CH), Instruction::End]);
+    set_bounds(P_CHILD, P_CX, P_CY, P_CW, P_CH, body);
+}
+
+/// Grows or shrinks the main size of the line's items so they fill P_AVAILABLE, as
+/// `layout::resolve_flexible` does: free space goes out by `grow`, overflow is taken
+/// back by `shrink` × size, and items pushed past their min or max freeze there
+/// while the rest share again.
+fn resolve_flexible(body: &mut Vec<Instruction<'static>>) {
+    body.extend([Instruction::I32Const(0), Instruction::LocalSet(P_TOTAL)]);
+    let mut sum = vec![];
+    add(P_TOTAL, load(P_ITEM, ITEM_MAIN).to_vec(), &mut sum);
+    for_items(P_LINE, P_LINE_END, sum, body);
+    body.extend([
+        Instruction::LocalGet(P_TOTAL),
+        Instruction::LocalGet(P_AVAILABLE),
+        Instruction::I32LtS,
+        Instruction::LocalSet(P_GROWING),
+    ]);
+    let mut weightless = vec![];
+    store(P_ITEM, ITEM_FROZEN, [weight(), vec![Instruction::I32Eqz]].concat(), &mut weightless);
+    for_items(P_LINE, P_LINE_END, weightless, body);
+
+    body.extend([
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(P_AVAILABLE),
+        Instruction::LocalSet(P_REMAINING),
+        Instruction::I32Const(0),
+        Instruction::LocalSet(P_TOTAL),
+        Instruction::I32Const(0),
+        Instruction::LocalSet(P_UNFROZEN),
+    ]);
+    let mut tally = vec![Instruction::LocalGet(P_REMAINING)];
+    tally.extend(load(P_ITEM, ITEM_MAIN));
+    tally.extend([Instruction::I32Sub, Instruction::LocalSet(P_REMAINING)]);
+    let mut share = vec![];
+    add(P_TOTAL, weight(), &mut share);
+    add(P_UNFROZEN, vec![Instruction::I32Const(1)], &mut share);
+    tally.extend(unfrozen(share));
+    for_items(P_LINE, P_LINE_END, tally, body);
+    body.extend([
+        Instruction::LocalGet(P_UNFROZEN),
+        Instruction::I32Eqz,
+        Instruction::BrIf(1),
+        Instruction::I32Const(0),
+        Instruction::LocalSet(P_HANDED),
+    ]);
+
+    // main + remaining * weight / total, in i64 so the product cannot overflow
+    let mut offer = load(P_ITEM, ITEM_MAIN).to_vec();
+    offer.extend([Instruction::LocalGet(P_REMAINING), Instruction::I64ExtendI32S]);
+    offer.extend(weight());
+    offer.extend([
+        Instruction::I64ExtendI32S,
+        Instruction::I64Mul,
+        Instruction::LocalGet(P_TOTAL),
+        Instruction::I64ExtendI32S,
+        Instruction::I64DivS,
+        Instruction::I32WrapI64,
+        Instruction::I32Add,
+        Instruction::LocalSet(P_TARGET),
+    ]);
+    store(P_ITEM, ITEM_TARGET, vec![Instruction::LocalGet(P_TARGET)], &mut offer);
+    let mut handed = vec![Instruction::LocalGet(P_TARGET)];
+    handed.extend(load(P_ITEM, ITEM_MAIN));
+    handed.push(Instruction::I32Sub);
+    add(P_HANDED, handed, &mut offer);
+    offer.extend([Instruction::LocalGet(P_ITEM), Instruction::LocalSet(P_LAST)]);
+    for_items(P_LINE, P_LINE_END, unfrozen(offer), body);
+    // Rounding leftovers go to the last item so the line fills exactly
+    let mut leftover = load(P_LAST, ITEM_TARGET).to_vec();
+    leftover.extend([
+        Instruction::LocalGet(P_REMAINING),
+        Instruction::I32Add,
+        Instruction::LocalGet(P_HANDED),
+        Instruction::I32Sub,
+    ]);
+    store(P_LAST, ITEM_TARGET, leftover, body);
+
+    body.extend([Instruction::I32Const(0), Instruction::LocalSet(P_CLAMPED)]);
+    let mut bound = load(P_ITEM, ITEM_TARGET).to_vec();
+    bound.push(Instruction::LocalSet(P_TARGET));
+    clamp(P_TARGET, flex_field(FLEX_MIN), flex_field(FLEX_MAX), &mut bound);
+    at_least(P_TARGET, vec![Instruction::I32Const(0)], &mut bound);
+    bound.push(Instruction::LocalGet(P_TARGET));
+    bound.extend(load(P_ITEM, ITEM_TARGET));
+    bound.extend([Instruction::I32Ne, Instruction::If(BlockType::Empty)]);
+    store(P_ITEM, ITEM_MAIN, vec![Instruction::LocalGet(P_TARGET)], &mut bound);
+    store(P_ITEM, ITEM_FROZEN, vec![Instruction::I32Const(1)], &mut bound);
+    bound.extend([Instruction::I32Const(1), Instruction::LocalSet(P_CLAMPED), Instruction::End]);
+    for_items(P_LINE, P_LINE_END, unfrozen(bound), body);
+    body.extend([Instruction::LocalGet(P_CLAMPED), Instruction::BrIf(0)]);
+
+    let mut apply = vec![];
+    store(P_ITEM, ITEM_MAIN, load(P_ITEM, ITEM_TARGET).to_vec(), &mut apply);
+    for_items(P_LINE, P_LINE_END, unfrozen(apply), body);
+    body.extend([Instruction::End, Instruction::End]);
+}
+
+/// Places the item in P_ITEM at the cursor on the current line: stretched across
+/// the line within its min/max cross size, otherwise cut to it and aligned.
+fn place_item(body: &mut Vec<Instruction<'static>>) {
+    body.extend(load(P_ITEM, ITEM_NODE));
+    body.push(Instruction::LocalSet(P_CHILD));
+    node_size(P_CHILD, P_W2, P_H2, body);
+    body.extend(load(P_ITEM, ITEM_MAIN));
+    body.push(Instruction::LocalSet(P_MAIN));
+    body.extend([
+        Instruction::LocalGet(P_ALIGN),
+        Instruction::I32Const(ALIGN_STRETCH),
+        Instruction::I32Eq,
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(P_LINE_CROSS),
+        Instruction::LocalSet(P_CROSS),
+    ]);
+    clamp(P_CROSS, flex_field(FLEX_MIN_CROSS), flex_field(FLEX_MAX_CROSS), body);
+    body.push(Instruction::Else);
+    body.extend(load(P_ITEM, ITEM_CROSS));
+    body.push(Instruction::LocalSet(P_CROSS));
+    at_most(P_CROSS, vec![Instruction::LocalGet(P_LINE_CROSS)], body);
+    body.extend([Instruction::End, Instruction::I32Const(0), Instruction::LocalSet(P_OFFSET)]);
+    let leftover = || vec![Instruction::LocalGet(P_LINE_CROSS), Instruction::LocalGet(P_CROSS), Instruction::I32Sub];
+    let mut center = leftover();
+    center.extend([Instruction::I32Const(2), Instruction::I32DivS, Instruction::LocalSet(P_OFFSET)]);
+    when(P_ALIGN, ALIGN_CENTER, center, body);
+    let mut end = leftover();
+    end.push(Instruction::LocalSet(P_OFFSET));
+    when(P_ALIGN, ALIGN_END, end, body);
+    add(P_OFFSET, vec![Instruction::LocalGet(P_LINE_START)], body);
+    select(P_CX, P_CURSOR, P_OFFSET, P_HORIZONTAL, body);
+    add(P_CX, vec![Instruction::LocalGet(P_INNER_X)], body);
+    select(P_CY, P_OFFSET, P_CURSOR, P_HORIZONTAL, body);
+    add(P_CY, vec![Instruction::LocalGet(P_INNER_Y)], body);
+    select(P_CW, P_MAIN, P_CROSS, P_HORIZONTAL, body);
+    select(P_CH, P_CROSS, P_MAIN, P_HORIZONTAL, body);
+
+    is_kind(P_CHILD, KIND_OPEN, body);
+    body.extend([
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(P_CHILD),
+        Instruction::LocalGet(P_CX),
+        Instruction::LocalGet(P_CY),
+        Instruction::LocalGet(P_CW),
+        Instruction::LocalGet(P_CH),
+        Instruction::LocalGet(P_END),
+        Instruction::Call(PLACE_NODE_FUNC),
+        Instruction::Drop,
+        Instruction::Else,
+        Instruction::LocalGet(P_CHILD),
+        Instruction::LocalGet(P_CX),
+        Instruction::I32Store(field(X)),
+        Instruction::LocalGet(P_CHILD),
+        Instruction::LocalGet(P_CY),
+        Instruction::I32Store(field(Y)),
+    ]);
+    // A box takes its whole rect; a text keeps its own extent
+    is_kind(P_CHILD, KIND_RECT, body);
+    body.extend([
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(P_CHILD),
+        Instruction::LocalGet(P_CW),
+        Instruction::I32Store(field(A)),
+        Instruction::LocalGet(P_CHILD),
+        Instruction::LocalGet(P_CH),
+        Instruction::I32Store(field(B)),
+        Instruction::Else,
+        Instruction::LocalGet(P_W2),
+        Instruction::LocalSet(P_CW),
+        Instruction::LocalGet(P_H2),
+        Instruction::LocalSet(P_CH),
+        Instruction::End,
+    ]);
+    set_bounds(P_CHILD, P_CX, P_CY, P_CW, P_CH, body);
+    body.push(Instruction::End);
+    add(P_CURSOR, vec![Instruction::LocalGet(P_MAIN), Instruction::LocalGet(P_STEP), Instruction::I32Add], body);
+}
+
+/// `place_node(open, x, y, w, h, items) -> node after its CLOSE`: gives the measured
+/// container the rect `x, y, w, h` and lines up its flow children inside it as
+/// `layout::Flow::place` does for static groups: in lines when it wraps, each line
+/// grown or shrunk to fill the group. Their item records go from `items` on, the
+/// items of nested containers after them.
+pub fn place_node_body() -> Vec<Instruction<'static>> {
+    let mut body = vec![];
+    set_bounds(P_NODE, P_X, P_Y, P_W, P_H, &mut body);
+    unpack(P_NODE, P_HORIZONTAL, P_GAP, P_PADDING, &mut body);
+    for (target, shift, mask) in [(P_ALIGN, 1, 3), (P_JUSTIFY, 3, 7), (P_WRAP, 6, 1)] {
+        body.extend(load(P_NODE, X));
+        body.extend([Instruction::I32Const(shift), Instruction::I32ShrU, Instruction::I32Const(mask), Instruction::I32And, Instruction::LocalSet(target)]);
+    }
+
+    // Inner rect: the frame less padding on every side
+    for (inner, outer) in [(P_INNER_X, P_X), (P_INNER_Y, P_Y)] {
+        body.extend([Instruction::LocalGet(outer), Instruction::LocalGet(P_PADDING), Instruction::I32Add, Instruction::LocalSet(inner)]);
+    }
+    for (inner, outer) in [(P_W2, P_W), (P_H2, P_H)] {
+        body.extend([
+            Instruction::LocalGet(outer),
+            Instruction::LocalGet(P_PADDING),
+            Instruction::I32Const(1),
+            Instruction::I32Shl,
+            Instruction::I32Sub,
+            Instruction::LocalTee(inner),
+            Instruction::I32Const(0),
+            Instruction::LocalGet(inner),
+            Instruction::I32Const(0),
+            Instruction::I32GtS,
+            Instruction::Select,
+            Instruction::LocalSet(inner),
+        ]);
+    }
+    select(P_INNER_MAIN, P_W2, P_H2, P_HORIZONTAL, &mut body);
+    select(P_INNER_CROSS, P_H2, P_W2, P_HORIZONTAL, &mut body);
+
+    // The default FLEX node sits just past the frame, see layout_frame
+    body.extend([
+        Instruction::GlobalGet(FRAME_GLOBAL),
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Mul,
+        Instruction::I32Add,
+        Instruction::LocalTee(P_DEFAULT),
+        Instruction::LocalSet(P_FLEX),
+        Instruction::LocalGet(P_ITEMS),
+        Instruction::LocalSet(P_END),
+    ]);
+
+    // Pass 1: the direct flow children become items, skipping nested containers' content
+    body.extend([
+        Instruction::LocalGet(P_NODE),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Add,
+        Instruction::LocalSet(P_CHILD),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+    ]);
+    body.extend(load(P_CHILD, KIND));
+    body.push(Instruction::LocalSet(P_KIND));
+    is_kind(P_CHILD, KIND_CLOSE, &mut body);
+    body.extend([
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(P_DEPTH),
+        Instruction::I32Eqz,
+        Instruction::BrIf(2),
+        Instruction::LocalGet(P_DEPTH),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::LocalSet(P_DEPTH),
+        Instruction::Else,
+        Instruction::LocalGet(P_DEPTH),
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::Empty),
+    ]);
+    is_kind(P_CHILD, KIND_FLEX, &mut body);
+    body.extend([
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(P_CHILD),
+        Instruction::LocalSet(P_FLEX),
+        Instruction::Else,
+        Instruction::LocalGet(P_KIND),
+        Instruction::I32Const(FLOW),
+        Instruction::I32And,
+        Instruction::If(BlockType::Empty),
+    ]);
+    gather_item(&mut body);
+    // Not a flow child: a grid's node
+    body.push(Instruction::Else);
+    move_grid_node(&mut body);
+    body.extend([Instruction::End, Instruction::End, Instruction::End]);
+    is_kind(P_CHILD, KIND_OPEN, &mut body);
+    body.push(Instruction::If(BlockType::Empty));
+    add(P_DEPTH, vec![Instruction::I32Const(1)], &mut body);
+    body.extend([Instruction::End, Instruction::End]);
+    add(P_CHILD, vec![Instruction::I32Const(NODE_RECORD)], &mut body);
+    body.extend([
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+        Instruction::LocalGet(P_CHILD),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Add,
+        Instruction::LocalSet(P_NEXT),
+    ]);
+
+    // Pass 2, line by line: as many items as fit, all of them unless the group wraps
+    body.extend([
+        Instruction::LocalGet(P_ITEMS),
+        Instruction::LocalSet(P_LINE),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(P_LINE),
+        Instruction::LocalGet(P_END),
+        Instruction::I32GeU,
+        Instruction::BrIf(1),
+        Instruction::I32Const(0),
+        Instruction::LocalSet(P_USED),
+        Instruction::I32Const(0),
+        Instruction::LocalSet(P_COUNT),
+        Instruction::LocalGet(P_LINE),
+        Instruction::LocalSet(P_ITEM),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(P_ITEM),
+        Instruction::LocalGet(P_END),
+        Instruction::I32GeU,
+        Instruction::BrIf(1),
+    ]);
+    body.extend(load(P_ITEM, ITEM_MAIN));
+    body.extend([
+        Instruction::LocalSet(P_MAIN),
+        Instruction::LocalGet(P_WRAP),
+        Instruction::LocalGet(P_COUNT),
+        Instruction::I32Const(0),
+        Instruction::I32GtS,
+        Instruction::I32And,
+        Instruction::LocalGet(P_USED),
+        Instruction::LocalGet(P_GAP),
+        Instruction::I32Add,
+        Instruction::LocalGet(P_MAIN),
+        Instruction::I32Add,
+        Instruction::LocalGet(P_INNER_MAIN),
+        Instruction::I32GtS,
+        Instruction::I32And,
+        Instruction::BrIf(1),
+    ]);
+    add(P_USED, vec![
+        Instruction::LocalGet(P_MAIN),
+        Instruction::LocalGet(P_GAP),
+        Instruction::I32Const(0),
+        Instruction::LocalGet(P_COUNT),
+        Instruction::I32Const(0),
+        Instruction::I32GtS,
+        Instruction::Select,
+        Instruction::I32Add,
+    ], &mut body);
+    add(P_COUNT, vec![Instruction::I32Const(1)], &mut body);
+    add(P_ITEM, vec![Instruction::I32Const(NODE_RECORD)], &mut body);
+    body.extend([
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+        Instruction::LocalGet(P_ITEM),
+        Instruction::LocalSet(P_LINE_END),
+        Instruction::LocalGet(P_INNER_MAIN),
+    ]);
+    body.extend(gaps(P_COUNT, P_GAP));
+    body.extend([Instruction::I32Sub, Instruction::LocalSet(P_AVAILABLE)]);
+    resolve_flexible(&mut body);
+
+    // A wrapped line is as thick as its thickest item, a single one spans the group
+    body.extend([
+        Instruction::I32Const(0),
+        Instruction::LocalGet(P_INNER_CROSS),
+        Instruction::LocalGet(P_WRAP),
+        Instruction::Select,
+        Instruction::LocalSet(P_LINE_CROSS),
+        Instruction::I32Const(0),
+        Instruction::LocalSet(P_TOTAL),
+    ]);
+    let mut extent = vec![];
+    add(P_TOTAL, load(P_ITEM, ITEM_MAIN).to_vec(), &mut extent);
+    extent.extend([Instruction::LocalGet(P_WRAP), Instruction::If(BlockType::Empty)]);
+    at_least(P_LINE_CROSS, load(P_ITEM, ITEM_CROSS).to_vec(), &mut extent);
+    extent.push(Instruction::End);
+    for_items(P_LINE, P_LINE_END, extent, &mut body);
+
+    // Free space and how justify spreads it: (first offset, distance between items)
+    body.extend([
+        Instruction::LocalGet(P_AVAILABLE),
+        Instruction::LocalGet(P_TOTAL),
+        Instruction::I32Sub,
+        Instruction::LocalTee(P_FREE),
+        Instruction::I32Const(0),
+        Instruction::LocalGet(P_FREE),
+        Instruction::I32Const(0),
+        Instruction::I32GtS,
+        Instruction::Select,
+        Instruction::LocalSet(P_SPREAD),
+        Instruction::I32Const(0),
+        Instruction::LocalSet(P_CURSOR),
+        Instruction::LocalGet(P_GAP),
+        Instruction::LocalSet(P_STEP),
+    ]);
+    when(P_JUSTIFY, JUSTIFY_CENTER, divide(P_CURSOR, P_FREE, vec![Instruction::I32Const(2)]), &mut body);
+    when(P_JUSTIFY, JUSTIFY_END, vec![Instruction::LocalGet(P_FREE), Instruction::LocalSet(P_CURSOR)], &mut body);
+    let mut between = vec![Instruction::LocalGet(P_COUNT), Instruction::I32Const(1), Instruction::I32GtS, Instruction::If(BlockType::Empty)];
+    between.extend(step(P_SPREAD, vec![Instruction::LocalGet(P_COUNT), Instruction::I32Const(1), Instruction::I32Sub]));
+    between.push(Instruction::End);
+    when(P_JUSTIFY, JUSTIFY_BETWEEN, between, &mut body);
+    let mut around = divide(P_CURSOR, P_SPREAD, vec![Instruction::LocalGet(P_COUNT), Instruction::I32Const(1), Instruction::I32Shl]);
+    around.extend(step(P_SPREAD, vec![Instruction::LocalGet(P_COUNT)]));
+    when(P_JUSTIFY, JUSTIFY_AROUND, around, &mut body);
+    let evenly_parts = || vec![Instruction::LocalGet(P_COUNT), Instruction::I32Const(1), Instruction::I32Add];
+    let mut evenly = divide(P_CURSOR, P_SPREAD, evenly_parts());
+    evenly.extend(step(P_SPREAD, evenly_parts()));
+    when(P_JUSTIFY, JUSTIFY_EVENLY, evenly, &mut body);
+
+    let mut place = vec![];
+    place_item(&mut place);
+    for_items(P_LINE, P_LINE_END, place, &mut body);
+    add(P_LINE_START, vec![Instruction::LocalGet(P_LINE_CROSS), Instruction::LocalGet(P_GAP), Instruction::I32Add], &mut body);
+    body.extend([
+        Instruction::LocalGet(P_LINE_END),
+        Instruction::LocalSet(P_LINE),
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+    ]);
+
+    // Flags and spacing are read: keep the rect instead, for the background
+    for (offset, value) in [(X, P_X), (Y, P_Y), (A, P_W), (B, P_H)] {
+        body.extend([Instruction::LocalGet(P_NODE), Instruction::LocalGet(value), Instruction::I32Store(field(offset))]);
+    }
+    body.extend([Instruction::LocalGet(P_NEXT), Instruction::End]);
+    body
+}
+
+// Locals of layout_frame(first, x, y, keep)
+const L_FIRST: u32 = 0;
+const L_X: u32 = 1;
+const L_Y: u32 = 2;
+const L_KEEP: u32 = 3;
+const L_ROOT: u32 = 4;
+const L_READ: u32 = 5;
+const L_WRITE: u32 = 6;
+const L_END: u32 = 7;
+const L_KIND: u32 = 8;
+const L_SCRATCH: u32 = 9; // records reserved past the frame: the default FLEX node, then items
+pub const LAYOUT_FRAME_LOCALS: u32 = 6;
+
+/// `layout_frame(first, x, y, keep)`: lays out the container recorded from node
+/// `first` on at `x, y`, then drops the OPEN/CLOSE/FLEX nodes so only boxes and texts
+/// remain, an OPEN with a fill, stroke or tag turning into the box behind its children.
+/// `place_node` keeps its item records in frame capacity reserved past the
+/// container, one per node at most. Unless `keep` (the container was drawn inside `render`), those are
+/// drawn straight away and taken back out of the frame. A container recorded
+/// inside another one (drawn by a function called there) is left for the outer
+/// layout to place.
+pub fn layout_frame_body() -> Vec<Instruction<'static>> {
+    let node = |local: u32| vec![
+        Instruction::GlobalGet(FRAME_GLOBAL),
+        Instruction::LocalGet(local),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Mul,
+        Instruction::I32Add,
+    ];
+    let mut body = vec![Instruction::GlobalGet(OPEN_DEPTH_GLOBAL), Instruction::If(BlockType::Empty), Instruction::Return, Instruction::End];
+
+    // Reserve the scratch first: growing the frame may move it
+    body.extend([
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::LocalGet(L_FIRST),
+        Instruction::I32Sub,
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::LocalTee(L_SCRATCH),
+        Instruction::LocalSet(L_READ),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(L_READ),
+        Instruction::I32Eqz,
+        Instruction::BrIf(1),
+        Instruction::Call(RESERVE_NODE_FUNC),
+        Instruction::Drop,
+    ]);
+    add(L_READ, vec![Instruction::I32Const(-1)], &mut body);
+    body.extend([
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::LocalGet(L_SCRATCH),
+        Instruction::I32Sub,
+        Instruction::GlobalSet(FRAME_LEN_GLOBAL),
+    ]);
+    body.extend([
+        Instruction::GlobalGet(FRAME_GLOBAL),
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Mul,
+        Instruction::I32Add,
+        Instruction::LocalSet(L_SCRATCH),
+    ]);
+    let defaults = flex_fields(&FlexItem::default(), true);
+    store(L_SCRATCH, KIND, vec![Instruction::I32Const(KIND_FLEX)], &mut body);
+    for (offset, value) in [FLEX_GROW, FLEX_SHRINK, FLEX_BASIS, FLEX_MIN, FLEX_MAX, FLEX_MIN_CROSS, FLEX_MAX_CROSS].into_iter().zip(defaults) {
+        store(L_SCRATCH, offset, vec![Instruction::I32Const(value)], &mut body);
+    }
+
+    body.extend(node(L_FIRST));
+    body.extend([
+        Instruction::LocalTee(L_ROOT),
+        Instruction::Call(MEASURE_NODE_FUNC),
+        Instruction::Drop,
+        Instruction::LocalGet(L_ROOT),
+        Instruction::LocalGet(L_X),
+        Instruction::LocalGet(L_Y),
+    ]);
+    body.extend(load(L_ROOT, A));
+    body.extend(load(L_ROOT, B));
+    body.extend([
+        Instruction::LocalGet(L_SCRATCH),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Add,
+        Instruction::Call(PLACE_NODE_FUNC),
+        Instruction::Drop,
+    ]);
+
+    // Compact: keep boxes, texts and backgrounds, in order, as plain nodes
+    body.extend([
+        Instruction::LocalGet(L_ROOT),
+        Instruction::LocalTee(L_READ),
+        Instruction::LocalSet(L_WRITE),
+        Instruction::GlobalGet(FRAME_GLOBAL),
+        Instruction::GlobalGet(FRAME_LEN_GLOBAL),
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32Mul,
+        Instruction::I32Add,
+        Instruction::LocalSet(L_END),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(L_READ),
+        Instruction::LocalGet(L_END),
+        Instruction::I32GeU,
+        Instruction::BrIf(1),
+    ]);
+    body.extend(load(L_READ, KIND));
+    body.extend([
+        Instruction::I32Const(KIND_MASK),
+        Instruction::I32And,
+        Instruction::LocalTee(L_KIND),
+        Instruction::I32Const(KIND_OPEN),
+        Instruction::I32LtU,
+        Instruction::LocalGet(L_KIND),
+        Instruction::I32Const(KIND_OPEN),
+        Instruction::I32Eq,
+    ]);
+    // A tagged OPEN stays too, as an unpainted rect telling the host where its element landed
+    body.extend(load(L_READ, FILL));
+    body.extend(load(L_READ, STROKE));
+    body.push(Instruction::I32Or);
+    body.extend(load(L_READ, KIND));
+    body.extend([
+        Instruction::I32Const(TAG_SHIFT),
+        Instruction::I32ShrU,
+        Instruction::I32Or,
+        Instruction::I32Const(0),
+        Instruction::I32Ne,
+        Instruction::I32And,
+        Instruction::I32Or,
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(L_WRITE),
+        Instruction::LocalGet(L_KIND),
+        Instruction::I32Const(KIND_RECT),
+        Instruction::LocalGet(L_KIND),
+        Instruction::I32Const(KIND_OPEN),
+        Instruction::I32LtU,
+        Instruction::Select,
+    ]);
+    body.extend(load(L_READ, KIND));
+    body.extend([
+        Instruction::I32Const(-1 << TAG_SHIFT),
+        Instruction::I32And,
+        Instruction::I32Or,
+        Instruction::I32Store(field(KIND)),
+        Instruction::LocalGet(L_WRITE),
+        Instruction::I32Const(X as i32),
+        Instruction::I32Add,
+        Instruction::LocalGet(L_READ),
+        Instruction::I32Const(X as i32),
+        Instruction::I32Add,
+        Instruction::I32Const(NODE_RECORD - X as i32),
+        Instruction::MemoryCopy { src_mem: 0, dst_mem: 0 },
+    ]);
+    add(L_WRITE, vec![Instruction::I32Const(NODE_RECORD)], &mut body);
+    body.push(Instruction::End);
+    add(L_READ, vec![Instruction::I32Const(NODE_RECORD)], &mut body);
+    body.extend([
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+        Instruction::LocalGet(L_WRITE),
+        Instruction::GlobalGet(FRAME_GLOBAL),
+        Instruction::I32Sub,
+        Instruction::I32Const(NODE_RECORD),
+        Instruction::I32DivU,
+        Instruction::GlobalSet(FRAME_LEN_GLOBAL),
+    ]);
+
+    // Drawn outside `render`: paint now, the frame on screen is not ours to extend
+    body.extend([
+        Instruction::LocalGet(L_KEEP),
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(L_ROOT),
+        Instruction::LocalSet(L_READ),
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(L_READ),
+        Instruction::LocalGet(L_WRITE),
+        Instruction::I32GeU,
+        Instruction::BrIf(1),
+    ]);
+    is_kind(L_READ, KIND_TEXT, &mut body);
+    body.push(Instruction::If(BlockType::Empty));
+    body.extend(frame::host_draw(true, |offset| load(L_READ, offset).to_vec()));
+    body.push(Instruction::Else);
+    body.extend(frame::host_draw(false, |offset| load(L_READ, offset).to_vec()));
+    body.push(Instruction::End);
+    add(L_READ, vec![Instruction::I32Const(NODE_RECORD)], &mut body);
+    body.extend([
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+        Instruction::LocalGet(L_FIRST),
+        Instruction::GlobalSet(FRAME_LEN_GLOBAL),
+        Instruction::End,
+        Instruction::End,
+    ]);
+    body
+}
@@ -0,0 +1,49 @@
+use std::collections::{BTreeMap, HashMap};
+use wasm_encoder::{BlockType, Instruction};
+use crate::parser::Node;
+use crate::transpile::infer_type;
+
+/// A top-level `state name = value`, kept in a mutable wasm global so every
+/// function, handler and `render` sees the same value.
+#[derive(Debug, Clone)]
+pub struct StateVar {
+    pub global: u32,
+    pub ty: String, // W++ type of the initial value
+}
+
+/// Name → global of every state variable; globals are numbered from `first_global` in declaration order.
+pub fn collect_states(ast: &[Node], first_global: u32) -> HashMap<String, StateVar> {
+    let mut states = HashMap::new();
+    for node in ast {
+        if let Node::State { name, value, .. } = node {
+            if states.contains_key(name) {
+                panic!("❌ State '{}' is declared twice", name);
+            }
+            let var = StateVar { global: first_global + states.len() as u32, ty: infer_type(value, &HashMap::new()) };
+            println!("🔁 [State] '{}': {} in global {}", name, var.ty, var.global);
+            states.insert(name.clone(), var);
+        }
+    }
+    states
+}
+
+/// States in global order, for the global and name sections.
+pub fn by_global(states: &HashMap<String, StateVar>) -> BTreeMap<u32, &str> {
+    states.iter().map(|(name, var)| (var.global, name.as_str())).collect()
+}
+
+/// Stores the value on top of the stack into `var` and, unless a redraw is already
+/// pending or under way, marks the UI dirty and asks the host for one. Writes made
+/// while rendering show in the frame being recorded from then on, and never loop.
+pub fn emit_store(var: &StateVar, dirty_global: u32, rendering_global: u32, request_render: u32, instructions: &mut Vec<Instruction>) {
+    instructions.push(Instruction::GlobalSet(var.global));
+    instructions.push(Instruction::GlobalGet(dirty_global));
+    instructions.push(Instruction::GlobalGet(rendering_global));
+    instructions.push(Instruction::I32Or);
+    instructions.push(Instruction::I32Eqz);
+    instructions.push(Instruction::If(BlockType::Empty));
+    instructions.push(Instruction::I32Const(1));
+    instructions.push(Instruction::GlobalSet(dirty_global));
+    instructions.push(Instruction::Call(request_render));
+    instructions.push(Instruction::End);
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use crate::layout::TEXT_HEIGHT;
+use crate::parser::{Node, Primitive, Style, BOX_STYLE, FIGURE_STYLE, FONT_FAMILIES, GROUP_STYLE, IMAGE_STYLE, LINE_STYLE, TEXT_STYLE};
+
+// Styling: what `fill:`, `stroke:`, `color:`, ... resolve to. Every box and text
+// node of the frame carries its paint as three i32s (see frame.rs), handed to the
+// host's `drawRect`/`drawText` unpacked: colors as 0xRRGGBBAA, 0 drawing nothing,
+// fonts as `family | size << 8` with `family` an index into FONT_FAMILIES.
+
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("black", 0x000000FF),
+    ("white", 0xFFFFFFFF),
+    ("red", 0xFF0000FF),
+    ("green", 0x008000FF),
+    ("blue", 0x0000FFFF),
+    ("yellow", 0xFFFF00FF),
+    ("orange", 0xFFA500FF),
+    ("purple", 0x800080FF),
+    ("gray", 0x808080FF),
+    ("grey", 0x808080FF),
+    ("silver", 0xC0C0C0FF),
+    ("navy", 0x000080FF),
+    ("teal", 0x008080FF),
+    ("transparent", 0),
+];
+/// Unstyled boxes are outlined in red and texts filled in green, as the hosts drew them before
+const DEFAULT_STROKE: u32 = 0xFF0000FF;
+const DEFAULT_COLOR: u32 = 0x008000FF;
+const DEFAULT_LINE: u32 = 0x000000FF;
+const DEFAULT_STROKE_WIDTH: i32 = 1;
+const OPAQUE: i32 = 100;
+
+/// `"#0af"`, `"#0af8"`, `"#00aaff"`, `"#00aaff80"` or a name from NAMED_COLORS, as 0xRRGGBBAA.
+pub fn parse_color(text: &str) -> Option<u32> {
+    let Some(hex) = text.strip_prefix('#') else {
+        return NAMED_COLORS.iter().find(|(name, _)| *name == text).map(|(_, rgba)| *rgba);
+    };
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    match hex.len() {
+        // One digit per channel: #0af is #00aaff
+        3 | 4 => {
+            let digits = hex.len() as u32;
+            let rgba = (0..digits).fold(0, |rgba, i| (rgba << 8) | (((value >> (4 * (digits - 1 - i))) & 0xF) * 0x11));
+            Some(if digits == 3 { rgba << 8 | 0xFF } else { rgba })
+        }
+        6 => Some(value << 8 | 0xFF),
+        8 => Some(value),
+        _ => None,
+    }
+}
+
+fn color(value: &Option<String>, default: u32) -> i32 {
+    value.as_deref().map_or(default, |color| parse_color(color).unwrap()) as i32
+}
+
+/// Line height of a text in `style`, in pixels
+pub fn font_size(style: &Style) -> i32 {
+    style.font_size.unwrap_or(TEXT_HEIGHT)
+}
+
+/// `measureText`'s font for a text in `style`
+pub fn font(style: &Style) -> i32 {
+    let family = style.font.as_deref().map_or(0, |font| FONT_FAMILIES.iter().position(|f| *f == font).unwrap());
+    family as i32 | font_size(style) << 8
+}
+
+/// Third paint word: `stroke_width | radius << 8 | opacity << 16`
+fn shape(style: &Style) -> i32 {
+    style.stroke_width.unwrap_or(DEFAULT_STROKE_WIDTH) | style.radius.unwrap_or(0) << 8 | style.opacity.unwrap_or(OPAQUE) << 16
+}
+
+/// Paint of a box: `[fill, stroke, shape]`, outlined in the default stroke unless given a fill or stroke.
+pub fn box_paint(style: &Style) -> [i32; 3] {
+    let stroke = if style.fill.is_none() { DEFAULT_STROKE } else { 0 };
+    [color(&style.fill, 0), color(&style.stroke, stroke), shape(style)]
+}
+
+/// Background of a group, drawn behind its children: all zeros, nothing to draw, without a fill or stroke.
+pub fn group_paint(style: &Style) -> [i32; 3] {
+    if style.fill.is_none() && style.stroke.is_none() {
+        return [0; 3];
+    }
+    [color(&style.fill, 0), color(&style.stroke, 0), shape(style)]
+}
+
+/// Paint of a line, circle or path: a circle or path is painted like a box, a line
+/// stroked in black unless given a stroke. An image only takes `opacity:`.
+pub fn shape_paint(primitive: &Primitive, style: &Style) -> [i32; 3] {
+    match primitive {
+        Primitive::Line { .. } => [0, color(&style.stroke, DEFAULT_LINE), shape(style)],
+        Primitive::Circle { .. } | Primitive::Path(_) => box_paint(style),
+        Primitive::Image(_) => [0, 0, style.opacity.unwrap_or(OPAQUE) << 16],
+    }
+}
+
+/// Style properties a line, circle, path or image takes
+pub fn shape_style(primitive: &Primitive) -> &'static [&'static str] {
+    match primitive {
+        Primitive::Line { .. } => LINE_STYLE,
+        Primitive::Circle { .. } | Primitive::Path(_) => FIGURE_STYLE,
+        Primitive::Image(_) => IMAGE_STYLE,
+    }
+}
+
+/// Paint of a text: `[color, font, opacity << 16]`
+pub fn text_paint(style: &Style) -> [i32; 3] {
+    [color(&style.color, DEFAULT_COLOR), font(style), style.opacity.unwrap_or(OPAQUE) << 16]
+}
+
+/// `nodes` with the top-level `style` declarations of `theme` in place of the ones of the
+/// same name, so switching themes is a recompile with another `--theme` file.
+pub fn with_theme(mut nodes: Vec<Node>, theme: Vec<Node>) -> Vec<Node> {
+    for declaration in theme {
+        let Node::Style { name, .. } = &declaration else {
+            panic!("❌ A theme holds only style declarations, got {:?}", declaration);
+        };
+        match nodes.iter().position(|node| matches!(node, Node::Style { name: declared, .. } if declared == name)) {
+            Some(index) => nodes[index] = declaration,
+            None => nodes.push(declaration),
+        }
+    }
+    nodes
+}
+
+/// Copies of `nodes` in which every node with a `style: Name` takes the properties of
+/// that declaration it does not set itself, then every group hands its `font:`,
+/// `font_size:` and `color:` to the nodes inside it that set none, and its `opacity:`
+/// multiplies theirs. Functions start afresh: what a called function draws does not
+/// inherit from the caller.
+pub fn inherit(nodes: &[Node]) -> Vec<Node> {
+    let declared = declarations(nodes);
+    let mut nodes = nodes.to_vec();
+    for node in &mut nodes {
+        if !matches!(node, Node::Style { .. }) {
+            pass_down(node, &Style::default(), &declared);
+        }
+    }
+    nodes
+}
+
+/// Top-level `style` declarations by name, each with the one its own `style:` names folded in
+fn declarations(nodes: &[Node]) -> HashMap<String, Style> {
+    let mut declared = HashMap::new();
+    for node in nodes {
+        if let Node::Style { name, style, span } = node {
+            if declared.insert(name.clone(), style.clone()).is_some() {
+                panic!("❌ Style '{}' at line {} is declared twice", name, span.line);
+            }
+        }
+    }
+    let names: Vec<String> = declared.keys().cloned().collect();
+    for name in names {
+        let mut resolved = declared[&name].clone();
+        let mut seen = vec![name.clone()];
+        while let Some(base) = resolved.name.take() {
+            if seen.contains(&base) {
+                panic!("❌ Style '{}' is based on itself through {}", name, seen.join(" -> "));
+            }
+            let base_style = declared.get(&base).unwrap_or_else(|| panic!("❌ Style '{}' is based on undeclared style '{}'", name, base));
+            take_declared(&mut resolved, base_style, GROUP_STYLE);
+            resolved.name = base_style.name.clone();
+            seen.push(base);
+        }
+        declared.insert(name, resolved);
+    }
+    declared
+}
+
+/// Fills the properties among `allowed` that `style` leaves unset from the declaration its `style:` names
+fn apply_declared(style: &mut Style, allowed: &[&str], declared: &HashMap<String, Style>) {
+    if let Some(name) = &style.name {
+        let base = declared.get(name).unwrap_or_else(|| panic!("❌ Unknown style '{}'", name)).clone();
+        take_declared(style, &base, allowed);
+    }
+}
+
+fn take_declared(style: &mut Style, base: &Style, allowed: &[&str]) {
+    let takes = |property: &str| allowed.contains(&property);
+    if takes("fill") {
+        style.fill = style.fill.take().or_else(|| base.fill.clone());
+    }
+    if takes("stroke") {
+        style.stroke = style.stroke.take().or_else(|| base.stroke.clone());
+    }
+    if takes("stroke_width") {
+        style.stroke_width = style.stroke_width.or(base.stroke_width);
+    }
+    if takes("radius") {
+        style.radius = style.radius.or(base.radius);
+    }
+    if takes("opacity") {
+        style.opacity = style.opacity.or(base.opacity);
+    }
+    if takes("font") {
+        style.font = style.font.take().or_else(|| base.font.clone());
+    }
+    if takes("font_size") {
+        style.font_size = style.font_size.or(base.font_size);
+    }
+    if takes("color") {
+        style.color = style.color.take().or_else(|| base.color.clone());
+    }
+}
+
+fn pass_down(node: &mut Node, inherited: &Style, declared: &HashMap<String, Style>) {
+    match node {
+        Node::Box { style, .. } => {
+            apply_declared(style, BOX_STYLE, declared);
+            style.opacity = combined_opacity(style, inherited);
+        }
+        Node::Shape { primitive, style, .. } => {
+            apply_declared(style, shape_style(primitive), declared);
+            style.opacity = combined_opacity(style, inherited);
+        }
+        Node::Text { style, .. } => {
+            apply_declared(style, TEXT_STYLE, declared);
+            take_text_style(style, inherited);
+        }
+        Node::Group { style, children, .. } => {
+            apply_declared(style, GROUP_STYLE, declared);
+            take_text_style(style, inherited);
+            let passed = Style {
+                font: style.font.clone(),
+                font_size: style.font_size,
+                color: style.color.clone(),
+                opacity: style.opacity,
+                ..Style::default()
+            };
+            for child in children {
+                pass_down(child, &passed, declared);
+            }
+        }
+        Node::Grid { children, .. } | Node::List { items: children, .. } | Node::Item { children, .. } | Node::For { body: children, .. } => {
+            for child in children {
+                pass_down(child, inherited, declared);
+            }
+        }
+        Node::If { then_body, else_body, .. } => {
+            for child in then_body.iter_mut().chain(else_body.iter_mut().flatten()) {
+                pass_down(child, inherited, declared);
+            }
+        }
+        Node::Match { arms, .. } => {
+            for child in arms.iter_mut().flat_map(|(_, body)| body) {
+                pass_down(child, inherited, declared);
+            }
+        }
+        Node::Function { body, .. } => {
+            for child in body {
+                pass_down(child, &Style::default(), declared);
+            }
+        }
+        Node::Style { name, span, .. } => panic!("❌ Style '{}' at line {} must be declared at top level", name, span.line),
+        _ => {}
+    }
+}
+
+fn take_text_style(style: &mut Style, inherited: &Style) {
+    style.font = style.font.take().or_else(|| inherited.font.clone());
+    style.font_size = style.font_size.or(inherited.font_size);
+    style.color = style.color.take().or_else(|| inherited.color.clone());
+    style.opacity = combined_opacity(style, inherited);
+}
+
+fn combined_opacity(style: &Style, inherited: &Style) -> Option<i32> {
+    match (style.opacity, inherited.opacity) {
+        (own, None) => own,
+        (own, Some(outer)) => Some(own.unwrap_or(OPAQUE) * outer / OPAQUE),
+    }
+}
@@ -1,20 +1,104 @@
 use wasm_encoder::*;
-use crate::parser::{Node, Expr};
-use crate::map::{ElementMap, SemanticMap};
-use serde_json::json;
-use std::collections::HashMap;
+use crate::parser::{self, Handlers, Node, Expr, Pattern, Primitive, Span};
+use crate::map::{
+    self, BoxProps, CircleProps, CodeMap, ElementMap, ElementProps, GridProps, GroupProps, IfBranch, IfProps, ImageProps, ItemProps, LineProps, ListProps, MatchArm,
+    MatchProps, PathProps, SemanticMap, TextProps,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::rc::Rc;
 use crate::parser::FunctionMeta;
-
-
-
-const DRAW_RECT_FUNC: u32 = 0;
-const GC_ALLOC_FUNC: u32 = 1;
-const DRAW_TEXT_FUNC: u32 = 2;
-const ADD_ROOT_FUNC: u32 = 3;
+use crate::closure::{self, ClosureMeta};
+use crate::debuginfo;
+use crate::state::{self, StateVar};
+use crate::phase::{self, Phase};
+use crate::frame;
+use crate::runtime_layout;
+use crate::layout;
+use crate::style;
+use crate::a11y::{self, Bounds};
+use crate::literals::{self, LiteralPool};
+
+
+
+pub(crate) const DRAW_RECT_FUNC: u32 = 0;
+pub(crate) const GC_ALLOC_FUNC: u32 = 1;
+pub(crate) const DRAW_TEXT_FUNC: u32 = 2;
+pub(crate) const ADD_ROOT_FUNC: u32 = 3;
 const MARK_USED_FUNC: u32 = 4;
+const HOST_AWAIT_FUNC: u32 = 6; // host_await(op, a, b, task): host calls `resume(task, result)` when done
+pub(crate) const CLEAR_RECT_FUNC: u32 = 7; // clearRect(x, y, w, h): wipe the dirty region before redrawing it
+const REQUEST_RENDER_FUNC: u32 = 8; // request_render(): host calls `render()` soon, e.g. next frame
+pub(crate) const MEASURE_TEXT_FUNC: u32 = 9; // measureText(ptr, len, font) -> width in pixels of the UTF-8 text
+// Primitives, see frame::host_draw
+pub(crate) const DRAW_LINE_FUNC: u32 = 10;   // drawLine(x1, y1, x2, y2, stroke, stroke_width, opacity)
+pub(crate) const DRAW_CIRCLE_FUNC: u32 = 11; // drawCircle(cx, cy, radius, fill, stroke, stroke_width, opacity)
+pub(crate) const DRAW_PATH_FUNC: u32 = 12;   // drawPath(x, y, ptr, len, fill, stroke, stroke_width, opacity)
+pub(crate) const DRAW_IMAGE_FUNC: u32 = 13;  // drawImage(x, y, w, h, src ptr, src len, opacity)
+const IMPORTED_FUNCS: u32 = 14;
+const RESUME_FUNC: u32 = IMPORTED_FUNCS + 2; // after run and gc_tick
+const A11Y_TREE_FUNC: u32 = IMPORTED_FUNCS + 3; // a11y_tree() -> ptr to [len: u32][JSON bytes]
+const HIT_TEST_FUNC: u32 = IMPORTED_FUNCS + 4;  // hit_test(x, y) -> semantic map element id, or -1
+const DISPATCH_EVENT_FUNC: u32 = IMPORTED_FUNCS + 5; // dispatch_event(kind, x, y, key) -> 1 if a handler ran
+const RENDER_FUNC: u32 = IMPORTED_FUNCS + 6; // render(): rebuild the frame from the current state and draw what changed
+// Retained frame, see frame.rs
+pub(crate) const RESERVE_NODE_FUNC: u32 = IMPORTED_FUNCS + 7;
+const DRAW_RECT_NODE_FUNC: u32 = IMPORTED_FUNCS + 8; // draw_rect_node(x, y, w, h, fill, stroke, shape), also lines, circles, ..., see frame.rs
+const DRAW_TEXT_NODE_FUNC: u32 = IMPORTED_FUNCS + 9; // draw_text_node(x, y, ptr, len, color, font, shape)
+pub(crate) const NODES_EQUAL_FUNC: u32 = IMPORTED_FUNCS + 10;
+const COMMIT_FRAME_FUNC: u32 = IMPORTED_FUNCS + 11;
+// Layout at render time, see runtime_layout.rs
+const PUSH_NODE_FUNC: u32 = IMPORTED_FUNCS + 12;             // push_node(kind, x, y, a, b, fill, stroke, shape)
+const LAYOUT_FRAME_FUNC: u32 = IMPORTED_FUNCS + 13;          // layout_frame(first, x, y, keep)
+pub(crate) const MEASURE_NODE_FUNC: u32 = IMPORTED_FUNCS + 14; // measure_node(open) -> next
+pub(crate) const PLACE_NODE_FUNC: u32 = IMPORTED_FUNCS + 15;   // place_node(open, x, y, w, h, items) -> next
+pub(crate) const SET_BOUNDS_FUNC: u32 = IMPORTED_FUNCS + 16;   // set_bounds(tag, x, y, w, h): update hit_test's table
+const RESET_BOUNDS_FUNC: u32 = IMPORTED_FUNCS + 17;          // reset_bounds(): back to the compile-time bounds
+const RESIZE_FUNC: u32 = IMPORTED_FUNCS + 18;                // resize(w, h): the host's canvas size, see CANVAS_WIDTH_GLOBAL
+const HIT_TABLE_GLOBAL: u32 = 0; // hit_test's copy of the bounds table, 0 until first call
+const HOVERED_GLOBAL: u32 = 1;   // element under the pointer at the last hover event, -1 for none
+const DIRTY_GLOBAL: u32 = 2;     // 1 while a requested render is pending
+pub(crate) const FRAME_GLOBAL: u32 = 3;      // node buffer of the frame being rendered
+pub(crate) const FRAME_LEN_GLOBAL: u32 = 4;
+pub(crate) const FRAME_CAP_GLOBAL: u32 = 5;
+pub(crate) const PREV_FRAME_GLOBAL: u32 = 6; // node buffer of the frame on screen
+pub(crate) const PREV_LEN_GLOBAL: u32 = 7;
+pub(crate) const PREV_CAP_GLOBAL: u32 = 8;
+pub(crate) const RENDERING_GLOBAL: u32 = 9;  // 1 inside `render`, when draws become frame nodes
+pub(crate) const OPEN_DEPTH_GLOBAL: u32 = 10; // containers recorded for runtime layout and not closed yet
+const LITERALS_GLOBAL: u32 = 11;    // base of the literal pool, copied into memory by `run`, see literals.rs
+const CANVAS_WIDTH_GLOBAL: u32 = 12;  // canvas size top-level groups follow, `--canvas` until the host calls resize
+const CANVAS_HEIGHT_GLOBAL: u32 = 13;
+const REPEATED_GLOBAL: u32 = 14;      // hit records of elements a `for` generates, appended by set_bounds each frame
+const REPEATED_LEN_GLOBAL: u32 = 15;  // records in use
+const REPEATED_CAP_GLOBAL: u32 = 16;  // records allocated
+const FIRST_STATE_GLOBAL: u32 = 17; // `state` variables follow, see `state::collect_states`
+const HIT_RECORD: i32 = 20;      // [id, x, y, width, height] as i32
+const REPEATED_CAPACITY: i32 = 16; // records of the first repeated buffer, doubled when full
+// `kind` argument of dispatch_event
+const EVENT_CLICK: i32 = 0;
+const EVENT_KEY: i32 = 1;
+const EVENT_HOVER: i32 = 2;
 const TYPE_BOX: i32 = 1;
-const TYPE_TEXT: i32 = 2;
-const TYPE_GROUP: i32 = 3;
+const TYPE_LITERALS: i32 = 2; // the literal pool, see literals.rs
+const TYPE_CLOSURE: i32 = 4; // [table slot, env ptr]
+const TYPE_ENV: i32 = 5;     // captured values, 4 bytes each
+const TYPE_TASK: i32 = 6;    // async frame, see TASK_* offsets
+const TYPE_BYTES: i32 = 7;   // [len: u32][bytes], as handed out by host_await load/fetch
+pub(crate) const TYPE_FRAME: i32 = 8; // retained frame nodes, see frame.rs
+const TYPE_ARRAY: i32 = 9;   // [len: u32][element: i32]..., values or pointers
+// Async task frame layout; saved locals follow the header, 4 bytes each
+const TASK_STATE: u64 = 0;
+const TASK_STEP_SLOT: u64 = 4;
+const TASK_DONE: u64 = 8;
+const TASK_RESULT: u64 = 12;
+const TASK_WAITER: u64 = 16;
+const TASK_HEADER: u64 = 20;
+// host_await op codes
+const ASYNC_SLEEP: i32 = 0; // a = milliseconds, result 0
+const ASYNC_LOAD: i32 = 1;  // a/b = path ptr/len, result = ptr to [len: u32][bytes]
+const ASYNC_FETCH: i32 = 2; // a/b = url ptr/len, result as for load
+const GC_SCRATCH: &str = "$gc"; // local_map key for the GC scratch local
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct FunctionSignature {
@@ -27,24 +111,72 @@ pub struct FunctionMetadata {
     pub node: Node,
     pub wasm_index: u32,
     pub returns: usize, // <- how many values this function returns
+    pub closure: Option<ClosureMeta>, // lambdas and function-reference thunks
 }
 
 
 
 
-pub fn compile_to_wasm(ast: &[Node]) -> (Vec<u8>, String) {
+/// A finished function body, plus what the name section and line table need.
+struct CompiledFunction {
+    locals: Vec<(u32, ValType)>,
+    instructions: Vec<Instruction<'static>>, // including the final `End`
+    local_names: HashMap<String, u32>,
+    lines: Vec<(usize, Span)>, // (instruction index, statement span)
+}
+
+impl CompiledFunction {
+    /// Encodes the body and returns it with the byte offset of every instruction
+    /// (plus one past the end), relative to the start of the function entry's body.
+    fn encode(&self) -> (Function, Vec<u32>) {
+        let mut func = Function::new(self.locals.iter().copied());
+        let mut offsets = Vec::with_capacity(self.instructions.len() + 1);
+        for instr in &self.instructions {
+            offsets.push(func.byte_len() as u32);
+            func.instruction(instr);
+        }
+        offsets.push(func.byte_len() as u32);
+        (func, offsets)
+    }
+}
+
+/// Everything `compile_to_wasm` produces; the JSON outputs are written next to `ui.wasm`.
+pub struct CompiledModule {
+    pub wasm: Vec<u8>,
+    pub semantic_map: String,
+    pub source_map: String,
+    pub a11y_tree: String,
+}
+
+/// Compiles the program into the wasm module plus its side files.
+/// `source_file` names the `.wpp` file in the source map; the module points at
+/// the source map through a `sourceMappingURL` section holding `source_map_url`.
+/// Top-level groups without a size fill `canvas`.
+pub fn compile_to_wasm(ast: &[Node], source_file: &str, source_map_url: &str, canvas: layout::Size) -> CompiledModule {
+    let ast = &style::inherit(ast);
     let mut module = Module::new();
     let mut stack_counter: i32 = 0; // ⬅️ Add this at the start of draw_ui compilation
 
     // === Type Section ===
     let mut types = TypeSection::new();
+    // Closure types come first so `closure::closure_type_index` stays stable
+    for n in 0..=closure::MAX_CLOSURE_PARAMS {
+        types.function(vec![ValType::I32; n + 1], []);
+        types.function(vec![ValType::I32; n + 1], [ValType::I32]);
+    }
+    assert_eq!(types.len(), closure::closure_type_count());
     let draw_ui_type = types.len(); types.function([], []);
-    let draw_rect_type = types.len(); types.function([ValType::I32; 4], []);
+    let a11y_tree_type = types.len(); types.function([], [ValType::I32]);
+    let draw_rect_type = types.len(); types.function([ValType::I32; 9], []); // x, y, w, h, fill, stroke, stroke_width, radius, opacity
     let gc_alloc_type = types.len(); types.function([ValType::I32; 2], [ValType::I32]);
-    let draw_text_type = types.len(); types.function([ValType::I32; 4], []);
+    let draw_text_type = types.len(); types.function([ValType::I32; 7], []); // x, y, ptr, len, color, font, opacity
+    let clear_rect_type = types.len(); types.function([ValType::I32; 4], []);
+    let draw_shape_type = types.len(); types.function([ValType::I32; 7], []); // drawLine, drawCircle, drawImage
+    let draw_path_type = types.len(); types.function([ValType::I32; 8], []); // x, y, ptr, len, fill, stroke, stroke_width, opacity
     let add_root_type = types.len(); types.function([ValType::I32], []);
     let mark_used_type = types.len(); types.function([ValType::I32], []);
     let gc_tick_type = types.len(); types.function([], []);
+    let host_await_type = types.len(); types.function([ValType::I32; 4], []);
     let mut next_index = BUILTIN_FUNCS.len() as u32; // Start at 5, reserve 0–4
 
 
@@ -52,6 +184,8 @@ pub fn compile_to_wasm(ast: &[Node]) -> (Vec<u8>, String) {
     let mut function_signatures: HashMap<FunctionSignature, FunctionMetadata> = HashMap::new();
     let mut function_indices = HashMap::<FunctionSignature, u32>::new();
     let mut function_type_indices = HashMap::<FunctionSignature, u32>::new();
+    let states = state::collect_states(ast, FIRST_STATE_GLOBAL);
+    let returning = closure::returning_functions(ast);
 
     for node in ast {
     if let Node::Function { name, params, .. } = node {
@@ -67,32 +201,66 @@ pub fn compile_to_wasm(ast: &[Node]) -> (Vec<u8>, String) {
         // ⛔ Don't insert metadata yet
         // Just save the signature to populate metadata *later*
         function_signatures.insert(sig.clone(), FunctionMetadata {
-            returns: 0,
+            returns: (is_async(node) || returning.contains(&sig)) as usize, // async calls yield their task
             wasm_index: 0, // ← dummy for now
             node: node.clone(),
+            closure: None,
         });
     }
 }
 
+    // === Lambda lifting: lambdas and function references become table-callable functions ===
+    for lifted in closure::collect_closures(ast) {
+        function_signatures.insert(lifted.sig, FunctionMetadata {
+            returns: lifted.closure.returns_value as usize,
+            wasm_index: 0,
+            node: lifted.node,
+            closure: Some(lifted.closure),
+        });
+    }
+
 
     // === Index prep ===
-    let imported_funcs = 6; // ✅ six imported functions (not counting memory)
-let draw_ui_func_index = imported_funcs as u32; // = 6
-let gc_tick_func_index = draw_ui_func_index + 1; // = 7
-let user_func_start_index = draw_ui_func_index + 1; // = 8
+    let imported_funcs = IMPORTED_FUNCS; // ✅ fourteen imported functions (not counting memory)
+let draw_ui_func_index = imported_funcs; // = 14
+let gc_tick_func_index = draw_ui_func_index + 1; // = 15
+let resume_func_index = gc_tick_func_index + 1; // = 16
+assert_eq!(resume_func_index, RESUME_FUNC);
+let a11y_tree_func_index = resume_func_index + 1; // = 17
+assert_eq!(a11y_tree_func_index, A11Y_TREE_FUNC);
+let hit_test_func_index = a11y_tree_func_index + 1; // = 18
+assert_eq!(hit_test_func_index, HIT_TEST_FUNC);
+let dispatch_event_func_index = hit_test_func_index + 1; // = 19
+assert_eq!(dispatch_event_func_index, DISPATCH_EVENT_FUNC);
+let render_func_index = dispatch_event_func_index + 1; // = 20
+assert_eq!(render_func_index, RENDER_FUNC);
+// reserve_node, draw_rect_node, draw_text_node, nodes_equal, commit_frame = 21..=25
+// push_node, layout_frame, measure_node, place_node, set_bounds, reset_bounds = 26..=31
+// resize = 32
+let user_func_start_index = RESIZE_FUNC + 1; // = 33
 
 
     // === Add user-defined function types and assign indices ===
-    for sig in function_signatures.keys() {
+    for (sig, meta) in function_signatures.iter() {
         let wasm_param_types: Vec<ValType> = sig.param_types.iter().map(|ty| match ty.as_str() {
             "int" => ValType::I32,
             "string" => ValType::I32,
+            t if t.starts_with("func") => ValType::I32, // closure pointer
             _ => panic!("Unsupported type: {}", ty),
         }).collect();
 
-        let type_index = types.len();
-        types.function(wasm_param_types.clone(), Vec::new());
-        function_type_indices.insert(sig.clone(), type_index as u32);
+        if let Some(closure) = &meta.closure {
+            // Async steps are always called as (task, resume value)
+            let param_count = if closure.resumes.is_some() { 1 } else { sig.param_types.len() };
+            let type_index = closure::closure_type_index(param_count, closure.returns_value);
+            function_type_indices.insert(sig.clone(), type_index);
+        } else {
+            let type_index = types.len();
+            // Async functions return their task frame
+            let results = vec![ValType::I32; meta.returns];
+            types.function(wasm_param_types.clone(), results);
+            function_type_indices.insert(sig.clone(), type_index as u32);
+        }
 
         let current_index = user_func_start_index + function_indices.len() as u32;
         function_indices.insert(sig.clone(), current_index);
@@ -115,33 +283,123 @@ for (sig, index) in &function_indices {
         memory64: false,
         shared: false,
     }));
-    imports.import("env", "drawRect", EntityType::Function(draw_rect_type as u32));
-    imports.import("env", "gc_alloc", EntityType::Function(gc_alloc_type as u32));
-    imports.import("env", "drawText", EntityType::Function(draw_text_type as u32));
-    imports.import("env", "add_root", EntityType::Function(add_root_type as u32));
-    imports.import("env", "mark_used", EntityType::Function(mark_used_type as u32));
-    imports.import("env", "gc_tick", EntityType::Function(gc_tick_type as u32));
+    let host_imports = [
+        ("drawRect", draw_rect_type),
+        ("gc_alloc", gc_alloc_type),
+        ("drawText", draw_text_type),
+        ("add_root", add_root_type),
+        ("mark_used", mark_used_type),
+        ("gc_tick", gc_tick_type),
+        ("host_await", host_await_type),
+        ("clearRect", clear_rect_type),
+        ("request_render", draw_ui_type),
+        ("measureText", closure::closure_type_index(2, true)),
+        ("drawLine", draw_shape_type),
+        ("drawCircle", draw_shape_type),
+        ("drawPath", draw_path_type),
+        ("drawImage", draw_shape_type),
+    ];
+    assert_eq!(host_imports.len() as u32, IMPORTED_FUNCS);
+    let mut function_names: BTreeMap<u32, String> = BTreeMap::new();
+    for (index, (name, type_index)) in host_imports.iter().enumerate() {
+        imports.import("env", name, EntityType::Function(*type_index as u32));
+        function_names.insert(index as u32, name.to_string());
+    }
     module.section(&imports);
 
     // === Function Section ===
     let mut functions = FunctionSection::new();
     functions.function(draw_ui_type as u32);
     functions.function(gc_tick_type as u32);
+    functions.function(closure::closure_type_index(1, false)); // resume(task, value)
+    functions.function(a11y_tree_type);
+    functions.function(closure::closure_type_index(1, true)); // hit_test(x, y) -> id
+    functions.function(closure::closure_type_index(3, true)); // dispatch_event(kind, x, y, key) -> handled
+    functions.function(draw_ui_type); // render()
+    functions.function(a11y_tree_type); // reserve_node() -> ptr
+    functions.function(closure::closure_type_index(6, false)); // draw_rect_node(x, y, w, h, fill, stroke, shape)
+    functions.function(closure::closure_type_index(6, false)); // draw_text_node(x, y, ptr, len, color, font, shape)
+    functions.function(closure::closure_type_index(1, true)); // nodes_equal(a, b) -> same
+    functions.function(draw_ui_type); // commit_frame()
+    functions.function(closure::closure_type_index(7, false)); // push_node(kind, x, y, a, b, fill, stroke, shape)
+    functions.function(closure::closure_type_index(3, false)); // layout_frame(first, x, y, keep)
+    functions.function(closure::closure_type_index(0, true)); // measure_node(open) -> next
+    functions.function(closure::closure_type_index(5, true)); // place_node(open, x, y, w, h, items) -> next
+    functions.function(closure::closure_type_index(4, false)); // set_bounds(tag, x, y, w, h)
+    functions.function(draw_ui_type); // reset_bounds()
+    functions.function(closure::closure_type_index(1, false)); // resize(w, h)
     for sig in function_signatures.keys() {
         let type_index = *function_type_indices.get(sig).unwrap();
         functions.function(type_index);
     }
     module.section(&functions);
 
+    // === Table Section: funcref slot N holds function N, so a closure's slot is its function index ===
+    let table_size = user_func_start_index + function_signatures.len() as u32;
+    let mut tables = TableSection::new();
+    tables.table(TableType {
+        element_type: RefType::FUNCREF,
+        minimum: table_size,
+        maximum: Some(table_size),
+    });
+    module.section(&tables);
+
+    // === Global Section ===
+    let mut globals = GlobalSection::new();
+    globals.global(GlobalType { val_type: ValType::I32, mutable: true }, &ConstExpr::i32_const(0));
+    globals.global(GlobalType { val_type: ValType::I32, mutable: true }, &ConstExpr::i32_const(-1));
+    for global in DIRTY_GLOBAL..FIRST_STATE_GLOBAL {
+        let initial = match global {
+            DIRTY_GLOBAL => 1, // until `run` draws the first frame, so writes before it ask for no redraw
+            CANVAS_WIDTH_GLOBAL => canvas.width,
+            CANVAS_HEIGHT_GLOBAL => canvas.height,
+            _ => 0,
+        };
+        globals.global(GlobalType { val_type: ValType::I32, mutable: true }, &ConstExpr::i32_const(initial));
+    }
+    for _ in 0..states.len() {
+        // Set to the declared value by `run`
+        globals.global(GlobalType { val_type: ValType::I32, mutable: true }, &ConstExpr::i32_const(0));
+    }
+    module.section(&globals);
+
     // === Export Section ===
     let mut exports = ExportSection::new();
     exports.export("run", ExportKind::Func, draw_ui_func_index);
     exports.export("gc_tick", ExportKind::Func, gc_tick_func_index);
+    exports.export("resume", ExportKind::Func, resume_func_index);
+    exports.export("a11y_tree", ExportKind::Func, a11y_tree_func_index);
+    exports.export("hit_test", ExportKind::Func, hit_test_func_index);
+    exports.export("dispatch_event", ExportKind::Func, dispatch_event_func_index);
+    exports.export("render", ExportKind::Func, render_func_index);
+    exports.export("resize", ExportKind::Func, RESIZE_FUNC);
+    // The frame on screen, read by the host to mirror the a11y tree after each render
+    exports.export("frame", ExportKind::Global, PREV_FRAME_GLOBAL);
+    exports.export("frame_len", ExportKind::Global, PREV_LEN_GLOBAL);
     module.section(&exports);
 
+    // === Element Section ===
+    let mut table_funcs: Vec<u32> = function_indices.values().copied().collect();
+    table_funcs.sort();
+    let mut elements = ElementSection::new();
+    if !table_funcs.is_empty() {
+        elements.active(
+            Some(0),
+            &ConstExpr::i32_const(table_funcs[0] as i32),
+            RefType::FUNCREF,
+            Elements::Functions(&table_funcs),
+        );
+    }
+    module.section(&elements);
+
+    // Passive segments of a11y_tree, hit_test and the literal pool; must precede the code section that uses `memory.init`
+    module.section(&DataCountSection { count: 3 });
+
     // === Code Section ===
-    let mut codes = CodeSection::new();
     let mut local_types: HashMap<String, String> = HashMap::new(); // 🔁 shared across all compile_node calls
+    for (name, var) in &states {
+        local_types.insert(name.clone(), var.ty.clone());
+    }
 let mut reverse_func_index: HashMap<u32, FunctionSignature> = HashMap::new();
 for (sig, index) in &function_indices {
     reverse_func_index.insert(*index, sig.clone());
@@ -153,162 +411,70 @@ let mut ordered_sigs: Vec<FunctionSignature> = function_signatures
 
 ordered_sigs.sort_by_key(|sig| function_indices.get(sig).copied());
 
-
-for sig in ordered_sigs {
-    let node = &function_signatures[&sig]; // safely look it up
-
-    println!("\n🧠 [FunctionCompile] Compiling function '{}({:?})'", sig.name, sig.param_types);
-
-    if let Node::Function { params, body, .. } = &node.node {
-        println!("    └── Params count = {}", params.len());
-        println!("🧩 [Function] Compiling function '{}({:?})'", sig.name, sig.param_types);
-
-        let mut func = Function::new(vec![(1, ValType::I32)]); // reserve local[0] for GC ptr
-        let mut local_map = HashMap::new();
-
-        for (i, (param_name, _)) in params.iter().enumerate() {
-            local_map.insert(param_name.clone(), i as u32);
-        }
-
-        let mut offset = 0;
-        let mut wasm_locals: Vec<(u32, ValType)> = vec![];
-        let mut dummy_map: Vec<ElementMap> = vec![];
-        let mut body_instrs: Vec<Instruction> = vec![];
-
-        println!("🔽 [Function Body] Starting statements for '{}'", sig.name);
-let mut total_stack = 0;
-let mut max_stack = 0;
-let mut local_stack_counter = 0;
-let mut local_idx: u32 = 0; // ← this makes the type known and fixes the error
-
-for stmt in body {
-    println!("   🔸 [stmt] Compiling statement in {:?}: {:?}", sig.name, stmt);
-
-    let stack = crate::transpile::compile_node(
-    stmt,
-    &mut body_instrs,
-    &mut dummy_map,
-    &mut offset,
-    &mut local_map,
-    &mut local_types,
-    &mut wasm_locals,
-    &function_indices,
-    &function_signatures,
-    &mut local_stack_counter,
-    &mut local_idx  // ✅ local per-function
-);
-
-
-    println!("   📏 Stack returned by stmt = {}", stack);
-if sig.name == "describe" && sig.param_types == vec!["string".to_string()] {
-    println!("🧠 Dumping function '{}({:?})'", sig.name, sig.param_types);
-    for (i, instr) in body_instrs.iter().enumerate() {
-        println!("   {:>3}: {:?}", i, instr);
+// Named functions compile first; lambdas wait until `run` is compiled so the
+// types of the variables they capture are known.
+let (closure_sigs, named_sigs): (Vec<_>, Vec<_>) = ordered_sigs
+    .into_iter()
+    .partition(|sig| function_signatures[sig].closure.is_some());
+
+let literals: Rc<RefCell<LiteralPool>> = Rc::default();
+let mut user_codes: BTreeMap<u32, CompiledFunction> = BTreeMap::new();
+for sig in named_sigs {
+    if is_async(&function_signatures[&sig].node) {
+        let step_sig = closure::step_signature(&sig);
+        let (entry, step) = compile_async_function(&sig, &function_indices, &mut function_signatures, &states, &mut local_types, &literals);
+        user_codes.insert(function_indices[&sig], entry);
+        user_codes.insert(function_indices[&step_sig], step);
+        continue;
     }
-    println!("   🧮 Final stack before `end`: {}", local_stack_counter);
+    let func = compile_user_function(&sig, &function_indices, &function_signatures, &states, &mut local_types, &literals);
+    user_codes.insert(function_indices[&sig], func);
 }
 
-    total_stack += stack;
-    max_stack = max_stack.max(total_stack); // 🔥 Track peak height
-
-    if stack > 0 {
-    for _ in 0..stack {
-        println!("   🧯 Dropping leftover stack value");
-        body_instrs.push(Instruction::Drop);
-    }
-}
-if let Some(idx) = function_indices.get(&sig) {
-    println!("📦 Compiling {}({:?}) → index {}", sig.name, sig.param_types, idx);
-}
-
-}
-        println!("🔁 Inferred return count for '{}' = {}", sig.name, max_stack);
-// 🧠 Store return count = max stack height
-if let Some(meta) = function_signatures.get_mut(&sig) {
-    meta.returns = max_stack as usize;
-}
-
-
-// 🧾 After compiling all statements in the function
-println!(
-    "📦 [FunctionEnd] Final stack for '{}' = {}, after drops pushed",
-    sig.name, total_stack
-);
-
-// 🧹 Final cleanup before `End`
-
-println!("🧠 Function {:?} has index {:?}", sig, function_indices.get(&sig));
-
-
-
-        println!("✅ Function '{}' done → Instruction count: {}", sig.name, body_instrs.len());
-
-         
-
-        // 🧹 Drop any leftover values from stack
-if local_stack_counter > 0 {
-    println!("🧹 Appending {} Drop(s) to balance stack", local_stack_counter);
-    for _ in 0..local_stack_counter {
-        body_instrs.push(Instruction::Drop);
-    }
-}
-
-// ➕ Emit all instructions including Drop(s)
-for instr in &body_instrs {
-    func.instruction(instr);
-}
-
-// Final sanity check
-if local_stack_counter != 0 {
-    println!(
-        "❌ STACK MISMATCH in user function '{}({:?})' → final stack = {}",
-        sig.name, sig.param_types, local_stack_counter
-    );
-    panic!("User function '{}' ends with unbalanced stack", sig.name);
-} else {
-    println!("✅ Stack clean at end of '{}({:?})'", sig.name, sig.param_types);
-}
-
-for (i, instr) in body_instrs.iter().enumerate() {
-    println!("🧾 {:>3}: {:?}", i, instr);
-}
-println!("🧠 Pushed final Drop(s), stack should now be 0: {}", local_stack_counter);
-
-        func.instruction(&Instruction::End);
-
-        println!(
-            "🧪 Compiled function at index {} with {} instructions",
-            codes.len(),
-            body_instrs.len()
-        );
-
-        codes.function(&func);
-    }
-}
-   // === draw_ui body ===
+   // === render body: record the top-level statements that draw into a new frame, then commit it ===
+let drawing = phase::drawing_functions(ast);
+let tasks = phase::async_functions(ast);
+let statements: Vec<(&Node, Phase)> = ast
+    .iter()
+    .filter(|node| !matches!(node, Node::Function { .. } | Node::State { .. } | Node::Style { .. }))
+    .map(|node| (node, phase::phase(node, &drawing, &tasks)))
+    .collect();
 let mut wasm_locals: Vec<(u32, ValType)> = vec![(1, ValType::I32)];
 let mut local_map: HashMap<String, u32> = HashMap::new();
-let mut instructions = vec![];
-let mut map = vec![];
-let mut offset = 0i32;
+let mut instructions = vec![
+    Instruction::I32Const(0),
+    Instruction::GlobalSet(DIRTY_GLOBAL),
+    Instruction::I32Const(0),
+    Instruction::GlobalSet(FRAME_LEN_GLOBAL),
+    Instruction::I32Const(1),
+    Instruction::GlobalSet(RENDERING_GLOBAL),
+];
+if ast.iter().any(layout::uses_runtime_layout) {
+    // Bounds reported by the previous frame's layout may belong to content that is gone
+    instructions.push(Instruction::Call(RESET_BOUNDS_FUNC));
+}
+let mut map = CodeMap { file: source_file.to_string(), records_bounds: true, literals: literals.clone(), ..CodeMap::default() };
 
 println!("📦 Compiling AST:\n{:#?}", ast);
 let mut local_idx = 0;
-for node in ast {
-    if !matches!(node, Node::Function { .. }) {
+for (node, phase) in &statements {
+    if *phase != Phase::Run {
         println!("🔵 [compile_to_wasm] Compiling top-level node: {:?}", node);
+        let fitted = layout::fit_to_canvas(node, canvas);
+        map.canvas_fill = layout::canvas_fill(node);
+        let node = fitted.as_ref().unwrap_or(node);
 
 
         let stack = crate::transpile::compile_node(
             node,
             &mut instructions,
             &mut map,
-            &mut offset,
             &mut local_map,
             &mut local_types,
             &mut wasm_locals,
             &function_indices,
             &mut function_signatures,
+            &states,
             &mut stack_counter,
             &mut local_idx,
         );
@@ -333,9 +499,13 @@ for (i, instr) in instructions.iter().enumerate() {
         }
         Instruction::Call(index) => {
     match *index {
-        DRAW_RECT_FUNC => sim_stack -= 4,
+        DRAW_RECT_FUNC => sim_stack -= 9,
+        DRAW_RECT_NODE_FUNC | DRAW_TEXT_NODE_FUNC => sim_stack -= 7,
         GC_ALLOC_FUNC => { sim_stack -= 2; sim_stack += 1; }
-        DRAW_TEXT_FUNC => sim_stack -= 4,
+        DRAW_TEXT_FUNC => sim_stack -= 7,
+        PUSH_NODE_FUNC => sim_stack -= 8,
+        LAYOUT_FRAME_FUNC => sim_stack -= 4,
+        RESET_BOUNDS_FUNC => {}
         ADD_ROOT_FUNC | MARK_USED_FUNC => sim_stack -= 1,
         _ => {
             if let Some((sig, meta)) = function_signatures.iter().find(|(_, f)| f.wasm_index == *index) {
@@ -439,41 +609,77 @@ if draw_ui_stack != 0 {
     panic!("Unbalanced stack in draw_ui");
 }
 
-// 🔍 Add a call to greet() manually
-// 🔍 Simulate a top-level call to greet()
-let greet_sig = FunctionSignature {
-    name: "greet".to_string(),
-    param_types: vec![],
-};
-if let Some(index) = function_indices.get(&greet_sig) {
-    println!("📣 Simulating top-level call to greet() → index {}", index);
-
-    let call_instr = Instruction::Call(*index);
-    instructions.push(call_instr);
+instructions.push(Instruction::Call(COMMIT_FRAME_FUNC));
+instructions.push(Instruction::End);
+stack_counter = 0; // Reset before gc_tick or other functions
 
-    // simulate stack changes like normal top-level call
-    let ret_count = function_signatures[&greet_sig].returns;
-    if ret_count > 0 {
-        println!("🪓 [Top-Level] Dropping {} return values from greet()", ret_count);
-        for _ in 0..ret_count {
-            instructions.push(Instruction::Drop);
+let mut elements = std::mem::take(&mut map.elements);
+let a11y_json = serde_json::to_string_pretty(&a11y::document(std::mem::take(&mut map.a11y))).unwrap();
+local_map.entry(GC_SCRATCH.to_string()).or_insert(0);
+user_codes.insert(render_func_index, CompiledFunction {
+    locals: wasm_locals.clone(),
+    instructions,
+    local_names: local_map,
+    lines: map.lines,
+});
+
+// === run(): give every state its declared value, run the statements that do work once, then draw the first frame ===
+let mut run_locals: Vec<(u32, ValType)> = vec![(1, ValType::I32)];
+let mut run_local_map: HashMap<String, u32> = HashMap::from([(GC_SCRATCH.to_string(), 0)]);
+let mut run_instructions = vec![];
+let mut run_map = CodeMap { file: source_file.to_string(), literals: literals.clone(), ..CodeMap::default() };
+for node in ast {
+    if let Node::State { name, value, span } = node {
+        run_map.lines.push((run_instructions.len(), *span));
+        let pushed = compile_expr(
+            value,
+            &mut run_instructions,
+            &mut run_map,
+            &run_local_map,
+            &mut local_types,
+            &mut run_locals,
+            &function_indices,
+            &function_signatures,
+            &states,
+            &mut stack_counter,
+        );
+        if pushed == 0 {
+            // String literals leave their pointer in the scratch local
+            run_instructions.push(Instruction::LocalGet(scratch_local(&run_local_map)));
         }
+        run_instructions.push(Instruction::GlobalSet(states[name].global));
     }
-} else {
-    println!("❌ Could not find 'greet()' in function_indices");
 }
-
-
-
-
-instructions.push(Instruction::End);
-stack_counter = 0; // Reset before gc_tick or other functions
-
-let mut draw_ui = Function::new(wasm_locals.clone());
-for instr in &instructions {
-    draw_ui.instruction(instr);
+let mut run_local_idx = 0;
+for (node, phase) in &statements {
+    if *phase != Phase::Render {
+        println!("🟣 [run] Compiling top-level node once: {:?}", node);
+        let stack = compile_node(
+            node,
+            &mut run_instructions,
+            &mut run_map,
+            &mut run_local_map,
+            &mut local_types,
+            &mut run_locals,
+            &function_indices,
+            &function_signatures,
+            &states,
+            &mut stack_counter,
+            &mut run_local_idx,
+        );
+        for _ in 0..stack {
+            run_instructions.push(Instruction::Drop);
+        }
+    }
 }
-codes.function(&draw_ui);
+run_instructions.push(Instruction::Call(render_func_index));
+run_instructions.push(Instruction::End);
+user_codes.insert(draw_ui_func_index, CompiledFunction {
+    locals: run_locals,
+    instructions: run_instructions,
+    local_names: run_local_map,
+    lines: run_map.lines,
+});
 
 
 
@@ -484,24 +690,24 @@ codes.function(&draw_ui);
      // === GC Tick Stub ===
     println!("🔧 [gc_tick] Starting GC tick function");
 
-let mut gc_tick_func = Function::new(vec![]);
+let mut gc_tick_func = vec![];
 
 println!("  ➕ Pushing i32.const 8");
-gc_tick_func.instruction(&Instruction::I32Const(8));
+gc_tick_func.push(Instruction::I32Const(8));
 
 println!("  ➕ Pushing i32.const TYPE_BOX ({})", TYPE_BOX);
-gc_tick_func.instruction(&Instruction::I32Const(TYPE_BOX));
+gc_tick_func.push(Instruction::I32Const(TYPE_BOX));
 
 println!("  🛠️ Calling GC_ALLOC_FUNC");
-gc_tick_func.instruction(&Instruction::Call(GC_ALLOC_FUNC));
+gc_tick_func.push(Instruction::Call(GC_ALLOC_FUNC));
 
 println!("  🧹 Dropping allocated ptr (only one value)");
-gc_tick_func.instruction(&Instruction::Drop);
+gc_tick_func.push(Instruction::Drop);
 
 
 
 // ✅ SAFETY: ensure there's *absolutely* nothing left
-gc_tick_func.instruction(&Instruction::End);
+gc_tick_func.push(Instruction::End);
 
 
     // ✅ DEBUG: Sanity check the GC Tick instruction list
@@ -514,7 +720,12 @@ println!("   ▶ End");
 
 
 // ✅ Add compiled function to code section
-codes.function(&gc_tick_func);
+user_codes.insert(gc_tick_func_index, CompiledFunction {
+    locals: vec![],
+    instructions: gc_tick_func,
+    local_names: HashMap::new(),
+    lines: vec![],
+});
 
 
     
@@ -522,25 +733,1480 @@ codes.function(&gc_tick_func);
 
 
 
+// === Lifted lambdas and thunks ===
+for sig in closure_sigs {
+    if function_signatures[&sig].closure.as_ref().unwrap().resumes.is_some() {
+        continue; // compiled together with its async function
+    }
+    let func = compile_user_function(&sig, &function_indices, &function_signatures, &states, &mut local_types, &literals);
+    user_codes.insert(function_indices[&sig], func);
+}
+
+// === resume(task, value): re-enter an async task through its step slot ===
+user_codes.insert(resume_func_index, CompiledFunction {
+    locals: vec![],
+    instructions: vec![
+        Instruction::LocalGet(0),
+        Instruction::LocalGet(1),
+        Instruction::LocalGet(0),
+        Instruction::I32Load(task_field(TASK_STEP_SLOT)),
+        Instruction::CallIndirect { ty: closure::closure_type_index(1, false), table: 0 },
+        Instruction::End,
+    ],
+    local_names: HashMap::from([("$task".to_string(), 0), ("$value".to_string(), 1)]),
+    lines: vec![],
+});
+
+function_names.insert(draw_ui_func_index, "run".to_string());
+function_names.insert(gc_tick_func_index, "gc_tick_stub".to_string()); // exported as gc_tick; the import keeps that name
+function_names.insert(resume_func_index, "resume".to_string());
+function_names.insert(a11y_tree_func_index, "a11y_tree".to_string());
+function_names.insert(hit_test_func_index, "hit_test".to_string());
+function_names.insert(dispatch_event_func_index, "dispatch_event".to_string());
+function_names.insert(render_func_index, "render".to_string());
+function_names.insert(RESERVE_NODE_FUNC, "reserve_node".to_string());
+function_names.insert(DRAW_RECT_NODE_FUNC, "draw_rect_node".to_string());
+function_names.insert(DRAW_TEXT_NODE_FUNC, "draw_text_node".to_string());
+function_names.insert(NODES_EQUAL_FUNC, "nodes_equal".to_string());
+function_names.insert(COMMIT_FRAME_FUNC, "commit_frame".to_string());
+function_names.insert(PUSH_NODE_FUNC, "push_node".to_string());
+function_names.insert(LAYOUT_FRAME_FUNC, "layout_frame".to_string());
+function_names.insert(MEASURE_NODE_FUNC, "measure_node".to_string());
+function_names.insert(PLACE_NODE_FUNC, "place_node".to_string());
+function_names.insert(SET_BOUNDS_FUNC, "set_bounds".to_string());
+function_names.insert(RESET_BOUNDS_FUNC, "reset_bounds".to_string());
+function_names.insert(RESIZE_FUNC, "resize".to_string());
+
+// === Retained frame: draws inside render become nodes, diffed by commit_frame ===
+user_codes.insert(RESERVE_NODE_FUNC, CompiledFunction {
+    locals: vec![(1, ValType::I32)],
+    instructions: frame::reserve_node_body(),
+    local_names: HashMap::from([("$grown".to_string(), 0)]),
+    lines: vec![],
+});
+for (index, text) in [(DRAW_RECT_NODE_FUNC, false), (DRAW_TEXT_NODE_FUNC, true)] {
+    user_codes.insert(index, CompiledFunction {
+        locals: vec![(1, ValType::I32)],
+        instructions: frame::record_node_body(text),
+        local_names: HashMap::from([("$node".to_string(), 7)]),
+        lines: vec![],
+    });
+}
+user_codes.insert(NODES_EQUAL_FUNC, CompiledFunction {
+    locals: vec![(2, ValType::I32)],
+    instructions: frame::nodes_equal_body(),
+    local_names: HashMap::from([("$a".to_string(), 0), ("$b".to_string(), 1)]),
+    lines: vec![],
+});
+user_codes.insert(COMMIT_FRAME_FUNC, CompiledFunction {
+    locals: vec![(frame::COMMIT_FRAME_LOCALS, ValType::I32)],
+    instructions: frame::commit_frame_body(),
+    local_names: HashMap::new(),
+    lines: vec![],
+});
+
+// === Runtime layout: groups whose content is only known while rendering ===
+user_codes.insert(PUSH_NODE_FUNC, CompiledFunction {
+    locals: vec![(1, ValType::I32)],
+    instructions: frame::push_node_body(),
+    local_names: HashMap::from([("$node".to_string(), 8)]),
+    lines: vec![],
+});
+user_codes.insert(LAYOUT_FRAME_FUNC, CompiledFunction {
+    locals: vec![(runtime_layout::LAYOUT_FRAME_LOCALS, ValType::I32)],
+    instructions: runtime_layout::layout_frame_body(),
+    local_names: HashMap::from([("first".to_string(), 0), ("x".to_string(), 1), ("y".to_string(), 2), ("keep".to_string(), 3)]),
+    lines: vec![],
+});
+user_codes.insert(MEASURE_NODE_FUNC, CompiledFunction {
+    locals: vec![(runtime_layout::MEASURE_NODE_LOCALS, ValType::I32)],
+    instructions: runtime_layout::measure_node_body(),
+    local_names: HashMap::from([("open".to_string(), 0)]),
+    lines: vec![],
+});
+user_codes.insert(PLACE_NODE_FUNC, CompiledFunction {
+    locals: vec![(runtime_layout::PLACE_NODE_LOCALS, ValType::I32)],
+    instructions: runtime_layout::place_node_body(),
+    local_names: HashMap::from([("open".to_string(), 0), ("x".to_string(), 1), ("y".to_string(), 2), ("w".to_string(), 3), ("h".to_string(), 4), ("items".to_string(), 5)]),
+    lines: vec![],
+});
+
+// === a11y_tree(): copy the accessibility tree JSON out of passive data segment 0 ===
+let a11y_len = a11y_json.len() as i32;
+user_codes.insert(a11y_tree_func_index, CompiledFunction {
+    locals: vec![(1, ValType::I32)],
+    instructions: vec![
+        Instruction::I32Const(a11y_len + 4),
+        Instruction::I32Const(TYPE_BYTES),
+        Instruction::Call(GC_ALLOC_FUNC),
+        Instruction::LocalTee(0),
+        Instruction::I32Const(a11y_len),
+        Instruction::I32Store(MemArg { offset: 0, align: 2, memory_index: 0 }),
+        Instruction::LocalGet(0),
+        Instruction::I32Const(4),
+        Instruction::I32Add,
+        Instruction::I32Const(0),
+        Instruction::I32Const(a11y_len),
+        Instruction::MemoryInit { mem: 0, data_index: 0 },
+        Instruction::LocalGet(0),
+        Instruction::End,
+    ],
+    local_names: HashMap::from([("$buffer".to_string(), 0)]),
+    lines: vec![],
+});
+// === hit_test(x, y): scan the bounds table in passive data segment 1, copied to memory once ===
+let hit_entries = map::hit_order(&elements);
+let mut hit_table = Vec::with_capacity(hit_entries.len() * HIT_RECORD as usize);
+for (id, bounds) in &hit_entries {
+    for field in [*id as i32, bounds.x, bounds.y, bounds.width, bounds.height] {
+        hit_table.extend_from_slice(&field.to_le_bytes());
+    }
+}
+println!("🎯 [hit_test] {} laid-out elements in the bounds table", hit_entries.len());
+let hit_len = hit_table.len() as i32;
+let field = |index: u64| Instruction::I32Load(MemArg { offset: 4 * index, align: 2, memory_index: 0 });
+// reset_bounds(): (re)load the table, allocating it on first use
+let table = 0;
+user_codes.insert(RESET_BOUNDS_FUNC, CompiledFunction {
+    locals: vec![(1, ValType::I32)],
+    instructions: vec![
+        Instruction::GlobalGet(HIT_TABLE_GLOBAL),
+        Instruction::LocalTee(table),
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::Empty),
+        Instruction::I32Const(hit_len),
+        Instruction::I32Const(TYPE_BYTES),
+        Instruction::Call(GC_ALLOC_FUNC),
+        Instruction::LocalTee(table),
+        Instruction::Call(ADD_ROOT_FUNC),
+        Instruction::LocalGet(table),
+        Instruction::GlobalSet(HIT_TABLE_GLOBAL),
+        Instruction::End,
+        Instruction::LocalGet(table),
+        Instruction::I32Const(0),
+        Instruction::I32Const(hit_len),
+        Instruction::MemoryInit { mem: 0, data_index: 1 },
+        Instruction::I32Const(0),
+        Instruction::GlobalSet(REPEATED_LEN_GLOBAL),
+        Instruction::End,
+    ],
+    local_names: HashMap::from([("$table".to_string(), table)]),
+    lines: vec![],
+});
+// set_bounds(tag, x, y, w, h): bounds found by runtime layout for element `tag - 1`
+let (tag, record, end) = (0, 5, 6);
+let mut set_bounds = vec![
+    Instruction::LocalGet(tag),
+    Instruction::I32Eqz,
+    Instruction::If(BlockType::Empty),
+    Instruction::Return,
+    Instruction::End,
+    Instruction::GlobalGet(HIT_TABLE_GLOBAL),
+    Instruction::I32Eqz,
+    Instruction::If(BlockType::Empty),
+    Instruction::Call(RESET_BOUNDS_FUNC),
+    Instruction::End,
+    Instruction::GlobalGet(HIT_TABLE_GLOBAL),
+    Instruction::LocalTee(record),
+    Instruction::I32Const(hit_len),
+    Instruction::I32Add,
+    Instruction::LocalSet(end),
+    Instruction::Block(BlockType::Empty),
+    Instruction::Loop(BlockType::Empty),
+    Instruction::LocalGet(record),
+    Instruction::LocalGet(end),
+    Instruction::I32GeU,
+    Instruction::BrIf(1),
+    Instruction::LocalGet(record),
+    field(0),
+    Instruction::LocalGet(tag),
+    Instruction::I32Const(1),
+    Instruction::I32Sub,
+    Instruction::I32Eq,
+    Instruction::If(BlockType::Empty),
+];
+for (index, param) in [(1, 1), (2, 2), (3, 3), (4, 4)] {
+    set_bounds.push(Instruction::LocalGet(record));
+    set_bounds.push(Instruction::LocalGet(param));
+    set_bounds.push(Instruction::I32Store(MemArg { offset: 4 * index, align: 2, memory_index: 0 }));
+}
+set_bounds.extend([
+    Instruction::Return,
+    Instruction::End,
+    Instruction::LocalGet(record),
+    Instruction::I32Const(HIT_RECORD),
+    Instruction::I32Add,
+    Instruction::LocalSet(record),
+    Instruction::Br(0),
+    Instruction::End,
+    Instruction::End,
+    // Not in the table: one pass of an element a `for` repeats, append its record
+    Instruction::GlobalGet(REPEATED_LEN_GLOBAL),
+    Instruction::GlobalGet(REPEATED_CAP_GLOBAL),
+    Instruction::I32GeU,
+    Instruction::If(BlockType::Empty),
+    // capacity = capacity == 0 ? REPEATED_CAPACITY : capacity * 2
+    Instruction::I32Const(REPEATED_CAPACITY),
+    Instruction::GlobalGet(REPEATED_CAP_GLOBAL),
+    Instruction::I32Const(1),
+    Instruction::I32Shl,
+    Instruction::GlobalGet(REPEATED_CAP_GLOBAL),
+    Instruction::I32Eqz,
+    Instruction::Select,
+    Instruction::GlobalSet(REPEATED_CAP_GLOBAL),
+    Instruction::GlobalGet(REPEATED_CAP_GLOBAL),
+    Instruction::I32Const(HIT_RECORD),
+    Instruction::I32Mul,
+    Instruction::I32Const(TYPE_BYTES),
+    Instruction::Call(GC_ALLOC_FUNC),
+    Instruction::LocalTee(record),
+    Instruction::Call(ADD_ROOT_FUNC),
+    Instruction::LocalGet(record),
+    Instruction::GlobalGet(REPEATED_GLOBAL),
+    Instruction::GlobalGet(REPEATED_LEN_GLOBAL),
+    Instruction::I32Const(HIT_RECORD),
+    Instruction::I32Mul,
+    Instruction::MemoryCopy { src_mem: 0, dst_mem: 0 },
+    Instruction::LocalGet(record),
+    Instruction::GlobalSet(REPEATED_GLOBAL),
+    Instruction::End,
+    Instruction::GlobalGet(REPEATED_GLOBAL),
+    Instruction::GlobalGet(REPEATED_LEN_GLOBAL),
+    Instruction::I32Const(HIT_RECORD),
+    Instruction::I32Mul,
+    Instruction::I32Add,
+    Instruction::LocalTee(record),
+    Instruction::LocalGet(tag),
+    Instruction::I32Const(1),
+    Instruction::I32Sub,
+    Instruction::I32Store(MemArg { offset: 0, align: 2, memory_index: 0 }),
+]);
+for (index, param) in [(1, 1), (2, 2), (3, 3), (4, 4)] {
+    set_bounds.push(Instruction::LocalGet(record));
+    set_bounds.push(Instruction::LocalGet(param));
+    set_bounds.push(Instruction::I32Store(MemArg { offset: 4 * index, align: 2, memory_index: 0 }));
+}
+set_bounds.extend([
+    Instruction::GlobalGet(REPEATED_LEN_GLOBAL),
+    Instruction::I32Const(1),
+    Instruction::I32Add,
+    Instruction::GlobalSet(REPEATED_LEN_GLOBAL),
+    Instruction::End,
+]);
+user_codes.insert(SET_BOUNDS_FUNC, CompiledFunction {
+    locals: vec![(2, ValType::I32)],
+    instructions: set_bounds,
+    local_names: HashMap::from([("tag".to_string(), tag), ("$record".to_string(), record), ("$end".to_string(), end)]),
+    lines: vec![],
+});
+let (x, y, table, record) = (0, 1, 2, 3);
+// Whether (x, y) falls in `record`; if so return its id. (x - left) <u width covers both edges in one compare
+let hit = |record: u32| vec![
+    Instruction::LocalGet(x),
+    Instruction::LocalGet(record),
+    field(1),
+    Instruction::I32Sub,
+    Instruction::LocalGet(record),
+    field(3),
+    Instruction::I32LtU,
+    Instruction::LocalGet(y),
+    Instruction::LocalGet(record),
+    field(2),
+    Instruction::I32Sub,
+    Instruction::LocalGet(record),
+    field(4),
+    Instruction::I32LtU,
+    Instruction::I32And,
+    Instruction::If(BlockType::Empty),
+    Instruction::LocalGet(record),
+    field(0),
+    Instruction::Return,
+    Instruction::End,
+];
+let mut hit_test = vec![
+    Instruction::GlobalGet(HIT_TABLE_GLOBAL),
+    Instruction::I32Eqz,
+    Instruction::If(BlockType::Empty),
+    Instruction::Call(RESET_BOUNDS_FUNC),
+    Instruction::End,
+    // Repeated elements first, last placed first: they sit inside the lists of the table
+    Instruction::GlobalGet(REPEATED_GLOBAL),
+    Instruction::GlobalGet(REPEATED_LEN_GLOBAL),
+    Instruction::I32Const(HIT_RECORD),
+    Instruction::I32Mul,
+    Instruction::I32Add,
+    Instruction::LocalSet(record),
+    Instruction::Block(BlockType::Empty),
+    Instruction::Loop(BlockType::Empty),
+    Instruction::LocalGet(record),
+    Instruction::GlobalGet(REPEATED_GLOBAL),
+    Instruction::I32LeU,
+    Instruction::BrIf(1),
+    Instruction::LocalGet(record),
+    Instruction::I32Const(HIT_RECORD),
+    Instruction::I32Sub,
+    Instruction::LocalSet(record),
+];
+hit_test.extend(hit(record));
+hit_test.extend([
+    Instruction::Br(0),
+    Instruction::End,
+    Instruction::End,
+    Instruction::GlobalGet(HIT_TABLE_GLOBAL),
+    Instruction::LocalTee(table),
+    Instruction::LocalSet(record),
+    Instruction::Block(BlockType::Empty),
+    Instruction::Loop(BlockType::Empty),
+    Instruction::LocalGet(record),
+    Instruction::LocalGet(table),
+    Instruction::I32Const(hit_len),
+    Instruction::I32Add,
+    Instruction::I32GeU,
+    Instruction::BrIf(1),
+]);
+hit_test.extend(hit(record));
+hit_test.extend([
+    Instruction::LocalGet(record),
+    Instruction::I32Const(HIT_RECORD),
+    Instruction::I32Add,
+    Instruction::LocalSet(record),
+    Instruction::Br(0),
+    Instruction::End,
+    Instruction::End,
+    Instruction::I32Const(-1),
+    Instruction::End,
+]);
+user_codes.insert(hit_test_func_index, CompiledFunction {
+    locals: vec![(2, ValType::I32)],
+    instructions: hit_test,
+    local_names: HashMap::from([
+        ("x".to_string(), x),
+        ("y".to_string(), y),
+        ("$table".to_string(), table),
+        ("$record".to_string(), record),
+    ]),
+    lines: vec![],
+});
+
+// === dispatch_event(kind, x, y, key): hit-test, then call the handler bubbled to that element ===
+let (kind, x, y, key, target) = (0, 1, 2, 3, 4);
+let mut dispatch = vec![
+    Instruction::LocalGet(x),
+    Instruction::LocalGet(y),
+    Instruction::Call(HIT_TEST_FUNC),
+    Instruction::LocalSet(target),
+    // Hover fires once when the pointer enters an element, not on every move
+    Instruction::LocalGet(kind),
+    Instruction::I32Const(EVENT_HOVER),
+    Instruction::I32Eq,
+    Instruction::If(BlockType::Empty),
+    Instruction::LocalGet(target),
+    Instruction::GlobalGet(HOVERED_GLOBAL),
+    Instruction::I32Eq,
+    Instruction::If(BlockType::Empty),
+    Instruction::I32Const(0),
+    Instruction::Return,
+    Instruction::End,
+    Instruction::LocalGet(target),
+    Instruction::GlobalSet(HOVERED_GLOBAL),
+    Instruction::End,
+];
+for (id, handlers) in map::bubbled_handlers(&elements) {
+    for (event, handler) in [(EVENT_CLICK, &handlers.on_click), (EVENT_KEY, &handlers.on_key), (EVENT_HOVER, &handlers.on_hover)] {
+        let Some(handler) = handler else { continue };
+        let (sig, args) = resolve_handler(handler, event, &function_indices);
+        println!("🖱️ [Event] element {} kind {} → {}({:?})", id, event, handler, sig.param_types);
+        dispatch.extend([
+            Instruction::LocalGet(target),
+            Instruction::I32Const(id as i32),
+            Instruction::I32Eq,
+            Instruction::LocalGet(kind),
+            Instruction::I32Const(event),
+            Instruction::I32Eq,
+            Instruction::I32And,
+            Instruction::If(BlockType::Empty),
+        ]);
+        dispatch.extend(args.iter().map(|arg| Instruction::LocalGet(*arg)));
+        dispatch.push(Instruction::Call(function_indices[&sig]));
+        for _ in 0..function_signatures[&sig].returns {
+            dispatch.push(Instruction::Drop); // its value or task frame
+        }
+        dispatch.extend([Instruction::I32Const(1), Instruction::Return, Instruction::End]);
+    }
+}
+dispatch.extend([Instruction::I32Const(0), Instruction::End]);
+user_codes.insert(dispatch_event_func_index, CompiledFunction {
+    locals: vec![(1, ValType::I32)],
+    instructions: dispatch,
+    local_names: HashMap::from([
+        ("kind".to_string(), kind),
+        ("x".to_string(), x),
+        ("y".to_string(), y),
+        ("key".to_string(), key),
+        ("$target".to_string(), target),
+    ]),
+    lines: vec![],
+});
+
+for (sig, index) in &function_indices {
+    let overloaded = function_indices.keys().filter(|other| other.name == sig.name).count() > 1;
+    let name = if overloaded { format!("{}({})", sig.name, sig.param_types.join(",")) } else { sig.name.clone() };
+    function_names.insert(*index, name);
+}
+
+// === resize(w, h): top-level groups laid out at render time follow the new canvas size ===
+let (new_width, new_height) = (0, 1);
+user_codes.insert(RESIZE_FUNC, CompiledFunction {
+    locals: vec![],
+    instructions: vec![
+        Instruction::LocalGet(new_width),
+        Instruction::GlobalSet(CANVAS_WIDTH_GLOBAL),
+        Instruction::LocalGet(new_height),
+        Instruction::GlobalSet(CANVAS_HEIGHT_GLOBAL),
+        Instruction::GlobalGet(DIRTY_GLOBAL),
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::Empty),
+        Instruction::I32Const(1),
+        Instruction::GlobalSet(DIRTY_GLOBAL),
+        Instruction::Call(REQUEST_RENDER_FUNC),
+        Instruction::End,
+        Instruction::End,
+    ],
+    local_names: HashMap::from([("w".to_string(), new_width), ("h".to_string(), new_height)]),
+    lines: vec![],
+});
+
+// === run() prologue: copy the literal pool, now complete, out of passive data segment 2 ===
+let pool_len = literals.borrow().bytes().len() as i32;
+if pool_len > 0 {
+    let pool = 0; // run's scratch local
+    let mut prologue = vec![
+        Instruction::I32Const(pool_len),
+        Instruction::I32Const(TYPE_LITERALS),
+        Instruction::Call(GC_ALLOC_FUNC),
+        Instruction::LocalTee(pool),
+        Instruction::Call(ADD_ROOT_FUNC),
+        Instruction::LocalGet(pool),
+        Instruction::GlobalSet(LITERALS_GLOBAL),
+        Instruction::LocalGet(pool),
+        Instruction::I32Const(0),
+        Instruction::I32Const(pool_len),
+        Instruction::MemoryInit { mem: 0, data_index: 2 },
+    ];
+    // Pointers inside the pool (strings in arrays) hold pool offsets until rebased
+    for at in literals.borrow().relocations() {
+        let slot = MemArg { offset: *at as u64, align: 2, memory_index: 0 };
+        prologue.extend([
+            Instruction::LocalGet(pool),
+            Instruction::LocalGet(pool),
+            Instruction::I32Load(slot),
+            Instruction::LocalGet(pool),
+            Instruction::I32Add,
+            Instruction::I32Store(slot),
+        ]);
+    }
+    println!("🧵 [run] Literal pool of {} bytes, {} pointers to rebase", pool_len, literals.borrow().relocations().len());
+    let run = user_codes.get_mut(&draw_ui_func_index).unwrap();
+    for (index, _) in &mut run.lines {
+        *index += prologue.len();
+    }
+    run.instructions.splice(0..0, prologue);
+}
+
+// Code entries must follow function index order: run, gc_tick, resume, a11y_tree, hit_test,
+// dispatch_event, render, the frame functions, then user functions.
+// Line marks and element ranges are collected relative to the code section body first.
+let mut codes = CodeSection::new();
+let mut line_rows: Vec<(u32, Span)> = vec![];
+let mut local_names = IndirectNameMap::new();
+for (index, compiled) in &user_codes {
+    let (func, offsets) = compiled.encode();
+    let body_start = codes.byte_len() as u32 + debuginfo::leb128_len(func.byte_len() as u32);
+    for (instr, span) in &compiled.lines {
+        if span.line > 0 {
+            line_rows.push((body_start + offsets[*instr], *span));
+        }
+    }
+    if *index == render_func_index {
+        for element in elements.iter_mut() {
+            element.wasm_start = body_start + offsets[element.wasm_start as usize];
+            element.wasm_end = body_start + offsets[element.wasm_end as usize];
+        }
+    }
+    codes.function(&func);
+
+    let mut names: Vec<(u32, String)> = compiled.local_names.iter()
+        .map(|(name, idx)| (*idx, name.trim_start_matches('$').to_string()))
+        .collect();
+    names.sort();
+    names.dedup_by_key(|(idx, _)| *idx);
+    let mut name_map = NameMap::new();
+    for (idx, name) in &names {
+        name_map.append(*idx, name);
+    }
+    local_names.append(*index, &name_map);
+}
+
+// Section body = function count + entries; shift the marks to module offsets
+let count_len = debuginfo::leb128_len(codes.len());
+let section_len = count_len + codes.byte_len() as u32;
+let code_body_start = module.as_slice().len() as u32 + 1 + debuginfo::leb128_len(section_len) + count_len;
+for (offset, _) in line_rows.iter_mut() {
+    *offset += code_body_start;
+}
+for element in elements.iter_mut() {
+    element.wasm_start += code_body_start;
+    element.wasm_end += code_body_start;
+}
+// Nested statements can start at the same offset as their parent; keep the innermost
+line_rows.reverse();
+line_rows.sort_by_key(|(offset, _)| *offset);
+line_rows.dedup_by_key(|(offset, _)| *offset);
+
     module.section(&codes);
 
+    let mut data = DataSection::new();
+    data.passive(a11y_json.bytes());
+    data.passive(hit_table);
+    data.passive(literals.borrow().bytes().to_vec());
+    module.section(&data);
+
+    // === Debug info: name section, W++ line table, source map link ===
+    let mut func_name_map = NameMap::new();
+    for (index, name) in &function_names {
+        func_name_map.append(*index, name);
+    }
+    let mut names = NameSection::new();
+    names.functions(&func_name_map);
+    names.locals(&local_names);
+    let mut global_names = NameMap::new();
+    global_names.append(HIT_TABLE_GLOBAL, "hit_table");
+    global_names.append(HOVERED_GLOBAL, "hovered");
+    global_names.append(DIRTY_GLOBAL, "dirty");
+    global_names.append(FRAME_GLOBAL, "frame");
+    global_names.append(FRAME_LEN_GLOBAL, "frame_len");
+    global_names.append(FRAME_CAP_GLOBAL, "frame_cap");
+    global_names.append(PREV_FRAME_GLOBAL, "prev_frame");
+    global_names.append(PREV_LEN_GLOBAL, "prev_len");
+    global_names.append(PREV_CAP_GLOBAL, "prev_cap");
+    global_names.append(RENDERING_GLOBAL, "rendering");
+    global_names.append(OPEN_DEPTH_GLOBAL, "open_depth");
+    global_names.append(LITERALS_GLOBAL, "literals");
+    global_names.append(CANVAS_WIDTH_GLOBAL, "canvas_width");
+    global_names.append(CANVAS_HEIGHT_GLOBAL, "canvas_height");
+    global_names.append(REPEATED_GLOBAL, "repeated");
+    global_names.append(REPEATED_LEN_GLOBAL, "repeated_len");
+    global_names.append(REPEATED_CAP_GLOBAL, "repeated_cap");
+    for (global, name) in state::by_global(&states) {
+        global_names.append(global, name);
+    }
+    names.globals(&global_names);
+    module.section(&names);
+
+    let line_table = debuginfo::line_table(&line_rows);
+    module.section(&CustomSection { name: debuginfo::LINE_TABLE_SECTION, data: &line_table });
+    let url = debuginfo::source_mapping_url(source_map_url);
+    module.section(&CustomSection { name: "sourceMappingURL", data: &url });
+    println!("🗺️ [Debug] {} named functions, {} line table rows", function_names.len(), line_rows.len());
+
     let wasm = module.finish();
-    let semantic = SemanticMap { elements: map };
+    let semantic = SemanticMap { version: map::MAP_VERSION, elements };
     let map_json = serde_json::to_string_pretty(&semantic).unwrap();
+    let source_map = debuginfo::source_map_json(source_file, &line_rows);
 
-    (wasm, map_json)
+    CompiledModule { wasm, semantic_map: map_json, source_map, a11y_tree: a11y_json }
+}
+
+/// Compiles one user function (or lifted lambda / thunk) body.
+fn compile_user_function(
+    sig: &FunctionSignature,
+    function_indices: &HashMap<FunctionSignature, u32>,
+    function_signatures: &HashMap<FunctionSignature, FunctionMetadata>,
+    states: &HashMap<String, StateVar>,
+    local_types: &mut HashMap<String, String>,
+    literals: &Rc<RefCell<LiteralPool>>,
+) -> CompiledFunction {
+    let meta = function_signatures[sig].clone(); // safely look it up
+
+    println!("\n🧠 [FunctionCompile] Compiling function '{}({:?})'", sig.name, sig.param_types);
+
+    let Node::Function { params, body, .. } = &meta.node else {
+        panic!("❌ Function metadata for '{}' does not hold a function node", sig.name);
+    };
+    println!("    └── Params count = {}", params.len());
+    println!("🧩 [Function] Compiling function '{}({:?})'", sig.name, sig.param_types);
+
+    // Closures take their env pointer as wasm param 0
+    let param_base = meta.closure.is_some() as u32;
+    let mut local_map = HashMap::new();
+
+    for (i, (param_name, param_type)) in params.iter().enumerate() {
+        local_map.insert(param_name.clone(), param_base + i as u32);
+        local_types.insert(param_name.clone(), param_type.clone());
+    }
+    // reserve the first declared local for the GC ptr
+    local_map.insert(GC_SCRATCH.to_string(), param_base + params.len() as u32);
+
+    let mut wasm_locals: Vec<(u32, ValType)> = vec![(1, ValType::I32)];
+    let mut code_map = CodeMap { literals: literals.clone(), ..CodeMap::default() };
+    let mut body_instrs: Vec<Instruction> = vec![];
+
+    if let Some(closure) = &meta.closure {
+        // 🔓 Unpack captured values from the env object into locals
+        for (i, name) in closure.captures.iter().enumerate() {
+            let idx = alloc_local(&local_map, &mut wasm_locals);
+            body_instrs.push(Instruction::LocalGet(0));
+            body_instrs.push(Instruction::I32Load(MemArg { offset: 4 * i as u64, align: 2, memory_index: 0 }));
+            body_instrs.push(Instruction::LocalSet(idx));
+            local_map.insert(name.clone(), idx);
+            println!("   🔓 Capture '{}' → local {}", name, idx);
+        }
+
+        // 🔗 Thunks just forward their params to the named function, and its value back
+        if let Some(target) = &closure.target {
+            for i in 0..params.len() {
+                body_instrs.push(Instruction::LocalGet(param_base + i as u32));
+            }
+            body_instrs.push(Instruction::Call(function_indices[target]));
+            for _ in closure.returns_value as usize..function_signatures[target].returns {
+                body_instrs.push(Instruction::Drop);
+            }
+        }
+    }
+
+    println!("🔽 [Function Body] Starting statements for '{}'", sig.name);
+    let mut local_stack_counter = 0;
+    let mut local_idx: u32 = 0;
+    let returns_value = match &meta.closure {
+        Some(closure) => closure.returns_value,
+        None => meta.returns == 1 && !is_async(&meta.node),
+    };
+
+    for (i, stmt) in body.iter().enumerate() {
+        println!("   🔸 [stmt] Compiling statement in {:?}: {:?}", sig.name, stmt);
+
+        // `=> expr` lambdas and functions ending in a value leave it on the stack as the result
+        if returns_value && i + 1 == body.len() {
+            if let Node::Expr(expr, _) = stmt {
+                let pushed = compile_expr(
+                    expr,
+                    &mut body_instrs,
+                    &mut code_map,
+                    &local_map,
+                    local_types,
+                    &mut wasm_locals,
+                    function_indices,
+                    function_signatures,
+                    states,
+                    &mut local_stack_counter,
+                );
+                assert_eq!(pushed, 1, "Function '{}' must end in one value", sig.name);
+                continue;
+            }
+        }
+
+        let stack = compile_node(
+            stmt,
+            &mut body_instrs,
+            &mut code_map,
+            &mut local_map,
+            local_types,
+            &mut wasm_locals,
+            function_indices,
+            function_signatures,
+            states,
+            &mut local_stack_counter,
+            &mut local_idx,
+        );
+
+        println!("   📏 Stack returned by stmt = {}", stack);
+
+        for _ in 0..stack {
+            println!("   🧯 Dropping leftover stack value");
+            body_instrs.push(Instruction::Drop);
+        }
+    }
+
+    // Final sanity check
+    if local_stack_counter != 0 {
+        panic!("User function '{}' ends with unbalanced stack ({})", sig.name, local_stack_counter);
+    }
+
+    body_instrs.push(Instruction::End);
+
+    println!("✅ Function '{}' done → Instruction count: {}", sig.name, body_instrs.len());
+    for (i, instr) in body_instrs.iter().enumerate() {
+        println!("🧾 {:>3}: {:?}", i, instr);
+    }
+
+    if param_base == 1 {
+        local_map.insert("$env".to_string(), 0);
+    }
+    if code_map.elements.iter().any(|e| e.props.handlers().is_some_and(|h| *h != Handlers::default())) {
+        println!("⚠️ [Event] Handlers on nodes drawn by '{}' are ignored; only top-level UI nodes receive events", sig.name);
+    }
+    CompiledFunction { locals: wasm_locals, instructions: body_instrs, local_names: local_map, lines: code_map.lines }
+}
+
+/// The overload of `name` an `on_*` handler calls, and which dispatch_event locals it gets:
+/// `(x, y)` for clicks and hovers and `(key)` for keys when declared, otherwise nothing.
+fn resolve_handler(name: &str, event: i32, function_indices: &HashMap<FunctionSignature, u32>) -> (FunctionSignature, Vec<u32>) {
+    let with_args = if event == EVENT_KEY {
+        (vec!["int".to_string()], vec![3]) // key
+    } else {
+        (vec!["int".to_string(); 2], vec![1, 2]) // x, y
+    };
+    for (param_types, args) in [with_args, (vec![], vec![])] {
+        let sig = FunctionSignature { name: name.to_string(), param_types };
+        if function_indices.contains_key(&sig) {
+            return (sig, args);
+        }
+    }
+    let expected = if event == EVENT_KEY { "(key: int)" } else { "(x: int, y: int)" };
+    panic!("❌ Event handler '{}' must be a function taking () or {}", name, expected);
+}
+
+fn is_async(node: &Node) -> bool {
+    matches!(node, Node::Function { is_async: true, .. })
+}
+
+fn task_field(offset: u64) -> MemArg {
+    MemArg { offset, align: 2, memory_index: 0 }
+}
+
+/// How an async segment hands over to the next one.
+enum SegmentEnd {
+    /// Save the locals, start the awaited operation and return; `resume` re-enters at the next segment
+    Suspend { start: Vec<Instruction<'static>>, span: Span },
+    /// Continue at `targets[i]` for the first of `tests` that leaves non-zero, else at the last target
+    Branch { tests: Vec<Vec<Instruction<'static>>>, targets: Vec<u32> },
+    /// Continue at that segment
+    Goto(u32),
+    /// End of the body: complete the task
+    Finish,
+}
+
+/// One straight-line piece of an async body.
+struct AsyncSegment {
+    code: Vec<Instruction<'static>>,
+    end: SegmentEnd,
+    bind: Option<u32>,         // local receiving the resume value when the segment starts
+    lines: Vec<(usize, Span)>, // statement marks into `code`
+}
+
+/// Cuts an async body into segments at every `await`, including those in the
+/// branches of an `if`/`match`; the parser rejects them anywhere else.
+struct AsyncLowering<'a> {
+    segments: Vec<AsyncSegment>,
+    current: Vec<Instruction<'static>>,
+    bind: Option<u32>, // of the segment being filled
+    map: CodeMap,
+    local_map: HashMap<String, u32>,
+    local_types: &'a mut HashMap<String, String>,
+    wasm_locals: Vec<(u32, ValType)>,
+    function_indices: &'a HashMap<FunctionSignature, u32>,
+    function_signatures: &'a HashMap<FunctionSignature, FunctionMetadata>,
+    states: &'a HashMap<String, StateVar>,
+    stack_counter: i32,
+    local_idx: u32,
+}
+
+impl AsyncLowering<'_> {
+    /// Ends the segment being filled with `end`; returns its index
+    fn end_segment(&mut self, end: SegmentEnd) -> usize {
+        self.segments.push(AsyncSegment {
+            code: std::mem::take(&mut self.current),
+            end,
+            bind: self.bind.take(),
+            lines: std::mem::take(&mut self.map.lines),
+        });
+        self.segments.len() - 1
+    }
+
+    /// Index the next segment gets
+    fn next_segment(&self) -> u32 {
+        self.segments.len() as u32
+    }
+
+    fn compile(&mut self, stmt: &Node) -> i32 {
+        compile_node(stmt, &mut self.current, &mut self.map, &mut self.local_map, self.local_types, &mut self.wasm_locals, self.function_indices, self.function_signatures, self.states, &mut self.stack_counter, &mut self.local_idx)
+    }
+
+    fn compile_expr(&mut self, expr: &Expr, instructions: &mut Vec<Instruction<'static>>) -> i32 {
+        compile_expr(expr, instructions, &mut self.map, &self.local_map, self.local_types, &mut self.wasm_locals, self.function_indices, self.function_signatures, self.states, &mut self.stack_counter)
+    }
+
+    fn lower(&mut self, body: &[Node]) {
+        for stmt in body {
+            self.lower_statement(stmt);
+        }
+    }
+
+    fn lower_statement(&mut self, stmt: &Node) {
+        if !awaits(stmt) {
+            let stack = self.compile(stmt);
+            for _ in 0..stack {
+                self.current.push(Instruction::Drop);
+            }
+            return;
+        }
+        match stmt {
+            Node::Expr(Expr::Await(awaited), span) | Node::Let { value: Expr::Await(awaited), span, .. } => {
+                println!("   ⏸️ [Async] Suspension point #{}: {:?}", self.segments.len(), awaited);
+                let mut start = vec![];
+                compile_await_start(awaited, &mut start, &mut self.map, &self.local_map, self.local_types, &mut self.wasm_locals, self.function_indices, self.function_signatures, self.states, &mut self.stack_counter);
+                self.end_segment(SegmentEnd::Suspend { start, span: *span });
+                if let Node::Let { name, .. } = stmt {
+                    let idx = alloc_local(&self.local_map, &mut self.wasm_locals);
+                    self.local_map.insert(name.clone(), idx);
+                    self.local_types.insert(name.clone(), "int".to_string());
+                    self.bind = Some(idx);
+                }
+            }
+            Node::If { condition, then_body, else_body, .. } => {
+                println!("   🔀 [Async] Splitting if around its awaits");
+                let mut test = vec![];
+                let pushed = self.compile_expr(condition, &mut test);
+                assert_eq!(pushed, 1, "If condition must leave 1 value on stack");
+                let bodies: Vec<&[Node]> = std::iter::once(then_body.as_slice()).chain(else_body.as_deref()).collect();
+                self.lower_branches(vec![test], &bodies, else_body.is_some());
+            }
+            Node::Match { value, arms, .. } => {
+                println!("   🔀 [Async] Splitting match around its awaits");
+                let is_string = infer_value_type(value, self.local_types, self.function_signatures) == "string";
+                let mut subject_code = vec![];
+                self.compile_expr(value, &mut subject_code);
+                let subject = alloc_local(&self.local_map, &mut self.wasm_locals);
+                self.current.extend(subject_code);
+                self.current.push(Instruction::LocalSet(subject));
+                let mut tests = vec![];
+                let mut bodies: Vec<&[Node]> = vec![];
+                let mut default = false;
+                for (pattern, body) in arms {
+                    let mut test = vec![];
+                    match pattern {
+                        Pattern::Wildcard => {
+                            bodies.push(body);
+                            default = true;
+                            break;
+                        }
+                        Pattern::Int(_) if is_string => panic!("❌ Match on a string value cannot use int pattern {:?}", pattern),
+                        Pattern::Str(_) if !is_string => panic!("❌ Match on an int value cannot use string pattern {:?}", pattern),
+                        Pattern::Int(n) => test.extend([Instruction::LocalGet(subject), Instruction::I32Const(*n), Instruction::I32Eq]),
+                        Pattern::Str(s) => emit_string_equals(subject, s, &mut test),
+                    }
+                    tests.push(test);
+                    bodies.push(body);
+                }
+                self.lower_branches(tests, &bodies, default);
+            }
+            _ => unreachable!("the parser only lets await into if/match bodies: {:?}", stmt),
+        }
+    }
+
+    /// Ends the segment being filled with a branch to the first of `bodies` whose
+    /// test passes, or the last of them when `fallback`; they all continue at a
+    /// segment of their own after it.
+    fn lower_branches(&mut self, tests: Vec<Vec<Instruction<'static>>>, bodies: &[&[Node]], fallback: bool) {
+        let branch = self.end_segment(SegmentEnd::Branch { tests, targets: vec![] });
+        let mut targets = vec![];
+        let mut ends = vec![];
+        for body in bodies {
+            targets.push(self.next_segment());
+            self.lower(body);
+            ends.push(self.end_segment(SegmentEnd::Goto(0)));
+        }
+        let join = self.next_segment();
+        if !fallback {
+            targets.push(join);
+        }
+        for end in ends {
+            self.segments[end].end = SegmentEnd::Goto(join);
+        }
+        self.segments[branch].end = match std::mem::replace(&mut self.segments[branch].end, SegmentEnd::Finish) {
+            SegmentEnd::Branch { tests, .. } => SegmentEnd::Branch { tests, targets },
+            _ => unreachable!(),
+        };
+    }
+}
+
+/// Whether `stmt` suspends: an `await` or an `if`/`match` with one in its branches
+fn awaits(stmt: &Node) -> bool {
+    match stmt {
+        Node::Expr(Expr::Await(_), _) | Node::Let { value: Expr::Await(_), .. } => true,
+        Node::If { .. } | Node::Match { .. } => layout::branches(stmt).into_iter().flatten().any(awaits),
+        _ => false,
+    }
+}
+
+/// Lowers `async func` into an entry function and a resumable step function.
+///
+/// The entry allocates a task frame, stores the params and runs the first step.
+/// The step restores the locals from the frame, then dispatches on the frame's
+/// state with `br_table`, looping back to it whenever a branch around an `await`
+/// moves to another segment. Every `await` saves all locals into the frame,
+/// starts the host (or child task) operation and returns. `resume(task, value)`
+/// re-enters the step at the next state. A body ending in a value leaves it in
+/// the frame's result, which `let x = await` in the waiter receives.
+fn compile_async_function(
+    sig: &FunctionSignature,
+    function_indices: &HashMap<FunctionSignature, u32>,
+    function_signatures: &mut HashMap<FunctionSignature, FunctionMetadata>,
+    states: &HashMap<String, StateVar>,
+    local_types: &mut HashMap<String, String>,
+    literals: &Rc<RefCell<LiteralPool>>,
+) -> (CompiledFunction, CompiledFunction) {
+    let meta = function_signatures[sig].clone();
+    let Node::Function { params, body, span: func_span, .. } = &meta.node else {
+        panic!("❌ Function metadata for '{}' does not hold a function node", sig.name);
+    };
+    let step_sig = closure::step_signature(sig);
+    println!("\n⏳ [Async] Lowering '{}({:?})' into a state machine", sig.name, sig.param_types);
+
+    // Step locals: 0 = task, 1 = resume value, 2 = GC scratch, then W++ params and lets
+    let mut local_map: HashMap<String, u32> = HashMap::new();
+    local_map.insert(GC_SCRATCH.to_string(), 2);
+    let mut wasm_locals: Vec<(u32, ValType)> = vec![(1, ValType::I32)];
+    for (name, ty) in params {
+        let idx = alloc_local(&local_map, &mut wasm_locals);
+        local_map.insert(name.clone(), idx);
+        local_types.insert(name.clone(), ty.clone());
+    }
+
+    let mut lowering = AsyncLowering {
+        segments: vec![],
+        current: vec![],
+        bind: None,
+        map: CodeMap { literals: literals.clone(), ..CodeMap::default() },
+        local_map,
+        local_types,
+        wasm_locals,
+        function_indices,
+        function_signatures,
+        states,
+        stack_counter: 0,
+        local_idx: 0,
+    };
+    let (last, init) = match body.split_last() {
+        Some((last, init)) if !awaits(last) => (Some(last), init),
+        _ => (None, body.as_slice()),
+    };
+    lowering.lower(init);
+    match last {
+        // A trailing value is the task's result
+        Some(Node::Expr(value, span)) => {
+            lowering.map.lines.push((lowering.current.len(), *span));
+            let mut code = vec![];
+            match lowering.compile_expr(value, &mut code) {
+                0 => lowering.current.extend(code),
+                _ => {
+                    lowering.current.push(Instruction::LocalGet(0));
+                    lowering.current.extend(code);
+                    lowering.current.push(Instruction::I32Store(task_field(TASK_RESULT)));
+                }
+            }
+        }
+        Some(last) => lowering.lower_statement(last),
+        None => {}
+    }
+    lowering.end_segment(SegmentEnd::Finish);
+    let AsyncLowering { segments, local_map, wasm_locals, .. } = lowering;
+
+    // Every declared local except the scratch slot lives in the frame across suspensions
+    let saved: Vec<u32> = (3..2 + wasm_locals.iter().map(|(n, _)| *n).sum::<u32>()).collect();
+    let slot_of = |local: u32| TASK_HEADER + 4 * (local - 3) as u64;
+
+    let mut step_body: Vec<Instruction> = vec![];
+    let mut step_lines = vec![];
+    for &local in &saved {
+        step_body.push(Instruction::LocalGet(0));
+        step_body.push(Instruction::I32Load(task_field(slot_of(local))));
+        step_body.push(Instruction::LocalSet(local));
+    }
+    let count = segments.len() as u32;
+    let done_state = count;
+    step_body.push(Instruction::Loop(BlockType::Empty)); // $dispatch
+    for _ in 0..=done_state {
+        step_body.push(Instruction::Block(BlockType::Empty));
+    }
+    step_body.push(Instruction::LocalGet(0));
+    step_body.push(Instruction::I32Load(task_field(TASK_STATE)));
+    step_body.push(Instruction::BrTable((0..count).collect::<Vec<u32>>().into(), done_state));
+    step_body.push(Instruction::End);
+
+    for (k, segment) in segments.into_iter().enumerate() {
+        let k = k as u32;
+        if let Some(bind) = segment.bind {
+            step_body.push(Instruction::LocalGet(1));
+            step_body.push(Instruction::LocalSet(bind));
+        }
+        let base = step_body.len();
+        step_lines.extend(segment.lines.iter().map(|(instr, span)| (base + instr, *span)));
+        step_body.extend(segment.code);
+
+        // Blocks still open around this segment; one more is $dispatch
+        let dispatch = count - k;
+        let goto = |next: Vec<Instruction<'static>>, step_body: &mut Vec<Instruction>| {
+            step_body.push(Instruction::LocalGet(0));
+            step_body.extend(next);
+            step_body.push(Instruction::I32Store(task_field(TASK_STATE)));
+            step_body.push(Instruction::Br(dispatch));
+        };
+        match segment.end {
+            SegmentEnd::Suspend { start, span } => {
+                step_lines.push((step_body.len(), span));
+                for &local in &saved {
+                    step_body.push(Instruction::LocalGet(0));
+                    step_body.push(Instruction::LocalGet(local));
+                    step_body.push(Instruction::I32Store(task_field(slot_of(local))));
+                }
+                step_body.push(Instruction::LocalGet(0));
+                step_body.push(Instruction::I32Const(k as i32 + 1));
+                step_body.push(Instruction::I32Store(task_field(TASK_STATE)));
+                step_body.extend(start);
+                step_body.push(Instruction::Return);
+            }
+            SegmentEnd::Branch { tests, targets } => {
+                let mut next = vec![];
+                for (test, target) in tests.iter().zip(&targets) {
+                    next.extend(test.iter().cloned());
+                    next.push(Instruction::If(BlockType::Result(ValType::I32)));
+                    next.push(Instruction::I32Const(*target as i32));
+                    next.push(Instruction::Else);
+                }
+                next.push(Instruction::I32Const(*targets.last().unwrap() as i32));
+                next.extend(tests.iter().map(|_| Instruction::End));
+                goto(next, &mut step_body);
+            }
+            SegmentEnd::Goto(target) => goto(vec![Instruction::I32Const(target as i32)], &mut step_body),
+            SegmentEnd::Finish => {}
+        }
+        if k + 1 < count {
+            step_body.push(Instruction::End);
+        }
+    }
+
+    // ✅ Completion: mark done and wake whoever awaited us
+    step_body.push(Instruction::LocalGet(0));
+    step_body.push(Instruction::I32Const(done_state as i32));
+    step_body.push(Instruction::I32Store(task_field(TASK_STATE)));
+    step_body.push(Instruction::LocalGet(0));
+    step_body.push(Instruction::I32Const(1));
+    step_body.push(Instruction::I32Store(task_field(TASK_DONE)));
+    step_body.push(Instruction::LocalGet(0));
+    step_body.push(Instruction::I32Load(task_field(TASK_WAITER)));
+    step_body.push(Instruction::If(BlockType::Empty));
+    step_body.push(Instruction::LocalGet(0));
+    step_body.push(Instruction::I32Load(task_field(TASK_WAITER)));
+    step_body.push(Instruction::LocalGet(0));
+    step_body.push(Instruction::I32Load(task_field(TASK_RESULT)));
+    step_body.push(Instruction::Call(RESUME_FUNC));
+    step_body.push(Instruction::End);
+    step_body.push(Instruction::End); // outermost dispatch block
+    step_body.push(Instruction::End); // $dispatch
+    step_body.push(Instruction::End);
+
+    let mut step_names = local_map.clone();
+    step_names.insert("$task".to_string(), 0);
+    step_names.insert("$value".to_string(), 1);
+
+    // === Entry: allocate the frame, store params, run the first step, return the task ===
+    let frame_size = TASK_HEADER as i32 + 4 * saved.len() as i32;
+    let scratch = params.len() as u32;
+    let mut entry = vec![
+        Instruction::I32Const(frame_size),
+        Instruction::I32Const(TYPE_TASK),
+        Instruction::Call(GC_ALLOC_FUNC),
+        Instruction::LocalTee(scratch),
+        Instruction::Call(ADD_ROOT_FUNC),
+    ];
+    for (field, value) in [
+        (TASK_STATE, 0),
+        (TASK_STEP_SLOT, function_indices[&step_sig] as i32),
+        (TASK_DONE, 0),
+        (TASK_RESULT, 0),
+        (TASK_WAITER, 0),
+    ] {
+        entry.push(Instruction::LocalGet(scratch));
+        entry.push(Instruction::I32Const(value));
+        entry.push(Instruction::I32Store(task_field(field)));
+    }
+    for (i, (name, _)) in params.iter().enumerate() {
+        entry.push(Instruction::LocalGet(scratch));
+        entry.push(Instruction::LocalGet(i as u32));
+        entry.push(Instruction::I32Store(task_field(slot_of(local_map[name]))));
+    }
+    entry.push(Instruction::LocalGet(scratch));
+    entry.push(Instruction::I32Const(0));
+    entry.push(Instruction::Call(function_indices[&step_sig]));
+    entry.push(Instruction::LocalGet(scratch));
+    entry.push(Instruction::End);
+
+    let mut entry_names: HashMap<String, u32> = params.iter().enumerate().map(|(i, (name, _))| (name.clone(), i as u32)).collect();
+    entry_names.insert(GC_SCRATCH.to_string(), scratch);
+
+    println!("✅ [Async] '{}' → {} states, frame {} bytes", sig.name, done_state, frame_size);
+    (
+        CompiledFunction { locals: vec![(1, ValType::I32)], instructions: entry, local_names: entry_names, lines: vec![(0, *func_span)] },
+        CompiledFunction { locals: wasm_locals, instructions: step_body, local_names: step_names, lines: step_lines },
+    )
+}
+
+/// Emits the code that starts an awaited operation. Local 0 of the step function is the task.
+#[allow(clippy::too_many_arguments)]
+fn compile_await_start(
+    awaited: &Expr,
+    instructions: &mut Vec<Instruction>,
+    map: &mut CodeMap,
+    local_map: &HashMap<String, u32>,
+    local_types: &mut HashMap<String, String>,
+    wasm_locals: &mut Vec<(u32, ValType)>,
+    function_indices: &HashMap<FunctionSignature, u32>,
+    function_signatures: &HashMap<FunctionSignature, FunctionMetadata>,
+    states: &HashMap<String, StateVar>,
+    stack_counter: &mut i32,
+) {
+    let Expr::Call { name, args } = awaited else {
+        panic!("❌ Can only await a call, got {:?}", awaited);
+    };
+
+    let op = match name.as_str() {
+        "sleep" => Some(ASYNC_SLEEP),
+        "load" => Some(ASYNC_LOAD),
+        "fetch" => Some(ASYNC_FETCH),
+        _ => None,
+    };
+
+    if let Some(op) = op {
+        // 🌐 host_await(op, a, b, task)
+        instructions.push(Instruction::I32Const(op));
+        match (op, args.as_slice()) {
+            (ASYNC_SLEEP, [ms]) => {
+                compile_expr(ms, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter);
+                instructions.push(Instruction::I32Const(0));
+            }
+            (_, [Expr::StringLiteral(s)]) if op != ASYNC_SLEEP => {
+                compile_expr(&Expr::StringLiteral(s.clone()), instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter);
+                instructions.push(Instruction::I32Const(s.len() as i32));
+            }
+            _ => panic!("❌ Bad arguments for await {}(...): {:?}", name, args),
+        }
+        instructions.push(Instruction::LocalGet(0));
+        instructions.push(Instruction::Call(HOST_AWAIT_FUNC));
+        return;
+    }
+
+    // ⏳ Awaiting another async W++ function: start it, then register as its waiter
+    let sig = FunctionSignature {
+        name: name.clone(),
+        param_types: args.iter().map(|arg| infer_value_type(arg, local_types, function_signatures)).collect(),
+    };
+    match function_signatures.get(&sig) {
+        Some(meta) if is_async(&meta.node) => {}
+        _ => panic!("❌ await needs sleep/load/fetch or an async function, got {:?}", sig),
+    }
+
+    let scratch = scratch_local(local_map);
+    for arg in args {
+        compile_expr(arg, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter);
+    }
+    instructions.push(Instruction::Call(function_indices[&sig]));
+    instructions.push(Instruction::LocalSet(scratch));
+
+    // The child may already have finished synchronously; then resume ourselves right away
+    instructions.push(Instruction::LocalGet(scratch));
+    instructions.push(Instruction::I32Load(task_field(TASK_DONE)));
+    instructions.push(Instruction::If(BlockType::Empty));
+    instructions.push(Instruction::LocalGet(0));
+    instructions.push(Instruction::LocalGet(scratch));
+    instructions.push(Instruction::I32Load(task_field(TASK_RESULT)));
+    instructions.push(Instruction::Call(RESUME_FUNC));
+    instructions.push(Instruction::Else);
+    instructions.push(Instruction::LocalGet(scratch));
+    instructions.push(Instruction::LocalGet(0));
+    instructions.push(Instruction::I32Store(task_field(TASK_WAITER)));
+    instructions.push(Instruction::End);
+}
+
+/// Pushes 1 if the NUL-terminated string at `subject` equals `literal`, else 0.
+fn emit_string_equals(subject: u32, literal: &str, instructions: &mut Vec<Instruction>) {
+    instructions.push(Instruction::I32Const(1));
+    for (i, byte) in literal.bytes().chain(std::iter::once(0)).enumerate() {
+        instructions.push(Instruction::LocalGet(subject));
+        instructions.push(Instruction::I32Load8U(MemArg { offset: i as u64, align: 0, memory_index: 0 }));
+        instructions.push(Instruction::I32Const(byte as i32));
+        instructions.push(Instruction::I32Eq);
+        instructions.push(Instruction::I32And);
+    }
+}
+
+/// `child` of a group or grid moved into `rect`, the room its container gave it.
+/// Boxes and nested containers take the whole rect, other nodes its position.
+fn place_child(child: &Node, rect: Option<Bounds>) -> Node {
+    let rewritten = match (child, rect) {
+        (Node::Box { a11y, handlers, flex, style, span, .. }, Some(rect)) => Node::Box {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+            a11y: a11y.clone(),
+            handlers: handlers.clone(),
+            flex: flex.clone(),
+            style: style.clone(),
+            span: *span,
+        },
+        (Node::Text { value, a11y, handlers, flex, style, span, .. }, Some(rect)) => Node::Text {
+            x: rect.x,
+            y: rect.y,
+            value: value.clone(),
+            a11y: a11y.clone(),
+            handlers: handlers.clone(),
+            flex: flex.clone(),
+            style: style.clone(),
+            span: *span,
+        },
+        (Node::List { .. } | Node::Shape { .. }, Some(rect)) => {
+            // A list, line, circle, ... keeps its own size, only its position comes from us
+            let mut rewritten = child.clone();
+            if let Node::List { x, y, .. } | Node::Shape { x, y, .. } = &mut rewritten {
+                (*x, *y) = (rect.x, rect.y);
+            }
+            rewritten
+        }
+        (Node::Group { .. } | Node::Grid { .. }, Some(rect)) => {
+            // A nested container gets its rect from us, and lays out its own children in it
+            let mut rewritten = child.clone();
+            if let Node::Group { x, y, width, height, .. } | Node::Grid { x, y, width, height, .. } = &mut rewritten {
+                (*x, *y) = (rect.x, rect.y);
+                (*width, *height) = (Some(rect.width), Some(rect.height));
+            }
+            rewritten
+        }
+        _ => child.clone(),
+    };
+    println!("  🎨 Compiling child node (rewritten): {:?}", rewritten);
+    rewritten
+}
+
+/// Draws a box, line, circle, ... (`a, b` = width, height) or a text (`a, b` = ptr, len)
+/// at `x, y` in `paint` (see style.rs), or records it for the runtime layout: placed
+/// by its group or list, or moved along with the grid it sits in.
+fn emit_leaf(kind: i32, (x, y): (i32, i32), [a, b]: [Instruction<'static>; 2], paint: [Instruction<'static>; 3], map: &mut CodeMap, instructions: &mut Vec<Instruction>) {
+    if let Some((origin_x, origin_y)) = map.origin {
+        instructions.push(Instruction::I32Const(kind | element_tag(map) << frame::TAG_SHIFT));
+        instructions.push(Instruction::I32Const(x - origin_x));
+        instructions.push(Instruction::I32Const(y - origin_y));
+    } else if map.runtime_layout {
+        instructions.push(Instruction::I32Const(flow_kind(kind, map)));
+        instructions.push(Instruction::I32Const(0));
+        instructions.push(Instruction::I32Const(0));
+    } else if map.records_bounds {
+        // Only `render` runs this: record the node tagged, so the host can tell what each node on screen draws
+        instructions.push(Instruction::I32Const(kind | (map.next_id() as i32 + 1) << frame::TAG_SHIFT));
+        instructions.push(Instruction::I32Const(x));
+        instructions.push(Instruction::I32Const(y));
+    } else {
+        instructions.push(Instruction::I32Const(x));
+        instructions.push(Instruction::I32Const(y));
+    }
+    instructions.push(a);
+    instructions.push(b);
+    instructions.extend(paint);
+    let draw = if kind == frame::KIND_TEXT { DRAW_TEXT_NODE_FUNC } else { DRAW_RECT_NODE_FUNC };
+    instructions.push(Instruction::Call(if map.placed_at_runtime() || map.records_bounds { PUSH_NODE_FUNC } else { draw }));
+}
+
+/// Position recorded in the props of a leaf written at `(x, y)`: where it is estimated
+/// to land when its group or list places it at render time.
+fn placed_at(map: &CodeMap, (x, y): (i32, i32), bounds: Option<Bounds>) -> (i32, i32) {
+    match bounds {
+        Some(at) if map.runtime_layout => (at.x, at.y),
+        _ => (x, y),
+    }
+}
+
+/// Draws the fill and stroke of a group laid out while compiling behind its
+/// children, if it has any; untagged, the group's bounds are its own.
+fn emit_background(frame: Bounds, paint: [i32; 3], map: &CodeMap, instructions: &mut Vec<Instruction>) {
+    if paint == [0; 3] {
+        return;
+    }
+    let (origin_x, origin_y) = map.origin.unwrap_or((0, 0));
+    if map.origin.is_some() {
+        instructions.push(Instruction::I32Const(frame::KIND_RECT));
+    }
+    for value in [frame.x - origin_x, frame.y - origin_y, frame.width, frame.height] {
+        instructions.push(Instruction::I32Const(value));
+    }
+    instructions.extend(paint.map(Instruction::I32Const));
+    instructions.push(Instruction::Call(if map.origin.is_some() { PUSH_NODE_FUNC } else { DRAW_RECT_NODE_FUNC }));
+}
+
+/// Starts a layout pass unless one is running already: the nodes recorded from
+/// here on are laid out by `end_layout`. Returns the local holding the index of
+/// the first of them.
+fn begin_layout(map: &CodeMap, local_map: &HashMap<String, u32>, wasm_locals: &mut Vec<(u32, ValType)>, instructions: &mut Vec<Instruction>) -> Option<u32> {
+    (!map.runtime_layout).then(|| {
+        let first = alloc_local(local_map, wasm_locals);
+        instructions.push(Instruction::GlobalGet(FRAME_LEN_GLOBAL));
+        instructions.push(Instruction::LocalSet(first));
+        first
+    })
+}
+
+/// Lays out the container recorded since `begin_layout` at `x, y`. Outside
+/// `render` (e.g. in a handler) its nodes are drawn and dropped again.
+fn end_layout(first: Option<u32>, x: i32, y: i32, instructions: &mut Vec<Instruction>) {
+    if let Some(first) = first {
+        instructions.push(Instruction::LocalGet(first));
+        instructions.push(Instruction::I32Const(x));
+        instructions.push(Instruction::I32Const(y));
+        instructions.push(Instruction::GlobalGet(RENDERING_GLOBAL));
+        instructions.push(Instruction::Call(LAYOUT_FRAME_FUNC));
+    }
+}
+
+/// Pushes the address of a literal at `offset` in the pool, see literals.rs
+fn emit_literal(offset: u32, instructions: &mut Vec<Instruction>) {
+    instructions.push(Instruction::GlobalGet(LITERALS_GLOBAL));
+    instructions.push(Instruction::I32Const(offset as i32));
+    instructions.push(Instruction::I32Add);
+}
+
+/// Element id + 1 of the element about to be pushed, so runtime layout can
+/// report its bounds to hit_test; 0 when ids are not final.
+fn element_tag(map: &mut CodeMap) -> i32 {
+    if !map.records_bounds {
+        return 0;
+    }
+    map.tagged.insert(map.next_id());
+    map.next_id() as i32 + 1
+}
+
+/// Records a FLEX node ahead of `node` when it grows, shrinks or is clamped
+/// inside a container running `horizontal` laid out at render time.
+fn emit_flex(node: &Node, horizontal: bool, instructions: &mut Vec<Instruction>) {
+    let flex = layout::flex(node);
+    // A grid is no flow child: it keeps its compile-time place in the container
+    if !layout::is_flexible(flex) || matches!(node, Node::Grid { .. }) {
+        return;
+    }
+    instructions.push(Instruction::I32Const(frame::KIND_FLEX));
+    instructions.extend(runtime_layout::flex_fields(flex, horizontal).map(Instruction::I32Const));
+    instructions.push(Instruction::Call(PUSH_NODE_FUNC));
+}
+
+/// Compile-time estimates of where the nodes of an `if`/`match` branch land in
+/// `slot`, the room their container estimated for the whole `if`/`match`.
+fn branch_estimates(slot: Option<Bounds>, body: &[Node], map: &CodeMap) -> Vec<Bounds> {
+    let (horizontal, gap) = map.flow;
+    let flow = layout::branch_flow(horizontal, gap);
+    let laid_out: Vec<layout::Child> = body.iter().filter_map(|node| flow.child(node)).collect();
+    slot.map(|slot| flow.place(slot, &laid_out)).unwrap_or_default()
+}
+
+/// Kind word of a node recorded for runtime layout: placed by its container, and tagged.
+fn flow_kind(kind: i32, map: &mut CodeMap) -> i32 {
+    kind | frame::FLOW | element_tag(map) << frame::TAG_SHIFT
+}
+
+/// GC scratch local of the function being compiled (local 0 inside `run`).
+fn scratch_local(local_map: &HashMap<String, u32>) -> u32 {
+    local_map.get(GC_SCRATCH).copied().unwrap_or(0)
+}
+
+/// Declares a fresh i32 local and returns its index. Declared locals are numbered
+/// from the scratch slot onwards, which is always the first one declared.
+fn alloc_local(local_map: &HashMap<String, u32>, wasm_locals: &mut Vec<(u32, ValType)>) -> u32 {
+    let declared: u32 = wasm_locals.iter().map(|(count, _)| *count).sum();
+    wasm_locals.push((1, ValType::I32));
+    scratch_local(local_map) + declared
+}
+
+/// Leaves a pointer to a closure object `[table slot, env ptr]` on the stack. One
+/// that captures nothing is a literal; in `render` one that does is a literal too,
+/// its env refilled with the captured locals each time, so drawing allocates nothing.
+/// Anywhere else the captured locals are copied into a fresh env object first.
+fn emit_closure(
+    table_slot: u32,
+    captured_locals: &[u32],
+    instructions: &mut Vec<Instruction>,
+    map: &CodeMap,
+    local_map: &HashMap<String, u32>,
+    wasm_locals: &mut Vec<(u32, ValType)>,
+) {
+    if captured_locals.is_empty() {
+        emit_literal(map.literals.borrow_mut().intern_closure(table_slot), instructions);
+        return;
+    }
+    if map.records_bounds {
+        let offset = map.literals.borrow_mut().reserve_closure(table_slot, captured_locals.len());
+        for (i, local) in captured_locals.iter().enumerate() {
+            instructions.push(Instruction::GlobalGet(LITERALS_GLOBAL));
+            instructions.push(Instruction::LocalGet(*local));
+            instructions.push(Instruction::I32Store(MemArg { offset: (offset + 8 + 4 * i as u32) as u64, align: 2, memory_index: 0 }));
+        }
+        emit_literal(offset, instructions);
+        return;
+    }
+
+    let env = alloc_local(local_map, wasm_locals);
+    let scratch = scratch_local(local_map);
+
+    instructions.push(Instruction::I32Const(4 * captured_locals.len() as i32));
+    instructions.push(Instruction::I32Const(TYPE_ENV));
+    instructions.push(Instruction::Call(GC_ALLOC_FUNC));
+    instructions.push(Instruction::LocalTee(env));
+    instructions.push(Instruction::Call(ADD_ROOT_FUNC));
+    for (i, local) in captured_locals.iter().enumerate() {
+        instructions.push(Instruction::LocalGet(env));
+        instructions.push(Instruction::LocalGet(*local));
+        instructions.push(Instruction::I32Store(MemArg { offset: 4 * i as u64, align: 2, memory_index: 0 }));
+    }
+
+    instructions.push(Instruction::I32Const(8));
+    instructions.push(Instruction::I32Const(TYPE_CLOSURE));
+    instructions.push(Instruction::Call(GC_ALLOC_FUNC));
+    instructions.push(Instruction::LocalTee(scratch));
+    instructions.push(Instruction::Call(ADD_ROOT_FUNC));
+
+    instructions.push(Instruction::LocalGet(scratch));
+    instructions.push(Instruction::I32Const(table_slot as i32));
+    instructions.push(Instruction::I32Store(MemArg { offset: 0, align: 2, memory_index: 0 }));
+    instructions.push(Instruction::LocalGet(scratch));
+    instructions.push(Instruction::LocalGet(env));
+    instructions.push(Instruction::I32Store(MemArg { offset: 4, align: 2, memory_index: 0 }));
+
+    instructions.push(Instruction::LocalGet(scratch));
+}
+
+/// Type of a value for overload resolution. Unlike `infer_type`, this knows that a
+/// bare function name is a function reference.
+fn infer_value_type(
+    expr: &Expr,
+    local_types: &HashMap<String, String>,
+    function_signatures: &HashMap<FunctionSignature, FunctionMetadata>,
+) -> String {
+    if let Expr::Identifier(name) = expr {
+        if !local_types.contains_key(name) {
+            if let Some((sig, meta)) = function_signatures.iter().find(|(sig, _)| sig.name == closure::thunk_name(name)) {
+                return closure::func_type(&sig.param_types, meta.closure.as_ref().is_some_and(|c| c.returns_value));
+            }
+        }
+    }
+    infer_type(expr, local_types)
 }
 
 pub fn compile_expr(
     expr: &Expr,
     instructions: &mut Vec<Instruction>,
-    map: &mut Vec<ElementMap>,
-    offset_counter: &mut i32,
+    map: &mut CodeMap,
     local_map: &HashMap<String, u32>,
     local_types: &mut HashMap<String, String>,
     wasm_locals: &mut Vec<(u32, ValType)>,
     function_indices: &HashMap<FunctionSignature, u32>,
     function_signatures: &HashMap<FunctionSignature, FunctionMetadata>,
+    states: &HashMap<String, StateVar>,
     stack_counter: &mut i32,
 ) -> i32 {
 
@@ -561,51 +2227,58 @@ pub fn compile_expr(
                 instructions.push(Instruction::LocalGet(index));
                 println!("   ↳ Found. Pushed LocalGet({})", index);
                 1
+            } else if let Some(var) = states.get(name) {
+                instructions.push(Instruction::GlobalGet(var.global));
+                println!("   ↳ State. Pushed GlobalGet({})", var.global);
+                1
+            } else if let Some((sig, _)) = function_signatures.iter().find(|(sig, _)| sig.name == closure::thunk_name(name)) {
+                // 🔗 Bare function name used as a value → closure over its thunk
+                let slot = function_indices[sig];
+                println!("   ↳ Function reference '{}' → table slot {}", name, slot);
+                emit_closure(slot, &[], instructions, map, local_map, wasm_locals);
+                1
             } else {
                 panic!("❌ Undefined variable: {}", name);
             }
         }
 
         Expr::StringLiteral(s) => {
-    let len = s.len() as i32;
-
-    // GC allocation
-    instructions.push(Instruction::I32Const(len));
-    instructions.push(Instruction::I32Const(TYPE_TEXT));
-    instructions.push(Instruction::Call(GC_ALLOC_FUNC));
-    instructions.push(Instruction::LocalTee(0)); // store pointer
-
-    // GC bookkeeping
-    instructions.push(Instruction::LocalGet(0));
-    instructions.push(Instruction::Call(ADD_ROOT_FUNC));
-
-    instructions.push(Instruction::LocalGet(0));
-    instructions.push(Instruction::Call(MARK_USED_FUNC));
+    // Pooled with its NUL terminator, so `match` can compare strings
+    let offset = map.literals.borrow_mut().intern_string(s);
+    emit_literal(offset, instructions);
+    1
+}
 
-    // Write bytes to memory
-    for (i, byte) in s.bytes().enumerate() {
-        instructions.push(Instruction::LocalGet(0));
-        instructions.push(Instruction::I32Const(i as i32));
-        instructions.push(Instruction::I32Add);
-        instructions.push(Instruction::I32Const(byte as i32));
-        instructions.push(Instruction::I32Store8(MemArg {
-            align: 0,
-            offset: 0,
-            memory_index: 0,
-        }));
-        //instructions.push(Instruction::Drop);
+        Expr::Array(elements) => {
+    // GC array [len][element]...; strings and closures are stored as pointers
+    let types: BTreeSet<String> = elements.iter().map(|e| infer_value_type(e, local_types, function_signatures)).collect();
+    if types.len() > 1 {
+        panic!("❌ Array elements must share one type, got {:?}", types);
+    }
+    if literals::is_constant_array(elements) {
+        let offset = map.literals.borrow_mut().intern_array(elements);
+        emit_literal(offset, instructions);
+        return 1;
     }
-
-    // ✅ VERY IMPORTANT:
-    // Only return a value if this is used in an expression (not a `Let`)
-    *stack_counter += 1;
+    let array = alloc_local(local_map, wasm_locals);
+    instructions.push(Instruction::I32Const(4 + 4 * elements.len() as i32));
+    instructions.push(Instruction::I32Const(TYPE_ARRAY));
+    instructions.push(Instruction::Call(GC_ALLOC_FUNC));
+    instructions.push(Instruction::LocalTee(array));
+    instructions.push(Instruction::Call(ADD_ROOT_FUNC));
+    instructions.push(Instruction::LocalGet(array));
+    instructions.push(Instruction::I32Const(elements.len() as i32));
+    instructions.push(Instruction::I32Store(MemArg { offset: 0, align: 2, memory_index: 0 }));
+    for (i, element) in elements.iter().enumerate() {
+        instructions.push(Instruction::LocalGet(array));
+        let stack = compile_expr(element, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter);
+        assert_eq!(stack, 1, "Array element must leave 1 value on stack");
+        instructions.push(Instruction::I32Store(MemArg { offset: 4 + 4 * i as u64, align: 2, memory_index: 0 }));
+    }
+    instructions.push(Instruction::LocalGet(array));
     1
 }
 
-
-
-
-
         Expr::Binary { left, op, right } => {
     println!("   ↳ Binary Expression: {:?} {} {:?}", left, op, right);
     
@@ -613,12 +2286,12 @@ pub fn compile_expr(
     left,  // ✅ this was missing
     instructions,
     map,
-    offset_counter,
     local_map,
     local_types,
     wasm_locals,
     function_indices,
     function_signatures,
+    states,
     stack_counter,
 );
 
@@ -626,12 +2299,12 @@ let right_stack: i32 = compile_expr(
     right, // ✅ also insert this here
     instructions,
     map,
-    offset_counter,
     local_map,
     local_types,
     wasm_locals,
     function_indices,
     function_signatures,
+    states,
     stack_counter,
 );
 
@@ -653,15 +2326,32 @@ let right_stack: i32 = compile_expr(
     println!("   ↳ Applied operator: '{}'", op);
     instructions.push(op_instr);
 
-    *stack_counter -= 1; // ✅ Consumes 2, produces 1 → net change: -1
     1
 }
 
 
+        Expr::Lambda { id, params, .. } => {
+            let sig = closure::lambda_signature(*id, params);
+            let slot = *function_indices.get(&sig).expect("Lambda was not lifted");
+            let captures = function_signatures[&sig].closure.as_ref().unwrap().captures.clone();
+
+            let captured_locals: Vec<u32> = captures.iter().map(|name| {
+                *local_map.get(name).unwrap_or_else(|| panic!("❌ Lambda #{} captures undefined variable '{}'", id, name))
+            }).collect();
+
+            println!("   ↳ Lambda #{} → table slot {}, captures {:?}", id, slot, captures);
+            emit_closure(slot, &captured_locals, instructions, map, local_map, wasm_locals);
+            1
+        }
+
+        Expr::Await(_) => {
+            panic!("❌ `await` is only allowed as a statement or `let x = await ...` directly inside an async func or an if/match there");
+        }
+
         Expr::Layout(inner_node) => {
-            let mut dummy_map = vec![];
-            let mut dummy_offset = 0;
+            let mut dummy_map = CodeMap { literals: map.literals.clone(), ..CodeMap::default() };
             let mut dummy_locals = HashMap::new();
+            dummy_locals.insert(GC_SCRATCH.to_string(), scratch_local(local_map));
             let mut dummy_types: HashMap<String, String> = HashMap::new();
             let mut dummy_layouts = vec![(1, ValType::I32)];
             let mut local_idx: u32 = 0;
@@ -672,12 +2362,12 @@ println!("   ↳ Compiling embedded layout node");
     inner_node,
     instructions,
     &mut dummy_map,
-    &mut dummy_offset,        // ✅ Correct order
     &mut dummy_locals,
     &mut dummy_types,         // ✅ now passes &mut HashMap<String, String>
     &mut dummy_layouts,
     function_indices,
     function_signatures,
+    states,
     stack_counter,
     &mut local_idx,
 
@@ -691,8 +2381,32 @@ println!("   ↳ Compiling embedded layout node");
         }
 
         Expr::Call { name, args } => {
+    // 📞 Calling a closure held in a local → call_indirect through the funcref table
+    if let (Some(&closure_local), Some(ty)) = (local_map.get(name), local_types.get(name)) {
+        if let Some((param_count, returns_value)) = closure::parse_func_type(ty) {
+            if param_count != args.len() {
+                panic!("❌ Closure '{}' of type {} called with {} args", name, ty, args.len());
+            }
+            println!("   ↳ Indirect call through closure '{}' ({})", name, ty);
+
+            instructions.push(Instruction::LocalGet(closure_local));
+            instructions.push(Instruction::I32Load(MemArg { offset: 4, align: 2, memory_index: 0 })); // env
+            for arg in args {
+                let pushed = compile_expr(arg, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter);
+                assert_eq!(pushed, 1, "Closure argument must leave 1 value on stack");
+            }
+            instructions.push(Instruction::LocalGet(closure_local));
+            instructions.push(Instruction::I32Load(MemArg { offset: 0, align: 2, memory_index: 0 })); // table slot
+            instructions.push(Instruction::CallIndirect {
+                ty: closure::closure_type_index(param_count, returns_value),
+                table: 0,
+            });
+            return returns_value as i32;
+        }
+    }
+
     let arg_types: Vec<String> = args.iter()
-        .map(|arg| infer_type(arg, local_types))
+        .map(|arg| infer_value_type(arg, local_types, function_signatures))
         .collect();
 
     let sig = FunctionSignature {
@@ -702,16 +2416,16 @@ println!("   ↳ Compiling embedded layout node");
 
     if let Some(index) = function_indices.get(&sig) {
         for arg in args {
-            *stack_counter += compile_expr(
+            compile_expr(
                 arg,
                 instructions,
                 map,
-                offset_counter,
                 local_map,
                 local_types,
                 wasm_locals,
                 function_indices,
                 function_signatures,
+                states,
                 stack_counter,
             );
         }
@@ -736,13 +2450,13 @@ println!("   ↳ Compiling embedded layout node");
 fn compile_expr_and_discard(
     expr: &Expr,
     instructions: &mut Vec<Instruction>,
-    map: &mut Vec<ElementMap>,
-    offset_counter: &mut i32,
+    map: &mut CodeMap,
     local_map: &HashMap<String, u32>,
     local_types: &mut HashMap<String, String>,
     wasm_locals: &mut Vec<(u32, ValType)>,
     function_indices: &HashMap<FunctionSignature, u32>,
     function_signatures: &HashMap<FunctionSignature, FunctionMetadata>, // ✅ ADD THIS
+    states: &HashMap<String, StateVar>,
     stack_counter: &mut i32,
 )
 
@@ -752,19 +2466,18 @@ fn compile_expr_and_discard(
     expr,
     instructions,
     map,
-    offset_counter,
     local_map,
     local_types,
     wasm_locals,
     function_indices,
     function_signatures, // ✅ this was missing
+    states,
     stack_counter,
 );
 
 
     if count > 0 {
     instructions.push(Instruction::Drop);
-    *stack_counter -= 1;
 } else {
     println!("⚠️ Tried to Drop expression with no stack value");
 }
@@ -776,13 +2489,13 @@ fn compile_expr_and_discard(
 pub fn compile_node(
     node: &Node,
     instructions: &mut Vec<Instruction>,
-    map: &mut Vec<ElementMap>,
-    offset_counter: &mut i32,
+    map: &mut CodeMap,
     local_map: &mut HashMap<String, u32>,
     local_types: &mut HashMap<String, String>,
     wasm_locals: &mut Vec<(u32, ValType)>,
     function_indices: &HashMap<FunctionSignature, u32>,
     function_signatures: &HashMap<FunctionSignature, FunctionMetadata>,
+    states: &HashMap<String, StateVar>,
     stack_counter: &mut i32,
     local_idx: &mut u32, // ✅ NEW: added to support LocalSet
 ) -> i32 {
@@ -791,23 +2504,24 @@ pub fn compile_node(
 
 
     println!("🔸 [compile_node] Entered with node: {:?}", node);
+    map.lines.push((instructions.len(), node.span()));
+    if map.runtime_layout {
+        emit_flex(node, map.flow.0, instructions);
+    }
     match node {
-        Node::Let { name, value } => {
+        Node::Let { name, value, .. } => {
     println!("🔸 [compile_node] Let {} = {:?}", name, value);
 
-    let offset_before: i32 = *stack_counter;
-let mut offset_counter: i32 = offset_before;
-
 let returned: i32 = compile_expr(
     value,
     instructions,
     map,
-    &mut offset_counter,   // ✅ now valid mutable borrow
     &local_map,
     local_types,
     wasm_locals,
     function_indices,
     function_signatures,
+    states,
     stack_counter,
 );
 
@@ -816,167 +2530,198 @@ let returned: i32 = compile_expr(
     if returned == 0 {
         // ✅ Special case: StringLiteral needs to store pointer manually
         if let Expr::StringLiteral(_) = value {
-            instructions.push(Instruction::LocalGet(0));
-            instructions.push(Instruction::LocalSet(*local_idx));
+            let idx = alloc_local(local_map, wasm_locals);
+            instructions.push(Instruction::LocalGet(scratch_local(local_map)));
+            instructions.push(Instruction::LocalSet(idx));
             println!("📍 Manually storing pointer from string literal to local '{}'", name);
-            local_map.insert(name.clone(), *local_idx);
+            local_map.insert(name.clone(), idx);
             let ty = infer_type(value, local_types);
             local_types.insert(name.clone(), ty);
-            *stack_counter -= 1;
-            *local_idx += 1;
+            *local_idx = idx + 1;
         } else {
             println!("⚠️ Let binding for '{}' returned no value — skipping LocalSet", name);
         }
     } else {
-        let ty = infer_type(value, local_types);
-        instructions.push(Instruction::LocalSet(*local_idx));
-        local_map.insert(name.clone(), *local_idx);
+        let ty = infer_value_type(value, local_types, function_signatures);
+        let idx = alloc_local(local_map, wasm_locals);
+        instructions.push(Instruction::LocalSet(idx));
+        local_map.insert(name.clone(), idx);
         local_types.insert(name.clone(), ty);
-        //*stack_counter -= 1;
-        
-        *local_idx += 1;
+        *local_idx = idx + 1;
 
     }
+    match value {
+        Expr::Array(elements) if literals::is_constant_array(elements) => map.arrays.insert(name.clone(), elements.clone()),
+        _ => map.arrays.remove(name),
+    };
 
     0
 }
 
-
-
-
-
-        Node::Group { direction, gap, align, justify, padding, children } => {
-    let group_offset = *offset_counter;
-
-    // === GC Allocation for Group ===
-    instructions.push(Instruction::I32Const(8));
-instructions.push(Instruction::I32Const(TYPE_GROUP));
-instructions.push(Instruction::Call(GC_ALLOC_FUNC));
-instructions.push(Instruction::LocalTee(0));
-instructions.push(Instruction::LocalGet(0));
-instructions.push(Instruction::Call(ADD_ROOT_FUNC));
-instructions.push(Instruction::Drop);
-
-
-    // === Layout Pre-Pass ===
-    let is_horizontal = direction == "horizontal";
-    let mut total_main_size = 0;
-    let mut child_dims = vec![]; // (main_size, cross_size)
-println!("📦 [Group] direction = {}, gap = {}, padding = {}, align = {}, justify = {}",
-         direction, gap, padding, align, justify);
-
-    for child in children {
-        match child {
-            Node::Box { width, height, .. } => {
-                let main = if is_horizontal { *width } else { *height };
-                let cross = if is_horizontal { *height } else { *width };
-                total_main_size += main + gap;
-                child_dims.push((main, cross));
+        Node::Assign { name, value, .. } => {
+            println!("🔸 [compile_node] Assign {} = {:?}", name, value);
+            map.arrays.remove(name);
+            let pushed = compile_expr(
+                value,
+                instructions,
+                map,
+                local_map,
+                local_types,
+                wasm_locals,
+                function_indices,
+                function_signatures,
+                states,
+                stack_counter,
+            );
+            if pushed == 0 {
+                // String literals leave their pointer in the scratch local
+                instructions.push(Instruction::LocalGet(scratch_local(local_map)));
             }
-            Node::Text { value, .. } => {
-    let (width, height) = match value {
-        Expr::StringLiteral(s) => {
-            let width = s.len() as i32 * 8;
-            (width, 16)
-        }
-        Expr::Identifier(_) => {
-            // You can’t know the actual length at compile time — use placeholder dimensions
-            (999, 16)
+            if let Some(&index) = local_map.get(name) {
+                instructions.push(Instruction::LocalSet(index));
+            } else if let Some(var) = states.get(name) {
+                state::emit_store(var, DIRTY_GLOBAL, RENDERING_GLOBAL, REQUEST_RENDER_FUNC, instructions);
+            } else {
+                panic!("❌ Cannot assign to undeclared variable '{}'", name);
+            }
+            0
         }
-        _ => panic!("Unsupported value type in Text layout: {:?}", value),
-    };
 
-    let main = if is_horizontal { width } else { height };
-    let cross = if is_horizontal { height } else { width };
-    total_main_size += main + gap;
-    child_dims.push((main, cross));
-}
+        Node::State { name, .. } => {
+            panic!("❌ State '{}' is initialised by run, not compiled in place", name);
+        }
 
-            _ => {
-                total_main_size += 50 + gap;
-                child_dims.push((50, 50));
-            }
+        Node::Group { direction, gap, align, justify, padding, x, y, width, height, wrap, children, a11y, handlers, flex, style, span }
+            if map.origin.is_none()
+                && (map.runtime_layout
+                    || !layout::is_static(node)
+                    || map.canvas_fill != (false, false)
+                    // A function may be called inside a runtime container, which must see the group whole
+                    || !map.records_bounds) =>
+        {
+    // === Laid out at render time: record OPEN, the children as flow nodes, CLOSE ===
+    let start = instructions.len();
+    println!("📐 [Group] Content known only at render time, laying out in layout_frame");
+    // Where the compile-time estimate puts us, for the map until hit_test knows better
+    let root = !map.runtime_layout;
+    let (fill_width, fill_height) = if root { std::mem::take(&mut map.canvas_fill) } else { (false, false) };
+    let frame = if !root {
+        map.estimate.take()
+    } else if layout::is_estimable(node) {
+        let size = layout::measure(node).unwrap();
+        Some(Bounds { x: *x, y: *y, width: size.width, height: size.height })
+    } else if let (Some(width), Some(height)) = (width, height) {
+        // Its content is not known, but the size it was given is
+        Some(Bounds { x: *x, y: *y, width: *width, height: *height })
+    } else {
+        None
+    };
+    let first = begin_layout(map, local_map, wasm_locals, instructions);
+
+    let kind_at = instructions.len();
+    instructions.push(Instruction::I32Const(0)); // kind and tag, once our id is known
+    let align_index = parser::ALIGNS.iter().position(|a| a == align).unwrap();
+    let justify_index = parser::JUSTIFIES.iter().position(|j| j == justify).unwrap();
+    instructions.push(Instruction::I32Const(runtime_layout::open_flags(direction == "horizontal", align_index, justify_index, *wrap)));
+    instructions.push(Instruction::I32Const(runtime_layout::open_spacing(*gap, *padding)));
+    let size = |fills: bool, canvas_global: u32, size: &Option<i32>| {
+        if fills { Instruction::GlobalGet(canvas_global) } else { Instruction::I32Const(size.unwrap_or(-1)) }
+    };
+    instructions.push(size(fill_width, CANVAS_WIDTH_GLOBAL, width));
+    instructions.push(size(fill_height, CANVAS_HEIGHT_GLOBAL, height));
+    instructions.extend(style::group_paint(style).map(Instruction::I32Const)); // kept as the background if any
+    instructions.push(Instruction::Call(PUSH_NODE_FUNC));
+
+    let flow = layout::Flow { horizontal: direction == "horizontal", gap: *gap, padding: *padding, align, justify, wrap: *wrap };
+    let laid_out: Vec<layout::Child> = children.iter().filter_map(|child| flow.child(child)).collect();
+    let mut child_rects = frame.map(|frame| flow.place(frame, &laid_out)).unwrap_or_default().into_iter();
+
+    let first_element = map.elements.len();
+    let first_a11y = map.a11y.len();
+    map.runtime_layout = true;
+    let outer_flow = std::mem::replace(&mut map.flow, (flow.horizontal, flow.gap));
+    for child in children {
+        if layout::measure(child).is_some() {
+            map.estimate = child_rects.next();
+        }
+        let stack = compile_node(child, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter, local_idx);
+        map.estimate = None;
+        for _ in 0..stack {
+            instructions.push(Instruction::Drop);
         }
     }
+    map.flow = outer_flow;
+    map.runtime_layout = !root;
 
-    if !children.is_empty() {
-        total_main_size -= gap;
+    for value in [frame::KIND_CLOSE, 0, 0, 0, 0, 0, 0, 0] {
+        instructions.push(Instruction::I32Const(value));
     }
+    instructions.push(Instruction::Call(PUSH_NODE_FUNC));
+    instructions[kind_at] = Instruction::I32Const(flow_kind(frame::KIND_OPEN, map));
+    end_layout(first, *x, *y, instructions);
+
+    // hit_test learns the real bounds through set_bounds once laid out
+    let children_a11y = map.a11y.split_off(first_a11y);
+    if let Some(group_a11y) = a11y::element(map.next_id(), Some("group"), None, a11y, frame.unwrap_or_default(), Some(map.locate(*span)), children_a11y) {
+        map.a11y.push(group_a11y);
+    }
+
+    map.adopt(first_element);
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props: ElementProps::Group(GroupProps {
+            direction: direction.clone(),
+            gap: *gap,
+            padding: *padding,
+            align: align.clone(),
+            justify: justify.clone(),
+            width: *width,
+            height: *height,
+            wrap: *wrap,
+            a11y: a11y.clone(),
+            handlers: handlers.clone(),
+            flex: flex.clone(),
+            style: style.clone(),
+        }),
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
+        pointer: None,
+        source: Some(map.locate(*span)),
+        bounds: frame,
+        repeated: false,
+    });
+
+    0
+}
 
-    // === Justify & Align Calculations ===
-    let container_size = 300; // TODO: make dynamic later
+        Node::Group { direction, gap, align, justify, padding, x, y, width, height, wrap, children, a11y, handlers, flex, style, span } => {
+    let start = instructions.len();
 
-    let mut cursor_main = match justify.as_str() {
-        "start" => *padding,
-        "center" => (container_size - total_main_size) / 2,
-        "end" => container_size - total_main_size - *padding,
-        "space-between" => *padding,
-        _ => *padding,
-    };
 
-    let space_between = if justify == "space-between" && children.len() > 1 {
-        (container_size - total_main_size + gap * ((children.len() - 1) as i32)) / ((children.len() - 1) as i32)
-    } else {
-        *gap
-    };
+    // === Layout: our rect comes from the parent (or the canvas), children are placed inside it ===
+    let flow = layout::Flow { horizontal: direction == "horizontal", gap: *gap, padding: *padding, align, justify, wrap: *wrap };
+println!("📦 [Group] direction = {}, gap = {}, padding = {}, align = {}, justify = {}",
+         direction, gap, padding, align, justify);
+    let size = layout::measure(node).unwrap();
+    let frame = Bounds { x: *x, y: *y, width: size.width, height: size.height };
+    let laid_out: Vec<layout::Child> = children.iter().filter_map(layout::child).collect();
+    let mut child_rects = flow.place(frame, &laid_out).into_iter();
+    emit_background(frame, style::group_paint(style), map, instructions);
 
     // === Child Rendering Pass ===
+    let first_element = map.elements.len();
+    let first_a11y = map.a11y.len();
     for (i, child) in children.iter().enumerate() {
-        let (main_size, cross_size) = child_dims[i];
-
-        let (x, y) = if is_horizontal {
-            let x = cursor_main;
-            let y = match align.as_str() {
-                "start" => *padding,
-                "center" => (container_size - cross_size) / 2,
-                "end" => container_size - cross_size - *padding,
-                _ => *padding,
-            };
-            (x, y)
-        } else {
-            let x = match align.as_str() {
-                "start" => *padding,
-                "center" => (container_size - cross_size) / 2,
-                "end" => container_size - cross_size - *padding,
-                _ => *padding,
-            };
-            let y = cursor_main;
-            (x, y)
-        };
+        // `let` and plain calls run in place but take no room in the layout
+        let rect = layout::measure(child).map(|_| child_rects.next().unwrap());
 println!("  🔧 Child #{}: original = {:?}", i, child);
-println!("     → Layout x = {}, y = {}, main_size = {}, cross_size = {}", x, y, main_size, cross_size);
-
-       let rewritten: Node = match child {
-    Node::Box { width, height, .. } => {
-        let rewritten = Node::Box {
-            x,
-            y,
-            width: *width,
-            height: *height,
-        };
-        println!("  🎨 Compiling child node (rewritten): {:?}", rewritten);
-        rewritten
-    }
-
-    Node::Text { value, .. } => {
-        let rewritten = Node::Text {
-            x,
-            y,
-            value: value.clone(),
-        };
-        println!("  🎨 Compiling child node (rewritten): {:?}", rewritten);
-        rewritten
-    }
+println!("     → Layout {:?}", rect);
 
-    _ => {
-        let rewritten = child.clone();
-        println!("  🎨 Compiling child node (rewritten - fallback): {:?}", rewritten);
-        rewritten
-    }
-};
+        let rewritten = place_child(child, rect);
 
 
-        let stack = compile_node(&rewritten, instructions, map, offset_counter, local_map, local_types, wasm_locals, function_indices,function_signatures, stack_counter, local_idx);
+        let stack = compile_node(&rewritten, instructions, map, local_map, local_types, wasm_locals, function_indices,function_signatures, states, stack_counter, local_idx);
         println!("   🔁 Recursively compiled child #{}: {:?} → stack = {}", i, rewritten, stack);
 
 
@@ -987,155 +2732,337 @@ println!("     → Layout x = {}, y = {}, main_size = {}, cross_size = {}", x, y
 }
 
 
-        cursor_main += main_size + space_between;
     }
 
-    map.push(ElementMap {
-        kind: "group".to_string(),
-        wasm_offset: group_offset as u32,
+    let bounds = map.bounds(frame);
+    let children_a11y = map.a11y.split_off(first_a11y);
+    if let Some(group_a11y) = a11y::element(map.next_id(), Some("group"), None, a11y, bounds.unwrap_or_default(), Some(map.locate(*span)), children_a11y) {
+        map.a11y.push(group_a11y);
+    }
+
+    map.adopt(first_element);
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props: ElementProps::Group(GroupProps {
+            direction: direction.clone(),
+            gap: *gap,
+            padding: *padding,
+            align: align.clone(),
+            justify: justify.clone(),
+            width: *width,
+            height: *height,
+            wrap: *wrap,
+            a11y: a11y.clone(),
+            handlers: handlers.clone(),
+            flex: flex.clone(),
+            style: style.clone(),
+        }),
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
         pointer: None,
-        source: None,
-        props: Some(json!({
-            "direction": direction,
-            "gap": gap,
-            "padding": padding,
-            "align": align,
-            "justify": justify
-        })),
+        source: Some(map.locate(*span)),
+        bounds,
+        repeated: false,
     });
 
     0
 }
 
+        Node::Grid { columns, rows, gap, padding, x, y, width, height, children, a11y, handlers, flex, span } => {
+    let start = instructions.len();
+
+    // === Layout: tracks are sized inside our rect, each child fills its cells ===
+    let grid = layout::Grid { columns, rows, gap: *gap, padding: *padding };
+    let size = layout::measure(node).unwrap();
+    let estimate = map.runtime_layout.then(|| map.estimate.take()).flatten();
+    let frame = Bounds { x: *x, y: *y, width: size.width, height: size.height };
+    let frame = estimate.map_or(frame, |at| Bounds { x: at.x, y: at.y, ..frame });
+    let laid_out: Vec<layout::Child> = children.iter().filter_map(layout::child).collect();
+    let mut child_rects = grid.place(frame, &laid_out).into_iter();
+    println!("▦ [Grid] {} columns, {} explicit rows, gap = {}, padding = {}, frame = {:?}",
+             columns.len(), rows.len(), gap, padding, frame);
+
+    // Inside a group laid out at render time the cells keep their layout and
+    // move along with the grid: its nodes are recorded relative to `frame`
+    let runtime = map.runtime_layout;
+    let kind_at = instructions.len();
+    let saved = (map.origin, map.estimated);
+    if runtime {
+        instructions.push(Instruction::I32Const(0)); // kind and tag, once our id is known
+        instructions.push(Instruction::I32Const(0));
+        instructions.push(Instruction::I32Const(0));
+        instructions.push(Instruction::I32Const(frame.width));
+        instructions.push(Instruction::I32Const(frame.height));
+        instructions.extend([0; 3].map(Instruction::I32Const)); // no background
+        instructions.push(Instruction::Call(PUSH_NODE_FUNC));
+        map.runtime_layout = false;
+        map.origin = Some((frame.x, frame.y));
+        map.estimated = estimate.is_some();
+    }
 
+    let first_element = map.elements.len();
+    let first_a11y = map.a11y.len();
+    for (i, child) in children.iter().enumerate() {
+        let rect = layout::measure(child).map(|_| child_rects.next().unwrap());
+        println!("  🔧 Cell child #{} → {:?}", i, rect);
+        let rewritten = place_child(child, rect);
+        let stack = compile_node(&rewritten, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter, local_idx);
+        if stack > 0 {
+            println!("🧯 [compile_node] Dropping leftover stack value after compiling {:?}", node);
+            instructions.push(Instruction::Drop);
+        }
+    }
 
+    if runtime {
+        (map.origin, map.estimated) = saved;
+        map.runtime_layout = true;
+        for value in [frame::KIND_CLOSE, 0, 0, 0, 0, 0, 0, 0] {
+            instructions.push(Instruction::I32Const(value));
+        }
+        instructions.push(Instruction::Call(PUSH_NODE_FUNC));
+        instructions[kind_at] = Instruction::I32Const(flow_kind(frame::KIND_OPEN, map));
+    }
+    let bounds = if runtime { estimate.map(|_| frame) } else { map.bounds(frame) };
 
+    let children_a11y = map.a11y.split_off(first_a11y);
+    if let Some(grid_a11y) = a11y::element(map.next_id(), Some("group"), None, a11y, bounds.unwrap_or_default(), Some(map.locate(*span)), children_a11y) {
+        map.a11y.push(grid_a11y);
+    }
 
-        Node::Box { x, y, width, height } => {
-    let off = *offset_counter;
-    println!("📦 [Box] Compiling Box at ({}, {}) size {}x{}", x, y, width, height);
-
-    println!("➡️ Allocating GC box...");
-    instructions.push(Instruction::I32Const(0)); *offset_counter += 5;
-    println!("   ↳ Pushed I32Const(0)");
-    
-    instructions.push(Instruction::I32Const(TYPE_BOX)); *offset_counter += 5;
-    println!("   ↳ Pushed I32Const(TYPE_BOX = {})", TYPE_BOX);
-    
-    instructions.push(Instruction::Call(GC_ALLOC_FUNC)); *offset_counter += 2;
-    println!("   ↳ Called GC_ALLOC_FUNC");
+    let tracks = |tracks: &[parser::Track]| tracks.iter().map(|track| track.to_string()).collect::<Vec<_>>().join(" ");
+    map.adopt(first_element);
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props: ElementProps::Grid(GridProps {
+            columns: tracks(columns),
+            rows: tracks(rows),
+            gap: *gap,
+            padding: *padding,
+            width: *width,
+            height: *height,
+            a11y: a11y.clone(),
+            handlers: handlers.clone(),
+            flex: flex.clone(),
+        }),
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
+        pointer: None,
+        source: Some(map.locate(*span)),
+        bounds,
+        repeated: false,
+    });
 
-    instructions.push(Instruction::LocalTee(0)); *offset_counter += 2;
-    println!("   ↳ Teed result into local[0]");
+    0
+}
 
-    instructions.push(Instruction::LocalGet(0));
-    instructions.push(Instruction::Call(ADD_ROOT_FUNC)); *offset_counter += 2;
-    println!("➡️ Added to GC roots");
 
-    instructions.push(Instruction::Drop);
-    println!("⚠️ Dropped extra pointer (was not reused immediately)");
 
-    instructions.push(Instruction::LocalGet(0));
-instructions.push(Instruction::Call(MARK_USED_FUNC));
 
 
-    println!("✅ Marked GC object as used");
+        Node::Box { x, y, width, height, a11y, handlers, flex, style, span } => {
+    let start = instructions.len();
+    println!("📦 [Box] Compiling Box at ({}, {}) size {}x{}", x, y, width, height);
 
     println!("🖼️ Drawing box with drawRect({}, {}, {}, {})", x, y, width, height);
-    instructions.push(Instruction::I32Const(*x)); *offset_counter += 5;
-    instructions.push(Instruction::I32Const(*y)); *offset_counter += 5;
-    instructions.push(Instruction::I32Const(*width)); *offset_counter += 5;
-    instructions.push(Instruction::I32Const(*height)); *offset_counter += 5;
-    instructions.push(Instruction::Call(DRAW_RECT_FUNC)); *offset_counter += 2;
+    let paint = style::box_paint(style).map(Instruction::I32Const);
+    emit_leaf(frame::KIND_RECT, (*x, *y), [Instruction::I32Const(*width), Instruction::I32Const(*height)], paint, map, instructions);
+
+    // A clickable box is announced as a button unless it says otherwise
+    let default_role = handlers.on_click.as_ref().map(|_| "button");
+    let bounds = map.bounds(Bounds { x: *x, y: *y, width: *width, height: *height });
+    if let Some(node) = a11y::element(map.next_id(), default_role, None, a11y, bounds.unwrap_or_default(), Some(map.locate(*span)), vec![]) {
+        map.a11y.push(node);
+    }
+    let (x, y) = &placed_at(map, (*x, *y), bounds);
 
     println!("🗺️ Pushing box element to semantic map...");
-    map.push(ElementMap {
-        kind: "box".to_string(),
-        wasm_offset: off as u32,
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props: ElementProps::Box(BoxProps {
+            x: *x,
+            y: *y,
+            width: *width,
+            height: *height,
+            a11y: a11y.clone(),
+            handlers: handlers.clone(),
+            flex: flex.clone(),
+            style: style.clone(),
+        }),
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
+        pointer: None,
+        source: Some(map.locate(*span)),
+        bounds,
+        repeated: false,
+    });
+
+    0
+}
+
+        Node::Shape { primitive, x, y, width, height, a11y, handlers, flex, style, span } => {
+    let start = instructions.len();
+    println!("✏️ [Shape] Compiling {} at ({}, {}) size {}x{}", primitive.name(), x, y, width, height);
+
+    let [fill, stroke, shape] = style::shape_paint(primitive, style);
+    let (id, data) = match primitive {
+        Primitive::Line { .. } => (if primitive.rising() { frame::PRIMITIVE_RISING_LINE } else { frame::PRIMITIVE_LINE }, None),
+        Primitive::Circle { .. } => (frame::PRIMITIVE_CIRCLE, None),
+        Primitive::Path(d) => {
+            let paint = [fill.to_le_bytes(), stroke.to_le_bytes()].concat();
+            (frame::PRIMITIVE_PATH, Some([paint, d.as_bytes().to_vec()].concat()))
+        }
+        Primitive::Image(src) => (frame::PRIMITIVE_IMAGE, Some(src.as_bytes().to_vec())),
+    };
+
+    // A path or image hands the host its pooled data in place of fill and stroke, see frame.rs
+    let shape = Instruction::I32Const(shape | id << frame::PRIMITIVE_SHIFT);
+    let paint = match data {
+        Some(bytes) => {
+            let offset = map.literals.borrow_mut().intern(&bytes);
+            emit_literal(offset, instructions);
+            instructions.push(Instruction::LocalSet(scratch_local(local_map)));
+            [Instruction::LocalGet(scratch_local(local_map)), Instruction::I32Const(bytes.len() as i32), shape]
+        }
+        None => [Instruction::I32Const(fill), Instruction::I32Const(stroke), shape],
+    };
+    emit_leaf(frame::KIND_RECT, (*x, *y), [Instruction::I32Const(*width), Instruction::I32Const(*height)], paint, map, instructions);
+
+    let default_role = handlers.on_click.as_ref().map(|_| "button");
+    let bounds = map.bounds(Bounds { x: *x, y: *y, width: *width, height: *height });
+    if let Some(node) = a11y::element(map.next_id(), default_role, None, a11y, bounds.unwrap_or_default(), Some(map.locate(*span)), vec![]) {
+        map.a11y.push(node);
+    }
+
+    let (a11y, handlers, flex, style) = (a11y.clone(), handlers.clone(), flex.clone(), style.clone());
+    let (x, y) = &placed_at(map, (*x, *y), bounds);
+    let props = match primitive {
+        // Endpoints and centre as written; where a group puts them is in `bounds`
+        Primitive::Line { x1, y1, x2, y2 } => ElementProps::Line(LineProps { x1: *x1, y1: *y1, x2: *x2, y2: *y2, a11y, handlers, flex, style }),
+        Primitive::Circle { cx, cy } => ElementProps::Circle(CircleProps { cx: *cx, cy: *cy, radius: width / 2, a11y, handlers, flex, style }),
+        Primitive::Path(d) => ElementProps::Path(PathProps { x: *x, y: *y, width: *width, height: *height, d: d.clone(), a11y, handlers, flex, style }),
+        Primitive::Image(src) => ElementProps::Image(ImageProps { x: *x, y: *y, width: *width, height: *height, src: src.clone(), a11y, handlers, flex, style }),
+    };
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props,
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
         pointer: None,
-        source: Some(format!("wpp:x={},y={}", x, y)),
-        props: Some(json!({ "x": x, "y": y, "width": width, "height": height })),
+        source: Some(map.locate(*span)),
+        bounds,
+        repeated: false,
     });
 
     0
 }
 
-        Node::Text { x, y, value } => {
-    let off = *offset_counter;
+        Node::Text { x, y, value, a11y, handlers, flex, style, span } => {
+    let start = instructions.len();
     println!("📝 [Text] Compiling Text at ({}, {}) with value {:?}", x, y, value);
 
     let leaves_value_on_stack = match value {
         Expr::StringLiteral(s) => {
             let len = s.len() as i32;
-            println!("➡️ Allocating text buffer for string of length {}", len);
-
-            // Allocate buffer and tee to local[0]
-            instructions.push(Instruction::I32Const(len));
-            instructions.push(Instruction::I32Const(TYPE_TEXT));
-            instructions.push(Instruction::Call(GC_ALLOC_FUNC));
-            instructions.push(Instruction::LocalTee(0)); // Save to local[0] AND keep on stack
-
-            // Add to GC root
-            instructions.push(Instruction::Call(ADD_ROOT_FUNC));
-
-            // Mark used
-            instructions.push(Instruction::LocalGet(0));
-            instructions.push(Instruction::Call(MARK_USED_FUNC));
-
-            // Write bytes to ptr - 8 with offset
-            println!("✍️ Writing string bytes to memory:");
-            for (i, byte) in s.bytes().enumerate() {
-    instructions.push(Instruction::LocalGet(0));         // ptr
-    instructions.push(Instruction::I32Const(i as i32));  // i
-    instructions.push(Instruction::I32Add);              // ptr + i
-    instructions.push(Instruction::I32Const(byte as i32));
-    instructions.push(Instruction::I32Store8(MemArg {
-        align: 0,
-        offset: 0,               // not i!
-        memory_index: 0,
-    }));
-}
-
+            let offset = map.literals.borrow_mut().intern_string(s);
+            emit_literal(offset, instructions);
+            instructions.push(Instruction::LocalSet(scratch_local(local_map)));
 
             // Draw text
-            instructions.push(Instruction::I32Const(*x));
-            instructions.push(Instruction::I32Const(*y));
-            instructions.push(Instruction::LocalGet(0));
-            instructions.push(Instruction::I32Const(len));
             println!("🖍️ Emitting drawText({}, {}, ptr, {})", x, y, len);
-            instructions.push(Instruction::Call(DRAW_TEXT_FUNC));
+            let ptr = Instruction::LocalGet(scratch_local(local_map));
+            let paint = style::text_paint(style).map(Instruction::I32Const);
+            emit_leaf(frame::KIND_TEXT, (*x, *y), [ptr, Instruction::I32Const(len)], paint, map, instructions);
 
             false
         }
 
         Expr::Identifier(var) => {
             println!("🔗 [Text] Resolving identifier '{}'", var);
-            if let Some(&idx) = local_map.get(var) {
-                instructions.push(Instruction::I32Const(*x));
-                instructions.push(Instruction::I32Const(*y));
-                instructions.push(Instruction::LocalGet(idx));
-                instructions.push(Instruction::I32Const(999)); // placeholder
-                instructions.push(Instruction::Call(DRAW_TEXT_FUNC));
-                instructions.push(Instruction::Drop);
-                println!("🖼️ Drew text from variable '{}'", var);
-                false
+            let (source, ty) = if let Some(&idx) = local_map.get(var) {
+                (Instruction::LocalGet(idx), local_types.get(var).cloned().unwrap_or_default())
+            } else if let Some(state) = states.get(var) {
+                (Instruction::GlobalGet(state.global), state.ty.clone())
             } else {
                 panic!("❌ Unknown string variable '{}'", var);
+            };
+            if ty != "string" {
+                panic!("❌ Text value '{}' is a {}, not a string", var, ty);
             }
+
+            // Length up to the NUL terminator every string value carries
+            let text = alloc_local(local_map, wasm_locals);
+            let len = alloc_local(local_map, wasm_locals);
+            instructions.push(source);
+            instructions.push(Instruction::LocalSet(text));
+            instructions.push(Instruction::I32Const(0));
+            instructions.push(Instruction::LocalSet(len));
+            instructions.push(Instruction::Block(BlockType::Empty));
+            instructions.push(Instruction::Loop(BlockType::Empty));
+            instructions.push(Instruction::LocalGet(text));
+            instructions.push(Instruction::LocalGet(len));
+            instructions.push(Instruction::I32Add);
+            instructions.push(Instruction::I32Load8U(MemArg { offset: 0, align: 0, memory_index: 0 }));
+            instructions.push(Instruction::I32Eqz);
+            instructions.push(Instruction::BrIf(1));
+            instructions.push(Instruction::LocalGet(len));
+            instructions.push(Instruction::I32Const(1));
+            instructions.push(Instruction::I32Add);
+            instructions.push(Instruction::LocalSet(len));
+            instructions.push(Instruction::Br(0));
+            instructions.push(Instruction::End);
+            instructions.push(Instruction::End);
+
+            let paint = style::text_paint(style).map(Instruction::I32Const);
+            emit_leaf(frame::KIND_TEXT, (*x, *y), [Instruction::LocalGet(text), Instruction::LocalGet(len)], paint, map, instructions);
+            println!("🖼️ Drew text from variable '{}'", var);
+            false
         }
 
         _ => panic!("❌ Unsupported text value: {:?}", value),
     };
 
+    let name = match value {
+        Expr::StringLiteral(text) => Some(text.clone()),
+        _ => None, // only known at runtime
+    };
+    let size = layout::text_size(value, style::font_size(style));
+    let width = if name.is_some() { size.width } else { 0 };
+    // Only the text of a `for` over a constant array is estimated without being a literal
+    let estimated = name.is_none() && map.runtime_layout && map.estimate.is_some();
+    let bounds = map.bounds(Bounds { x: *x, y: *y, width, height: size.height })
+        .map(|at| if estimated { at } else { Bounds { width, height: size.height, ..at } });
+    let (x, y) = &placed_at(map, (*x, *y), bounds);
+
+    if let Some(node) = a11y::element(map.next_id(), Some("text"), name.clone(), a11y, bounds.unwrap_or_default(), Some(map.locate(*span)), vec![]) {
+        map.a11y.push(node);
+    }
+
     println!("🗺️ Pushing text element to semantic map...");
-    map.push(ElementMap {
-        kind: "text".to_string(),
-        wasm_offset: off as u32,
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props: ElementProps::Text(TextProps {
+            x: *x,
+            y: *y,
+            text: name.clone(),
+            a11y: a11y.clone(),
+            handlers: handlers.clone(),
+            flex: flex.clone(),
+            style: style.clone(),
+        }),
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
         pointer: None,
-        source: Some(format!("wpp:text='{:?}'", value)),
-        props: Some(json!({ "x": x, "y": y })),
+        source: Some(map.locate(*span)),
+        bounds: bounds.filter(|_| name.is_some() || estimated),
+        repeated: false,
     });
 
+
     if leaves_value_on_stack {
         1
     } else {
@@ -1147,69 +3074,246 @@ instructions.push(Instruction::Call(MARK_USED_FUNC));
 
 
 
-        Node::List { direction, gap, padding, items } => {
-    let is_horizontal = direction == "horizontal";
-    let mut cursor = *padding;
-println!("📋 [List] direction = {}, gap = {}, padding = {}", direction, gap, padding);
-
-    for item in items {
-        if let Node::Item { value } = item {
-            let width = value.len() as i32 * 8;
-            let height = 16;
-
-            let (x, y) = if is_horizontal {
-                (cursor, *padding)
-            } else {
-                (*padding, cursor)
-            };
-
-            let rewritten = Node::Text {
-                x,
-                y,
-                value: Expr::StringLiteral(value.clone()),
+        Node::List { direction, gap, padding, x, y, items, a11y, flex, span } => {
+    // === Laid out at render time, where the host measures the items: on its own or
+    // as one more container of the group around it ===
+    let start = instructions.len();
+    println!("📋 [List] direction = {}, gap = {}, padding = {}", direction, gap, padding);
+    if map.origin.is_some() {
+        panic!("❌ List at line {} is in a grid inside a group laid out at render time; grids there only hold content known at compile time", span.line);
+    }
+    let root = !map.runtime_layout;
+    // Every `for` over a constant array unrolled, so the list can be estimated with what it generates
+    let unrolled = layout::unroll(items, &map.arrays);
+    let frame = match &unrolled {
+        Some(unrolled) if root => {
+            let mut estimable = node.clone();
+            if let Node::List { items, .. } = &mut estimable {
+                *items = unrolled.concat();
+            }
+            layout::is_estimable(&estimable).then(|| {
+                let size = layout::measure(&estimable).unwrap();
+                Bounds { x: *x, y: *y, width: size.width, height: size.height }
+            })
+        }
+        _ if root => None,
+        _ => map.estimate.take(),
+    };
+    let first = begin_layout(map, local_map, wasm_locals, instructions);
+
+    let kind_at = instructions.len();
+    instructions.push(Instruction::I32Const(0)); // kind and tag, once our id is known
+    instructions.push(Instruction::I32Const(runtime_layout::open_flags(direction == "horizontal", 0, 0, false)));
+    instructions.push(Instruction::I32Const(runtime_layout::open_spacing(*gap, *padding)));
+    instructions.push(Instruction::I32Const(-1));
+    instructions.push(Instruction::I32Const(-1));
+    instructions.extend([0; 3].map(Instruction::I32Const)); // no background
+    instructions.push(Instruction::Call(PUSH_NODE_FUNC));
+
+    let flow = layout::list_flow(direction, *gap, *padding);
+    let unrolled = unrolled.unwrap_or_else(|| items.iter().map(|item| vec![item.clone()]).collect());
+    let laid_out: Vec<layout::Child> = unrolled.iter().flatten().filter_map(|item| flow.child(item)).collect();
+    let mut item_rects = frame.map(|frame| flow.place(frame, &laid_out)).unwrap_or_default().into_iter();
+
+    let first_element = map.elements.len();
+    let first_a11y = map.a11y.len();
+    map.runtime_layout = true;
+    let outer_flow = std::mem::replace(&mut map.flow, (flow.horizontal, flow.gap));
+    for (item, generated) in items.iter().zip(&unrolled) {
+        let rects: Vec<Bounds> = item_rects.by_ref().take(generated.iter().filter_map(layout::measure).count()).collect();
+        match item {
+            // The map records one pass: where the first lands
+            Node::For { body, .. } => map.pass_estimates = rects.into_iter().take(body.iter().filter_map(layout::measure).count()).collect(),
+            _ => map.estimate = rects.first().copied(),
+        }
+        let stack = compile_node(item, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter, local_idx);
+        map.estimate = None;
+        map.pass_estimates.clear();
+        for _ in 0..stack {
+            instructions.push(Instruction::Drop);
+        }
+    }
+    map.flow = outer_flow;
+    map.runtime_layout = !root;
 
-            };
-println!("  📎 Item '{}': → rendered at ({}, {})", value, x, y);
+    for value in [frame::KIND_CLOSE, 0, 0, 0, 0, 0, 0, 0] {
+        instructions.push(Instruction::I32Const(value));
+    }
+    instructions.push(Instruction::Call(PUSH_NODE_FUNC));
+    instructions[kind_at] = Instruction::I32Const(flow_kind(frame::KIND_OPEN, map));
+    end_layout(first, *x, *y, instructions);
 
-            let stack = compile_node(&rewritten, instructions, map, offset_counter, local_map, local_types, wasm_locals, function_indices,function_signatures, stack_counter, local_idx);
+    let items_a11y = map.a11y.split_off(first_a11y);
+    if let Some(list_a11y) = a11y::element(map.next_id(), Some("list"), None, a11y, frame.unwrap_or_default(), Some(map.locate(*span)), items_a11y) {
+        map.a11y.push(list_a11y);
+    }
 
+    map.adopt(first_element);
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props: ElementProps::List(ListProps {
+            direction: direction.clone(),
+            gap: *gap,
+            padding: *padding,
+            a11y: a11y.clone(),
+            flex: flex.clone(),
+        }),
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
+        pointer: None,
+        source: Some(map.locate(*span)),
+        bounds: frame,
+        repeated: false,
+    });
 
-            if stack > 0 {
-    println!("🧯 [compile_node] Dropping leftover stack value after compiling {:?}", node);
-    instructions.push(Instruction::Drop);
+    0
 }
 
-
-            cursor += if is_horizontal { width } else { height } + gap;
+        Node::Item { children, a11y, handlers, span } => {
+    // === One container of the list, stacking its nodes ===
+    let start = instructions.len();
+    let frame = map.estimate.take();
+    let kind_at = instructions.len();
+    instructions.push(Instruction::I32Const(0)); // kind and tag, once our id is known
+    instructions.push(Instruction::I32Const(runtime_layout::open_flags(false, 0, 0, false)));
+    instructions.push(Instruction::I32Const(runtime_layout::open_spacing(0, 0)));
+    instructions.push(Instruction::I32Const(-1));
+    instructions.push(Instruction::I32Const(-1));
+    instructions.extend([0; 3].map(Instruction::I32Const)); // no background
+    instructions.push(Instruction::Call(PUSH_NODE_FUNC));
+
+    let laid_out: Vec<layout::Child> = children.iter().filter_map(|child| layout::ITEM_FLOW.child(child)).collect();
+    let mut child_rects = frame.map(|frame| layout::ITEM_FLOW.place(frame, &laid_out)).unwrap_or_default().into_iter();
+    let first_element = map.elements.len();
+    let first_a11y = map.a11y.len();
+    let outer_flow = std::mem::replace(&mut map.flow, (layout::ITEM_FLOW.horizontal, layout::ITEM_FLOW.gap));
+    for child in children {
+        if layout::measure(child).is_some() {
+            map.estimate = child_rects.next();
         }
+        let stack = compile_node(child, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter, local_idx);
+        map.estimate = None;
+        for _ in 0..stack {
+            instructions.push(Instruction::Drop);
+        }
+    }
+    map.flow = outer_flow;
+
+    for value in [frame::KIND_CLOSE, 0, 0, 0, 0, 0, 0, 0] {
+        instructions.push(Instruction::I32Const(value));
+    }
+    instructions.push(Instruction::Call(PUSH_NODE_FUNC));
+    instructions[kind_at] = Instruction::I32Const(flow_kind(frame::KIND_OPEN, map));
+
+    // `item(value: "a")` is announced as one listitem named "a", not as the text drawn for it
+    let mut children_a11y = map.a11y.split_off(first_a11y);
+    let name = match children.as_slice() {
+        [Node::Text { value: Expr::StringLiteral(text), .. }] => Some(text.clone()),
+        _ => None,
+    };
+    if name.is_some() {
+        children_a11y.clear();
+    }
+    if let Some(node) = a11y::element(map.next_id(), Some("listitem"), name, a11y, frame.unwrap_or_default(), Some(map.locate(*span)), children_a11y) {
+        map.a11y.push(node);
     }
 
-    map.push(ElementMap {
-        kind: "list".to_string(),
-        wasm_offset: *offset_counter as u32,
+    map.adopt(first_element);
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props: ElementProps::Item(ItemProps { a11y: a11y.clone(), handlers: handlers.clone() }),
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
         pointer: None,
-        source: None,
-        props: Some(json!({
-            "direction": direction,
-            "gap": gap,
-            "padding": padding
-        })),
+        source: Some(map.locate(*span)),
+        bounds: frame,
+        repeated: false,
     });
 
+    0
+}
+
+        Node::For { name, items, body, span } => {
+    // === The body's items once per array element, `name` bound to it ===
+    println!("🔁 [For] {} in {:?}", name, items);
+    let ty = infer_value_type(items, local_types, function_signatures);
+    let Some(element_ty) = ty.strip_prefix('[').and_then(|ty| ty.strip_suffix(']')).map(str::to_string) else {
+        panic!("❌ for at line {} needs an array, got a {}", span.line, ty);
+    };
+    let stack = compile_expr(items, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter);
+    assert_eq!(stack, 1, "for needs its array on the stack");
+    let array = alloc_local(local_map, wasm_locals);
+    let index = alloc_local(local_map, wasm_locals);
+    let element = alloc_local(local_map, wasm_locals);
+    instructions.push(Instruction::LocalSet(array));
+    instructions.push(Instruction::I32Const(0));
+    instructions.push(Instruction::LocalSet(index));
+    instructions.push(Instruction::Block(BlockType::Empty));
+    instructions.push(Instruction::Loop(BlockType::Empty));
+    instructions.push(Instruction::LocalGet(index));
+    instructions.push(Instruction::LocalGet(array));
+    instructions.push(Instruction::I32Load(MemArg { offset: 0, align: 2, memory_index: 0 }));
+    instructions.push(Instruction::I32GeU);
+    instructions.push(Instruction::BrIf(1));
+    instructions.push(Instruction::LocalGet(array));
+    instructions.push(Instruction::LocalGet(index));
+    instructions.push(Instruction::I32Const(4));
+    instructions.push(Instruction::I32Mul);
+    instructions.push(Instruction::I32Add);
+    instructions.push(Instruction::I32Load(MemArg { offset: 4, align: 2, memory_index: 0 }));
+    instructions.push(Instruction::LocalSet(element));
+
+    // One set of elements stands for every pass: those placed at render time report
+    // where each pass lands, and hit_test keeps one record per pass
+    let shadowed = (local_map.insert(name.clone(), element), local_types.insert(name.clone(), element_ty));
+    let first_element = map.elements.len();
+    let mut estimates = std::mem::take(&mut map.pass_estimates).into_iter();
+    for item in body {
+        if layout::measure(item).is_some() {
+            map.estimate = estimates.next();
+        }
+        let stack = compile_node(item, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter, local_idx);
+        map.estimate = None;
+        for _ in 0..stack {
+            instructions.push(Instruction::Drop);
+        }
+    }
+    for element in &mut map.elements[first_element..] {
+        element.repeated = map.tagged.contains(&element.id);
+    }
+    match shadowed {
+        (Some(index), Some(ty)) => {
+            local_map.insert(name.clone(), index);
+            local_types.insert(name.clone(), ty);
+        }
+        _ => {
+            local_map.remove(name);
+            local_types.remove(name);
+        }
+    }
+
+    instructions.push(Instruction::LocalGet(index));
+    instructions.push(Instruction::I32Const(1));
+    instructions.push(Instruction::I32Add);
+    instructions.push(Instruction::LocalSet(index));
+    instructions.push(Instruction::Br(0));
+    instructions.push(Instruction::End);
+    instructions.push(Instruction::End);
     0
 }
 
 
-        Node::Expr(expr) => {
+        Node::Expr(expr, _) => {
     let result = compile_expr(
-        expr, instructions, map, offset_counter, local_map,
-        local_types, wasm_locals, function_indices, function_signatures,
+        expr, instructions, map, local_map,
+        local_types, wasm_locals, function_indices, function_signatures, states,
         stack_counter,
     );
 
     if result > 0 {
         instructions.push(Instruction::Drop);
-        *stack_counter -= 1;
     }
 
     0 // <- always return 0 for Node-level expr
@@ -1222,54 +3326,238 @@ println!("  📎 Item '{}': → rendered at ({}, {})", value, x, y);
 
 
 
-       Node::If { condition, then_body, else_body } => {
-        println!("🧪 [If] Compiling condition: {:?}", condition);
-    let count = compile_expr(
-    condition,
-    instructions,
-    map,
-    offset_counter,
-    local_map,
-    local_types,
-    wasm_locals,
-    function_indices,
-    function_signatures,
-    stack_counter,
-);
- // ✅ Push the condition
-    println!("🔀 Entering IF block");
-    instructions.push(Instruction::If(BlockType::Empty));
+        Node::Match { value, value_source, value_span, arms, .. } => {
+    println!("🔀 [Match] Compiling match on {:?} with {} arms", value, arms.len());
+    let start = instructions.len();
+    let first_element = map.elements.len();
 
+    let is_string = infer_value_type(value, local_types, function_signatures) == "string";
+    let pushed = compile_expr(value, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter);
+    assert_eq!(pushed, 1, "Match value must leave 1 value on stack");
+    let subject = alloc_local(local_map, wasm_locals);
+    instructions.push(Instruction::LocalSet(subject));
 
-    let mut max_stack = 0;
-println!("🟩 [Then] Block has {} statements", then_body.len());
+    // Arms after a `_` can never run; repeated patterns are shadowed by the first one
+    let mut seen: Vec<&Pattern> = vec![];
+    let mut live_arms: Vec<&(Pattern, Vec<Node>)> = vec![];
+    let mut default: Option<&Vec<Node>> = None;
+    for arm in arms {
+        let (pattern, body) = arm;
+        if default.is_some() || seen.contains(&pattern) {
+            println!("⚠️ [Match] Unreachable arm {:?}", pattern);
+            continue;
+        }
+        match pattern {
+            Pattern::Wildcard => default = Some(body),
+            Pattern::Int(_) if is_string => panic!("❌ Match on a string value cannot use int pattern {:?}", pattern),
+            Pattern::Str(_) if !is_string => panic!("❌ Match on an int value cannot use string pattern {:?}", pattern),
+            _ => {
+                seen.push(pattern);
+                live_arms.push(arm);
+            }
+        }
+    }
+    if default.is_none() {
+        println!("⚠️ [Match] Non-exhaustive match on {:?}: no `_ => {{ ... }}` arm, unmatched values do nothing", value);
+    }
 
-    for stmt in then_body {
-        println!("   🟢 Compiling THEN stmt: {:?}", stmt);
+    let ints: Vec<i32> = live_arms.iter().filter_map(|(p, _)| match p { Pattern::Int(n) => Some(*n), _ => None }).collect();
+    let (min, max) = (ints.iter().copied().min().unwrap_or(0), ints.iter().copied().max().unwrap_or(0));
+    let span = max as i64 - min as i64 + 1;
+    let dense = !is_string && ints.len() >= 3 && span <= 2 * ints.len() as i64 && span <= 1024;
+
+    // The room the container estimated for us is that of the largest arm; each arm lines up in it
+    let slot = map.estimate.take();
+    let mut compile_body = |body: &Vec<Node>, instructions: &mut Vec<Instruction>, local_map: &mut HashMap<String, u32>, wasm_locals: &mut Vec<(u32, ValType)>, local_types: &mut HashMap<String, String>| {
+        let mut rects = branch_estimates(slot, body, map).into_iter();
+        for stmt in body {
+            if layout::measure(stmt).is_some() {
+                map.estimate = rects.next();
+            }
+            let stack = compile_node(stmt, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter, local_idx);
+            map.estimate = None;
+            for _ in 0..stack {
+                instructions.push(Instruction::Drop);
+            }
+        }
+    };
 
-    max_stack = max_stack.max(compile_node(stmt, instructions, map, offset_counter, local_map, local_types, wasm_locals, function_indices, function_signatures, stack_counter, local_idx)
-);
+    if dense {
+        // 🧮 Dense int arms → one br_table jump
+        println!("   ↳ Dense arms {}..={} → br_table", min, max);
+        let n = live_arms.len() as u32;
+        instructions.push(Instruction::Block(BlockType::Empty)); // $end
+        instructions.push(Instruction::Block(BlockType::Empty)); // $default
+        for _ in 0..n {
+            instructions.push(Instruction::Block(BlockType::Empty)); // one per arm
+        }
+        let targets: Vec<u32> = (min..=max)
+            .map(|v| ints.iter().position(|n| *n == v).map_or(n, |i| i as u32))
+            .collect();
+        instructions.push(Instruction::LocalGet(subject));
+        instructions.push(Instruction::I32Const(min));
+        instructions.push(Instruction::I32Sub);
+        instructions.push(Instruction::BrTable(targets.into(), n));
+        for (i, (_, body)) in live_arms.iter().enumerate() {
+            instructions.push(Instruction::End);
+            compile_body(body, instructions, local_map, wasm_locals, local_types);
+            instructions.push(Instruction::Br(n - i as u32));
+        }
+        instructions.push(Instruction::End); // $default
+        if let Some(body) = default {
+            compile_body(body, instructions, local_map, wasm_locals, local_types);
+        }
+        instructions.push(Instruction::End); // $end
+    } else {
+        // 🔗 Sparse ints or strings → if/else chain
+        for (pattern, body) in &live_arms {
+            match pattern {
+                Pattern::Int(n) => {
+                    instructions.push(Instruction::LocalGet(subject));
+                    instructions.push(Instruction::I32Const(*n));
+                    instructions.push(Instruction::I32Eq);
+                }
+                Pattern::Str(s) => emit_string_equals(subject, s, instructions),
+                Pattern::Wildcard => unreachable!(),
+            }
+            instructions.push(Instruction::If(BlockType::Empty));
+            compile_body(body, instructions, local_map, wasm_locals, local_types);
+            instructions.push(Instruction::Else);
+        }
+        if let Some(body) = default {
+            compile_body(body, instructions, local_map, wasm_locals, local_types);
+        }
+        for _ in &live_arms {
+            instructions.push(Instruction::End);
+        }
+    }
+
+    map.adopt(first_element);
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props: ElementProps::Match(MatchProps {
+            value: value_source.clone(),
+            value_span: *value_span,
+            arms: arms.iter().map(|(pattern, body)| MatchArm {
+                pattern: pattern.to_string(),
+                statements: body.len(),
+            }).collect(),
+        }),
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
+        pointer: None,
+        source: Some(map.locate(node.span())),
+        bounds: None, // depends on the arm taken at runtime
+        repeated: false,
+    });
+
+    0
 }
 
-if let Some(else_branch) = else_body {
-    println!("🔁 Entering ELSE block");
-    instructions.push(Instruction::Else);
-    println!("🟥 [Else] Block has {} statements", else_branch.len());
-    for stmt in else_branch {
-        println!("   🔴 Compiling ELSE stmt: {:?}", stmt);
-        max_stack = max_stack.max(compile_node(stmt, instructions, map, offset_counter, local_map, local_types, wasm_locals, function_indices, function_signatures, stack_counter, local_idx)
-);
+
+       Node::If { .. } => {
+    let start = instructions.len();
+    let first_element = map.elements.len();
+
+    // Flatten `else if` chains: each nested if that is the only else statement is one more branch
+    let mut branches: Vec<(&Expr, &Vec<Node>)> = vec![];
+    let mut conditions: Vec<IfBranch> = vec![];
+    let mut else_branch: Option<&[Node]> = None;
+    let mut current = node;
+    while let Node::If { condition, condition_source, condition_span, then_body, else_body, .. } = current {
+        branches.push((condition, then_body));
+        conditions.push(IfBranch {
+            condition: condition_source.clone(),
+            condition_span: *condition_span,
+            statements: then_body.len(),
+        });
+        match else_body.as_deref() {
+            Some([nested @ Node::If { .. }]) => current = nested,
+            other => {
+                else_branch = other;
+                break;
+            }
+        }
     }
-}
-println!("🧱 Closing IF/ELSE block");
 
-    instructions.push(Instruction::End);
-println!("📥 IF block left value on stack ({}), dropping...", max_stack);
+    // The room the container estimated for us is that of the largest branch; each branch lines up in it
+    let slot = map.estimate.take();
+    for (i, (condition, then_body)) in branches.iter().enumerate() {
+        println!("🧪 [If] Compiling condition #{}: {:?}", i, condition);
+        let count = compile_expr(
+            condition,
+            instructions,
+            map,
+            local_map,
+            local_types,
+            wasm_locals,
+            function_indices,
+            function_signatures,
+            states,
+            stack_counter,
+        );
+        assert_eq!(count, 1, "If condition must leave 1 value on stack");
+        println!("🔀 Entering IF block");
+        instructions.push(Instruction::If(BlockType::Empty));
+
+        println!("🟩 [Then] Block has {} statements", then_body.len());
+        let mut rects = branch_estimates(slot, then_body, map).into_iter();
+        for stmt in then_body.iter() {
+            println!("   🟢 Compiling THEN stmt: {:?}", stmt);
+            if layout::measure(stmt).is_some() {
+                map.estimate = rects.next();
+            }
+            let stack = compile_node(stmt, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter, local_idx);
+            map.estimate = None;
+            for _ in 0..stack {
+                instructions.push(Instruction::Drop);
+            }
+        }
 
-    if max_stack > 0 {
-        instructions.push(Instruction::Drop);
+        if i + 1 < branches.len() || else_branch.is_some() {
+            println!("🔁 Entering ELSE block");
+            instructions.push(Instruction::Else);
+        }
+    }
+
+    if let Some(else_branch) = else_branch {
+        println!("🟥 [Else] Block has {} statements", else_branch.len());
+        let mut rects = branch_estimates(slot, else_branch, map).into_iter();
+        for stmt in else_branch {
+            println!("   🔴 Compiling ELSE stmt: {:?}", stmt);
+            if layout::measure(stmt).is_some() {
+                map.estimate = rects.next();
+            }
+            let stack = compile_node(stmt, instructions, map, local_map, local_types, wasm_locals, function_indices, function_signatures, states, stack_counter, local_idx);
+            map.estimate = None;
+            for _ in 0..stack {
+                instructions.push(Instruction::Drop);
+            }
+        }
+    }
+
+    println!("🧱 Closing IF/ELSE chain ({} branches)", branches.len());
+    for _ in &branches {
+        instructions.push(Instruction::End);
     }
 
+    map.adopt(first_element);
+    map.elements.push(ElementMap {
+        id: map.next_id(),
+        parent: None,
+        props: ElementProps::If(IfProps {
+            branches: conditions,
+            else_statements: else_branch.map(|body| body.len()),
+        }),
+        wasm_start: start as u32,
+        wasm_end: instructions.len() as u32,
+        pointer: None,
+        source: Some(map.locate(node.span())),
+        bounds: None, // depends on the branch taken at runtime
+        repeated: false,
+    });
+
     0
 }
 
@@ -1288,6 +3576,12 @@ pub fn infer_type(expr: &Expr, locals: &HashMap<String, String>) -> String {
     match expr {
         Expr::Literal(_) => "int".to_string(),
         Expr::StringLiteral(_) => "string".to_string(),
+        Expr::Array(elements) => format!("[{}]", elements.first().map_or("int".to_string(), |e| infer_type(e, locals))),
+        Expr::Lambda { params, returns_value, .. } => {
+            let param_types: Vec<String> = params.iter().map(|(_, ty)| ty.clone()).collect();
+            closure::func_type(&param_types, *returns_value)
+        }
+        Expr::Identifier(name) if locals.contains_key(name) => locals[name].clone(),
         Expr::Identifier(name) => {
             // Very basic logic: use variable naming heuristics
             if name.starts_with('s') {
@@ -1300,11 +3594,13 @@ pub fn infer_type(expr: &Expr, locals: &HashMap<String, String>) -> String {
     }
 }
 const BUILTIN_FUNCS: &[(u32, i32)] = &[
-    (0, -4),       // draw_rect(x, y, w, h) → 0
+    (0, -9),       // draw_rect(x, y, w, h, fill, stroke, stroke_width, radius, opacity) → 0
     (1, -2 + 1),   // gc_alloc(len, type) → ptr
-    (2, -4),       // draw_text(x, y, text, size) → 0
+    (2, -7),       // draw_text(x, y, text, size, color, font, opacity) → 0
     (3, -1),       // add_root(ptr) → 0
     (4, -1),       // drop_root(ptr) → 0
+    (6, -4),       // host_await(op, a, b, task) → 0
+    (9, -3 + 1),   // measure_text(ptr, len, font) → width
 ];
 
 